@@ -0,0 +1,31 @@
+//! Print a grid of which opcodes the CPU implements
+//!
+//! ```text
+//! cargo run --example opcode_coverage
+//! ```
+
+use gameboy::cpu::CPU;
+
+fn print_grid(title: &str, cb: bool) {
+  println!("{}", title);
+  print!("   ");
+  for low in 0..16 {
+    print!(" {:X}", low);
+  }
+  println!();
+  for high in 0..16u8 {
+    print!("{:X}x ", high);
+    for low in 0..16u8 {
+      let mark = if CPU::is_opcode_implemented(high << 4 | low, cb) { '#' } else { '.' };
+      print!(" {}", mark);
+    }
+    println!();
+  }
+  println!();
+}
+
+fn main() {
+  print_grid("unprefixed", false);
+  print_grid("0xCB prefixed", true);
+  println!("{}/512 opcodes implemented", CPU::implemented_opcode_count());
+}
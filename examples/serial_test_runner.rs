@@ -0,0 +1,41 @@
+//! Run a test ROM headlessly and print what it sends over the serial port
+//!
+//! Most test ROMs (e.g. blargg's cpu_instrs) report their results as text over the serial port,
+//! ending with "Passed" or "Failed". Mooneye's ROMs are understood too, see `gameboy::harness`.
+//!
+//! ```text
+//! cargo run --example serial_test_runner -- <rom> [max cycles]
+//! ```
+
+use {
+  gameboy::{
+    harness::{self, Verdict},
+    Cartridge,
+    LoadMode,
+  },
+  std::{env::args, fs, process},
+};
+
+fn main() {
+  let args: Vec<_> = args().collect();
+  if args.len() < 2 {
+    eprintln!("usage: {} <rom> [max cycles]", args[0]);
+    process::exit(2);
+  }
+  let max_cycles = args
+    .get(2)
+    .map(|x| x.parse().expect("max cycles should be a number"))
+    .unwrap_or(harness::DEFAULT_TIMEOUT_CYCLES);
+
+  let rom = fs::read(&args[1]).expect("failed to read rom");
+  let cartridge = Cartridge::from_bytes(&rom, LoadMode::Strict).expect("failed to load cartridge");
+  let report = harness::run(cartridge, max_cycles);
+
+  println!("{}", report.serial_text());
+  match &report.verdict {
+    Verdict::TimedOut => eprintln!("gave up after {} cycles", report.cycles),
+    Verdict::Error(error) => eprintln!("{}", error),
+    Verdict::Passed | Verdict::Failed => {}
+  }
+  process::exit(if report.verdict == Verdict::Passed { 0 } else { 1 });
+}
@@ -0,0 +1,143 @@
+//! The arithmetic behind the CPU's ALU opcodes, computing Z, N, H and C from the operands as
+//! described in the Pan Docs
+
+/// The flags an operation sets, `None` for those it leaves alone
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags {
+  pub z: Option<bool>,
+  pub n: Option<bool>,
+  pub h: Option<bool>,
+  pub c: Option<bool>,
+}
+
+/// ADD, `a + b`
+pub fn add8(a: u8, b: u8) -> (u8, Flags) {
+  adc8(a, b, false)
+}
+
+/// ADC, `a + b + carry`
+pub fn adc8(a: u8, b: u8, carry: bool) -> (u8, Flags) {
+  let carry = carry as u8;
+  let result = a.wrapping_add(b).wrapping_add(carry);
+  let flags = Flags {
+    z: Some(result == 0),
+    n: Some(false),
+    h: Some((a & 0x0F) + (b & 0x0F) + carry > 0x0F),
+    c: Some(a as u16 + b as u16 + carry as u16 > 0xFF),
+  };
+  (result, flags)
+}
+
+/// SUB and CP, `a - b`
+pub fn sub8(a: u8, b: u8) -> (u8, Flags) {
+  sbc8(a, b, false)
+}
+
+/// SBC, `a - b - carry`
+pub fn sbc8(a: u8, b: u8, carry: bool) -> (u8, Flags) {
+  let carry = carry as u8;
+  let result = a.wrapping_sub(b).wrapping_sub(carry);
+  let flags = Flags {
+    z: Some(result == 0),
+    n: Some(true),
+    h: Some(a & 0x0F < (b & 0x0F) + carry),
+    c: Some((a as u16) < b as u16 + carry as u16),
+  };
+  (result, flags)
+}
+
+/// INC r, C is left alone
+pub fn inc8(value: u8) -> (u8, Flags) {
+  let result = value.wrapping_add(1);
+  (result, Flags { z: Some(result == 0), n: Some(false), h: Some(value & 0x0F == 0x0F), c: None })
+}
+
+/// DEC r, C is left alone
+pub fn dec8(value: u8) -> (u8, Flags) {
+  let result = value.wrapping_sub(1);
+  (result, Flags { z: Some(result == 0), n: Some(true), h: Some(value & 0x0F == 0x00), c: None })
+}
+
+/// ADD HL,rr, the carries are out of bits 11 and 15 and Z is left alone
+pub fn add16(a: u16, b: u16) -> (u16, Flags) {
+  let (result, carry) = a.overflowing_add(b);
+  (result, Flags { z: None, n: Some(false), h: Some((a & 0x0FFF) + (b & 0x0FFF) > 0x0FFF), c: Some(carry) })
+}
+
+/// ADD SP,r8 and LD HL,SP+r8, `sp` plus the signed `offset`
+///
+/// The carries are those of an unsigned add of `offset` to the low byte of SP.
+pub fn add_sp(sp: u16, offset: u8) -> (u16, Flags) {
+  let flags = Flags {
+    z: Some(false),
+    n: Some(false),
+    h: Some((sp & 0x0F) + (offset & 0x0F) as u16 > 0x0F),
+    c: Some((sp & 0xFF) + offset as u16 > 0xFF),
+  };
+  (sp.wrapping_add(offset as i8 as u16), flags)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use quickcheck_macros::quickcheck;
+
+  /// The flags of `a ± b ± carry` worked out from the full width result instead: a bit carried into
+  /// bit n of the result is the one that differs from the xor of the operands' bit n
+  fn reference(a: u8, b: u8, carry: bool, subtract: bool) -> (u8, Flags) {
+    let (a, b, carry) = (a as i16, b as i16, carry as i16);
+    let wide = if subtract { a - b - carry } else { a + b + carry };
+    let result = wide as u8;
+    let flags = Flags {
+      z: Some(result == 0),
+      n: Some(subtract),
+      h: Some((a ^ b ^ wide) & 0x10 != 0),
+      c: Some((a ^ b ^ wide) & 0x100 != 0),
+    };
+    (result, flags)
+  }
+
+  #[test]
+  fn add_and_sub_match_reference_for_every_operand() {
+    for a in 0..=0xFF {
+      for b in 0..=0xFF {
+        for &carry in [false, true].iter() {
+          assert_eq!(adc8(a, b, carry), reference(a, b, carry, false), "0x{:02x} + 0x{:02x} + {}", a, b, carry);
+          assert_eq!(sbc8(a, b, carry), reference(a, b, carry, true), "0x{:02x} - 0x{:02x} - {}", a, b, carry);
+        }
+        assert_eq!(add8(a, b), adc8(a, b, false));
+        assert_eq!(sub8(a, b), sbc8(a, b, false));
+      }
+    }
+  }
+
+  #[test]
+  fn inc_and_dec_match_reference_for_every_operand() {
+    for value in 0..=0xFF {
+      let (sum, flags) = reference(value, 1, false, false);
+      assert_eq!(inc8(value), (sum, Flags { c: None, ..flags }));
+      let (difference, flags) = reference(value, 1, false, true);
+      assert_eq!(dec8(value), (difference, Flags { c: None, ..flags }));
+    }
+  }
+
+  #[quickcheck]
+  fn add16_carries_from_bits_11_and_15(a: u16, b: u16) -> bool {
+    let wide = a as u32 + b as u32;
+    let carries = a as u32 ^ b as u32 ^ wide;
+    add16(a, b)
+      == (
+        wide as u16,
+        Flags { z: None, n: Some(false), h: Some(carries & 0x1000 != 0), c: Some(carries & 0x10000 != 0) },
+      )
+  }
+
+  #[quickcheck]
+  fn add_sp_carries_like_an_unsigned_low_byte_add(sp: u16, offset: u8) -> bool {
+    let (low, flags) = add8(sp as u8, offset);
+    let (result, sp_flags) = add_sp(sp, offset);
+    result as u8 == low
+      && result == (sp as i32 + offset as i8 as i32) as u16
+      && sp_flags == Flags { z: Some(false), n: Some(false), ..flags }
+  }
+}
@@ -0,0 +1,1239 @@
+use {
+  crate::{
+    interrupt::Interrupt,
+    peripheral::Peripheral,
+    state::*,
+    util::*,
+  },
+  derivative::Derivative,
+  std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+  },
+};
+
+/// Volume envelope shared by the square and noise channels, NRx2
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Envelope {
+  /// Volume loaded on a trigger
+  initial_volume: u8,
+  increase: bool,
+  /// Envelope clocks between volume steps, 0 stops the envelope
+  period: u8,
+  volume: u8,
+  timer: u8,
+}
+
+impl Envelope {
+  const MAX_VOLUME: u8 = 15;
+
+  fn write(&mut self, value: u8) {
+    self.initial_volume = value >> 4;
+    self.increase = get_bit(value as u16, 3);
+    self.period = value & 0b111;
+  }
+
+  /// Whether NRx2 leaves the channel's DAC powered, which takes any of the upper 5 bits set
+  fn dac_enabled(value: u8) -> bool {
+    value & 0xF8 != 0
+  }
+
+  fn trigger(&mut self) {
+    self.volume = self.initial_volume;
+    self.timer = self.period;
+  }
+
+  /// Clocked at 64Hz by the frame sequencer
+  fn clock(&mut self) {
+    if self.period == 0 {
+      return;
+    }
+    self.timer = self.timer.saturating_sub(1);
+    if self.timer == 0 {
+      self.timer = self.period;
+      if self.increase && self.volume < Self::MAX_VOLUME {
+        self.volume += 1;
+      } else if !self.increase && self.volume > 0 {
+        self.volume -= 1;
+      }
+    }
+  }
+}
+
+impl SaveState for Envelope {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(&[self.initial_volume, self.increase as u8, self.period, self.volume, self.timer])
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    self.initial_volume = read_u8(r)?;
+    self.increase = read_bool(r)?;
+    self.period = read_u8(r)?;
+    self.volume = read_u8(r)?;
+    self.timer = read_u8(r)?;
+    Ok(())
+  }
+}
+
+/// Frequency sweep of channel 1, NR10
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Sweep {
+  /// Sweep clocks between frequency steps, 0 stops the sweep
+  period: u8,
+  negate: bool,
+  shift: u8,
+  timer: u8,
+  /// Copy of the frequency the sweep works from, taken on a trigger
+  shadow: u16,
+  enabled: bool,
+}
+
+impl Sweep {
+  fn write(&mut self, value: u8) {
+    self.period = (value >> 4) & 0b111;
+    self.negate = get_bit(value as u16, 3);
+    self.shift = value & 0b111;
+  }
+
+  /// The frequency after the next sweep step, which may overflow past `MAX_FREQUENCY`
+  fn next_frequency(&self) -> u16 {
+    let delta = self.shadow >> self.shift;
+    if self.negate {
+      self.shadow - delta
+    } else {
+      self.shadow + delta
+    }
+  }
+
+  /// A zero period reloads the timer with 8
+  fn reload(&mut self) {
+    self.timer = if self.period == 0 { 8 } else { self.period };
+  }
+}
+
+impl SaveState for Sweep {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(&[self.period, self.negate as u8, self.shift, self.timer])?;
+    write_u16(w, self.shadow)?;
+    write_bool(w, self.enabled)
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    self.period = read_u8(r)?;
+    self.negate = read_bool(r)?;
+    self.shift = read_u8(r)?;
+    self.timer = read_u8(r)?;
+    self.shadow = read_u16(r)?;
+    self.enabled = read_bool(r)?;
+    Ok(())
+  }
+}
+
+/// Channels 1 and 2, a square wave with a selectable duty cycle
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct SquareChannel {
+  enabled: bool,
+  dac_enabled: bool,
+  /// Index into `DUTY_PATTERNS`
+  duty: u8,
+  /// Step of the 8 step duty pattern being output
+  duty_step: u8,
+  length: u16,
+  length_enabled: bool,
+  frequency: u16,
+  /// Cycles until the next duty step
+  timer: u16,
+  envelope: Envelope,
+  /// Only channel 1 has a sweep unit, it stays disabled on channel 2
+  sweep: Sweep,
+}
+
+impl SquareChannel {
+  const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+  const MAX_LENGTH: u16        = 64;
+
+  fn period(&self) -> u16 {
+    (2048 - self.frequency) * 4
+  }
+
+  fn trigger(&mut self) {
+    self.enabled = self.dac_enabled;
+    if self.length == 0 {
+      self.length = Self::MAX_LENGTH;
+    }
+    self.timer = self.period();
+    self.envelope.trigger();
+
+    self.sweep.shadow = self.frequency;
+    self.sweep.reload();
+    self.sweep.enabled = self.sweep.period != 0 || self.sweep.shift != 0;
+    if self.sweep.shift != 0 && self.sweep.next_frequency() > Apu::MAX_FREQUENCY {
+      self.enabled = false;
+    }
+  }
+
+  fn step(&mut self, n_cycles: u16) {
+    let mut remaining = n_cycles;
+    while remaining >= self.timer {
+      remaining -= self.timer;
+      self.timer = self.period();
+      self.duty_step = (self.duty_step + 1) % 8;
+    }
+    self.timer -= remaining;
+  }
+
+  /// Clocked at 128Hz by the frame sequencer, only on channel 1
+  fn clock_sweep(&mut self) {
+    self.sweep.timer = self.sweep.timer.saturating_sub(1);
+    if self.sweep.timer != 0 {
+      return;
+    }
+    self.sweep.reload();
+    if !self.sweep.enabled || self.sweep.period == 0 {
+      return;
+    }
+    let frequency = self.sweep.next_frequency();
+    if frequency > Apu::MAX_FREQUENCY {
+      self.enabled = false;
+    } else if self.sweep.shift != 0 {
+      self.sweep.shadow = frequency;
+      self.frequency = frequency;
+      // the new frequency is checked for overflow straight away too
+      if self.sweep.next_frequency() > Apu::MAX_FREQUENCY {
+        self.enabled = false;
+      }
+    }
+  }
+
+  fn output(&self) -> u8 {
+    if get_bit(Self::DUTY_PATTERNS[self.duty as usize] as u16, 7 - self.duty_step) {
+      self.envelope.volume
+    } else {
+      0
+    }
+  }
+}
+
+impl SaveState for SquareChannel {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(&[self.enabled as u8, self.dac_enabled as u8, self.duty, self.duty_step])?;
+    write_u16(w, self.length)?;
+    write_bool(w, self.length_enabled)?;
+    write_u16(w, self.frequency)?;
+    write_u16(w, self.timer)?;
+    self.envelope.write_state(w)?;
+    self.sweep.write_state(w)
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    self.enabled = read_bool(r)?;
+    self.dac_enabled = read_bool(r)?;
+    self.duty = read_u8(r)?;
+    self.duty_step = read_u8(r)?;
+    self.length = read_u16(r)?;
+    self.length_enabled = read_bool(r)?;
+    self.frequency = read_u16(r)?;
+    self.timer = read_u16(r)?;
+    self.envelope.read_state(r)?;
+    self.sweep.read_state(r)
+  }
+}
+
+/// Channel 3, which plays back the 32 4-bit samples in wave RAM
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct WaveChannel {
+  enabled: bool,
+  /// NR30 bit 7
+  dac_enabled: bool,
+  length: u16,
+  length_enabled: bool,
+  /// NR32 bits 5-6, 0 mutes the channel and 1-3 play at 100%, 50% and 25%
+  volume_code: u8,
+  frequency: u16,
+  /// Cycles until the next sample
+  timer: u16,
+  /// Index of the sample being played
+  position: u8,
+}
+
+impl WaveChannel {
+  const MAX_LENGTH: u16 = 256;
+  const SAMPLES: u8     = 32;
+
+  fn period(&self) -> u16 {
+    (2048 - self.frequency) * 2
+  }
+
+  fn trigger(&mut self) {
+    self.enabled = self.dac_enabled;
+    if self.length == 0 {
+      self.length = Self::MAX_LENGTH;
+    }
+    self.timer = self.period();
+    self.position = 0;
+  }
+
+  fn step(&mut self, n_cycles: u16) {
+    let mut remaining = n_cycles;
+    while remaining >= self.timer {
+      remaining -= self.timer;
+      self.timer = self.period();
+      self.position = (self.position + 1) % Self::SAMPLES;
+    }
+    self.timer -= remaining;
+  }
+
+  fn output(&self, wave_ram: &[u8; Apu::WAVE_RAM_SIZE]) -> u8 {
+    let byte = wave_ram[self.position as usize / 2];
+    let sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+    match self.volume_code {
+      0 => 0,
+      code => sample >> (code - 1),
+    }
+  }
+}
+
+impl SaveState for WaveChannel {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    write_bool(w, self.enabled)?;
+    write_bool(w, self.dac_enabled)?;
+    write_u16(w, self.length)?;
+    write_bool(w, self.length_enabled)?;
+    write_u8(w, self.volume_code)?;
+    write_u16(w, self.frequency)?;
+    write_u16(w, self.timer)?;
+    write_u8(w, self.position)
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    self.enabled = read_bool(r)?;
+    self.dac_enabled = read_bool(r)?;
+    self.length = read_u16(r)?;
+    self.length_enabled = read_bool(r)?;
+    self.volume_code = read_u8(r)?;
+    self.frequency = read_u16(r)?;
+    self.timer = read_u16(r)?;
+    self.position = read_u8(r)?;
+    Ok(())
+  }
+}
+
+/// Channel 4, pseudo-random noise from a linear feedback shift register
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct NoiseChannel {
+  enabled: bool,
+  dac_enabled: bool,
+  length: u16,
+  length_enabled: bool,
+  envelope: Envelope,
+  /// NR43 bits 4-7
+  clock_shift: u8,
+  /// NR43 bit 3, feeding back into bit 6 as well for a shorter, more tonal 7 bit sequence
+  short_mode: bool,
+  /// NR43 bits 0-2, index into `DIVISORS`
+  divisor_code: u8,
+  /// Cycles until the next shift
+  timer: u16,
+  lfsr: u16,
+}
+
+impl NoiseChannel {
+  const DIVISORS: [u16; 8]  = [8, 16, 32, 48, 64, 80, 96, 112];
+  const MAX_LENGTH: u16     = 64;
+  /// Shift clocks of 14 and 15 stop the LFSR
+  const MAX_CLOCK_SHIFT: u8 = 13;
+
+  fn period(&self) -> u16 {
+    Self::DIVISORS[self.divisor_code as usize] << self.clock_shift.min(Self::MAX_CLOCK_SHIFT)
+  }
+
+  fn trigger(&mut self) {
+    self.enabled = self.dac_enabled;
+    if self.length == 0 {
+      self.length = Self::MAX_LENGTH;
+    }
+    self.timer = self.period();
+    self.envelope.trigger();
+    self.lfsr = 0x7FFF;
+  }
+
+  fn step(&mut self, n_cycles: u16) {
+    let mut remaining = n_cycles;
+    while remaining >= self.timer {
+      remaining -= self.timer;
+      self.timer = self.period();
+      if self.clock_shift <= Self::MAX_CLOCK_SHIFT {
+        self.shift();
+      }
+    }
+    self.timer -= remaining;
+  }
+
+  fn shift(&mut self) {
+    let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+    self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+    if self.short_mode {
+      self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+    }
+  }
+
+  fn output(&self) -> u8 {
+    if self.lfsr & 1 == 0 {
+      self.envelope.volume
+    } else {
+      0
+    }
+  }
+}
+
+impl SaveState for NoiseChannel {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    write_bool(w, self.enabled)?;
+    write_bool(w, self.dac_enabled)?;
+    write_u16(w, self.length)?;
+    write_bool(w, self.length_enabled)?;
+    self.envelope.write_state(w)?;
+    w.write_all(&[self.clock_shift, self.short_mode as u8, self.divisor_code])?;
+    write_u16(w, self.timer)?;
+    write_u16(w, self.lfsr)
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    self.enabled = read_bool(r)?;
+    self.dac_enabled = read_bool(r)?;
+    self.length = read_u16(r)?;
+    self.length_enabled = read_bool(r)?;
+    self.envelope.read_state(r)?;
+    self.clock_shift = read_u8(r)?;
+    self.short_mode = read_bool(r)?;
+    self.divisor_code = read_u8(r)?;
+    self.timer = read_u16(r)?;
+    self.lfsr = read_u16(r)?;
+    Ok(())
+  }
+}
+
+/// One of the four sound channels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+  Square1,
+  Square2,
+  Wave,
+  Noise,
+}
+
+impl Channel {
+  pub const ALL: [Channel; 4] = [Channel::Square1, Channel::Square2, Channel::Wave, Channel::Noise];
+}
+
+/// A snapshot of a sound channel for debugging tools, see `Apu::channel_state`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelState {
+  /// Playing, as reported in NR52
+  pub enabled: bool,
+  pub dac_enabled: bool,
+  /// Pitch of the tone in Hz, for the noise channel the rate the LFSR shifts at
+  pub frequency_hz: f32,
+  /// The envelope's volume 0-15, for the wave channel 15 scaled by its output level
+  pub volume: u8,
+  /// Duty cycle 0-3 of the square channels, 12.5%, 25%, 50% and 75%
+  pub duty: Option<u8>,
+  /// Length counter steps left, silencing the channel when they run out if `length_enabled`
+  pub length: u16,
+  pub length_enabled: bool,
+  /// Left out of the mix by `Apu::set_channel_muted`
+  pub muted: bool,
+  /// The channel's output at each of the last `Apu::WAVEFORM_SIZE` samples, oldest first, in
+  /// -1.0..=1.0 before mixing
+  pub waveform: Vec<f32>,
+}
+
+/// The audio processing unit (NR10-NR52 and wave RAM)
+///
+/// Two square channels, a wave channel and a noise channel are mixed into interleaved stereo
+/// samples at `sample_rate`. A frame sequencer clocked at 512Hz drives their length counters,
+/// envelopes and channel 1's sweep.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Apu {
+  /// NR10-NR52 as last written, reads apply `READ_MASKS` over them
+  #[derivative(Debug = "ignore")]
+  registers: [u8; Apu::REGISTER_COUNT],
+  #[derivative(Debug = "ignore")]
+  wave_ram: [u8; Apu::WAVE_RAM_SIZE],
+  /// NR52 bit 7, while off every register but NR52 and wave RAM ignores writes
+  powered: bool,
+  square1: SquareChannel,
+  square2: SquareChannel,
+  wave: WaveChannel,
+  noise: NoiseChannel,
+  /// Cycles elapsed towards the next frame sequencer step
+  sequencer_cycles: u16,
+  /// Step of the 8 step frame sequencer clocked next
+  sequencer_step: u8,
+  /// Stereo samples produced per second
+  sample_rate: u32,
+  /// Cycles elapsed towards the next sample, scaled by `sample_rate`
+  sample_cycles: u32,
+  /// Interleaved left and right samples waiting to be drained
+  #[derivative(Debug = "ignore")]
+  #[cfg_attr(feature = "serde", serde(skip))]
+  samples: VecDeque<f32>,
+  /// Channels left out of the mix, a choice of the frontend's kept across power cycles
+  #[cfg_attr(feature = "serde", serde(skip))]
+  muted: [bool; 4],
+  /// Each channel's recent output, for `channel_state`
+  #[derivative(Debug = "ignore")]
+  #[cfg_attr(feature = "serde", serde(skip))]
+  waveforms: [VecDeque<f32>; 4],
+}
+
+impl Default for Apu {
+  fn default() -> Self {
+    Self {
+      registers: [0; Self::REGISTER_COUNT],
+      wave_ram: [0; Self::WAVE_RAM_SIZE],
+      powered: false,
+      square1: SquareChannel::default(),
+      square2: SquareChannel::default(),
+      wave: WaveChannel::default(),
+      noise: NoiseChannel::default(),
+      sequencer_cycles: 0,
+      sequencer_step: 0,
+      sample_rate: Self::DEFAULT_SAMPLE_RATE,
+      sample_cycles: 0,
+      samples: VecDeque::with_capacity(Self::BUFFER_SIZE),
+      muted: [false; 4],
+      waveforms: Default::default(),
+    }
+  }
+}
+
+impl Apu {
+  /// Stereo samples produced per second unless changed with `set_sample_rate`
+  pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+  pub const CHANNELS: usize          = 2;
+  /// Samples of each channel's output kept for `channel_state`
+  pub const WAVEFORM_SIZE: usize     = 512;
+
+  pub const NR10_ADDRESS: u16   = 0xFF10;
+  pub const NR11_ADDRESS: u16   = 0xFF11;
+  pub const NR12_ADDRESS: u16   = 0xFF12;
+  pub const NR13_ADDRESS: u16   = 0xFF13;
+  pub const NR14_ADDRESS: u16   = 0xFF14;
+  pub const NR21_ADDRESS: u16   = 0xFF16;
+  pub const NR22_ADDRESS: u16   = 0xFF17;
+  pub const NR23_ADDRESS: u16   = 0xFF18;
+  pub const NR24_ADDRESS: u16   = 0xFF19;
+  pub const NR30_ADDRESS: u16   = 0xFF1A;
+  pub const NR31_ADDRESS: u16   = 0xFF1B;
+  pub const NR32_ADDRESS: u16   = 0xFF1C;
+  pub const NR33_ADDRESS: u16   = 0xFF1D;
+  pub const NR34_ADDRESS: u16   = 0xFF1E;
+  pub const NR41_ADDRESS: u16   = 0xFF20;
+  pub const NR42_ADDRESS: u16   = 0xFF21;
+  pub const NR43_ADDRESS: u16   = 0xFF22;
+  pub const NR44_ADDRESS: u16   = 0xFF23;
+  pub const NR50_ADDRESS: u16   = 0xFF24;
+  pub const NR51_ADDRESS: u16   = 0xFF25;
+  pub const NR52_ADDRESS: u16   = 0xFF26;
+  pub const UNUSED_START: u16   = 0xFF27;
+  pub const UNUSED_END: u16     = 0xFF2F;
+  pub const WAVE_RAM_START: u16 = 0xFF30;
+  pub const WAVE_RAM_END: u16   = 0xFF3F;
+  pub const START_ADDRESS: u16  = Self::NR10_ADDRESS;
+  pub const END_ADDRESS: u16    = Self::WAVE_RAM_END;
+
+  const REGISTER_COUNT: usize = 0x20;
+  const WAVE_RAM_SIZE: usize  = 0x10;
+  /// Bits of the sound registers from NR10 on that always read as 1, being write-only or unused
+  const READ_MASKS: [u8; Self::REGISTER_COUNT] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+  ];
+  /// Sound registers as the DMG bios leaves them, NR52 first so the rest aren't ignored. NR14
+  /// retriggers channel 1, which is still playing the boot chime.
+  const POST_BOOT_REGISTERS: [(u16, u8); 21] = [
+    (Self::NR52_ADDRESS, 0xF1),
+    (Self::NR10_ADDRESS, 0x80),
+    (Self::NR11_ADDRESS, 0xBF),
+    (Self::NR12_ADDRESS, 0xF3),
+    (Self::NR13_ADDRESS, 0xFF),
+    (Self::NR14_ADDRESS, 0xBF),
+    (Self::NR21_ADDRESS, 0x3F),
+    (Self::NR22_ADDRESS, 0x00),
+    (Self::NR23_ADDRESS, 0xFF),
+    (Self::NR24_ADDRESS, 0xBF),
+    (Self::NR30_ADDRESS, 0x7F),
+    (Self::NR31_ADDRESS, 0xFF),
+    (Self::NR32_ADDRESS, 0x9F),
+    (Self::NR33_ADDRESS, 0xFF),
+    (Self::NR34_ADDRESS, 0xBF),
+    (Self::NR41_ADDRESS, 0xFF),
+    (Self::NR42_ADDRESS, 0x00),
+    (Self::NR43_ADDRESS, 0x00),
+    (Self::NR44_ADDRESS, 0xBF),
+    (Self::NR50_ADDRESS, 0x77),
+    (Self::NR51_ADDRESS, 0xF3),
+  ];
+
+  const CPU_CLOCK_HZ: u32 = 4_194_304;
+  /// Samples buffered before the oldest are dropped, a little under 0.1s at the default rate
+  const BUFFER_SIZE: usize = 4096 * Self::CHANNELS;
+  /// The frame sequencer steps at 512Hz
+  const SEQUENCER_PERIOD: u16   = 8192;
+  const MAX_FREQUENCY: u16      = 2047;
+  const TRIGGER_BIT_N: u8       = 7;
+  const LENGTH_ENABLE_BIT_N: u8 = 6;
+  const POWER_BIT_N: u8         = 7;
+
+  /// Set the sound registers to the values the DMG bios leaves them at
+  pub fn post_boot(&mut self) {
+    for &(address, value) in Self::POST_BOOT_REGISTERS.iter() {
+      self.write(address, value);
+    }
+  }
+
+  /// Produce `sample_rate` stereo samples per second from now on, e.g. the rate of the output device
+  ///
+  /// # Panics
+  /// if `sample_rate` is 0 or faster than the CPU clock
+  pub fn set_sample_rate(&mut self, sample_rate: u32) {
+    assert!(sample_rate > 0 && sample_rate <= Self::CPU_CLOCK_HZ, "unsupported sample rate {}", sample_rate);
+    self.sample_rate = sample_rate;
+    self.sample_cycles = 0;
+  }
+
+  pub fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  /// Advance the APU `n_cycles`, buffering any samples that fall due
+  pub fn step(&mut self, n_cycles: u8) {
+    let mut remaining = n_cycles as u32;
+    while remaining > 0 {
+      // run up to the next sample so it sees the channels as they are at that point
+      let until_sample = (Self::CPU_CLOCK_HZ - self.sample_cycles).div_ceil(self.sample_rate);
+      let n = remaining.min(until_sample);
+      self.run(n as u16);
+      remaining -= n;
+
+      self.sample_cycles += n * self.sample_rate;
+      if self.sample_cycles >= Self::CPU_CLOCK_HZ {
+        self.sample_cycles -= Self::CPU_CLOCK_HZ;
+        let outputs = self.channel_outputs();
+        for (waveform, &output) in self.waveforms.iter_mut().zip(outputs.iter()) {
+          if waveform.len() == Self::WAVEFORM_SIZE {
+            waveform.pop_front();
+          }
+          waveform.push_back(output);
+        }
+        let (left, right) = self.mix(outputs);
+        self.push(left);
+        self.push(right);
+      }
+    }
+  }
+
+  /// Advance the channels and frame sequencer `n_cycles`
+  fn run(&mut self, n_cycles: u16) {
+    if !self.powered {
+      return;
+    }
+    self.square1.step(n_cycles);
+    self.square2.step(n_cycles);
+    self.wave.step(n_cycles);
+    self.noise.step(n_cycles);
+
+    self.sequencer_cycles += n_cycles;
+    while self.sequencer_cycles >= Self::SEQUENCER_PERIOD {
+      self.sequencer_cycles -= Self::SEQUENCER_PERIOD;
+      self.clock_sequencer();
+    }
+  }
+
+  /// Length counters are clocked on even steps, the sweep on steps 2 and 6 and envelopes on step 7
+  fn clock_sequencer(&mut self) {
+    let step = self.sequencer_step;
+    if step.is_multiple_of(2) {
+      Self::clock_length(&mut self.square1.length, self.square1.length_enabled, &mut self.square1.enabled);
+      Self::clock_length(&mut self.square2.length, self.square2.length_enabled, &mut self.square2.enabled);
+      Self::clock_length(&mut self.wave.length, self.wave.length_enabled, &mut self.wave.enabled);
+      Self::clock_length(&mut self.noise.length, self.noise.length_enabled, &mut self.noise.enabled);
+    }
+    if step == 2 || step == 6 {
+      self.square1.clock_sweep();
+    }
+    if step == 7 {
+      self.square1.envelope.clock();
+      self.square2.envelope.clock();
+      self.noise.envelope.clock();
+    }
+    self.sequencer_step = (step + 1) % 8;
+  }
+
+  /// Count down a channel's length, silencing it when it runs out
+  fn clock_length(length: &mut u16, length_enabled: bool, enabled: &mut bool) {
+    if length_enabled && *length > 0 {
+      *length -= 1;
+      if *length == 0 {
+        *enabled = false;
+      }
+    }
+  }
+
+  /// The current output of each channel's DAC in -1.0..=1.0, muted or not
+  fn channel_outputs(&self) -> [f32; 4] {
+    if !self.powered {
+      return [0.0; 4];
+    }
+    // each DAC maps 0-15 onto 1.0 to -1.0
+    let dac = |enabled: bool, digital: u8| if enabled { 1.0 - digital as f32 / 7.5 } else { 0.0 };
+    [
+      dac(self.square1.enabled && self.square1.dac_enabled, self.square1.output()),
+      dac(self.square2.enabled && self.square2.dac_enabled, self.square2.output()),
+      dac(self.wave.enabled && self.wave.dac_enabled, self.wave.output(&self.wave_ram)),
+      dac(self.noise.enabled && self.noise.dac_enabled, self.noise.output()),
+    ]
+  }
+
+  /// Mix the channels' `outputs` into the left and right terminals, each in -1.0..=1.0
+  fn mix(&self, mut outputs: [f32; 4]) -> (f32, f32) {
+    if !self.powered {
+      return (0.0, 0.0);
+    }
+    for (output, &muted) in outputs.iter_mut().zip(self.muted.iter()) {
+      if muted {
+        *output = 0.0;
+      }
+    }
+
+    let nr50 = self.register(Self::NR50_ADDRESS);
+    let nr51 = self.register(Self::NR51_ADDRESS);
+    // NR51 bits 0-3 send channels 1-4 to the right terminal and bits 4-7 to the left
+    let terminal = |shift: u8, volume: u8| {
+      let sum: f32 = outputs
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| get_bit(nr51 as u16, shift + i as u8))
+        .map(|(_, x)| x)
+        .sum();
+      sum / outputs.len() as f32 * (volume + 1) as f32 / 8.0
+    };
+    (terminal(4, (nr50 >> 4) & 0b111), terminal(0, nr50 & 0b111))
+  }
+
+  fn push(&mut self, sample: f32) {
+    if self.samples.len() == Self::BUFFER_SIZE {
+      self.samples.pop_front();
+    }
+    self.samples.push_back(sample);
+  }
+
+  /// Number of interleaved samples waiting to be drained
+  pub fn buffered(&self) -> usize {
+    self.samples.len()
+  }
+
+  /// Move up to `out.len()` buffered interleaved samples into `out`, filling the rest with silence
+  ///
+  /// # Returns
+  /// the number of buffered samples written
+  pub fn drain(&mut self, out: &mut [f32]) -> usize {
+    let n = out.len().min(self.samples.len());
+    for (x, sample) in out.iter_mut().zip(self.samples.drain(..n)) {
+      *x = sample;
+    }
+    for x in out[n..].iter_mut() {
+      *x = 0.0;
+    }
+    n
+  }
+
+  /// Whether each of channels 1-4 is playing, as reported in the lower bits of NR52
+  pub fn channels_enabled(&self) -> [bool; 4] {
+    [self.square1.enabled, self.square2.enabled, self.wave.enabled, self.noise.enabled]
+  }
+
+  /// Leave `channel` out of the mix, or put it back, without the game noticing
+  pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+    self.muted[channel as usize] = muted;
+  }
+
+  /// A snapshot of `channel` as it is now, with its recent output
+  pub fn channel_state(&self, channel: Channel) -> ChannelState {
+    let hz = |period: u16, steps: u32| Self::CPU_CLOCK_HZ as f32 / (period as u32 * steps) as f32;
+    let square = |square: &SquareChannel| ChannelState {
+      enabled: square.enabled,
+      dac_enabled: square.dac_enabled,
+      frequency_hz: hz(square.period(), 8),
+      volume: square.envelope.volume,
+      duty: Some(square.duty),
+      length: square.length,
+      length_enabled: square.length_enabled,
+      muted: false,
+      waveform: vec![],
+    };
+    let state = match channel {
+      Channel::Square1 => square(&self.square1),
+      Channel::Square2 => square(&self.square2),
+      Channel::Wave => ChannelState {
+        enabled: self.wave.enabled,
+        dac_enabled: self.wave.dac_enabled,
+        frequency_hz: hz(self.wave.period(), WaveChannel::SAMPLES as u32),
+        volume: match self.wave.volume_code {
+          0 => 0,
+          code => Envelope::MAX_VOLUME >> (code - 1),
+        },
+        duty: None,
+        length: self.wave.length,
+        length_enabled: self.wave.length_enabled,
+        muted: false,
+        waveform: vec![],
+      },
+      Channel::Noise => ChannelState {
+        enabled: self.noise.enabled,
+        dac_enabled: self.noise.dac_enabled,
+        frequency_hz: hz(self.noise.period(), 1),
+        volume: self.noise.envelope.volume,
+        duty: None,
+        length: self.noise.length,
+        length_enabled: self.noise.length_enabled,
+        muted: false,
+        waveform: vec![],
+      },
+    };
+    ChannelState {
+      muted: self.muted[channel as usize],
+      waveform: self.waveforms[channel as usize].iter().cloned().collect(),
+      ..state
+    }
+  }
+
+  fn register(&self, address: u16) -> u8 {
+    self.registers[(address - Self::START_ADDRESS) as usize]
+  }
+
+  /// The 11 bit frequency split over NRx3 and the lower bits of NRx4
+  fn frequency(&self, nrx3_address: u16) -> u16 {
+    ((self.register(nrx3_address + 1) & 0b111) as u16) << 8 | self.register(nrx3_address) as u16
+  }
+
+  fn power_off(&mut self) {
+    // wave RAM survives
+    *self = Self {
+      wave_ram: self.wave_ram,
+      sample_rate: self.sample_rate,
+      sample_cycles: self.sample_cycles,
+      samples: std::mem::take(&mut self.samples),
+      muted: self.muted,
+      waveforms: std::mem::take(&mut self.waveforms),
+      ..Self::default()
+    };
+  }
+}
+
+impl Memory for Apu {
+  fn read(&self, address: u16) -> u8 {
+    match address {
+      Self::NR52_ADDRESS => {
+        let status = self
+          .channels_enabled()
+          .iter()
+          .enumerate()
+          .fold(0, |status, (i, &enabled)| set_bit(status, i as u8, enabled));
+        set_bit(status, Self::POWER_BIT_N, self.powered) as u8 | Self::READ_MASKS[(address - Self::START_ADDRESS) as usize]
+      }
+      Self::NR10_ADDRESS..=Self::NR52_ADDRESS => {
+        self.register(address) | Self::READ_MASKS[(address - Self::START_ADDRESS) as usize]
+      }
+      Self::WAVE_RAM_START..=Self::WAVE_RAM_END => self.wave_ram[(address - Self::WAVE_RAM_START) as usize],
+      Self::UNUSED_START..=Self::UNUSED_END => 0xFF,
+      _ => unreachable!("address '0x{:x}' is not an APU register", address),
+    }
+  }
+
+  fn write(&mut self, address: u16, value: u8) {
+    match address {
+      Self::NR52_ADDRESS => {
+        let powered = get_bit(value as u16, Self::POWER_BIT_N);
+        if self.powered && !powered {
+          self.power_off();
+        }
+        if !self.powered && powered {
+          self.sequencer_step = 0;
+        }
+        self.powered = powered;
+      }
+      Self::WAVE_RAM_START..=Self::WAVE_RAM_END => self.wave_ram[(address - Self::WAVE_RAM_START) as usize] = value,
+      _ if !self.powered => {}
+      Self::NR10_ADDRESS..=Self::NR51_ADDRESS => {
+        self.registers[(address - Self::START_ADDRESS) as usize] = value;
+        let trigger = get_bit(value as u16, Self::TRIGGER_BIT_N);
+        let length_enabled = get_bit(value as u16, Self::LENGTH_ENABLE_BIT_N);
+        match address {
+          Self::NR10_ADDRESS => self.square1.sweep.write(value),
+          Self::NR11_ADDRESS => {
+            self.square1.duty = value >> 6;
+            self.square1.length = SquareChannel::MAX_LENGTH - (value & 0x3F) as u16;
+          }
+          Self::NR12_ADDRESS => {
+            self.square1.envelope.write(value);
+            self.square1.dac_enabled = Envelope::dac_enabled(value);
+            self.square1.enabled &= self.square1.dac_enabled;
+          }
+          Self::NR13_ADDRESS => self.square1.frequency = self.frequency(Self::NR13_ADDRESS),
+          Self::NR14_ADDRESS => {
+            self.square1.frequency = self.frequency(Self::NR13_ADDRESS);
+            self.square1.length_enabled = length_enabled;
+            if trigger {
+              self.square1.trigger();
+            }
+          }
+          Self::NR21_ADDRESS => {
+            self.square2.duty = value >> 6;
+            self.square2.length = SquareChannel::MAX_LENGTH - (value & 0x3F) as u16;
+          }
+          Self::NR22_ADDRESS => {
+            self.square2.envelope.write(value);
+            self.square2.dac_enabled = Envelope::dac_enabled(value);
+            self.square2.enabled &= self.square2.dac_enabled;
+          }
+          Self::NR23_ADDRESS => self.square2.frequency = self.frequency(Self::NR23_ADDRESS),
+          Self::NR24_ADDRESS => {
+            self.square2.frequency = self.frequency(Self::NR23_ADDRESS);
+            self.square2.length_enabled = length_enabled;
+            if trigger {
+              self.square2.trigger();
+            }
+          }
+          Self::NR30_ADDRESS => {
+            self.wave.dac_enabled = get_bit(value as u16, 7);
+            self.wave.enabled &= self.wave.dac_enabled;
+          }
+          Self::NR31_ADDRESS => self.wave.length = WaveChannel::MAX_LENGTH - value as u16,
+          Self::NR32_ADDRESS => self.wave.volume_code = (value >> 5) & 0b11,
+          Self::NR33_ADDRESS => self.wave.frequency = self.frequency(Self::NR33_ADDRESS),
+          Self::NR34_ADDRESS => {
+            self.wave.frequency = self.frequency(Self::NR33_ADDRESS);
+            self.wave.length_enabled = length_enabled;
+            if trigger {
+              self.wave.trigger();
+            }
+          }
+          Self::NR41_ADDRESS => self.noise.length = NoiseChannel::MAX_LENGTH - (value & 0x3F) as u16,
+          Self::NR42_ADDRESS => {
+            self.noise.envelope.write(value);
+            self.noise.dac_enabled = Envelope::dac_enabled(value);
+            self.noise.enabled &= self.noise.dac_enabled;
+          }
+          Self::NR43_ADDRESS => {
+            self.noise.clock_shift = value >> 4;
+            self.noise.short_mode = get_bit(value as u16, 3);
+            self.noise.divisor_code = value & 0b111;
+          }
+          Self::NR44_ADDRESS => {
+            self.noise.length_enabled = length_enabled;
+            if trigger {
+              self.noise.trigger();
+            }
+          }
+          // NR50 and NR51 are read straight from `registers` when mixing
+          _ => {}
+        }
+      }
+      Self::UNUSED_START..=Self::UNUSED_END => {}
+      _ => unreachable!("address '0x{:x}' is not an APU register", address),
+    }
+  }
+}
+
+impl Peripheral for Apu {
+  fn claims(&self, address: u16) -> bool {
+    (Self::START_ADDRESS..=Self::END_ADDRESS).contains(&address)
+  }
+
+  fn tick(&mut self, n_cycles: u8, double_speed: bool) -> Option<Interrupt> {
+    // sound runs at the same rate whatever speed the CPU is at
+    self.step(if double_speed { n_cycles / 2 } else { n_cycles });
+    None
+  }
+}
+
+impl SaveState for Apu {
+  /// The buffered samples and sample rate belong to the frontend and aren't written
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(&self.registers)?;
+    w.write_all(&self.wave_ram)?;
+    write_bool(w, self.powered)?;
+    self.square1.write_state(w)?;
+    self.square2.write_state(w)?;
+    self.wave.write_state(w)?;
+    self.noise.write_state(w)?;
+    write_u16(w, self.sequencer_cycles)?;
+    write_u8(w, self.sequencer_step)?;
+    write_u32(w, self.sample_cycles)
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    r.read_exact(&mut self.registers)?;
+    r.read_exact(&mut self.wave_ram)?;
+    self.powered = read_bool(r)?;
+    self.square1.read_state(r)?;
+    self.square2.read_state(r)?;
+    self.wave.read_state(r)?;
+    self.noise.read_state(r)?;
+    self.sequencer_cycles = read_u16(r)?;
+    self.sequencer_step = read_u8(r)?;
+    // a state saved at another sample rate may be a little ahead
+    self.sample_cycles = read_u32(r)?.min(Self::CPU_CLOCK_HZ - 1);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  /// An APU powered on with every channel sent to both terminals at full volume
+  fn powered_apu() -> Apu {
+    let mut apu = Apu::default();
+    apu.write(Apu::NR52_ADDRESS, 0x80);
+    apu.write(Apu::NR50_ADDRESS, 0x77);
+    apu.write(Apu::NR51_ADDRESS, 0xFF);
+    apu
+  }
+
+  fn run(apu: &mut Apu, n_cycles: u32) {
+    for _ in 0..n_cycles / 4 {
+      apu.step(4);
+    }
+  }
+
+  #[test]
+  fn samples_are_produced_at_the_sample_rate() {
+    let mut apu = Apu::default();
+    // a 64th of a second
+    for _ in 0..Apu::CPU_CLOCK_HZ / 64 / 128 {
+      apu.step(128);
+    }
+    assert_eq!(apu.buffered(), 689 * Apu::CHANNELS);
+
+    let mut apu = Apu::default();
+    apu.set_sample_rate(48000);
+    for _ in 0..Apu::CPU_CLOCK_HZ / 64 / 128 {
+      apu.step(128);
+    }
+    assert_eq!(apu.buffered(), 750 * Apu::CHANNELS);
+  }
+
+  #[test]
+  fn drain_underruns_to_silence() {
+    let mut apu = Apu::default();
+    apu.samples.extend([0.5, -0.5]);
+    let mut out = [1.0; 4];
+    assert_eq!(apu.drain(&mut out), 2);
+    assert_eq!(out, [0.5, -0.5, 0.0, 0.0]);
+    assert_eq!(apu.buffered(), 0);
+  }
+
+  #[test]
+  fn oldest_samples_are_dropped_when_full() {
+    let mut apu = Apu::default();
+    for i in 0..Apu::BUFFER_SIZE + 2 {
+      apu.push(i as f32);
+    }
+    let mut out = [0.0; 1];
+    apu.drain(&mut out);
+    assert_eq!(out, [2.0]);
+  }
+
+  #[test]
+  fn restored_state_keeps_sample_timing() {
+    let mut apu = Apu::default();
+    apu.step(80);
+    let mut state = vec![];
+    apu.write_state(&mut state).unwrap();
+
+    let mut restored = Apu::default();
+    restored.read_state(&mut state.as_slice()).unwrap();
+    // 80 + 20 cycles is just over a sample
+    restored.step(20);
+    assert_eq!(restored.buffered(), Apu::CHANNELS);
+  }
+
+  #[test]
+  fn square_channel_plays_its_duty_cycle() {
+    let mut apu = powered_apu();
+    apu.write(Apu::NR21_ADDRESS, 0x80); // 50% duty
+    apu.write(Apu::NR22_ADDRESS, 0xF0); // full volume, no envelope
+    // 1024Hz, 4 * (2048 - 1920) cycles per duty step
+    apu.write(Apu::NR23_ADDRESS, 0x80);
+    apu.write(Apu::NR24_ADDRESS, 0x87);
+    assert_eq!(apu.channels_enabled(), [false, true, false, false]);
+    assert_eq!(apu.read(Apu::NR52_ADDRESS), 0xF2);
+
+    let mut out = vec![0.0; 2 * 44100 / 100];
+    run(&mut apu, Apu::CPU_CLOCK_HZ / 100);
+    apu.drain(&mut out);
+    let high = out.iter().filter(|&&x| x > 0.0).count();
+    let low = out.iter().filter(|&&x| x < 0.0).count();
+    assert!(high > 0 && low > 0);
+    // half the time each way, give or take the edges
+    assert!((high as i32 - low as i32).abs() < out.len() as i32 / 10, "{} high, {} low", high, low);
+  }
+
+  #[test]
+  fn length_counter_silences_channel() {
+    let mut apu = powered_apu();
+    apu.write(Apu::NR42_ADDRESS, 0xF0);
+    // 63 leaves a length of 1, gone at the first length clock
+    apu.write(Apu::NR41_ADDRESS, 0x3F);
+    apu.write(Apu::NR44_ADDRESS, 0xC0);
+    assert!(apu.channels_enabled()[3]);
+    run(&mut apu, Apu::SEQUENCER_PERIOD as u32);
+    assert!(!apu.channels_enabled()[3]);
+
+    // without length enabled it plays on
+    apu.write(Apu::NR41_ADDRESS, 0x3F);
+    apu.write(Apu::NR44_ADDRESS, 0x80);
+    run(&mut apu, 8 * Apu::SEQUENCER_PERIOD as u32);
+    assert!(apu.channels_enabled()[3]);
+  }
+
+  #[test]
+  fn envelope_fades_volume() {
+    let mut apu = powered_apu();
+    // volume 2, decreasing every envelope clock
+    apu.write(Apu::NR12_ADDRESS, 0x21);
+    apu.write(Apu::NR14_ADDRESS, 0x80);
+    assert_eq!(apu.square1.envelope.volume, 2);
+    // the envelope is clocked once per 8 sequencer steps
+    run(&mut apu, 8 * Apu::SEQUENCER_PERIOD as u32);
+    assert_eq!(apu.square1.envelope.volume, 1);
+    run(&mut apu, 16 * Apu::SEQUENCER_PERIOD as u32);
+    assert_eq!(apu.square1.envelope.volume, 0);
+  }
+
+  #[test]
+  fn sweep_overflow_disables_channel_1() {
+    let mut apu = powered_apu();
+    apu.write(Apu::NR12_ADDRESS, 0xF0);
+    // period 1, adding the frequency shifted right by 1
+    apu.write(Apu::NR10_ADDRESS, 0x11);
+    apu.write(Apu::NR13_ADDRESS, 0x00);
+    apu.write(Apu::NR14_ADDRESS, 0x84);
+    assert!(apu.channels_enabled()[0]);
+    // 0x400 -> 0x600, which would overflow on the next step
+    run(&mut apu, 4 * Apu::SEQUENCER_PERIOD as u32);
+    assert!(!apu.channels_enabled()[0]);
+
+    // overflowing on the trigger disables it straight away
+    apu.write(Apu::NR13_ADDRESS, 0xFF);
+    apu.write(Apu::NR14_ADDRESS, 0x87);
+    assert!(!apu.channels_enabled()[0]);
+  }
+
+  #[test]
+  fn wave_channel_plays_wave_ram() {
+    let mut apu = powered_apu();
+    for address in Apu::WAVE_RAM_START..=Apu::WAVE_RAM_END {
+      apu.write(address, 0xF0);
+    }
+    assert_eq!(apu.read(Apu::WAVE_RAM_START), 0xF0);
+    apu.write(Apu::NR30_ADDRESS, 0x80);
+    apu.write(Apu::NR32_ADDRESS, 0x20); // 100%
+    apu.write(Apu::NR34_ADDRESS, 0x80);
+    assert_eq!(apu.wave.output(&apu.wave_ram), 0x0F);
+    apu.wave.step(apu.wave.period());
+    assert_eq!(apu.wave.output(&apu.wave_ram), 0x00);
+
+    // the DAC switching off silences it
+    apu.write(Apu::NR30_ADDRESS, 0x00);
+    assert!(!apu.channels_enabled()[2]);
+  }
+
+  #[test]
+  fn channel_state_reports_the_square_channel_and_muting_silences_it() {
+    let mut apu = powered_apu();
+    apu.write(Apu::NR21_ADDRESS, 0xFF); // 75% duty, length 1
+    apu.write(Apu::NR22_ADDRESS, 0xC0);
+    apu.write(Apu::NR23_ADDRESS, 0x80);
+    apu.write(Apu::NR24_ADDRESS, 0x87);
+    run(&mut apu, Apu::CPU_CLOCK_HZ / 1000);
+
+    let state = apu.channel_state(Channel::Square2);
+    assert!(state.enabled && state.dac_enabled && !state.muted);
+    assert_eq!(state.frequency_hz, 1024.0);
+    assert_eq!((state.volume, state.duty, state.length, state.length_enabled), (12, Some(3), 1, false));
+    assert!(state.waveform.iter().any(|&x| x > 0.0) && state.waveform.iter().any(|&x| x < 0.0));
+    assert!(apu.channel_state(Channel::Square1).waveform.iter().all(|&x| x == 0.0));
+    assert_eq!(apu.channel_state(Channel::Wave).duty, None);
+
+    // muted, the mix is silent but the channel still plays to its scope
+    apu.set_channel_muted(Channel::Square2, true);
+    apu.drain(&mut vec![0.0; Apu::BUFFER_SIZE]);
+    let mut out = vec![1.0; 2 * 44100 / 1000];
+    run(&mut apu, Apu::CPU_CLOCK_HZ / 1000);
+    apu.drain(&mut out);
+    assert!(out.iter().all(|&x| x == 0.0));
+    let state = apu.channel_state(Channel::Square2);
+    assert!(state.muted && state.waveform.iter().any(|&x| x != 0.0));
+
+    // muting is kept across a power cycle
+    apu.write(Apu::NR52_ADDRESS, 0x00);
+    apu.write(Apu::NR52_ADDRESS, 0x80);
+    assert!(apu.channel_state(Channel::Square2).muted);
+    apu.set_channel_muted(Channel::Square2, false);
+    assert!(!apu.channel_state(Channel::Square2).muted);
+  }
+
+  #[test]
+  fn noise_lfsr_produces_both_levels() {
+    let mut apu = powered_apu();
+    apu.write(Apu::NR42_ADDRESS, 0xF0);
+    apu.write(Apu::NR43_ADDRESS, 0x00);
+    apu.write(Apu::NR44_ADDRESS, 0x80);
+    let mut seen = [false; 2];
+    for _ in 0..64 {
+      apu.noise.step(apu.noise.period());
+      seen[(apu.noise.output() != 0) as usize] = true;
+    }
+    assert_eq!(seen, [true, true]);
+  }
+
+  #[test]
+  fn powering_off_clears_registers_but_not_wave_ram() {
+    let mut apu = powered_apu();
+    apu.write(Apu::NR22_ADDRESS, 0xF0);
+    apu.write(Apu::NR24_ADDRESS, 0x80);
+    apu.write(Apu::WAVE_RAM_START, 0x12);
+
+    apu.write(Apu::NR52_ADDRESS, 0x00);
+    assert_eq!(apu.read(Apu::NR52_ADDRESS), 0x70);
+    assert_eq!(apu.read(Apu::NR50_ADDRESS), 0x00);
+    assert_eq!(apu.read(Apu::WAVE_RAM_START), 0x12);
+    // writes are ignored until it's powered back on
+    apu.write(Apu::NR50_ADDRESS, 0x77);
+    assert_eq!(apu.read(Apu::NR50_ADDRESS), 0x00);
+  }
+
+  #[test]
+  fn post_boot_leaves_channel_1_playing() {
+    let mut apu = Apu::default();
+    apu.post_boot();
+    assert_eq!(apu.read(Apu::NR52_ADDRESS), 0xF1);
+    assert_eq!(apu.read(Apu::NR10_ADDRESS), 0x80);
+    assert_eq!(apu.read(Apu::NR51_ADDRESS), 0xF3);
+  }
+}
@@ -1,16 +1,27 @@
+// failure_derive emits its impls inside an anonymous const
+#![allow(non_local_definitions)]
+
 use {
   std::{
+    convert::TryFrom,
     io::{
       self,
       BufRead,
       prelude::*,
     },
-    fs::{File},
+    fs::{self, File},
     env::{args},
+    path::Path,
   },
   gameboy::{
+    disasm::{disassemble, register_name, Radix},
+    watch::{Access, Watchpoint, WatchKind},
+    ppu::PPU,
+    cartridge::MbcKind,
     Gameboy,
-    Cartridge
+    Cartridge,
+    LoadMode,
+    StepResult,
   },
   failure::{
     Fail,
@@ -20,12 +31,18 @@ use {
 
 #[derive(Debug, Fail)]
 enum AppError {
-  #[fail(display = "failed to parse cartridge")]
-  FailedToParseCartridge,
   #[fail(display = "not enough arguments")]
   NotEnoughArguments,
 }
 
+/// Settings that last for the whole debugging session
+#[derive(Debug, Default)]
+struct Session {
+  /// Radix operands are written in when disassembling
+  radix: Radix,
+  /// Write hardware registers by name when disassembling and dumping memory
+  symbols: bool,
+}
 
 fn main() -> Result<(), Error> {
   let args: Vec<_> = args().collect();
@@ -40,102 +57,242 @@ fn main() -> Result<(), Error> {
     let mut bios = [0; gameboy::mmu::MMU::BIOS_SIZE];
     let mut file = File::open(&args[1])?;
     file.read_to_end(&mut buffer)?;
-    bios.copy_from_slice(&mut buffer[0..gameboy::mmu::MMU::BIOS_SIZE]);
+    bios.copy_from_slice(&buffer[0..gameboy::mmu::MMU::BIOS_SIZE]);
     bios
   };
 
   let cartridge = {
-    let mut buffer = vec![];
-    let mut file = File::open(&args[2])?;
-    file.read_to_end(&mut buffer)?;
-    match Cartridge::maybe_from_bytes(buffer.as_ref()) {
-      Some(cartridge) => cartridge,
-      _ => return Err(AppError::FailedToParseCartridge.into())
+    let cartridge = Cartridge::from_file(&args[2], LoadMode::Lenient)?;
+    if let MbcKind::Unknown(cartridge_type) = cartridge.info().mbc {
+      eprintln!("warning: unknown cartridge type 0x{:02x}, loading with MBC1 banking", cartridge_type);
     }
+    cartridge
   };
 
   let stdin = io::stdin();
   let stdin_lock = stdin.lock();
   let mut reader = io::BufReader::new(stdin_lock);
   let mut gameboy = Gameboy::new(bios);
+  gameboy.mmu.cartridge = Some(cartridge);
+  // battery saves live next to the rom
+  let save_path = Path::new(&args[2]).with_extension("sav");
+  if let Ok(save) = fs::read(&save_path) {
+    if let Err(e) = gameboy.load_save_ram(&save) {
+      eprintln!("warning: ignoring {}: {}", save_path.display(), e);
+    }
+  }
   let mut buffer = String::new();
+  let mut session = Session::default();
 
   loop {
     print!(">");
     io::stdout().flush()?;
     buffer.clear();
-    reader.read_line(&mut buffer)?;
+    if reader.read_line(&mut buffer)? == 0 {
+      break;
+    }
     let commands: Vec<_> = buffer.trim().split(" ").collect();
-    if execute_command(commands.as_ref(), &mut gameboy)? {
+    if execute_command(commands.as_ref(), &mut gameboy, &mut session)? {
       break;
     }
   }
+
+  if gameboy.save_ram_dirty() {
+    if let Some(save) = gameboy.save_ram() {
+      fs::write(&save_path, save)?;
+    }
+  }
   Ok(())
 }
 
 
-fn execute_command(commands: &[&str], gameboy: &mut Gameboy) -> Result<bool, Error> {
+fn execute_command(commands: &[&str], gameboy: &mut Gameboy, session: &mut Session) -> Result<bool, Error> {
   match commands[0] {
     "s" => match & commands[1..]{
       [] => {
-        gameboy.step();
-        execute_command(&["p"], gameboy)?;
-        execute_command(&["mpc"], gameboy)?;
+        let (_, instruction) = gameboy.step_debug()?;
+        println!("0x{:04x}: {}", instruction.address, instruction.display(session.radix).with_symbols(session.symbols));
+        execute_command(&["p"], gameboy, session)?;
+        execute_command(&["mpc"], gameboy, session)?;
         Ok(false)
       }
-      [n] if n.clone().chars().all(char::is_numeric) => {
+      [n] if n.chars().all(char::is_numeric) => {
         let n = n.parse().unwrap();
         for _ in 0..n {
-          gameboy.step();
+          gameboy.step()?;
         }
-        execute_command(&["p"], gameboy)?;
-        execute_command(&["mpc"], gameboy)?;
+        execute_command(&["p"], gameboy, session)?;
+        execute_command(&["mpc"], gameboy, session)?;
         Ok(false)
       }
       _ => {
         Ok(false)
       }
     }
+    "b" | "bp" | "break" => {
+      match commands.get(1) {
+        Some(address_str) => gameboy.set_breakpoint(parse_address(address_str)?),
+        None => {
+          let mut breakpoints: Vec<_> = gameboy.breakpoints().iter().collect();
+          breakpoints.sort();
+          for address in breakpoints {
+            println!("0x{:04x}", address);
+          }
+        }
+      }
+      Ok(false)
+    }
+    "bd" | "delete" => {
+      if let Some(address_str) = commands.get(1) {
+        let address = parse_address(address_str)?;
+        if !gameboy.clear_breakpoint(address) {
+          println!("no breakpoint at 0x{:04x}", address);
+        }
+      }
+      Ok(false)
+    }
+    "wp" | "watch" => {
+      match &commands[1..] {
+        [] => {
+          for watchpoint in gameboy.watchpoints() {
+            match watchpoint.value {
+              Some(value) => println!("0x{:04x} {:?} = 0x{:02x}", watchpoint.address, watchpoint.kind, value),
+              None => println!("0x{:04x} {:?}", watchpoint.address, watchpoint.kind),
+            }
+          }
+        }
+        [address_str, rest @ ..] => {
+          let kind = match rest.first() {
+            None | Some(&"rw") => WatchKind::ReadWrite,
+            Some(&"r") => WatchKind::Read,
+            Some(&"w") => WatchKind::Write,
+            Some(_) => {
+              println!("usage: wp <address> [r|w|rw] [value]");
+              return Ok(false);
+            }
+          };
+          let value = match rest.get(1) {
+            Some(value_str) => Some(u8::try_from(parse_address(value_str)?)?),
+            None => None,
+          };
+          gameboy.set_watchpoint(Watchpoint { address: parse_address(address_str)?, kind, value });
+        }
+      }
+      Ok(false)
+    }
+    "wd" | "unwatch" => {
+      if let Some(address_str) = commands.get(1) {
+        let address = parse_address(address_str)?;
+        if !gameboy.clear_watchpoints(address) {
+          println!("no watchpoint at 0x{:04x}", address);
+        }
+      }
+      Ok(false)
+    }
+    "cheat" => {
+      match &commands[1..] {
+        [] | ["list"] => {
+          for (n, cheat) in gameboy.cheats().iter().enumerate() {
+            println!("{} {}{}", n, cheat.text, if cheat.enabled { "" } else { " (disabled)" });
+          }
+        }
+        ["add", code] => println!("cheat {}", gameboy.add_cheat(code)?),
+        ["rm", n] => {
+          gameboy.remove_cheat(n.parse()?)?;
+        }
+        ["on", n] => gameboy.set_cheat_enabled(n.parse()?, true)?,
+        ["off", n] => gameboy.set_cheat_enabled(n.parse()?, false)?,
+        _ => println!("usage: cheat [list|add <code>|rm <n>|on <n>|off <n>]"),
+      }
+      Ok(false)
+    }
+    "c" | "continue" => {
+      // step off the breakpoint we're stopped at, if any
+      gameboy.step()?;
+      loop {
+        match gameboy.step_or_break()? {
+          StepResult::Executed(_) => {}
+          StepResult::Breakpoint(pc) => {
+            println!("breakpoint at 0x{:04x}", pc);
+            break;
+          }
+          StepResult::Watchpoint(_, hit) => {
+            let access = if hit.access == Access::Read { "read" } else { "write" };
+            println!("watchpoint: {} 0x{:02x} at 0x{:04x}, stopped at 0x{:04x}", access, hit.value, hit.address, gameboy.cpu.pc);
+            break;
+          }
+        }
+      }
+      execute_command(&["mpc"], gameboy, session)
+    }
+    "da" | "dis" | "disassemble" => {
+      let mut address = match commands.get(1) {
+        Some(address_str) => parse_address(address_str)?,
+        None => gameboy.cpu.pc,
+      };
+      let n: usize = match commands.get(2) {
+        Some(n) => n.parse()?,
+        None => 1,
+      };
+      for _ in 0..n {
+        let (instruction, len) = disassemble(address, &gameboy.mmu);
+        println!("0x{:04x}: {}", address, instruction.display(session.radix).with_symbols(session.symbols));
+        address = address.wrapping_add(len);
+      }
+      Ok(false)
+    }
+    "radix" => {
+      match commands.get(1) {
+        Some(&"hex") => session.radix = Radix::Hex,
+        Some(&"dec") => session.radix = Radix::Decimal,
+        _ => println!("usage: radix <hex|dec>"),
+      }
+      Ok(false)
+    }
+    "symbols" => {
+      match commands.get(1) {
+        Some(&"on") => session.symbols = true,
+        Some(&"off") => session.symbols = false,
+        _ => println!("usage: symbols <on|off>"),
+      }
+      Ok(false)
+    }
+    "i" | "info" => {
+      match gameboy.cartridge_info() {
+        Some(info) => println!("{:#?}", info),
+        None => println!("no cartridge loaded"),
+      }
+      Ok(false)
+    }
     "d" | "display" => {
-      let d: Vec<_> = gameboy.display().collect();
-      dbg!(d);
+      // one character per pixel, lightest to darkest
+      for row in gameboy.display().chunks(PPU::SCREEN_WIDTH) {
+        let line: String = row.iter().map(|&shade| [' ', '.', '+', '#'][shade as usize & 0b11]).collect();
+        println!("{}", line);
+      }
       Ok(false)
     }
     "mpc" => {
-      execute_command(&["m", format!("{}", gameboy.cpu.pc).as_str()], gameboy)
+      execute_command(&["m", format!("{}", gameboy.cpu.pc).as_str()], gameboy, session)
     }
     "m" | "mem" => match &commands[1..] {
       [] => {
         Ok(false)
       }
       [address_str] => {
-        let address = if address_str.chars().nth(0) == Some('0') && address_str.chars().nth(1) == Some('x') {
-          u16::from_str_radix(&address_str[2..], 16)?
-        } else {
-          address_str.parse::<u16>()?
-        };
-        println!("0x{:x} = {:x}", address, gameboy.read(address));
+        let address = parse_address(address_str)?;
+        match register_name(address).filter(|_| session.symbols) {
+          Some(name) => println!("0x{:x} ({}) = {:x}", address, name, gameboy.peek(address)),
+          None => println!("0x{:x} = {:x}", address, gameboy.peek(address)),
+        }
         Ok(false)
       }
       [start_address_str, end_address_str] => {
-        let start_address = if start_address_str.chars().nth(0) == Some('0') &&
-          start_address_str.chars().nth(1) == Some('x')
-        {
-          u16::from_str_radix(&start_address_str[2..], 16)?
-        } else {
-          start_address_str.parse::<u16>()?
-        };
-
-        let end_address = if end_address_str.chars().nth(0) == Some('0') &&
-          end_address_str.chars().nth(1) == Some('x')
-        {
-          u16::from_str_radix(&end_address_str[2..], 16)?
-        } else {
-          end_address_str.parse::<u16>()?
-        };
+        let start_address = parse_address(start_address_str)?;
+        let end_address = parse_address(end_address_str)?;
 
         for address in start_address..end_address {
-          execute_command(&["m", format!("{}", address).as_str()], gameboy)?;
+          execute_command(&["m", format!("{}", address).as_str()], gameboy, session)?;
         }
         Ok(false)
       }
@@ -154,3 +311,11 @@ fn execute_command(commands: &[&str], gameboy: &mut Gameboy) -> Result<bool, Err
     }
   }
 }
+
+/// Parse an address given either in decimal or as `0x` prefixed hex
+fn parse_address(address_str: &str) -> Result<u16, Error> {
+  match address_str.strip_prefix("0x") {
+    Some(hex) => Ok(u16::from_str_radix(hex, 16)?),
+    None => Ok(address_str.parse::<u16>()?),
+  }
+}
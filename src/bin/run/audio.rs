@@ -0,0 +1,131 @@
+//! Sound played through ALSA, loaded as the frontend starts so it still runs, silently, on machines
+//! without it
+
+use std::{
+  ffi::c_void,
+  mem,
+  os::raw::{c_char, c_int, c_long, c_uint, c_ulong},
+  ptr,
+  time::Duration,
+};
+
+extern "C" {
+  fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+  fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+  fn dlclose(handle: *mut c_void) -> c_int;
+}
+
+const RTLD_NOW: c_int = 2;
+
+type Pcm = c_void;
+
+const SND_PCM_STREAM_PLAYBACK: c_int = 0;
+/// Writes return -EAGAIN rather than blocking once the buffer is full
+const SND_PCM_NONBLOCK: c_int = 1;
+const SND_PCM_FORMAT_FLOAT_LE: c_int = 14;
+const SND_PCM_ACCESS_RW_INTERLEAVED: c_int = 3;
+/// What a write returns with the buffer full, -EAGAIN
+const EAGAIN_RESULT: c_long = -11;
+
+type OpenFn = unsafe extern "C" fn(*mut *mut Pcm, *const c_char, c_int, c_int) -> c_int;
+type SetParamsFn = unsafe extern "C" fn(*mut Pcm, c_int, c_int, c_uint, c_uint, c_int, c_uint) -> c_int;
+type WriteiFn = unsafe extern "C" fn(*mut Pcm, *const c_void, c_ulong) -> c_long;
+type DelayFn = unsafe extern "C" fn(*mut Pcm, *mut c_long) -> c_int;
+type RecoverFn = unsafe extern "C" fn(*mut Pcm, c_int, c_int) -> c_int;
+type CloseFn = unsafe extern "C" fn(*mut Pcm) -> c_int;
+
+/// The default ALSA device, playing interleaved stereo f32 samples
+pub struct Audio {
+  library: *mut c_void,
+  pcm: *mut Pcm,
+  writei: WriteiFn,
+  delay: DelayFn,
+  recover: RecoverFn,
+  close: CloseFn,
+}
+
+impl Audio {
+  const CHANNELS: usize = 2;
+
+  /// Open the default device at `sample_rate`, buffering up to `buffer` of sound
+  ///
+  /// # Returns
+  /// `None` if ALSA isn't installed or the device couldn't be opened
+  pub fn open(sample_rate: u32, buffer: Duration) -> Option<Audio> {
+    unsafe {
+      let library = dlopen(b"libasound.so.2\0".as_ptr() as *const c_char, RTLD_NOW);
+      if library.is_null() {
+        return None;
+      }
+      let audio = Self::open_with(library, sample_rate, buffer);
+      if audio.is_none() {
+        dlclose(library);
+      }
+      audio
+    }
+  }
+
+  unsafe fn open_with(library: *mut c_void, sample_rate: u32, buffer: Duration) -> Option<Audio> {
+    let open: OpenFn = mem::transmute(Self::symbol(library, b"snd_pcm_open\0")?);
+    let set_params: SetParamsFn = mem::transmute(Self::symbol(library, b"snd_pcm_set_params\0")?);
+    let writei: WriteiFn = mem::transmute(Self::symbol(library, b"snd_pcm_writei\0")?);
+    let delay: DelayFn = mem::transmute(Self::symbol(library, b"snd_pcm_delay\0")?);
+    let recover: RecoverFn = mem::transmute(Self::symbol(library, b"snd_pcm_recover\0")?);
+    let close: CloseFn = mem::transmute(Self::symbol(library, b"snd_pcm_close\0")?);
+
+    let mut pcm = ptr::null_mut();
+    let device = b"default\0".as_ptr() as *const c_char;
+    if open(&mut pcm, device, SND_PCM_STREAM_PLAYBACK, SND_PCM_NONBLOCK) < 0 {
+      return None;
+    }
+    let latency = buffer.as_micros() as c_uint;
+    let channels = Self::CHANNELS as c_uint;
+    if set_params(pcm, SND_PCM_FORMAT_FLOAT_LE, SND_PCM_ACCESS_RW_INTERLEAVED, channels, sample_rate, 1, latency) < 0 {
+      close(pcm);
+      return None;
+    }
+    Some(Audio { library, pcm, writei, delay, recover, close })
+  }
+
+  unsafe fn symbol(library: *mut c_void, name: &[u8]) -> Option<*mut c_void> {
+    let symbol = dlsym(library, name.as_ptr() as *const c_char);
+    if symbol.is_null() { None } else { Some(symbol) }
+  }
+
+  /// Queue interleaved stereo `samples` to be played, dropping whatever doesn't fit in the buffer
+  pub fn queue(&mut self, samples: &[f32]) {
+    let mut samples = &samples[..samples.len() - samples.len() % Self::CHANNELS];
+    while !samples.is_empty() {
+      let n_frames = (samples.len() / Self::CHANNELS) as c_ulong;
+      let written = unsafe { (self.writei)(self.pcm, samples.as_ptr() as *const c_void, n_frames) };
+      match written {
+        0 | EAGAIN_RESULT => return,
+        // after an underrun the device has to be prepared again before it plays
+        _ if written < 0 => {
+          if unsafe { (self.recover)(self.pcm, written as c_int, 1) } < 0 {
+            return;
+          }
+        }
+        _ => samples = &samples[written as usize * Self::CHANNELS..],
+      }
+    }
+  }
+
+  /// Interleaved samples queued that haven't been played yet
+  pub fn queued(&self) -> usize {
+    let mut delay = 0;
+    match unsafe { (self.delay)(self.pcm, &mut delay) } {
+      0 => delay.max(0) as usize * Self::CHANNELS,
+      _ => 0,
+    }
+  }
+}
+
+impl Drop for Audio {
+  fn drop(&mut self) {
+    unsafe {
+      (self.close)(self.pcm);
+      dlclose(self.library);
+    }
+  }
+}
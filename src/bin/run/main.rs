@@ -0,0 +1,170 @@
+// failure_derive emits its impls inside an anonymous const
+#![allow(non_local_definitions)]
+
+mod audio;
+mod window;
+
+use {
+  std::{
+    io,
+    env::args,
+    time::Duration,
+  },
+  gameboy::{
+    disasm::Radix,
+    pacer::{FramePacer, Speed},
+    ppu::Frame,
+    trace::Tracer,
+    cartridge::MbcKind,
+    Button,
+    Gameboy,
+    Cartridge,
+    LoadMode,
+  },
+  failure::{
+    Fail,
+    Error,
+  },
+  crate::{
+    audio::Audio,
+    window::{Event, Key, Window},
+  },
+};
+
+#[derive(Debug, Fail)]
+enum AppError {
+  #[fail(display = "not enough arguments")]
+  NotEnoughArguments,
+}
+
+const DEFAULT_SCALE: usize = 3;
+const SAMPLE_RATE: u32 = 44100;
+/// Sound the host's audio device can hold
+const AUDIO_BUFFER: Duration = Duration::from_millis(100);
+/// Sound kept queued ahead of the host, pacing emulation when it plays at normal speed
+const AUDIO_LATENCY: Duration = Duration::from_millis(50);
+
+/// The button `key` presses
+fn button(key: Key) -> Option<Button> {
+  match key {
+    Key::Right => Some(Button::Right),
+    Key::Left => Some(Button::Left),
+    Key::Up => Some(Button::Up),
+    Key::Down => Some(Button::Down),
+    Key::X => Some(Button::A),
+    Key::Z => Some(Button::B),
+    Key::Backspace => Some(Button::Select),
+    Key::Return => Some(Button::Start),
+    _ => None,
+  }
+}
+
+/// Play a cartridge in a window at the speed of the real hardware, with its sound
+///
+/// The arrow keys are the D-pad, X is A, Z is B, Return is Start and Backspace is Select. Holding
+/// Tab runs in turbo, Escape quits. Sound goes to the default ALSA device, if there is one.
+///
+/// `--scale=N` draws each pixel N pixels square. `--speed=N` runs N times as fast, `--uncapped` as
+/// fast as possible, and `--mute` leaves the sound off. `--trace` streams a line per executed
+/// instruction to stderr, `--trace=N` only every Nth instruction. `--decimal` writes the traced
+/// operands in decimal rather than hex.
+fn main() -> Result<(), Error> {
+  let args: Vec<_> = args().collect();
+
+  let mut trace_every = None;
+  let mut radix = Radix::Hex;
+  let mut speed = Speed::default();
+  let mut scale = DEFAULT_SCALE;
+  let mut mute = false;
+  let mut rom_path = None;
+  for arg in &args[1..] {
+    match arg.as_str() {
+      "--trace" => trace_every = Some(1),
+      "--decimal" => radix = Radix::Decimal,
+      "--uncapped" => speed = Speed::Uncapped,
+      "--mute" => mute = true,
+      arg if arg.starts_with("--trace=") => trace_every = Some(arg["--trace=".len()..].parse()?),
+      arg if arg.starts_with("--speed=") => speed = Speed::Multiplier(arg["--speed=".len()..].parse()?),
+      arg if arg.starts_with("--scale=") => scale = arg["--scale=".len()..].parse::<usize>()?.max(1),
+      arg => rom_path = Some(arg),
+    }
+  }
+
+  let rom_path = match rom_path {
+    Some(rom_path) => rom_path,
+    None => {
+      println!(
+        "usage: {} [--scale=N] [--speed=N|--uncapped] [--mute] [--trace[=N]] [--decimal] <rom>",
+        args[0]
+      );
+      return Err(AppError::NotEnoughArguments.into())
+    }
+  };
+
+  let cartridge = {
+    let cartridge = Cartridge::from_file(rom_path, LoadMode::Lenient)?;
+    if let MbcKind::Unknown(cartridge_type) = cartridge.info().mbc {
+      eprintln!("warning: unknown cartridge type 0x{:02x}, loading with MBC1 banking", cartridge_type);
+    }
+    cartridge
+  };
+  let title = cartridge.info().title;
+
+  let mut gameboy = Gameboy::new_skip_bios(cartridge);
+  let stderr = io::stderr();
+  let mut tracer = trace_every.map(|every| Tracer::new(stderr.lock(), every).with_radix(radix));
+
+  let mut window = Window::open(&title, Frame::WIDTH, Frame::HEIGHT, scale)?;
+  let mut pacer = FramePacer::default();
+  pacer.set_speed(speed);
+  let mut audio = if mute { None } else { Audio::open(SAMPLE_RATE, AUDIO_BUFFER) };
+  match audio {
+    Some(_) => {
+      gameboy.set_audio_sample_rate(SAMPLE_RATE);
+      pacer.sync_to_audio(SAMPLE_RATE, AUDIO_LATENCY);
+    }
+    None if !mute => eprintln!("warning: couldn't open an ALSA device, playing without sound"),
+    None => {}
+  }
+  // a frame's worth of samples with plenty to spare
+  let mut samples = vec![0.0; SAMPLE_RATE as usize / 10 * 2];
+
+  let mut frame_cycles = 0;
+  loop {
+    if let Some(tracer) = tracer.as_mut() {
+      tracer.trace(&gameboy)?;
+    }
+    frame_cycles += gameboy.step()? as u32;
+    if frame_cycles < FramePacer::FRAME_CYCLES {
+      continue;
+    }
+    frame_cycles -= FramePacer::FRAME_CYCLES;
+
+    while let Some(event) = window.poll_event() {
+      match event {
+        Event::Closed | Event::Key(Key::Escape, true) => return Ok(()),
+        Event::Key(Key::Tab, held) => {
+          if pacer.turbo() != held {
+            pacer.toggle_turbo();
+          }
+        }
+        Event::Key(key, true) => button(key).into_iter().for_each(|x| gameboy.press_button(x)),
+        Event::Key(key, false) => button(key).into_iter().for_each(|x| gameboy.release_button(x)),
+      }
+    }
+
+    if gameboy.is_frame_ready() {
+      window.draw(&gameboy.frame().to_rgba8888());
+      gameboy.take_frame();
+    }
+
+    match audio.as_mut() {
+      Some(audio) => {
+        let produced = gameboy.drain_audio(&mut samples);
+        audio.queue(&samples[..produced]);
+        pacer.wait_for_audio(audio.queued());
+      }
+      None => pacer.wait(),
+    }
+  }
+}
@@ -0,0 +1,335 @@
+//! A window drawn through Xlib, which every X11 desktop already has, so the frontend needs no crates
+//!
+//! Frames are scaled up by a whole factor and put to the window as a 24 bit TrueColor image. Key
+//! auto repeat is made detectable, so a held key sends one press and one release.
+
+use {
+  failure::Fail,
+  std::{
+    ffi::{c_void, CString},
+    os::raw::{c_char, c_int, c_long, c_uint, c_ulong},
+    ptr,
+  },
+};
+
+type Display = c_void;
+type Visual = c_void;
+type Gc = *mut c_void;
+type XWindow = c_ulong;
+type Atom = c_ulong;
+type KeySym = c_ulong;
+
+/// The leading fields of Xlib's XImage, which is only ever handled by pointer
+#[repr(C)]
+struct XImage {
+  width: c_int,
+  height: c_int,
+  xoffset: c_int,
+  format: c_int,
+  data: *mut c_char,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct XKeyEvent {
+  kind: c_int,
+  serial: c_ulong,
+  send_event: c_int,
+  display: *mut Display,
+  window: XWindow,
+  root: XWindow,
+  subwindow: XWindow,
+  time: c_ulong,
+  x: c_int,
+  y: c_int,
+  x_root: c_int,
+  y_root: c_int,
+  state: c_uint,
+  keycode: c_uint,
+  same_screen: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct XClientMessageEvent {
+  kind: c_int,
+  serial: c_ulong,
+  send_event: c_int,
+  display: *mut Display,
+  window: XWindow,
+  message_type: Atom,
+  format: c_int,
+  data: [c_long; 5],
+}
+
+#[repr(C)]
+union XEvent {
+  kind: c_int,
+  key: XKeyEvent,
+  client_message: XClientMessageEvent,
+  pad: [c_long; 24],
+}
+
+const KEY_PRESS: c_int = 2;
+const KEY_RELEASE: c_int = 3;
+const CLIENT_MESSAGE: c_int = 33;
+
+const KEY_PRESS_MASK: c_long = 1 << 0;
+const KEY_RELEASE_MASK: c_long = 1 << 1;
+
+const Z_PIXMAP: c_int = 2;
+
+#[link(name = "X11")]
+extern "C" {
+  fn XOpenDisplay(name: *const c_char) -> *mut Display;
+  fn XCloseDisplay(display: *mut Display) -> c_int;
+  fn XDefaultScreen(display: *mut Display) -> c_int;
+  fn XDefaultDepth(display: *mut Display, screen: c_int) -> c_int;
+  fn XDefaultVisual(display: *mut Display, screen: c_int) -> *mut Visual;
+  fn XDefaultGC(display: *mut Display, screen: c_int) -> Gc;
+  fn XRootWindow(display: *mut Display, screen: c_int) -> XWindow;
+  fn XBlackPixel(display: *mut Display, screen: c_int) -> c_ulong;
+  fn XCreateSimpleWindow(
+    display: *mut Display,
+    parent: XWindow,
+    x: c_int,
+    y: c_int,
+    width: c_uint,
+    height: c_uint,
+    border_width: c_uint,
+    border: c_ulong,
+    background: c_ulong,
+  ) -> XWindow;
+  fn XDestroyWindow(display: *mut Display, window: XWindow) -> c_int;
+  fn XStoreName(display: *mut Display, window: XWindow, name: *const c_char) -> c_int;
+  fn XSelectInput(display: *mut Display, window: XWindow, mask: c_long) -> c_int;
+  fn XInternAtom(display: *mut Display, name: *const c_char, only_if_exists: c_int) -> Atom;
+  fn XSetWMProtocols(display: *mut Display, window: XWindow, protocols: *mut Atom, count: c_int) -> c_int;
+  fn XkbSetDetectableAutoRepeat(display: *mut Display, detectable: c_int, supported: *mut c_int) -> c_int;
+  fn XMapWindow(display: *mut Display, window: XWindow) -> c_int;
+  fn XCreateImage(
+    display: *mut Display,
+    visual: *mut Visual,
+    depth: c_uint,
+    format: c_int,
+    offset: c_int,
+    data: *mut c_char,
+    width: c_uint,
+    height: c_uint,
+    bitmap_pad: c_int,
+    bytes_per_line: c_int,
+  ) -> *mut XImage;
+  fn XPutImage(
+    display: *mut Display,
+    drawable: XWindow,
+    gc: Gc,
+    image: *mut XImage,
+    src_x: c_int,
+    src_y: c_int,
+    dest_x: c_int,
+    dest_y: c_int,
+    width: c_uint,
+    height: c_uint,
+  ) -> c_int;
+  fn XPending(display: *mut Display) -> c_int;
+  fn XNextEvent(display: *mut Display, event: *mut XEvent) -> c_int;
+  fn XLookupKeysym(event: *mut XKeyEvent, index: c_int) -> KeySym;
+  fn XFlush(display: *mut Display) -> c_int;
+  fn XFree(data: *mut c_void) -> c_int;
+}
+
+#[derive(Debug, Fail)]
+pub enum WindowError {
+  #[fail(display = "couldn't connect to the X server, is DISPLAY set?")]
+  NoDisplay,
+  #[fail(display = "the X server's {} bit color depth isn't supported, 24 or more is needed", _0)]
+  UnsupportedDepth(i32),
+  #[fail(display = "couldn't create an image for the window")]
+  NoImage,
+}
+
+/// The keys the frontend listens for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+  Left,
+  Right,
+  Up,
+  Down,
+  Z,
+  X,
+  Return,
+  Backspace,
+  Tab,
+  Escape,
+  Other,
+}
+
+impl Key {
+  fn from_keysym(keysym: KeySym) -> Key {
+    match keysym {
+      0xFF51 => Key::Left,
+      0xFF52 => Key::Up,
+      0xFF53 => Key::Right,
+      0xFF54 => Key::Down,
+      0x007A | 0x005A => Key::Z,
+      0x0078 | 0x0058 => Key::X,
+      0xFF0D => Key::Return,
+      0xFF08 => Key::Backspace,
+      0xFF09 => Key::Tab,
+      0xFF1B => Key::Escape,
+      _ => Key::Other,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+  /// A key was pressed, or released if false
+  Key(Key, bool),
+  /// The window manager asked for the window to be closed
+  Closed,
+}
+
+pub struct Window {
+  display: *mut Display,
+  window: XWindow,
+  gc: Gc,
+  image: *mut XImage,
+  /// The image's pixels as 0x00RRGGBB, never reallocated as the image points into it
+  pixels: Vec<u32>,
+  width: usize,
+  height: usize,
+  scale: usize,
+  delete_window: Atom,
+}
+
+impl Window {
+  /// Open a window titled `title` showing frames of `width` by `height` pixels, each drawn `scale`
+  /// pixels square
+  pub fn open(title: &str, width: usize, height: usize, scale: usize) -> Result<Window, WindowError> {
+    let title = CString::new(title.replace('\0', "")).unwrap_or_default();
+    let (scaled_width, scaled_height) = (width * scale, height * scale);
+    unsafe {
+      let display = XOpenDisplay(ptr::null());
+      if display.is_null() {
+        return Err(WindowError::NoDisplay);
+      }
+      let screen = XDefaultScreen(display);
+      let depth = XDefaultDepth(display, screen);
+      if depth < 24 {
+        XCloseDisplay(display);
+        return Err(WindowError::UnsupportedDepth(depth));
+      }
+
+      let black = XBlackPixel(display, screen);
+      let window = XCreateSimpleWindow(
+        display,
+        XRootWindow(display, screen),
+        0,
+        0,
+        scaled_width as c_uint,
+        scaled_height as c_uint,
+        0,
+        black,
+        black,
+      );
+      XStoreName(display, window, title.as_ptr());
+      XSelectInput(display, window, KEY_PRESS_MASK | KEY_RELEASE_MASK);
+      let mut delete_window = XInternAtom(display, b"WM_DELETE_WINDOW\0".as_ptr() as *const c_char, 0);
+      XSetWMProtocols(display, window, &mut delete_window, 1);
+      XkbSetDetectableAutoRepeat(display, 1, ptr::null_mut());
+      XMapWindow(display, window);
+
+      let mut pixels = vec![0; scaled_width * scaled_height];
+      let image = XCreateImage(
+        display,
+        XDefaultVisual(display, screen),
+        depth as c_uint,
+        Z_PIXMAP,
+        0,
+        pixels.as_mut_ptr() as *mut c_char,
+        scaled_width as c_uint,
+        scaled_height as c_uint,
+        32,
+        0,
+      );
+      if image.is_null() {
+        XDestroyWindow(display, window);
+        XCloseDisplay(display);
+        return Err(WindowError::NoImage);
+      }
+
+      Ok(Window {
+        display,
+        window,
+        gc: XDefaultGC(display, screen),
+        image,
+        pixels,
+        width,
+        height,
+        scale,
+        delete_window,
+      })
+    }
+  }
+
+  /// Draw a frame of RGBA8888 pixels, row major
+  pub fn draw(&mut self, rgba: &[u8]) {
+    let scaled_width = self.width * self.scale;
+    for (y, row) in self.pixels.chunks_exact_mut(scaled_width).enumerate() {
+      let source = &rgba[y / self.scale * self.width * 4..];
+      for (x, pixel) in row.iter_mut().enumerate() {
+        let rgba = &source[x / self.scale * 4..];
+        *pixel = u32::from_be_bytes([0, rgba[0], rgba[1], rgba[2]]);
+      }
+    }
+    unsafe {
+      XPutImage(
+        self.display,
+        self.window,
+        self.gc,
+        self.image,
+        0,
+        0,
+        0,
+        0,
+        scaled_width as c_uint,
+        (self.height * self.scale) as c_uint,
+      );
+      XFlush(self.display);
+    }
+  }
+
+  /// The next key or close event waiting, or `None` once they've all been handled
+  pub fn poll_event(&mut self) -> Option<Event> {
+    unsafe {
+      while XPending(self.display) > 0 {
+        let mut event = XEvent { pad: [0; 24] };
+        XNextEvent(self.display, &mut event);
+        match event.kind {
+          KEY_PRESS | KEY_RELEASE => {
+            let key = Key::from_keysym(XLookupKeysym(&mut event.key, 0));
+            return Some(Event::Key(key, event.kind == KEY_PRESS));
+          }
+          CLIENT_MESSAGE if event.client_message.data[0] as Atom == self.delete_window => {
+            return Some(Event::Closed);
+          }
+          _ => {}
+        }
+      }
+    }
+    None
+  }
+}
+
+impl Drop for Window {
+  fn drop(&mut self) {
+    unsafe {
+      // the pixels are ours to free, only the image itself is Xlib's
+      (*self.image).data = ptr::null_mut();
+      XFree(self.image as *mut c_void);
+      XDestroyWindow(self.display, self.window);
+      XCloseDisplay(self.display);
+    }
+  }
+}
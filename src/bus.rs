@@ -0,0 +1,80 @@
+use {
+  crate::{mmu::MMU, ppu::PPU, util::Memory},
+  std::cell::{Cell, RefCell},
+};
+
+/// The MMU and PPU as the CPU sees them during an instruction
+///
+/// Every access takes a machine cycle, which is ticked through the PPU and the MMU's peripherals
+/// before the access is made, so they see the instruction's accesses at the cycle they happen on
+/// rather than all at once after it. The cycles an instruction spends without touching the bus are
+/// left for the caller to tick once it's done, see `ticked`.
+pub(crate) struct TickingBus<'a> {
+  mmu: RefCell<&'a mut MMU>,
+  ppu: RefCell<&'a mut PPU>,
+  ticked: Cell<u8>,
+}
+
+impl<'a> TickingBus<'a> {
+  /// Cycles in a machine cycle, the time one bus access takes
+  pub const ACCESS_CYCLES: u8 = 4;
+
+  pub fn new(mmu: &'a mut MMU, ppu: &'a mut PPU) -> Self {
+    Self { mmu: RefCell::new(mmu), ppu: RefCell::new(ppu), ticked: Cell::new(0) }
+  }
+
+  /// Cycles ticked by accesses so far
+  pub fn ticked(&self) -> u8 {
+    self.ticked.get()
+  }
+
+  /// Advance the PPU, then the MMU's peripherals, `n_cycles`
+  pub fn tick(&self, n_cycles: u8) {
+    let mut mmu = self.mmu.borrow_mut();
+    self.ppu.borrow_mut().step(&mut mmu, n_cycles);
+    mmu.step(n_cycles);
+    self.ticked.set(self.ticked.get().wrapping_add(n_cycles));
+  }
+}
+
+impl Memory for TickingBus<'_> {
+  fn read(&self, address: u16) -> u8 {
+    self.tick(Self::ACCESS_CYCLES);
+    self.mmu.borrow().read(address)
+  }
+
+  fn write(&mut self, address: u16, value: u8) {
+    self.tick(Self::ACCESS_CYCLES);
+    self.mmu.get_mut().write(address, value)
+  }
+
+  fn read_internal(&self, address: u16) -> u8 {
+    self.mmu.borrow().read(address)
+  }
+
+  fn write_internal(&mut self, address: u16, value: u8) {
+    self.mmu.get_mut().write(address, value)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use {super::*, crate::timer::Timer};
+
+  #[test]
+  fn accesses_tick_a_machine_cycle_first() {
+    let (mut mmu, mut ppu) = (MMU::default(), PPU::default());
+    // TIMA counting every 16 cycles
+    mmu.write(Timer::TAC_ADDRESS, 0b101);
+    let mut bus = TickingBus::new(&mut mmu, &mut ppu);
+    bus.read(0xC000);
+    bus.write(0xC000, 0x12);
+    bus.read_internal(Timer::TIMA_ADDRESS);
+    assert_eq!(bus.ticked(), 8);
+    bus.read(0xC000);
+    bus.read(0xC000);
+    // the fourth machine cycle has passed before this read sees TIMA
+    assert_eq!(bus.read(Timer::TIMA_ADDRESS), 1);
+    assert_eq!(bus.ticked(), 20);
+  }
+}
@@ -0,0 +1,202 @@
+use {
+  crate::state::*,
+  derivative::Derivative,
+  std::{
+    io::{self, Read, Write},
+    sync::Arc,
+  },
+};
+
+pub const SENSOR_WIDTH: usize  = 128;
+pub const SENSOR_HEIGHT: usize = 112;
+
+/// A source of images for the Game Boy Camera's sensor, such as a file or a webcam
+pub trait ImageSource {
+  /// Fill `image` with the scene in front of the lens, `SENSOR_WIDTH` by `SENSOR_HEIGHT` row by row,
+  /// 0 black to 255 white
+  fn capture(&self, image: &mut [u8]);
+}
+
+/// Sees nothing, as with the lens covered
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlankImage;
+
+impl ImageSource for BlankImage {
+  fn capture(&self, image: &mut [u8]) {
+    image.iter_mut().for_each(|x| *x = 0);
+  }
+}
+
+/// The sensor controller of the Game Boy Camera's MAC-GBD mapper, mapped at A000-BFFF when bit 4 of
+/// the RAM bank is set
+///
+/// A capture finishes as soon as it is started, rather than taking the real sensor's time, and writes
+/// the image dithered to 2 bits into the first bank of RAM as 16x14 tiles. Exposure scales the image,
+/// the edge enhancement and voltage registers are ignored.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Camera {
+  /// A000-A035, the last 48 of them the 4x4 dither matrix, 3 thresholds per pixel
+  #[cfg_attr(feature = "serde", serde(with = "crate::state::byte_array"))]
+  registers: [u8; Camera::REGISTER_COUNT],
+  /// Not serialized, a deserialized camera sees nothing
+  #[derivative(Debug = "ignore")]
+  #[cfg_attr(feature = "serde", serde(skip, default = "blank_image"))]
+  source: Arc<dyn ImageSource + Send + Sync>,
+}
+
+#[cfg(feature = "serde")]
+fn blank_image() -> Arc<dyn ImageSource + Send + Sync> {
+  Arc::new(BlankImage)
+}
+
+impl Default for Camera {
+  fn default() -> Self {
+    Self::new(BlankImage)
+  }
+}
+
+impl Camera {
+  /// Offset into the first RAM bank the captured image is written to
+  pub const IMAGE_RAM_OFFSET: usize = 0x100;
+  pub const IMAGE_SIZE: usize       = SENSOR_WIDTH * SENSOR_HEIGHT / 4;
+
+  const REGISTER_COUNT: usize         = 0x36;
+  /// The registers are echoed every 128 bytes
+  const REGISTER_MASK: u16            = 0x7F;
+  const CAPTURE_REGISTER: usize       = 0x00;
+  const CAPTURE_BIT: u8               = 0b0000_0001;
+  /// Only the capture register reads back, and only its low bits
+  const CAPTURE_READ_MASK: u8         = 0b0000_0111;
+  const EXPOSURE_HIGH_REGISTER: usize = 0x02;
+  const EXPOSURE_LOW_REGISTER: usize  = 0x03;
+  const DITHER_START_REGISTER: usize  = 0x06;
+  /// The exposure that leaves the image as the source sees it
+  const UNIT_EXPOSURE: u32            = 0x1000;
+  const TILE_BYTES: usize             = 16;
+  const TILES_WIDE: usize             = SENSOR_WIDTH / 8;
+
+  pub fn new(source: impl ImageSource + Send + Sync + 'static) -> Self {
+    Self { registers: [0; Self::REGISTER_COUNT], source: Arc::new(source) }
+  }
+
+  /// Take images from `source` from now on
+  pub fn set_source(&mut self, source: impl ImageSource + Send + Sync + 'static) {
+    self.source = Arc::new(source);
+  }
+
+  /// Read the register mapped at `address`, an offset into A000-BFFF
+  pub fn read(&self, address: u16) -> u8 {
+    match (address & Self::REGISTER_MASK) as usize {
+      Self::CAPTURE_REGISTER => self.registers[Self::CAPTURE_REGISTER] & Self::CAPTURE_READ_MASK,
+      _ => 0x00,
+    }
+  }
+
+  /// Write the register mapped at `address`, an offset into A000-BFFF, capturing an image into `ram`
+  /// if the write starts one
+  ///
+  /// # Returns
+  /// whether an image was captured
+  pub fn write(&mut self, address: u16, value: u8, ram: &mut [u8]) -> bool {
+    match self.registers.get_mut((address & Self::REGISTER_MASK) as usize) {
+      Some(register) => *register = value,
+      None => return false,
+    }
+    if self.registers[Self::CAPTURE_REGISTER] & Self::CAPTURE_BIT == 0 {
+      return false;
+    }
+    self.capture(ram);
+    self.registers[Self::CAPTURE_REGISTER] &= !Self::CAPTURE_BIT;
+    true
+  }
+
+  fn capture(&self, ram: &mut [u8]) {
+    let mut image = vec![0; SENSOR_WIDTH * SENSOR_HEIGHT];
+    self.source.capture(&mut image);
+    let exposure = u16::from_be_bytes([
+      self.registers[Self::EXPOSURE_HIGH_REGISTER],
+      self.registers[Self::EXPOSURE_LOW_REGISTER],
+    ]) as u32;
+
+    let image_ram = match ram.get_mut(Self::IMAGE_RAM_OFFSET..Self::IMAGE_RAM_OFFSET + Self::IMAGE_SIZE) {
+      Some(image_ram) => image_ram,
+      None => return,
+    };
+    image_ram.iter_mut().for_each(|x| *x = 0);
+    for y in 0..SENSOR_HEIGHT {
+      for x in 0..SENSOR_WIDTH {
+        let brightness = (image[y * SENSOR_WIDTH + x] as u32 * exposure / Self::UNIT_EXPOSURE).min(0xFF) as u8;
+        let shade = self.dither(x, y, brightness);
+        let tile = (y / 8) * Self::TILES_WIDE + x / 8;
+        let offset = tile * Self::TILE_BYTES + (y % 8) * 2;
+        let bit = 7 - (x % 8);
+        image_ram[offset] |= (shade & 1) << bit;
+        image_ram[offset + 1] |= (shade >> 1) << bit;
+      }
+    }
+  }
+
+  /// The shade 0-3 a pixel of `brightness` at `x`, `y` comes out as, from the thresholds of its place
+  /// in the dither matrix
+  fn dither(&self, x: usize, y: usize, brightness: u8) -> u8 {
+    let start = Self::DITHER_START_REGISTER + ((y % 4) * 4 + x % 4) * 3;
+    let thresholds = &self.registers[start..start + 3];
+    3 - thresholds.iter().take_while(|&&threshold| brightness >= threshold).count() as u8
+  }
+}
+
+impl SaveState for Camera {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(&self.registers)
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    r.read_exact(&mut self.registers)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  /// Black on the left half, white on the right
+  struct HalfAndHalf;
+
+  impl ImageSource for HalfAndHalf {
+    fn capture(&self, image: &mut [u8]) {
+      for (i, x) in image.iter_mut().enumerate() {
+        *x = if i % SENSOR_WIDTH < SENSOR_WIDTH / 2 { 0x00 } else { 0xFF };
+      }
+    }
+  }
+
+  #[test]
+  fn capture_dithers_the_image_into_ram_tiles() {
+    let mut camera = Camera::new(HalfAndHalf);
+    let mut ram = vec![0xAA; 0x2000];
+    camera.write(0x02, 0x10, &mut ram);
+    camera.write(0x03, 0x00, &mut ram);
+    for i in 0..16 {
+      for (n, &threshold) in [0x40, 0x80, 0xC0].iter().enumerate() {
+        camera.write((Camera::DITHER_START_REGISTER + i * 3 + n) as u16, threshold, &mut ram);
+      }
+    }
+    assert!(!camera.write(0x00, 0x06, &mut ram));
+    assert_eq!(camera.read(0x00), 0x06);
+    assert!(camera.write(0x80, 0x07, &mut ram));
+    // done as soon as it started
+    assert_eq!(camera.read(0x00), 0x06);
+    assert_eq!(camera.read(0x01), 0x00);
+
+    let image = &ram[Camera::IMAGE_RAM_OFFSET..];
+    // the first tile is black, the last tile in its row white
+    assert!(image[..Camera::TILE_BYTES].iter().all(|&x| x == 0xFF));
+    let last = (Camera::TILES_WIDE - 1) * Camera::TILE_BYTES;
+    assert!(image[last..last + Camera::TILE_BYTES].iter().all(|&x| x == 0x00));
+    // RAM before the image and after it is left alone
+    assert_eq!(ram[Camera::IMAGE_RAM_OFFSET - 1], 0xAA);
+    assert_eq!(ram[Camera::IMAGE_RAM_OFFSET + Camera::IMAGE_SIZE], 0xAA);
+  }
+}
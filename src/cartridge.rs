@@ -1,56 +1,1816 @@
 use {
-  crate::util::*,
+  crate::{
+    camera::{Camera, ImageSource},
+    rtc::{Clock, Rtc},
+    state::*,
+    util::*,
+  },
+  failure::Fail,
+  std::{
+    borrow::Cow,
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+    sync::Arc,
+  },
 };
 
+#[derive(Debug, Fail)]
+pub enum SaveError {
+  #[fail(display = "no cartridge is loaded")]
+  NoCartridge,
+  #[fail(display = "cartridge has no save ram")]
+  NoRam,
+  #[fail(display = "save ram is {} bytes but the cartridge has {} bytes", actual, expected)]
+  SizeMismatch { expected: usize, actual: usize },
+}
+
+#[derive(Debug, Fail)]
+pub enum CartridgeError {
+  #[fail(display = "rom is {} bytes, larger than any cartridge", size)]
+  TooLarge { size: usize },
+  #[fail(display = "unsupported cartridge type 0x{:02x}", cartridge_type)]
+  Unsupported { cartridge_type: u8 },
+  #[fail(display = "rom doesn't hold the Nintendo logo")]
+  BadLogo,
+  #[fail(display = "header checksum is 0x{:02x} but the header sums to 0x{:02x}", expected, actual)]
+  BadHeaderChecksum { expected: u8, actual: u8 },
+  #[fail(display = "global checksum is 0x{:04x} but the rom sums to 0x{:04x}", expected, actual)]
+  BadGlobalChecksum { expected: u16, actual: u16 },
+  #[fail(display = "rom is {} bytes but the header declares {} bytes", actual, expected)]
+  SizeMismatch { expected: usize, actual: usize },
+  #[fail(display = "{}", _0)]
+  Io(#[cause] io::Error),
+}
+
+impl From<io::Error> for CartridgeError {
+  fn from(error: io::Error) -> Self {
+    CartridgeError::Io(error)
+  }
+}
+
+/// What `Cartridge::from_bytes` does with a cartridge type byte it doesn't recognize
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoadMode {
+  /// Refuse to load it with `CartridgeError::Unsupported`
+  #[default]
+  Strict,
+  /// Load it with MBC1 banking, which lets most games at least boot
+  ///
+  /// `Cartridge::info` still reports the type as `MbcKind::Unknown` so a frontend can warn about it.
+  Lenient,
+  /// Refuse it as in `Strict`, and refuse anything that isn't a clean dump: a missing Nintendo logo,
+  /// a bad header or global checksum, or an image that isn't the size the header declares
+  Verified,
+}
+
+/// A source of ROM banks, letting a cartridge be backed by something other than an in-memory buffer
+/// such as a memory mapped file or a compressed image
+pub trait RomSource {
+  /// Number of 16KB banks in the ROM
+  fn bank_count(&self) -> usize;
+
+  /// Read the byte at `offset` into bank `bank`
+  fn read(&self, bank: usize, offset: u16) -> u8;
+}
+
+/// Storage backing a cartridge's ROM
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Rom {
+  /// The whole ROM held in memory
+  Buffer(Vec<u8>),
+  /// Banks fetched from a `RomSource` on demand, which can't be serialized
+  #[cfg_attr(feature = "serde", serde(skip))]
+  Source(Arc<dyn RomSource + Send + Sync>),
+}
+
+impl Rom {
+  const OUT_OF_RANGE_READ_VALUE: u8 = 0xFF;
+
+  /// Read the byte at `offset` into 16KB bank `bank`
+  pub fn read(&self, bank: usize, offset: u16) -> u8 {
+    match self {
+      Rom::Buffer(bytes) => bytes
+        .get(bank * Cartridge::ROM_BANK_SIZE + offset as usize)
+        .cloned()
+        .unwrap_or(Self::OUT_OF_RANGE_READ_VALUE),
+      Rom::Source(source) if bank < source.bank_count() => source.read(bank, offset),
+      Rom::Source(_) => Self::OUT_OF_RANGE_READ_VALUE,
+    }
+  }
+
+  /// Number of 16KB banks in the ROM
+  pub fn bank_count(&self) -> usize {
+    match self {
+      Rom::Buffer(bytes) => bytes.len() / Cartridge::ROM_BANK_SIZE,
+      Rom::Source(source) => source.bank_count(),
+    }
+  }
+
+  /// The contents of 16KB bank `bank`, borrowed when the ROM is held in memory
+  pub fn bank(&self, bank: usize) -> Cow<'_, [u8]> {
+    match self {
+      Rom::Buffer(bytes) => Cow::Borrowed(&bytes[bank * Cartridge::ROM_BANK_SIZE..(bank + 1) * Cartridge::ROM_BANK_SIZE]),
+      Rom::Source(_) => Cow::Owned((0..Cartridge::ROM_BANK_SIZE as u16).map(|offset| self.read(bank, offset)).collect()),
+    }
+  }
+}
+
+/// The memory bank controller a cartridge's header declares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MbcKind {
+  None,
+  MBC1,
+  MBC2,
+  MBC3,
+  MBC5,
+  HuC1,
+  PocketCamera,
+  Unknown(u8),
+}
+
+/// A summary of a cartridge's header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CartridgeInfo {
+  pub title: String,
+  pub mbc: MbcKind,
+  /// Size in bytes of the ROM as declared by the header
+  pub rom_size: usize,
+  /// Size in bytes of the external RAM as declared by the header
+  pub ram_size: usize,
+  /// The game uses CGB features
+  pub cgb: bool,
+  /// The game only runs on a CGB
+  pub cgb_only: bool,
+  /// The game uses SGB features
+  pub sgb: bool,
+  /// The external RAM is battery backed and should be saved
+  pub battery: bool,
+  /// The header checksum matches the header
+  pub header_checksum_valid: bool,
+}
+
+/// The cartridge header at 0100-014F of the first ROM bank
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CartridgeHeader {
+  /// Up to 15 upper case ASCII characters, trailing padding removed
+  pub title: String,
+  /// 0x80 if the game uses CGB features, 0xC0 if it only runs on a CGB
+  pub cgb_flag: u8,
+  /// 0x03 if the game uses SGB features
+  pub sgb_flag: u8,
+  /// The mapper and extra hardware on the cartridge
+  pub cartridge_type: u8,
+  pub rom_size_code: u8,
+  pub ram_size_code: u8,
+  /// 0x00 for cartridges sold in Japan, 0x01 for everywhere else
+  pub destination_code: u8,
+  pub header_checksum: u8,
+  /// The checksum of 0134-014C as the boot ROM computes it
+  computed_checksum: u8,
+  /// The sum of every byte of the ROM but these two, which nothing checks
+  pub global_checksum: u16,
+  /// 0104-0133 holds the Nintendo logo the boot ROM scrolls down and compares
+  logo_valid: bool,
+}
+
+impl CartridgeHeader {
+  const TITLE_HEADER_START_ADDRESS: u16    = 0x0134;
+  const TITLE_HEADER_END_ADDRESS: u16      = 0x0143;
+  const CGB_FLAG_HEADER_ADDRESS: u16       = 0x0143;
+  const SGB_FLAG_HEADER_ADDRESS: u16       = 0x0146;
+  const TYPE_HEADER_ADDRESS: u16           = 0x0147;
+  const ROM_SIZE_HEADER_ADDRESS: u16       = 0x0148;
+  const RAM_SIZE_HEADER_ADDRESS: u16       = 0x0149;
+  const DESTINATION_HEADER_ADDRESS: u16    = 0x014A;
+  const HEADER_CHECKSUM_ADDRESS: u16       = 0x014D;
+  const GLOBAL_CHECKSUM_ADDRESS: u16       = 0x014E;
+  const HEADER_END_ADDRESS: u16            = 0x0150;
+  const LOGO_START_ADDRESS: u16            = 0x0104;
+
+  const CGB_FLAG_BIT_N: u8       = 7;
+  const CGB_ONLY_FLAG: u8        = 0xC0;
+  const SGB_FLAG: u8             = 0x03;
+  const JAPANESE_DESTINATION: u8 = 0x00;
+  const MIN_ROM_SIZE: usize      = 0x8000;
+
+  const LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+  ];
+
+  /// Parse the header of a ROM image
+  ///
+  /// # Returns
+  /// `None` if the image is too short to hold a header
+  pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+    if bytes.len() >= Self::HEADER_END_ADDRESS as usize {
+      Some(Self::parse(|address| bytes[address as usize]))
+    } else {
+      None
+    }
+  }
+
+  /// Parse the header from the first ROM bank as read by `read`
+  fn parse(read: impl Fn(u16) -> u8) -> Self {
+    let title: String = (Self::TITLE_HEADER_START_ADDRESS..Self::TITLE_HEADER_END_ADDRESS)
+      .map(&read)
+      .take_while(|&x| x != 0)
+      .map(char::from)
+      .collect();
+    let computed_checksum = (Self::TITLE_HEADER_START_ADDRESS..Self::HEADER_CHECKSUM_ADDRESS)
+      .fold(0u8, |checksum, address| checksum.wrapping_sub(read(address)).wrapping_sub(1));
+    let logo_valid = (Self::LOGO_START_ADDRESS..)
+      .zip(Self::LOGO.iter())
+      .all(|(address, &x)| read(address) == x);
+
+    Self {
+      title: title.trim_end().to_string(),
+      cgb_flag: read(Self::CGB_FLAG_HEADER_ADDRESS),
+      sgb_flag: read(Self::SGB_FLAG_HEADER_ADDRESS),
+      cartridge_type: read(Self::TYPE_HEADER_ADDRESS),
+      rom_size_code: read(Self::ROM_SIZE_HEADER_ADDRESS),
+      ram_size_code: read(Self::RAM_SIZE_HEADER_ADDRESS),
+      destination_code: read(Self::DESTINATION_HEADER_ADDRESS),
+      header_checksum: read(Self::HEADER_CHECKSUM_ADDRESS),
+      computed_checksum,
+      global_checksum: u16::from_be_bytes([
+        read(Self::GLOBAL_CHECKSUM_ADDRESS),
+        read(Self::GLOBAL_CHECKSUM_ADDRESS + 1),
+      ]),
+      logo_valid,
+    }
+  }
+
+  /// The mapper described by the cartridge type
+  pub fn mbc(&self) -> MbcKind {
+    match self.cartridge_type {
+      0x00 | 0x08 | 0x09 => MbcKind::None,
+      0x01..=0x03 => MbcKind::MBC1,
+      0x05 | 0x06 => MbcKind::MBC2,
+      0x0F..=0x13 => MbcKind::MBC3,
+      0x19..=0x1E => MbcKind::MBC5,
+      0xFC => MbcKind::PocketCamera,
+      0xFF => MbcKind::HuC1,
+      unknown => MbcKind::Unknown(unknown),
+    }
+  }
+
+  /// Size in bytes of the ROM
+  pub fn rom_size(&self) -> usize {
+    Self::MIN_ROM_SIZE.checked_shl(self.rom_size_code as u32).unwrap_or_default()
+  }
+
+  /// Size in bytes of the external RAM
+  pub fn ram_size(&self) -> usize {
+    match self.ram_size_code {
+      0x01 => 0x800,
+      0x02 => 0x2000,
+      0x03 => 0x8000,
+      0x04 => 0x20000,
+      0x05 => 0x10000,
+      _ => 0,
+    }
+  }
+
+  /// The external RAM is battery backed and should be saved
+  pub fn battery(&self) -> bool {
+    matches!(self.cartridge_type, 0x03 | 0x06 | 0x09 | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0xFC | 0xFF)
+  }
+
+  /// The cartridge has an MBC3 real time clock
+  pub fn rtc(&self) -> bool {
+    matches!(self.cartridge_type, 0x0F | 0x10)
+  }
+
+  /// The cartridge has an MBC5 rumble motor
+  pub fn rumble(&self) -> bool {
+    matches!(self.cartridge_type, 0x1C..=0x1E)
+  }
+
+  pub fn cgb(&self) -> bool {
+    get_bit(self.cgb_flag as u16, Self::CGB_FLAG_BIT_N)
+  }
+
+  pub fn cgb_only(&self) -> bool {
+    self.cgb_flag == Self::CGB_ONLY_FLAG
+  }
+
+  pub fn sgb(&self) -> bool {
+    self.sgb_flag == Self::SGB_FLAG
+  }
+
+  pub fn japanese(&self) -> bool {
+    self.destination_code == Self::JAPANESE_DESTINATION
+  }
+
+  /// The header checksum matches the header, the boot ROM locks up if it doesn't
+  pub fn checksum_valid(&self) -> bool {
+    self.header_checksum == self.computed_checksum
+  }
+
+  /// The Nintendo logo is intact, the boot ROM locks up if it isn't
+  pub fn logo_valid(&self) -> bool {
+    self.logo_valid
+  }
+
+  /// The global checksum of the ROM image `rom`, as stored at 014E-014F
+  pub fn compute_global_checksum(rom: &[u8]) -> u16 {
+    let stored = Self::GLOBAL_CHECKSUM_ADDRESS as usize..=Self::GLOBAL_CHECKSUM_ADDRESS as usize + 1;
+    rom
+      .iter()
+      .enumerate()
+      .filter(|(i, _)| !stored.contains(i))
+      .fold(0u16, |sum, (_, &x)| sum.wrapping_add(x as u16))
+  }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Cartridge {
-  RomOnly(Box<[u8; Self::ROM_ONLY_SIZE]>),
-  MBC1 {},
-  MBC2 {},
-  MBC3 {},
-  MBC5 {},
+  RomOnly {
+    rom: Rom,
+    ram: Vec<u8>,
+    /// Set when the RAM is written, until it is taken by `save_ram`
+    ram_dirty: bool,
+  },
+  MBC1 {
+    rom: Rom,
+    ram: Vec<u8>,
+    ram_dirty: bool,
+    /// Lower 5 bits of the bank mapped at 4000-7FFF, never 0
+    rom_bank: u8,
+    /// The 2 bits written to 4000-5FFF, the upper bits of the ROM bank or, in advanced banking mode,
+    /// the RAM bank
+    bank2: u8,
+    /// Set by writing 1 to 6000-7FFF, `bank2` then also applies to 0000-3FFF and the RAM
+    advanced_banking: bool,
+    ram_enabled: bool,
+    /// Wired as an MBC1M multicart, the bit 4 of the ROM bank isn't connected and `bank2` picks one
+    /// of four 256KB games
+    multicart: bool,
+    /// Bank pinned at 4000-7FFF by a debugger whatever `rom_bank` says
+    forced_rom_bank: Option<usize>,
+    /// Bank pinned at A000-BFFF by a debugger
+    forced_ram_bank: Option<usize>,
+  },
+  MBC2 {
+    rom: Rom,
+    /// 512 half bytes, each held in the low 4 bits of a byte
+    ram: Vec<u8>,
+    ram_dirty: bool,
+    /// Bank mapped at 4000-7FFF, never 0
+    rom_bank: u8,
+    ram_enabled: bool,
+    /// Bank pinned at 4000-7FFF by a debugger whatever `rom_bank` says
+    forced_rom_bank: Option<usize>,
+  },
+  MBC3 {
+    rom: Rom,
+    ram: Vec<u8>,
+    ram_dirty: bool,
+    /// Bank mapped at 4000-7FFF, never 0
+    rom_bank: u8,
+    /// 00-03 maps that bank of RAM at A000-BFFF, 08-0C maps a clock register instead
+    ram_bank: u8,
+    /// Enables the clock registers as well as the RAM
+    ram_enabled: bool,
+    /// Only cartridge types 0x0F and 0x10 have a clock
+    rtc: Option<Rtc>,
+    /// Bank pinned at 4000-7FFF by a debugger whatever `rom_bank` says
+    forced_rom_bank: Option<usize>,
+    /// Bank pinned at A000-BFFF by a debugger
+    forced_ram_bank: Option<usize>,
+  },
+  MBC5 {
+    rom: Rom,
+    ram: Vec<u8>,
+    ram_dirty: bool,
+    /// 9 bit bank mapped at 4000-7FFF, which unlike the other mappers can be 0
+    rom_bank: u16,
+    ram_bank: u8,
+    ram_enabled: bool,
+    /// Whether the cartridge has a rumble motor, wired to bit 3 of the RAM bank register
+    has_rumble: bool,
+    /// Set while the game has the rumble motor on
+    rumble: bool,
+    /// Bank pinned at 4000-7FFF by a debugger whatever `rom_bank` says
+    forced_rom_bank: Option<usize>,
+    /// Bank pinned at A000-BFFF by a debugger
+    forced_ram_bank: Option<usize>,
+  },
   Rumble {},
-  HuC1 {},
+  HuC1 {
+    rom: Rom,
+    ram: Vec<u8>,
+    ram_dirty: bool,
+    /// Bank mapped at 4000-7FFF, never 0
+    rom_bank: u8,
+    ram_bank: u8,
+    /// Set by writing 0x0E to 0000-1FFF, A000-BFFF then reaches the infrared port instead of the RAM
+    ir_mode: bool,
+    /// Bank pinned at 4000-7FFF by a debugger whatever `rom_bank` says
+    forced_rom_bank: Option<usize>,
+    /// Bank pinned at A000-BFFF by a debugger
+    forced_ram_bank: Option<usize>,
+  },
+  PocketCamera {
+    rom: Rom,
+    ram: Vec<u8>,
+    ram_dirty: bool,
+    /// Bank mapped at 4000-7FFF, which can be 0
+    rom_bank: u8,
+    /// 00-0F maps that bank of RAM at A000-BFFF, setting bit 4 maps the camera registers instead
+    ram_bank: u8,
+    ram_enabled: bool,
+    camera: Camera,
+    /// Bank pinned at 4000-7FFF by a debugger whatever `rom_bank` says
+    forced_rom_bank: Option<usize>,
+    /// Bank pinned at A000-BFFF by a debugger
+    forced_ram_bank: Option<usize>,
+  },
 }
 
 impl Cartridge {
-  const ROM_ONLY_SIZE: usize = 0xFFFF;
+  /// The largest ROM any mapper can address, 512 banks
+  const MAX_ROM_SIZE: usize = 0x800000;
   const NO_RAM_READ_VALUE: u8 = 0xFF;
+  const NO_ROM_READ_VALUE: u8 = 0xFF;
+  pub const ROM_BANK_SIZE: usize = 0x4000;
+  pub const RAM_BANK_SIZE: usize = 0x2000;
+
+  const MBC1_RAM_ENABLE_END_ADDRESS: u16 = 0x1FFF;
+  const MBC1_ROM_BANK_START_ADDRESS: u16 = 0x2000;
+  const MBC1_ROM_BANK_END_ADDRESS: u16   = 0x3FFF;
+  const MBC1_BANK2_START_ADDRESS: u16    = 0x4000;
+  const MBC1_BANK2_END_ADDRESS: u16      = 0x5FFF;
+  const MBC1_MODE_START_ADDRESS: u16     = 0x6000;
+  const MBC1_MODE_END_ADDRESS: u16       = 0x7FFF;
+  const MBC1_RAM_ENABLE_VALUE: u8        = 0x0A;
+  const MBC1_ROM_BANK_MASK: u8           = 0x1F;
+  const MBC1_BANK2_MASK: u8              = 0b11;
+  const MBC1_BANK2_SHIFT: u8             = 5;
+  // MBC1M multicarts leave out bit 4 of the ROM bank, and hold a game with its own header every 16 banks
+  const MBC1M_ROM_BANK_MASK: u8 = 0x0F;
+  const MBC1M_BANK2_SHIFT: u8   = 4;
+  const MBC1M_ROM_BANKS: usize  = 0x40;
+  const MBC1M_GAME_BANKS: usize = 0x10;
+  // MBC3 has its registers at the same addresses as MBC1
+  const MBC3_ROM_BANK_MASK: u8 = 0x7F;
+  // MBC2 has a single register range at 0000-3FFF, bit 8 of the address picks the register
+  const MBC2_REGISTER_END_ADDRESS: u16 = 0x3FFF;
+  const MBC2_ROM_BANK_SELECT_BIT: u16  = 0x0100;
+  const MBC2_ROM_BANK_MASK: u8         = 0x0F;
+  /// The RAM is built into the mapper and echoed across A000-BFFF
+  const MBC2_RAM_SIZE: usize           = 0x200;
+  const MBC2_RAM_VALUE_MASK: u8        = 0x0F;
+  // HuC1 uses the MBC1 register ranges too, with a port for the infrared LED and sensor in place of
+  // the RAM enable
+  const HUC1_IR_SELECT_VALUE: u8 = 0x0E;
+  const HUC1_ROM_BANK_MASK: u8   = 0x3F;
+  const HUC1_RAM_BANK_MASK: u8   = 0x03;
+  /// The infrared port as read with no light seen, the MMU routes the port to its transceiver
+  const HUC1_IR_READ_VALUE: u8   = 0xC0;
+  // the Game Boy Camera's MAC-GBD too, with the camera registers mapped in place of a RAM bank
+  const CAMERA_ROM_BANK_MASK: u8 = 0x3F;
+  const CAMERA_RAM_BANK_MASK: u8 = 0x0F;
+  const CAMERA_SELECT_BIT: u8    = 0x10;
+  // as does MBC5, though it splits the ROM bank register in two
+  const MBC5_ROM_BANK_LOW_END_ADDRESS: u16    = 0x2FFF;
+  const MBC5_ROM_BANK_HIGH_START_ADDRESS: u16 = 0x3000;
+  const MBC5_RAM_BANK_MASK: u8                = 0x0F;
+  /// On rumble cartridges bit 3 of the RAM bank register drives the motor instead
+  const MBC5_RUMBLE_BIT: u8                   = 0b1000;
+
 
+  /// Load a cartridge from a ROM image with the mapper its header declares, padding it with zeros
+  /// to a whole number of banks
+  ///
+  /// Unknown cartridge types are loaded as in `LoadMode::Lenient`.
+  ///
+  /// # Returns
+  /// `None` if the image is larger than any cartridge
   pub fn maybe_from_bytes(bytes: &[u8]) -> Option<Self> {
-    if bytes.len() <= Self::ROM_ONLY_SIZE {
-      let mut buffer = [0; Self::ROM_ONLY_SIZE];
-      let buffer_len = bytes.len();
-      buffer[..buffer_len].clone_from_slice(&bytes[..buffer_len]);
-      Some(Cartridge::RomOnly(Box::new(buffer)))
+    Self::from_bytes(bytes, LoadMode::Lenient).ok()
+  }
+
+  /// Load a cartridge from a ROM image with the mapper its header declares
+  ///
+  /// Mappers that aren't emulated yet are loaded without banking, `mode` decides what happens to
+  /// cartridge types that aren't recognized at all.
+  pub fn from_bytes(bytes: &[u8], mode: LoadMode) -> Result<Self, CartridgeError> {
+    let rom = Self::padded_rom(bytes).ok_or(CartridgeError::TooLarge { size: bytes.len() })?;
+    if mode == LoadMode::Verified {
+      Self::verify(bytes)?;
+    }
+    Self::with_mapper(rom, mode)
+  }
+
+  /// Load a cartridge from the ROM image at `path`, as `from_bytes`
+  pub fn from_file(path: impl AsRef<Path>, mode: LoadMode) -> Result<Self, CartridgeError> {
+    Self::from_bytes(&fs::read(path)?, mode)
+  }
+
+  /// Check `bytes` is a clean dump, as the boot ROM and the header see it
+  fn verify(bytes: &[u8]) -> Result<(), CartridgeError> {
+    let header = CartridgeHeader::from_bytes(bytes).ok_or(CartridgeError::SizeMismatch {
+      expected: CartridgeHeader::MIN_ROM_SIZE,
+      actual: bytes.len(),
+    })?;
+    if !header.logo_valid() {
+      return Err(CartridgeError::BadLogo);
+    }
+    if !header.checksum_valid() {
+      return Err(CartridgeError::BadHeaderChecksum {
+        expected: header.header_checksum,
+        actual: header.computed_checksum,
+      });
+    }
+    if bytes.len() != header.rom_size() {
+      return Err(CartridgeError::SizeMismatch { expected: header.rom_size(), actual: bytes.len() });
+    }
+    let global_checksum = CartridgeHeader::compute_global_checksum(bytes);
+    if global_checksum != header.global_checksum {
+      return Err(CartridgeError::BadGlobalChecksum { expected: header.global_checksum, actual: global_checksum });
+    }
+    Ok(())
+  }
+
+  /// Copy a ROM image into memory padded with zeros to a whole number of banks, at least 2
+  fn padded_rom(bytes: &[u8]) -> Option<Rom> {
+    if bytes.len() <= Self::MAX_ROM_SIZE {
+      let n_banks = bytes.len().div_ceil(Self::ROM_BANK_SIZE).max(2);
+      let mut buffer = vec![0; n_banks * Self::ROM_BANK_SIZE];
+      buffer[..bytes.len()].clone_from_slice(bytes);
+      Some(Rom::Buffer(buffer))
     } else {
       None
     }
   }
 
+  /// Create a cartridge whose ROM banks are fetched from `source` as they are read rather than copied up front
+  ///
+  /// The mapper is picked from the header as in `LoadMode::Lenient`.
+  pub fn from_banks(source: impl RomSource + Send + Sync + 'static) -> Self {
+    Self::with_mapper(Rom::Source(Arc::new(source)), LoadMode::Lenient)
+      .expect("lenient loads accept any cartridge type")
+  }
+
+  /// Wrap `rom` in the mapper its header declares
+  fn with_mapper(rom: Rom, mode: LoadMode) -> Result<Self, CartridgeError> {
+    let header = CartridgeHeader::parse(|address| rom.read(0, address));
+    match (header.mbc(), mode) {
+      (MbcKind::MBC1, _) | (MbcKind::Unknown(_), LoadMode::Lenient) => Ok(Self::mbc1(rom, &header)),
+      (MbcKind::MBC2, _) => Ok(Self::mbc2(rom)),
+      (MbcKind::MBC3, _) => Ok(Self::mbc3(rom, &header)),
+      (MbcKind::HuC1, _) => Ok(Self::huc1(rom, &header)),
+      (MbcKind::PocketCamera, _) => Ok(Self::pocket_camera(rom, &header)),
+      (MbcKind::MBC5, _) => Ok(Self::mbc5(rom, &header)),
+      (MbcKind::Unknown(cartridge_type), _) => Err(CartridgeError::Unsupported { cartridge_type }),
+      _ => Ok(Self::rom_only(rom, &header)),
+    }
+  }
+
+  fn rom_only(rom: Rom, header: &CartridgeHeader) -> Self {
+    Cartridge::RomOnly { rom, ram: vec![0; header.ram_size()], ram_dirty: false }
+  }
+
+  fn mbc1(rom: Rom, header: &CartridgeHeader) -> Self {
+    Cartridge::MBC1 {
+      multicart: Self::is_multicart(&rom),
+      rom,
+      ram: vec![0; header.ram_size()],
+      ram_dirty: false,
+      rom_bank: 1,
+      bank2: 0,
+      advanced_banking: false,
+      ram_enabled: false,
+      forced_rom_bank: None,
+      forced_ram_bank: None,
+    }
+  }
+
+  /// A multicart's header declares a plain MBC1, but the games after the menu each start with a
+  /// header of their own, logo and all
+  fn is_multicart(rom: &Rom) -> bool {
+    rom.bank_count() == Self::MBC1M_ROM_BANKS
+      && CartridgeHeader::parse(|address| rom.read(Self::MBC1M_GAME_BANKS, address)).logo_valid()
+  }
+
+  /// The ROM bank shift and mask for `bank2` and the ROM bank register of an MBC1
+  fn mbc1_wiring(multicart: bool) -> (u8, u8) {
+    if multicart {
+      (Self::MBC1M_BANK2_SHIFT, Self::MBC1M_ROM_BANK_MASK)
+    } else {
+      (Self::MBC1_BANK2_SHIFT, Self::MBC1_ROM_BANK_MASK)
+    }
+  }
+
+  /// The header declares no RAM for MBC2, whose RAM is always there
+  fn mbc2(rom: Rom) -> Self {
+    Cartridge::MBC2 {
+      rom,
+      ram: vec![0; Self::MBC2_RAM_SIZE],
+      ram_dirty: false,
+      rom_bank: 1,
+      ram_enabled: false,
+      forced_rom_bank: None,
+    }
+  }
+
+  fn mbc3(rom: Rom, header: &CartridgeHeader) -> Self {
+    Cartridge::MBC3 {
+      rom,
+      ram: vec![0; header.ram_size()],
+      ram_dirty: false,
+      rom_bank: 1,
+      ram_bank: 0,
+      ram_enabled: false,
+      rtc: if header.rtc() { Some(Rtc::default()) } else { None },
+      forced_rom_bank: None,
+      forced_ram_bank: None,
+    }
+  }
+
+  fn huc1(rom: Rom, header: &CartridgeHeader) -> Self {
+    Cartridge::HuC1 {
+      rom,
+      ram: vec![0; header.ram_size()],
+      ram_dirty: false,
+      rom_bank: 1,
+      ram_bank: 0,
+      ir_mode: false,
+      forced_rom_bank: None,
+      forced_ram_bank: None,
+    }
+  }
+
+  fn pocket_camera(rom: Rom, header: &CartridgeHeader) -> Self {
+    Cartridge::PocketCamera {
+      rom,
+      ram: vec![0; header.ram_size()],
+      ram_dirty: false,
+      rom_bank: 1,
+      ram_bank: 0,
+      ram_enabled: false,
+      camera: Camera::default(),
+      forced_rom_bank: None,
+      forced_ram_bank: None,
+    }
+  }
+
+  fn mbc5(rom: Rom, header: &CartridgeHeader) -> Self {
+    Cartridge::MBC5 {
+      rom,
+      ram: vec![0; header.ram_size()],
+      ram_dirty: false,
+      rom_bank: 1,
+      ram_bank: 0,
+      ram_enabled: false,
+      has_rumble: header.rumble(),
+      rumble: false,
+      forced_rom_bank: None,
+      forced_ram_bank: None,
+    }
+  }
+
+  /// The header of the first ROM bank, whatever is mapped at 0000-3FFF
+  pub fn header(&self) -> CartridgeHeader {
+    CartridgeHeader::parse(|address| self.rom().map_or(0, |rom| rom.read(0, address)))
+  }
+
+  /// Summarize the cartridge's header
+  pub fn info(&self) -> CartridgeInfo {
+    let header = self.header();
+    CartridgeInfo {
+      mbc: header.mbc(),
+      rom_size: header.rom_size(),
+      ram_size: header.ram_size(),
+      cgb: header.cgb(),
+      cgb_only: header.cgb_only(),
+      sgb: header.sgb(),
+      battery: header.battery(),
+      header_checksum_valid: header.checksum_valid(),
+      title: header.title,
+    }
+  }
+
+  fn rom(&self) -> Option<&Rom> {
+    match self {
+      Self::RomOnly { rom, .. }
+      | Self::MBC1 { rom, .. }
+      | Self::MBC2 { rom, .. }
+      | Self::MBC3 { rom, .. }
+      | Self::MBC5 { rom, .. }
+      | Self::HuC1 { rom, .. }
+      | Self::PocketCamera { rom, .. } => Some(rom),
+      _ => None,
+    }
+  }
+
+  /// Number of 16KB banks in the ROM
+  pub fn bank_count(&self) -> usize {
+    self.rom().map(Rom::bank_count).unwrap_or_default()
+  }
+
+  /// Each 16KB bank of the ROM in order, for analysis tools
+  pub fn rom_banks(&self) -> impl Iterator<Item = Cow<'_, [u8]>> {
+    (0..self.bank_count()).map(move |bank| self.rom().expect("a cartridge with banks has a rom").bank(bank))
+  }
+
+  fn ram(&self) -> &[u8] {
+    match self {
+      Self::RomOnly { ram, .. }
+      | Self::MBC1 { ram, .. }
+      | Self::MBC2 { ram, .. }
+      | Self::MBC3 { ram, .. }
+      | Self::MBC5 { ram, .. }
+      | Self::HuC1 { ram, .. }
+      | Self::PocketCamera { ram, .. } => ram,
+      _ => &[],
+    }
+  }
+
+  fn ram_mut(&mut self) -> &mut [u8] {
+    match self {
+      Self::RomOnly { ram, .. }
+      | Self::MBC1 { ram, .. }
+      | Self::MBC2 { ram, .. }
+      | Self::MBC3 { ram, .. }
+      | Self::MBC5 { ram, .. }
+      | Self::HuC1 { ram, .. }
+      | Self::PocketCamera { ram, .. } => ram,
+      _ => &mut [],
+    }
+  }
+
+  fn ram_dirty_mut(&mut self) -> Option<&mut bool> {
+    match self {
+      Self::RomOnly { ram_dirty, .. }
+      | Self::MBC1 { ram_dirty, .. }
+      | Self::MBC2 { ram_dirty, .. }
+      | Self::MBC3 { ram_dirty, .. }
+      | Self::MBC5 { ram_dirty, .. }
+      | Self::HuC1 { ram_dirty, .. }
+      | Self::PocketCamera { ram_dirty, .. } => {
+        Some(ram_dirty)
+      }
+      _ => None,
+    }
+  }
+
+  /// Whether the external RAM has changed since it was last taken by `save_ram` or loaded by `load_ram`
+  pub fn ram_dirty(&self) -> bool {
+    match self {
+      Self::RomOnly { ram_dirty, .. }
+      | Self::MBC1 { ram_dirty, .. }
+      | Self::MBC2 { ram_dirty, .. }
+      | Self::MBC3 { ram_dirty, .. }
+      | Self::MBC5 { ram_dirty, .. }
+      | Self::HuC1 { ram_dirty, .. }
+      | Self::PocketCamera { ram_dirty, .. } => {
+        *ram_dirty
+      }
+      _ => false,
+    }
+  }
+
+  /// Whether the mapper lets the external RAM be accessed
+  fn ram_enabled(&self) -> bool {
+    match self {
+      Self::MBC1 { ram_enabled, .. }
+      | Self::MBC2 { ram_enabled, .. }
+      | Self::MBC3 { ram_enabled, .. }
+      | Self::MBC5 { ram_enabled, .. }
+      | Self::PocketCamera { ram_enabled, .. } => *ram_enabled,
+      _ => true,
+    }
+  }
+
+  /// The bank mapped at 4000-7FFF
+  pub fn current_rom_bank(&self) -> usize {
+    match self {
+      Self::MBC1 { rom, rom_bank, bank2, multicart, forced_rom_bank, .. } => {
+        let (shift, mask) = Self::mbc1_wiring(*multicart);
+        let bank = (*bank2 as usize) << shift | (*rom_bank & mask) as usize;
+        forced_rom_bank.unwrap_or(bank) % rom.bank_count().max(1)
+      }
+      Self::MBC2 { rom, rom_bank, forced_rom_bank, .. }
+      | Self::MBC3 { rom, rom_bank, forced_rom_bank, .. }
+      | Self::HuC1 { rom, rom_bank, forced_rom_bank, .. } => {
+        forced_rom_bank.unwrap_or(*rom_bank as usize) % rom.bank_count().max(1)
+      }
+      Self::MBC5 { rom, rom_bank, forced_rom_bank, .. } => {
+        forced_rom_bank.unwrap_or(*rom_bank as usize) % rom.bank_count().max(1)
+      }
+      Self::PocketCamera { rom, rom_bank, forced_rom_bank, .. } => {
+        forced_rom_bank.unwrap_or(*rom_bank as usize) % rom.bank_count().max(1)
+      }
+      _ => 1,
+    }
+  }
+
+  /// The bank mapped at 0000-3FFF, only ever other than 0 on large MBC1 ROMs in advanced banking mode
+  fn zero_rom_bank(&self) -> usize {
+    match self {
+      Self::MBC1 { rom, bank2, advanced_banking: true, multicart, .. } => {
+        let (shift, _) = Self::mbc1_wiring(*multicart);
+        ((*bank2 as usize) << shift) % rom.bank_count().max(1)
+      }
+      _ => 0,
+    }
+  }
+
+  /// The bank of external RAM mapped at A000-BFFF
+  pub fn current_ram_bank(&self) -> usize {
+    match self {
+      Self::MBC1 { ram, bank2, advanced_banking, forced_ram_bank, .. } => {
+        // the bank bits aren't connected on cartridges with a single bank of RAM
+        let ram_banks = (ram.len() / Self::RAM_BANK_SIZE).max(1);
+        let bank = if *advanced_banking { *bank2 as usize % ram_banks } else { 0 };
+        forced_ram_bank.unwrap_or(bank)
+      }
+      Self::MBC3 { ram_bank, forced_ram_bank, .. } => forced_ram_bank.unwrap_or(*ram_bank as usize),
+      Self::PocketCamera { ram, ram_bank, forced_ram_bank, .. } => {
+        let ram_banks = (ram.len() / Self::RAM_BANK_SIZE).max(1);
+        forced_ram_bank.unwrap_or((*ram_bank & Self::CAMERA_RAM_BANK_MASK) as usize % ram_banks)
+      }
+      Self::MBC5 { ram, ram_bank, forced_ram_bank, .. } | Self::HuC1 { ram, ram_bank, forced_ram_bank, .. } => {
+        let ram_banks = (ram.len() / Self::RAM_BANK_SIZE).max(1);
+        forced_ram_bank.unwrap_or(*ram_bank as usize % ram_banks)
+      }
+      _ => 0,
+    }
+  }
+
+  /// Pin `bank` at 4000-7FFF whatever the game writes to the mapper, or go back to the mapper's choice
+  /// with `None`
+  ///
+  /// # Returns
+  /// false if the cartridge has no mapper to override
+  pub fn force_rom_bank(&mut self, bank: Option<usize>) -> bool {
+    match self {
+      Self::MBC1 { forced_rom_bank, .. }
+      | Self::MBC2 { forced_rom_bank, .. }
+      | Self::MBC3 { forced_rom_bank, .. }
+      | Self::MBC5 { forced_rom_bank, .. }
+      | Self::HuC1 { forced_rom_bank, .. }
+      | Self::PocketCamera { forced_rom_bank, .. } => {
+        *forced_rom_bank = bank;
+        true
+      }
+      _ => false,
+    }
+  }
+
+  /// Pin `bank` of external RAM at A000-BFFF, or go back to the mapper's choice with `None`
+  ///
+  /// # Returns
+  /// false if the cartridge has no mapper to override
+  pub fn force_ram_bank(&mut self, bank: Option<usize>) -> bool {
+    match self {
+      Self::MBC1 { forced_ram_bank, .. }
+      | Self::MBC3 { forced_ram_bank, .. }
+      | Self::MBC5 { forced_ram_bank, .. }
+      | Self::HuC1 { forced_ram_bank, .. }
+      | Self::PocketCamera { forced_ram_bank, .. } => {
+        *forced_ram_bank = bank;
+        true
+      }
+      _ => false,
+    }
+  }
+
+  /// Replace the host clock the cartridge's real time clock counts from, for tests and for keeping
+  /// time with something other than the wall clock
+  ///
+  /// # Returns
+  /// false if the cartridge has no clock
+  pub fn set_rtc_clock(&mut self, clock: impl Clock + Send + Sync + 'static) -> bool {
+    match self {
+      Self::MBC3 { rtc: Some(rtc), .. } => {
+        rtc.set_clock(clock);
+        true
+      }
+      _ => false,
+    }
+  }
+
+  /// Whether the game has a rumble cartridge's motor switched on
+  pub fn rumble(&self) -> bool {
+    match self {
+      Self::MBC5 { rumble, .. } => *rumble,
+      _ => false,
+    }
+  }
+
+  /// Take Game Boy Camera images from `source` rather than a blank one, such as a file or a webcam
+  ///
+  /// # Returns
+  /// false if the cartridge has no camera
+  pub fn set_camera_source(&mut self, source: impl ImageSource + Send + Sync + 'static) -> bool {
+    match self {
+      Self::PocketCamera { camera, .. } => {
+        camera.set_source(source);
+        true
+      }
+      _ => false,
+    }
+  }
+
+  /// Whether a HuC1 cartridge has its infrared port mapped at A000-BFFF in place of RAM
+  pub fn infrared_mapped(&self) -> bool {
+    matches!(self, Self::HuC1 { ir_mode: true, .. })
+  }
+
+  /// The clock register mapped at A000-BFFF in place of RAM, if any
+  fn mapped_rtc_register(&self) -> Option<u8> {
+    match self {
+      Self::MBC3 { rtc: Some(_), ram_bank, forced_ram_bank: None, .. }
+        if (Rtc::FIRST_REGISTER..=Rtc::LAST_REGISTER).contains(ram_bank) => Some(*ram_bank),
+      _ => None,
+    }
+  }
+
+  /// Offset of `address` into the external RAM, in the current bank
+  fn ram_offset(&self, address: u16) -> usize {
+    match self {
+      Self::MBC2 { .. } => address as usize % Self::MBC2_RAM_SIZE,
+      _ => self.current_ram_bank() * Self::RAM_BANK_SIZE + address as usize,
+    }
+  }
+
   pub fn read_ram(&self, address: u16) -> u8 {
-    Self::NO_RAM_READ_VALUE
+    if let Self::HuC1 { ir_mode: true, .. } = self {
+      return Self::HUC1_IR_READ_VALUE;
+    }
+    if let Self::PocketCamera { camera, ram_bank, forced_ram_bank: None, .. } = self {
+      if ram_bank & Self::CAMERA_SELECT_BIT != 0 {
+        return camera.read(address);
+      }
+    }
+    if !self.ram_enabled() {
+      return Self::NO_RAM_READ_VALUE;
+    }
+    if let (Some(register), Self::MBC3 { rtc: Some(rtc), .. }) = (self.mapped_rtc_register(), self) {
+      return rtc.read(register);
+    }
+    let value = self.ram().get(self.ram_offset(address)).cloned().unwrap_or(Self::NO_RAM_READ_VALUE);
+    match self {
+      // the upper half of each byte isn't connected
+      Self::MBC2 { .. } => value | !Self::MBC2_RAM_VALUE_MASK,
+      _ => value,
+    }
   }
 
   pub fn write_ram(&mut self, address: u16, value: u8) {
-    match self {
-      _ => unimplemented!("cannot write value = {} to address '{}'", value, address)
+    if let Self::PocketCamera { camera, ram, ram_dirty, ram_bank, forced_ram_bank: None, .. } = self {
+      if *ram_bank & Self::CAMERA_SELECT_BIT != 0 {
+        // the image lands in the battery backed RAM
+        *ram_dirty |= camera.write(address, value, ram);
+        return;
+      }
     }
+    // writes in infrared mode switch the LED, the MMU routes them to its transceiver
+    if !self.ram_enabled() || matches!(self, Self::HuC1 { ir_mode: true, .. }) {
+      return;
+    }
+    if let (Some(register), Self::MBC3 { rtc: Some(rtc), .. }) = (self.mapped_rtc_register(), &mut *self) {
+      rtc.write(register, value);
+      return;
+    }
+    let offset = self.ram_offset(address);
+    let value = match self {
+      Self::MBC2 { .. } => value & Self::MBC2_RAM_VALUE_MASK,
+      _ => value,
+    };
+    let changed = match self.ram_mut().get_mut(offset) {
+      Some(x) if *x != value => {
+        *x = value;
+        true
+      }
+      _ => false,
+    };
+    if let (true, Some(ram_dirty)) = (changed, self.ram_dirty_mut()) {
+      *ram_dirty = true;
+    }
+  }
+
+  /// The battery backed external RAM to write to a save file, marking it clean
+  ///
+  /// # Returns
+  /// `None` if the cartridge has no RAM or no battery to keep it
+  pub fn save_ram(&mut self) -> Option<&[u8]> {
+    if !self.header().battery() || self.ram().is_empty() {
+      return None;
+    }
+    if let Some(ram_dirty) = self.ram_dirty_mut() {
+      *ram_dirty = false;
+    }
+    Some(self.ram())
+  }
+
+  /// A copy of the external RAM for writing to a battery save, or `None` if the cartridge has no RAM
+  pub fn dump_ram(&self) -> Option<Vec<u8>> {
+    match self.ram() {
+      [] => None,
+      ram => Some(ram.to_vec()),
+    }
+  }
+
+  /// Restore the external RAM from a battery save created by `dump_ram` or `save_ram`
+  pub fn load_ram(&mut self, bytes: &[u8]) -> Result<(), SaveError> {
+    let ram = self.ram_mut();
+    if ram.is_empty() {
+      return Err(SaveError::NoRam);
+    }
+    if ram.len() != bytes.len() {
+      return Err(SaveError::SizeMismatch { expected: ram.len(), actual: bytes.len() });
+    }
+    ram.copy_from_slice(bytes);
+    if let Some(ram_dirty) = self.ram_dirty_mut() {
+      *ram_dirty = false;
+    }
+    Ok(())
   }
 }
 
 impl Memory for Cartridge {
   fn read(&self, address: u16) -> u8 {
+    let bank = address as usize / Self::ROM_BANK_SIZE;
+    let offset = address % Self::ROM_BANK_SIZE as u16;
     match self {
-      Self::RomOnly(inner) => inner[address as usize],
-      _ => unimplemented!()
+      Self::RomOnly { rom, .. } => rom.read(bank, offset),
+      Self::MBC1 { rom, .. } if bank == 0 => rom.read(self.zero_rom_bank(), offset),
+      Self::MBC1 { rom, .. } => rom.read(self.current_rom_bank(), offset),
+      Self::MBC2 { rom, .. }
+      | Self::MBC3 { rom, .. }
+      | Self::MBC5 { rom, .. }
+      | Self::HuC1 { rom, .. }
+      | Self::PocketCamera { rom, .. } => {
+        rom.read(if bank == 0 { 0 } else { self.current_rom_bank() }, offset)
+      }
+      // mappers without an implementation have nothing mapped
+      Self::Rumble {} => Self::NO_ROM_READ_VALUE,
     }
   }
 
-  fn write(&mut self, _address: u16, _value: u8) {
+  fn write(&mut self, address: u16, value: u8) {
     match self {
-      Self::RomOnly(_) => { /* noop */ },
-      _ => unimplemented!()
+      Self::RomOnly { .. } => { /* noop */ },
+      Self::MBC1 { rom_bank, bank2, advanced_banking, ram_enabled, .. } => match address {
+        0..=Self::MBC1_RAM_ENABLE_END_ADDRESS => *ram_enabled = value & 0x0F == Self::MBC1_RAM_ENABLE_VALUE,
+        Self::MBC1_ROM_BANK_START_ADDRESS..=Self::MBC1_ROM_BANK_END_ADDRESS => {
+          // bank 0 can't be mapped at 4000-7FFF, selecting it selects bank 1. Only the lower bits
+          // are checked, so 0x20, 0x40 and 0x60 select the bank after them too.
+          *rom_bank = (value & Self::MBC1_ROM_BANK_MASK).max(1);
+        }
+        Self::MBC1_BANK2_START_ADDRESS..=Self::MBC1_BANK2_END_ADDRESS => *bank2 = value & Self::MBC1_BANK2_MASK,
+        Self::MBC1_MODE_START_ADDRESS..=Self::MBC1_MODE_END_ADDRESS => *advanced_banking = value & 1 != 0,
+        _ => {}
+      },
+      Self::MBC2 { rom_bank, ram_enabled, .. } => match address {
+        0..=Self::MBC2_REGISTER_END_ADDRESS if address & Self::MBC2_ROM_BANK_SELECT_BIT != 0 => {
+          *rom_bank = (value & Self::MBC2_ROM_BANK_MASK).max(1);
+        }
+        0..=Self::MBC2_REGISTER_END_ADDRESS => *ram_enabled = value & 0x0F == Self::MBC1_RAM_ENABLE_VALUE,
+        _ => {}
+      },
+      Self::MBC3 { rom_bank, ram_bank, ram_enabled, rtc, .. } => match address {
+        0..=Self::MBC1_RAM_ENABLE_END_ADDRESS => *ram_enabled = value & 0x0F == Self::MBC1_RAM_ENABLE_VALUE,
+        // unlike MBC1 all 7 bits are checked for bank 0
+        Self::MBC1_ROM_BANK_START_ADDRESS..=Self::MBC1_ROM_BANK_END_ADDRESS => {
+          *rom_bank = (value & Self::MBC3_ROM_BANK_MASK).max(1);
+        }
+        Self::MBC1_BANK2_START_ADDRESS..=Self::MBC1_BANK2_END_ADDRESS => *ram_bank = value,
+        Self::MBC1_MODE_START_ADDRESS..=Self::MBC1_MODE_END_ADDRESS => {
+          if let Some(rtc) = rtc {
+            rtc.write_latch(value);
+          }
+        }
+        _ => {}
+      },
+      Self::MBC5 { rom_bank, ram_bank, ram_enabled, has_rumble, rumble, .. } => match address {
+        0..=Self::MBC1_RAM_ENABLE_END_ADDRESS => *ram_enabled = value & 0x0F == Self::MBC1_RAM_ENABLE_VALUE,
+        Self::MBC1_ROM_BANK_START_ADDRESS..=Self::MBC5_ROM_BANK_LOW_END_ADDRESS => {
+          *rom_bank = (*rom_bank & 0x100) | value as u16;
+        }
+        // 3000-3FFF holds bit 8
+        Self::MBC5_ROM_BANK_HIGH_START_ADDRESS..=Self::MBC1_ROM_BANK_END_ADDRESS => {
+          *rom_bank = (*rom_bank & 0xFF) | ((value as u16 & 1) << 8);
+        }
+        Self::MBC1_BANK2_START_ADDRESS..=Self::MBC1_BANK2_END_ADDRESS if *has_rumble => {
+          *rumble = value & Self::MBC5_RUMBLE_BIT != 0;
+          *ram_bank = value & Self::MBC5_RAM_BANK_MASK & !Self::MBC5_RUMBLE_BIT;
+        }
+        Self::MBC1_BANK2_START_ADDRESS..=Self::MBC1_BANK2_END_ADDRESS => *ram_bank = value & Self::MBC5_RAM_BANK_MASK,
+        _ => {}
+      },
+      Self::HuC1 { rom_bank, ram_bank, ir_mode, .. } => match address {
+        0..=Self::MBC1_RAM_ENABLE_END_ADDRESS => *ir_mode = value & 0x0F == Self::HUC1_IR_SELECT_VALUE,
+        Self::MBC1_ROM_BANK_START_ADDRESS..=Self::MBC1_ROM_BANK_END_ADDRESS => {
+          *rom_bank = (value & Self::HUC1_ROM_BANK_MASK).max(1);
+        }
+        Self::MBC1_BANK2_START_ADDRESS..=Self::MBC1_BANK2_END_ADDRESS => *ram_bank = value & Self::HUC1_RAM_BANK_MASK,
+        _ => {}
+      },
+      Self::PocketCamera { rom_bank, ram_bank, ram_enabled, .. } => match address {
+        0..=Self::MBC1_RAM_ENABLE_END_ADDRESS => *ram_enabled = value & 0x0F == Self::MBC1_RAM_ENABLE_VALUE,
+        Self::MBC1_ROM_BANK_START_ADDRESS..=Self::MBC1_ROM_BANK_END_ADDRESS => {
+          *rom_bank = value & Self::CAMERA_ROM_BANK_MASK;
+        }
+        Self::MBC1_BANK2_START_ADDRESS..=Self::MBC1_BANK2_END_ADDRESS => {
+          *ram_bank = value & (Self::CAMERA_SELECT_BIT | Self::CAMERA_RAM_BANK_MASK);
+        }
+        _ => {}
+      },
+      Self::Rumble {} => {}
+    }
+  }
+}
+
+impl SaveState for Cartridge {
+  /// Writes the external RAM, the ROM is supplied when the gameboy is created
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(self.ram())?;
+    if let Self::MBC1 { rom_bank, bank2, advanced_banking, ram_enabled, .. } = self {
+      write_u8(w, *rom_bank)?;
+      write_u8(w, *bank2)?;
+      write_bool(w, *advanced_banking)?;
+      write_bool(w, *ram_enabled)?;
+    }
+    if let Self::MBC2 { rom_bank, ram_enabled, .. } = self {
+      write_u8(w, *rom_bank)?;
+      write_bool(w, *ram_enabled)?;
+    }
+    if let Self::MBC3 { rom_bank, ram_bank, ram_enabled, rtc, .. } = self {
+      write_u8(w, *rom_bank)?;
+      write_u8(w, *ram_bank)?;
+      write_bool(w, *ram_enabled)?;
+      if let Some(rtc) = rtc {
+        rtc.write_state(w)?;
+      }
+    }
+    if let Self::HuC1 { rom_bank, ram_bank, ir_mode, .. } = self {
+      write_u8(w, *rom_bank)?;
+      write_u8(w, *ram_bank)?;
+      write_bool(w, *ir_mode)?;
+    }
+    if let Self::PocketCamera { rom_bank, ram_bank, ram_enabled, camera, .. } = self {
+      write_u8(w, *rom_bank)?;
+      write_u8(w, *ram_bank)?;
+      write_bool(w, *ram_enabled)?;
+      camera.write_state(w)?;
+    }
+    if let Self::MBC5 { rom_bank, ram_bank, ram_enabled, rumble, .. } = self {
+      write_u16(w, *rom_bank)?;
+      write_u8(w, *ram_bank)?;
+      write_bool(w, *ram_enabled)?;
+      write_bool(w, *rumble)?;
+    }
+    Ok(())
+  }
+
+  /// Marks the RAM dirty, as it may no longer match the battery save
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    r.read_exact(self.ram_mut())?;
+    if let Some(ram_dirty) = self.ram_dirty_mut() {
+      *ram_dirty = true;
+    }
+    if let Self::MBC1 { rom_bank, bank2, advanced_banking, ram_enabled, .. } = self {
+      *rom_bank = read_u8(r)?;
+      *bank2 = read_u8(r)?;
+      *advanced_banking = read_bool(r)?;
+      *ram_enabled = read_bool(r)?;
+    }
+    if let Self::MBC2 { rom_bank, ram_enabled, .. } = self {
+      *rom_bank = read_u8(r)?;
+      *ram_enabled = read_bool(r)?;
+    }
+    if let Self::MBC3 { rom_bank, ram_bank, ram_enabled, rtc, .. } = self {
+      *rom_bank = read_u8(r)?;
+      *ram_bank = read_u8(r)?;
+      *ram_enabled = read_bool(r)?;
+      if let Some(rtc) = rtc {
+        rtc.read_state(r)?;
+      }
+    }
+    if let Self::HuC1 { rom_bank, ram_bank, ir_mode, .. } = self {
+      *rom_bank = read_u8(r)?;
+      *ram_bank = read_u8(r)?;
+      *ir_mode = read_bool(r)?;
+    }
+    if let Self::PocketCamera { rom_bank, ram_bank, ram_enabled, camera, .. } = self {
+      *rom_bank = read_u8(r)?;
+      *ram_bank = read_u8(r)?;
+      *ram_enabled = read_bool(r)?;
+      camera.read_state(r)?;
+    }
+    if let Self::MBC5 { rom_bank, ram_bank, ram_enabled, rumble, .. } = self {
+      *rom_bank = read_u16(r)?;
+      *ram_bank = read_u8(r)?;
+      *ram_enabled = read_bool(r)?;
+      *rumble = read_bool(r)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use {
+    super::*,
+    std::sync::Mutex,
+  };
+
+  /// Serves banks filled with their own bank number, recording which banks were fetched
+  #[derive(Default)]
+  struct FakeRomSource {
+    fetched: Arc<Mutex<Vec<usize>>>,
+  }
+
+  impl RomSource for FakeRomSource {
+    fn bank_count(&self) -> usize {
+      2
+    }
+
+    fn read(&self, bank: usize, _offset: u16) -> u8 {
+      self.fetched.lock().unwrap().push(bank);
+      bank as u8 + 0x10
+    }
+  }
+
+  #[test]
+  fn banked_reads_are_fetched_from_rom_source() {
+    let source = FakeRomSource::default();
+    let fetched = source.fetched.clone();
+    let cartridge = Cartridge::from_banks(source);
+    // only the header in the first bank is read up front
+    assert!(fetched.lock().unwrap().iter().all(|&bank| bank == 0));
+    fetched.lock().unwrap().clear();
+
+    assert_eq!(cartridge.read(0x0000), 0x10);
+    assert_eq!(cartridge.read(0x3FFF), 0x10);
+    assert_eq!(cartridge.read(0x4000), 0x11);
+    assert_eq!(cartridge.read(0x7FFF), 0x11);
+    assert_eq!(*fetched.lock().unwrap(), vec![0, 0, 1, 1]);
+  }
+
+  #[test]
+  fn buffered_rom_reads_by_bank() {
+    let mut bytes = vec![0x00; 0x8000];
+    bytes[0x4000] = 0x01;
+    let cartridge = Cartridge::maybe_from_bytes(&bytes).unwrap();
+
+    assert_eq!(cartridge.read(0x0000), 0x00);
+    assert_eq!(cartridge.read(0x4000), 0x01);
+  }
+
+  fn cartridge_with_ram_size(header_value: u8) -> Cartridge {
+    let mut bytes = vec![0x00; 0x8000];
+    bytes[CartridgeHeader::RAM_SIZE_HEADER_ADDRESS as usize] = header_value;
+    Cartridge::maybe_from_bytes(&bytes).unwrap()
+  }
+
+  #[test]
+  fn ram_is_sized_from_header() {
+    let mut cartridge = cartridge_with_ram_size(0x02);
+    cartridge.write_ram(0x1FFF, 0x12);
+    assert_eq!(cartridge.read_ram(0x1FFF), 0x12);
+    assert_eq!(cartridge.dump_ram().map(|x| x.len()), Some(0x2000));
+
+    assert!(cartridge_with_ram_size(0x00).dump_ram().is_none());
+  }
+
+  #[test]
+  fn load_ram_checks_size() {
+    let mut cartridge = cartridge_with_ram_size(0x02);
+    match cartridge.load_ram(&[0; 0x800]) {
+      Err(SaveError::SizeMismatch { expected: 0x2000, actual: 0x800 }) => {}
+      result => panic!("unexpected result {:?}", result),
+    }
+    match cartridge_with_ram_size(0x00).load_ram(&[0; 0x800]) {
+      Err(SaveError::NoRam) => {}
+      result => panic!("unexpected result {:?}", result),
+    }
+  }
+
+  #[test]
+  fn info_is_read_from_header() {
+    let mut bytes = vec![0x00; 0x8000];
+    bytes[0x0134..0x0134 + 8].copy_from_slice(b"TETRIS\0\0");
+    bytes[0x0143] = 0x80;
+    bytes[0x0146] = 0x03;
+    bytes[0x0147] = 0x09; // ROM+RAM+BATTERY
+    bytes[0x0148] = 0x00;
+    bytes[0x0149] = 0x02;
+    bytes[0x014D] = 0x7E;
+    let info = Cartridge::maybe_from_bytes(&bytes).unwrap().info();
+
+    assert_eq!(info, CartridgeInfo {
+      title: "TETRIS".to_string(),
+      mbc: MbcKind::None,
+      rom_size: 0x8000,
+      ram_size: 0x2000,
+      cgb: true,
+      cgb_only: false,
+      sgb: true,
+      battery: true,
+      header_checksum_valid: true,
+    });
+
+    bytes[0x014D] = 0x00;
+    assert!(!Cartridge::maybe_from_bytes(&bytes).unwrap().info().header_checksum_valid);
+  }
+
+  #[test]
+  fn writes_mark_battery_ram_dirty_until_saved() {
+    let mut bytes = vec![0x00; 0x8000];
+    bytes[CartridgeHeader::TYPE_HEADER_ADDRESS as usize] = 0x03; // MBC1+RAM+BATTERY
+    bytes[CartridgeHeader::RAM_SIZE_HEADER_ADDRESS as usize] = 0x02;
+    let mut cartridge = Cartridge::maybe_from_bytes(&bytes).unwrap();
+    cartridge.write(0x0000, 0x0A);
+    assert!(!cartridge.ram_dirty());
+
+    // writing what is already there changes nothing
+    cartridge.write_ram(0x0000, 0x00);
+    assert!(!cartridge.ram_dirty());
+    cartridge.write_ram(0x0000, 0x12);
+    assert!(cartridge.ram_dirty());
+    assert_eq!(cartridge.save_ram().map(|x| x[0]), Some(0x12));
+    assert!(!cartridge.ram_dirty());
+
+    // without a battery there's nothing to save
+    assert!(cartridge_with_ram_size(0x02).save_ram().is_none());
+  }
+
+  #[test]
+  fn header_is_parsed() {
+    let mut bytes = vec![0x00; 0x150];
+    bytes[0x0134..0x0134 + 6].copy_from_slice(b"POKEMO");
+    bytes[0x0143] = 0xC0;
+    bytes[0x0147] = 0x10; // MBC3+TIMER+RAM+BATTERY
+    bytes[0x0148] = 0x06;
+    bytes[0x0149] = 0x03;
+    bytes[0x014A] = 0x01;
+    let header = CartridgeHeader::from_bytes(&bytes).unwrap();
+
+    assert_eq!(header.title, "POKEMO");
+    assert_eq!(header.mbc(), MbcKind::MBC3);
+    assert_eq!(header.rom_size(), 0x200000);
+    assert_eq!(header.ram_size(), 0x8000);
+    assert!(header.cgb() && header.cgb_only() && !header.sgb());
+    assert!(header.battery() && header.rtc() && !header.rumble());
+    assert!(!header.japanese());
+
+    assert!(CartridgeHeader::from_bytes(&bytes[..0x14F]).is_none());
+  }
+
+  #[test]
+  fn mapper_is_selected_from_header() {
+    for &(cartridge_type, mbc) in &[(0x00, MbcKind::None), (0x03, MbcKind::MBC1), (0x13, MbcKind::MBC3), (0x1B, MbcKind::MBC5)] {
+      let mut cartridge = Cartridge::maybe_from_bytes(&rom_with_type(cartridge_type)).unwrap();
+      assert_eq!(cartridge.header().mbc(), mbc);
+      // only the mappers switch banks
+      cartridge.write(0x2000, 3);
+      let expected = if mbc == MbcKind::None { 1 } else { 3 };
+      assert_eq!(cartridge.read(0x4000), expected, "cartridge type 0x{:02x}", cartridge_type);
+    }
+  }
+
+  #[test]
+  fn rom_banks_are_enumerated_in_order() {
+    let bytes: Vec<u8> = (0..4).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+    let cartridge = Cartridge::maybe_from_bytes(&bytes).unwrap();
+    assert_eq!(cartridge.bank_count(), 4);
+
+    let banks: Vec<_> = cartridge.rom_banks().collect();
+    assert_eq!(banks.len(), 4);
+    for (i, bank) in banks.iter().enumerate() {
+      assert_eq!(bank.len(), 0x4000);
+      assert!(bank.iter().all(|&x| x == i as u8));
+    }
+  }
+
+  #[test]
+  fn rom_source_banks_are_enumerated() {
+    let cartridge = Cartridge::from_banks(FakeRomSource::default());
+    let banks: Vec<_> = cartridge.rom_banks().collect();
+    assert_eq!(banks.len(), 2);
+    assert!(banks[1].iter().all(|&x| x == 0x11));
+  }
+
+  fn rom_with_type(cartridge_type: u8) -> Vec<u8> {
+    let mut bytes: Vec<u8> = (0..4).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+    bytes[CartridgeHeader::TYPE_HEADER_ADDRESS as usize] = cartridge_type;
+    bytes
+  }
+
+  #[test]
+  fn strict_load_rejects_unknown_type() {
+    match Cartridge::from_bytes(&rom_with_type(0xAB), LoadMode::Strict) {
+      Err(CartridgeError::Unsupported { cartridge_type: 0xAB }) => {}
+      Err(e) => panic!("unexpected error {:?}", e),
+      Ok(_) => panic!("unknown cartridge type was loaded"),
+    }
+    assert!(Cartridge::from_bytes(&rom_with_type(0x01), LoadMode::Strict).is_ok());
+  }
+
+  /// A 4 bank image with the logo, header and checksums a verified load asks for
+  fn clean_dump(cartridge_type: u8) -> Vec<u8> {
+    let mut bytes = rom_with_type(cartridge_type);
+    let logo = CartridgeHeader::LOGO_START_ADDRESS as usize;
+    bytes[logo..logo + CartridgeHeader::LOGO.len()].copy_from_slice(&CartridgeHeader::LOGO);
+    bytes[CartridgeHeader::ROM_SIZE_HEADER_ADDRESS as usize] = 0x01;
+    let header_checksum = CartridgeHeader::from_bytes(&bytes).unwrap().computed_checksum;
+    bytes[CartridgeHeader::HEADER_CHECKSUM_ADDRESS as usize] = header_checksum;
+    let global_checksum = CartridgeHeader::compute_global_checksum(&bytes).to_be_bytes();
+    let at = CartridgeHeader::GLOBAL_CHECKSUM_ADDRESS as usize;
+    bytes[at..at + 2].copy_from_slice(&global_checksum);
+    bytes
+  }
+
+  #[test]
+  fn verified_load_rejects_bad_dumps() {
+    let bytes = clean_dump(0x01);
+    assert!(Cartridge::from_bytes(&bytes, LoadMode::Verified).is_ok());
+
+    let mut bad_logo = bytes.clone();
+    bad_logo[CartridgeHeader::LOGO_START_ADDRESS as usize] ^= 0xFF;
+    assert!(matches!(Cartridge::from_bytes(&bad_logo, LoadMode::Verified), Err(CartridgeError::BadLogo)));
+
+    let mut bad_header = bytes.clone();
+    bad_header[CartridgeHeader::TITLE_HEADER_START_ADDRESS as usize] = b'X';
+    assert!(matches!(
+      Cartridge::from_bytes(&bad_header, LoadMode::Verified),
+      Err(CartridgeError::BadHeaderChecksum { .. })
+    ));
+
+    let mut bad_rom = bytes.clone();
+    bad_rom[0x4000] ^= 0xFF;
+    assert!(matches!(
+      Cartridge::from_bytes(&bad_rom, LoadMode::Verified),
+      Err(CartridgeError::BadGlobalChecksum { .. })
+    ));
+    // the other modes don't care
+    assert!(Cartridge::from_bytes(&bad_rom, LoadMode::Strict).is_ok());
+
+    match Cartridge::from_bytes(&bytes[..0xC000], LoadMode::Verified) {
+      Err(CartridgeError::SizeMismatch { expected: 0x10000, actual: 0xC000 }) => {}
+      result => panic!("unexpected result {:?}", result.map(|_| ())),
+    }
+
+    assert!(matches!(
+      Cartridge::from_bytes(&clean_dump(0xAB), LoadMode::Verified),
+      Err(CartridgeError::Unsupported { cartridge_type: 0xAB })
+    ));
+  }
+
+  #[test]
+  fn from_file_reports_io_errors() {
+    assert!(matches!(
+      Cartridge::from_file("/nonexistent/rom.gb", LoadMode::Lenient),
+      Err(CartridgeError::Io(_))
+    ));
+  }
+
+  #[test]
+  fn lenient_load_falls_back_to_mbc1_banking() {
+    let mut cartridge = Cartridge::from_bytes(&rom_with_type(0xAB), LoadMode::Lenient).unwrap();
+    assert_eq!(cartridge.info().mbc, MbcKind::Unknown(0xAB));
+    assert_eq!(cartridge.read(0x4000), 1);
+
+    cartridge.write(0x2000, 3);
+    assert_eq!(cartridge.read(0x4000), 3);
+    assert_eq!(cartridge.read(0x0000), 0);
+    cartridge.write(0x2000, 0);
+    assert_eq!(cartridge.read(0x4000), 1);
+  }
+
+  #[test]
+  fn forced_rom_bank_overrides_mapper() {
+    let mut cartridge = Cartridge::from_bytes(&rom_with_type(0x01), LoadMode::Strict).unwrap();
+    cartridge.write(0x2000, 3);
+    assert!(cartridge.force_rom_bank(Some(2)));
+    assert_eq!(cartridge.current_rom_bank(), 2);
+    assert_eq!(cartridge.read(0x4000), 2);
+
+    // the game switching banks doesn't move it
+    cartridge.write(0x2000, 1);
+    assert_eq!(cartridge.read(0x4000), 2);
+    // even bank 0 can be pinned
+    cartridge.force_rom_bank(Some(0));
+    assert_eq!(cartridge.read(0x4000), 0);
+
+    cartridge.force_rom_bank(None);
+    assert_eq!(cartridge.current_rom_bank(), 1);
+    assert_eq!(cartridge.read(0x4000), 1);
+  }
+
+  #[test]
+  fn forced_ram_bank_offsets_external_ram() {
+    let mut bytes = rom_with_type(0x03);
+    // 32KB, four banks
+    bytes[CartridgeHeader::RAM_SIZE_HEADER_ADDRESS as usize] = 0x03;
+    let mut cartridge = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+    cartridge.write(0x0000, 0x0A);
+    assert_eq!(cartridge.current_ram_bank(), 0);
+
+    assert!(cartridge.force_ram_bank(Some(2)));
+    cartridge.write_ram(0x0010, 0x42);
+    assert_eq!(cartridge.dump_ram().unwrap()[2 * Cartridge::RAM_BANK_SIZE + 0x10], 0x42);
+    cartridge.force_ram_bank(None);
+    assert_eq!(cartridge.read_ram(0x0010), 0x00);
+  }
+
+  #[test]
+  fn rom_only_has_no_bank_to_force() {
+    let mut cartridge = Cartridge::maybe_from_bytes(&rom_with_type(0x00)).unwrap();
+    assert!(!cartridge.force_rom_bank(Some(2)));
+    assert_eq!(cartridge.current_rom_bank(), 1);
+  }
+
+  #[test]
+  fn mbc1_upper_bank_bits() {
+    // 2MB, each bank filled with the low byte of its number
+    let mut bytes: Vec<u8> = (0..0x80).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+    bytes[CartridgeHeader::TYPE_HEADER_ADDRESS as usize] = 0x01;
+    let mut cartridge = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+
+    cartridge.write(0x2000, 0x02);
+    cartridge.write(0x4000, 0x01);
+    assert_eq!(cartridge.read(0x4000), 0x22);
+    // 0000-3FFF only follows the upper bits in advanced banking mode
+    assert_eq!(cartridge.read(0x0000), 0x00);
+    cartridge.write(0x6000, 0x01);
+    assert_eq!(cartridge.read(0x0000), 0x20);
+    cartridge.write(0x6000, 0x00);
+    assert_eq!(cartridge.read(0x0000), 0x00);
+
+    // only the lower 5 bits are checked for 0
+    cartridge.write(0x2000, 0x20);
+    assert_eq!(cartridge.current_rom_bank(), 0x21);
+  }
+
+  #[test]
+  fn mbc1_ram_banking_mode() {
+    let mut bytes = rom_with_type(0x03);
+    // 32KB, four banks
+    bytes[CartridgeHeader::RAM_SIZE_HEADER_ADDRESS as usize] = 0x03;
+    let mut cartridge = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+    cartridge.write(0x0000, 0x0A);
+    cartridge.write(0x4000, 0x02);
+    // the RAM bank is only selected in advanced banking mode
+    assert_eq!(cartridge.current_ram_bank(), 0);
+    cartridge.write(0x6000, 0x01);
+    assert_eq!(cartridge.current_ram_bank(), 2);
+    cartridge.write_ram(0x0010, 0x42);
+    assert_eq!(cartridge.dump_ram().unwrap()[2 * Cartridge::RAM_BANK_SIZE + 0x10], 0x42);
+
+    // a single bank of RAM ignores the bank bits
+    let mut bytes = rom_with_type(0x03);
+    bytes[CartridgeHeader::RAM_SIZE_HEADER_ADDRESS as usize] = 0x02;
+    let mut cartridge = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+    cartridge.write(0x4000, 0x03);
+    cartridge.write(0x6000, 0x01);
+    assert_eq!(cartridge.current_ram_bank(), 0);
+  }
+
+  #[test]
+  fn mbc1_banking_survives_save_state() {
+    let mut bytes: Vec<u8> = (0..0x80).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+    bytes[CartridgeHeader::TYPE_HEADER_ADDRESS as usize] = 0x01;
+    let mut cartridge = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+    cartridge.write(0x2000, 0x05);
+    cartridge.write(0x4000, 0x03);
+    cartridge.write(0x6000, 0x01);
+    let mut state = vec![];
+    cartridge.write_state(&mut state).unwrap();
+
+    let mut restored = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+    restored.read_state(&mut state.as_slice()).unwrap();
+    assert_eq!((restored.current_rom_bank(), restored.read(0x0000)), (0x65, 0x60));
+  }
+
+  /// A clock stuck at one time
+  struct StoppedClock(u64);
+
+  impl Clock for StoppedClock {
+    fn now(&self) -> u64 {
+      self.0
+    }
+  }
+
+  #[test]
+  fn mbc1_multicart_is_detected_and_wired() {
+    // 1MB, four games of 16 banks each with a logo in their header
+    let mut bytes: Vec<u8> = (0..0x40).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+    bytes[CartridgeHeader::TYPE_HEADER_ADDRESS as usize] = 0x01;
+    bytes[CartridgeHeader::ROM_SIZE_HEADER_ADDRESS as usize] = 0x05;
+    for game in 0..4 {
+      let logo = game * 0x10 * 0x4000 + CartridgeHeader::LOGO_START_ADDRESS as usize;
+      bytes[logo..logo + CartridgeHeader::LOGO.len()].copy_from_slice(&CartridgeHeader::LOGO);
+    }
+    let mut cartridge = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+
+    // bank2 picks the game, shifted by 4 rather than 5
+    cartridge.write(0x4000, 0x02);
+    cartridge.write(0x2000, 0x03);
+    assert_eq!(cartridge.current_rom_bank(), 0x23);
+    assert_eq!(cartridge.read(0x4000), 0x23);
+    // bit 4 isn't connected, but still counts when checking for bank 0
+    cartridge.write(0x2000, 0x10);
+    assert_eq!(cartridge.current_rom_bank(), 0x20);
+    cartridge.write(0x6000, 0x01);
+    assert_eq!(cartridge.read(0x0000), 0x20);
+
+    // without the second header it's an ordinary 1MB MBC1
+    bytes[0x10 * 0x4000 + CartridgeHeader::LOGO_START_ADDRESS as usize] = 0x00;
+    let mut cartridge = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+    cartridge.write(0x4000, 0x02);
+    cartridge.write(0x2000, 0x03);
+    // bank 0x43, wrapped to the 64 there are
+    assert_eq!(cartridge.current_rom_bank(), 0x03);
+  }
+
+  #[test]
+  fn huc1_banks_and_infrared_port() {
+    let mut bytes: Vec<u8> = (0..8).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+    bytes[CartridgeHeader::TYPE_HEADER_ADDRESS as usize] = 0xFF; // HuC1+RAM+BATTERY
+    bytes[CartridgeHeader::RAM_SIZE_HEADER_ADDRESS as usize] = 0x03;
+    let mut cartridge = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+    assert_eq!(cartridge.info().mbc, MbcKind::HuC1);
+
+    cartridge.write(0x2000, 0x06);
+    assert_eq!(cartridge.read(0x4000), 6);
+    cartridge.write(0x2000, 0x00);
+    assert_eq!(cartridge.read(0x4000), 1);
+
+    // the RAM needs no enabling
+    cartridge.write(0x4000, 0x02);
+    cartridge.write_ram(0x0000, 0x12);
+    assert_eq!(cartridge.current_ram_bank(), 2);
+    assert_eq!(cartridge.read_ram(0x0000), 0x12);
+
+    cartridge.write(0x0000, 0x0E);
+    assert_eq!(cartridge.read_ram(0x0000), 0xC0);
+    cartridge.write_ram(0x0000, 0x01);
+    cartridge.write(0x0000, 0x0A);
+    assert_eq!(cartridge.read_ram(0x0000), 0x12);
+  }
+
+  #[test]
+  fn pocket_camera_maps_registers_over_ram() {
+    struct White;
+
+    impl ImageSource for White {
+      fn capture(&self, image: &mut [u8]) {
+        image.iter_mut().for_each(|x| *x = 0xFF);
+      }
     }
+
+    let mut bytes: Vec<u8> = (0..0x40).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+    bytes[CartridgeHeader::TYPE_HEADER_ADDRESS as usize] = 0xFC;
+    bytes[CartridgeHeader::ROM_SIZE_HEADER_ADDRESS as usize] = 0x05;
+    bytes[CartridgeHeader::RAM_SIZE_HEADER_ADDRESS as usize] = 0x04;
+    let mut cartridge = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+    assert_eq!(cartridge.info().mbc, MbcKind::PocketCamera);
+    assert!(cartridge.set_camera_source(White));
+
+    cartridge.write(0x2000, 0x00);
+    assert_eq!(cartridge.read(0x4000), 0);
+    cartridge.write(0x2000, 0x3F);
+    assert_eq!(cartridge.read(0x4000), 0x3F);
+
+    cartridge.write(0x0000, 0x0A);
+    cartridge.write_ram(Camera::IMAGE_RAM_OFFSET as u16, 0x12);
+    cartridge.save_ram();
+    cartridge.write(0x4000, 0x10);
+    // a white scene at full exposure, with thresholds all zero, comes out white
+    cartridge.write_ram(0x0002, 0x10);
+    cartridge.write_ram(0x0000, 0x01);
+    assert_eq!(cartridge.read_ram(0x0000), 0x00);
+    assert!(cartridge.ram_dirty());
+
+    cartridge.write(0x4000, 0x00);
+    assert_eq!(cartridge.read_ram(Camera::IMAGE_RAM_OFFSET as u16), 0x00);
+  }
+
+  #[test]
+  fn mbc2_banking_and_half_byte_ram() {
+    let mut bytes: Vec<u8> = (0..16).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+    bytes[CartridgeHeader::TYPE_HEADER_ADDRESS as usize] = 0x06; // MBC2+BATTERY
+    let mut cartridge = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+    assert_eq!(cartridge.info().mbc, MbcKind::MBC2);
+
+    // with bit 8 of the address set the write selects the ROM bank, anywhere in 0000-3FFF
+    cartridge.write(0x0100, 0x05);
+    assert_eq!(cartridge.read(0x4000), 5);
+    cartridge.write(0x3FFF, 0xF0);
+    assert_eq!(cartridge.current_rom_bank(), 1);
+    // with it clear the write enables the RAM, and doesn't touch the bank
+    assert_eq!(cartridge.read_ram(0x0000), 0xFF);
+    cartridge.write(0x00FF, 0x0A);
+    assert_eq!(cartridge.current_rom_bank(), 1);
+
+    cartridge.write_ram(0x0010, 0x5C);
+    assert_eq!(cartridge.read_ram(0x0010), 0xFC);
+    // 512 half bytes echoed across A000-BFFF
+    assert_eq!(cartridge.read_ram(0x0210), 0xFC);
+    assert_eq!(cartridge.read_ram(0x1E10), 0xFC);
+    assert!(cartridge.ram_dirty());
+    assert_eq!(cartridge.save_ram().map(|ram| ram.len()), Some(0x200));
+  }
+
+  #[test]
+  fn mbc3_rom_and_ram_banking() {
+    let mut bytes: Vec<u8> = (0..0x80).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+    bytes[CartridgeHeader::TYPE_HEADER_ADDRESS as usize] = 0x13;
+    bytes[CartridgeHeader::RAM_SIZE_HEADER_ADDRESS as usize] = 0x03;
+    let mut cartridge = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+    assert_eq!(cartridge.info().mbc, MbcKind::MBC3);
+
+    // all 7 bits are used, 0x20 isn't remapped like it is on MBC1
+    cartridge.write(0x2000, 0x20);
+    assert_eq!(cartridge.read(0x4000), 0x20);
+    cartridge.write(0x2000, 0x00);
+    assert_eq!(cartridge.read(0x4000), 0x01);
+
+    cartridge.write(0x0000, 0x0A);
+    cartridge.write(0x4000, 0x03);
+    cartridge.write_ram(0x0000, 0x42);
+    assert_eq!(cartridge.dump_ram().unwrap()[3 * Cartridge::RAM_BANK_SIZE], 0x42);
+    // no clock on this type, so the clock registers read as open bus
+    assert!(!cartridge.set_rtc_clock(StoppedClock(0)));
+    cartridge.write(0x4000, 0x08);
+    assert_eq!(cartridge.read_ram(0x0000), 0xFF);
+  }
+
+  #[test]
+  fn mbc3_clock_registers_are_latched() {
+    let mut bytes = rom_with_type(0x10);
+    bytes[CartridgeHeader::RAM_SIZE_HEADER_ADDRESS as usize] = 0x03;
+    let mut cartridge = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+    assert!(cartridge.set_rtc_clock(StoppedClock(1000)));
+    cartridge.write(0x0000, 0x0A);
+
+    // set the minutes then latch them
+    cartridge.write(0x4000, 0x09);
+    cartridge.write_ram(0x0000, 42);
+    assert_eq!(cartridge.read_ram(0x0000), 0);
+    cartridge.write(0x6000, 0x00);
+    cartridge.write(0x6000, 0x01);
+    assert_eq!(cartridge.read_ram(0x0000), 42);
+    // RAM is untouched
+    assert!(cartridge.dump_ram().unwrap().iter().all(|&x| x == 0));
+
+    let mut state = vec![];
+    cartridge.write_state(&mut state).unwrap();
+    let mut restored = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+    restored.read_state(&mut state.as_slice()).unwrap();
+    assert_eq!(restored.read_ram(0x0000), 42);
+  }
+
+  #[test]
+  fn mbc5_nine_bit_rom_bank() {
+    // 8MB, each bank filled with the low byte of its number and the last byte of bank 0x1FF marked
+    let mut bytes: Vec<u8> = (0..0x200).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+    bytes[CartridgeHeader::TYPE_HEADER_ADDRESS as usize] = 0x19;
+    bytes[0x1FF * 0x4000 + 0x3FFF] = 0xAA;
+    let mut cartridge = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+    assert_eq!(cartridge.info().mbc, MbcKind::MBC5);
+
+    cartridge.write(0x2000, 0xFF);
+    cartridge.write(0x3000, 0x01);
+    assert_eq!(cartridge.current_rom_bank(), 0x1FF);
+    assert_eq!(cartridge.read(0x7FFF), 0xAA);
+    // bank 0 can be mapped at 4000-7FFF
+    cartridge.write(0x2000, 0x00);
+    cartridge.write(0x3000, 0x00);
+    assert_eq!(cartridge.current_rom_bank(), 0);
+  }
+
+  #[test]
+  fn mbc5_ram_banks_and_rumble() {
+    let mut bytes = rom_with_type(0x1B);
+    // 128KB, sixteen banks
+    bytes[CartridgeHeader::RAM_SIZE_HEADER_ADDRESS as usize] = 0x04;
+    let mut cartridge = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+    cartridge.write(0x0000, 0x0A);
+    cartridge.write(0x4000, 0x0F);
+    assert_eq!(cartridge.current_ram_bank(), 0x0F);
+    assert!(!cartridge.rumble());
+
+    // on rumble cartridges bit 3 switches the motor rather than the bank
+    let mut bytes = rom_with_type(0x1E);
+    bytes[CartridgeHeader::RAM_SIZE_HEADER_ADDRESS as usize] = 0x03;
+    let mut cartridge = Cartridge::from_bytes(&bytes, LoadMode::Strict).unwrap();
+    cartridge.write(0x4000, 0x0A);
+    assert!(cartridge.rumble());
+    assert_eq!(cartridge.current_ram_bank(), 0x02);
+    cartridge.write(0x4000, 0x02);
+    assert!(!cartridge.rumble());
   }
 }
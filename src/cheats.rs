@@ -0,0 +1,188 @@
+//! GameShark and Game Genie cheat codes
+//!
+//! A GameShark code is eight hex digits `TTVVLLHH`, writing value `VV` to RAM address `HHLL` every
+//! VBlank. `TT` is normally 01, 8X and 9X pick work RAM bank X for D000-DFFF on CGB.
+//!
+//! A Game Genie code is `ABC-DEF` or `ABC-DEF-GHI`, patching ROM reads at address `(F ^ F)CDE` to
+//! return `AB`. With the third group the patch only applies while the ROM there holds the compare
+//! value, decoded from G and I, so it only hits the right bank.
+
+use failure::Fail;
+
+#[derive(Debug, Fail, PartialEq, Eq)]
+pub enum CheatError {
+  #[fail(display = "'{}' isn't a GameShark or Game Genie code", _0)]
+  InvalidCode(String),
+  #[fail(display = "no cheat {}", _0)]
+  NoSuchCheat(usize),
+}
+
+/// A decoded cheat code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheatCode {
+  /// Write `value` to `address` in RAM every VBlank
+  GameShark { bank: u8, address: u16, value: u8 },
+  /// Read `value` from `address` in ROM, if it held `compare`
+  GameGenie { address: u16, value: u8, compare: Option<u8> },
+}
+
+impl CheatCode {
+  const GAME_GENIE_ADDRESS_XOR: u16  = 0xF000;
+  const GAME_GENIE_COMPARE_XOR: u8   = 0xBA;
+  const GAME_SHARK_BANKED_BIT: u8    = 0x80;
+  const GAME_SHARK_BANK_BITS: u8     = 0x07;
+  const GAME_SHARK_BANKED_START: u16 = 0xD000;
+  const GAME_SHARK_BANKED_END: u16   = 0xDFFF;
+
+  /// Decode `code`, a GameShark code or a Game Genie code with or without its compare group
+  pub fn parse(code: &str) -> Result<CheatCode, CheatError> {
+    let invalid = || CheatError::InvalidCode(code.to_string());
+    let digits = code
+      .chars()
+      .filter(|&c| c != '-')
+      .map(|c| c.to_digit(16).map(|x| x as u8))
+      .collect::<Option<Vec<_>>>()
+      .ok_or_else(invalid)?;
+    let byte = |i: usize| (digits[i] << 4) | digits[i + 1];
+
+    match (code.contains('-'), digits.len()) {
+      (false, 8) => Ok(CheatCode::GameShark {
+        bank: byte(0),
+        value: byte(2),
+        address: u16::from_le_bytes([byte(4), byte(6)]),
+      }),
+      (true, 6) | (true, 9) => {
+        let nibble = |i: usize| digits[i] as u16;
+        let address = ((nibble(5) << 12) | (nibble(2) << 8) | (nibble(3) << 4) | nibble(4)) ^ Self::GAME_GENIE_ADDRESS_XOR;
+        // digit H is a check digit with no effect
+        let compare = Some(digits.len())
+          .filter(|&len| len == 9)
+          .map(|_| ((digits[6] << 4) | digits[8]).rotate_right(2) ^ Self::GAME_GENIE_COMPARE_XOR);
+        Ok(CheatCode::GameGenie { address, value: byte(0), compare })
+      }
+      _ => Err(invalid()),
+    }
+  }
+
+  /// The work RAM bank a GameShark code of type `bank` writes `address` in, if it picks one
+  fn shark_bank(bank: u8, address: u16) -> Option<usize> {
+    let banked = bank & Self::GAME_SHARK_BANKED_BIT != 0
+      && (Self::GAME_SHARK_BANKED_START..=Self::GAME_SHARK_BANKED_END).contains(&address);
+    Some((bank & Self::GAME_SHARK_BANK_BITS).max(1) as usize).filter(|_| banked)
+  }
+}
+
+/// A cheat code as entered, and whether it's applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+  pub code: CheatCode,
+  /// The code as it was entered
+  pub text: String,
+  pub enabled: bool,
+}
+
+/// The cheats applied to a machine, numbered in the order they were added
+#[derive(Debug, Clone, Default)]
+pub struct Cheats {
+  cheats: Vec<Cheat>,
+}
+
+impl Cheats {
+  /// Decode and enable `code`
+  ///
+  /// # Returns
+  /// the number of the new cheat
+  pub fn add(&mut self, code: &str) -> Result<usize, CheatError> {
+    let text = code.trim().to_uppercase();
+    let code = CheatCode::parse(&text)?;
+    self.cheats.push(Cheat { code, text, enabled: true });
+    Ok(self.cheats.len() - 1)
+  }
+
+  /// Remove cheat `n`, renumbering those after it
+  pub fn remove(&mut self, n: usize) -> Result<Cheat, CheatError> {
+    if n >= self.cheats.len() {
+      return Err(CheatError::NoSuchCheat(n));
+    }
+    Ok(self.cheats.remove(n))
+  }
+
+  pub fn set_enabled(&mut self, n: usize, enabled: bool) -> Result<(), CheatError> {
+    self.cheats.get_mut(n).ok_or(CheatError::NoSuchCheat(n))?.enabled = enabled;
+    Ok(())
+  }
+
+  pub fn list(&self) -> &[Cheat] {
+    &self.cheats
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.cheats.is_empty()
+  }
+
+  fn enabled(&self) -> impl Iterator<Item = &CheatCode> {
+    self.cheats.iter().filter(|cheat| cheat.enabled).map(|cheat| &cheat.code)
+  }
+
+  /// `value` read from ROM at `address`, as the enabled Game Genie codes patch it
+  pub fn patch_rom(&self, address: u16, value: u8) -> u8 {
+    self
+      .enabled()
+      .find_map(|code| match *code {
+        CheatCode::GameGenie { address: at, value: patched, compare }
+          if at == address && compare.is_none_or(|compare| compare == value) => Some(patched),
+        _ => None,
+      })
+      .unwrap_or(value)
+  }
+
+  /// The writes of the enabled GameShark codes, as a work RAM bank if the code picks one, an address
+  /// and a value
+  pub(crate) fn ram_writes(&self) -> impl Iterator<Item = (Option<usize>, u16, u8)> + '_ {
+    self.enabled().filter_map(|code| match *code {
+      CheatCode::GameShark { bank, address, value } => Some((CheatCode::shark_bank(bank, address), address, value)),
+      CheatCode::GameGenie { .. } => None,
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn codes_decode() {
+    assert_eq!(
+      CheatCode::parse("010238CD"),
+      Ok(CheatCode::GameShark { bank: 0x01, address: 0xCD38, value: 0x02 })
+    );
+    assert_eq!(
+      CheatCode::parse("00A-17B"),
+      Ok(CheatCode::GameGenie { address: 0x4A17, value: 0x00, compare: None })
+    );
+    // G and I are 0xC9, rotated right to 0x72 and xored to 0xC8
+    assert_eq!(
+      CheatCode::parse("00A-17B-C49"),
+      Ok(CheatCode::GameGenie { address: 0x4A17, value: 0x00, compare: Some(0xC8) })
+    );
+    assert!(CheatCode::parse("010238C").is_err());
+    assert!(CheatCode::parse("00A-17B-C4").is_err());
+    assert!(CheatCode::parse("0102XYCD").is_err());
+  }
+
+  #[test]
+  fn game_genie_patches_only_matching_rom() {
+    let mut cheats = Cheats::default();
+    let n = cheats.add("3ea-17b-c49").unwrap();
+    assert_eq!(cheats.list()[n].text, "3EA-17B-C49");
+    assert_eq!(cheats.patch_rom(0x4A17, 0xC8), 0x3E);
+    // another bank holding something else is left alone
+    assert_eq!(cheats.patch_rom(0x4A17, 0x12), 0x12);
+    assert_eq!(cheats.patch_rom(0x4A18, 0xC8), 0xC8);
+
+    cheats.set_enabled(n, false).unwrap();
+    assert_eq!(cheats.patch_rom(0x4A17, 0xC8), 0xC8);
+    assert!(cheats.remove(n).is_ok());
+    assert_eq!(cheats.remove(n), Err(CheatError::NoSuchCheat(n)));
+  }
+}
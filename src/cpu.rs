@@ -1,11 +1,36 @@
 use {
   crate::{
+    alu::{self, Flags},
+    disasm::{disassemble, Instruction},
+    interrupt::{Interrupt, InterruptSet},
     mmu::MMU,
+    model::Model,
+    state::*,
     util::*,
-  }
+  },
+  failure::Fail,
+  std::io::{self, Read, Write},
 };
 
+#[derive(Debug, Fail)]
+pub enum CpuError {
+  #[fail(display = "illegal opcode 0x{:02x} at 0x{:04x}", opcode, address)]
+  IllegalOpcode { opcode: u8, address: u16 },
+}
+
+/// What the CPU does when it executes an opcode that doesn't exist on the real hardware
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IllegalOpcodeBehavior {
+  /// Stop and report a `CpuError::IllegalOpcode`
+  #[default]
+  Error,
+  /// Lock up like the real CPU, never executing another instruction
+  Freeze,
+}
+
 #[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CPU {
   pub af: u16,
   pub bc: u16,
@@ -13,7 +38,21 @@ pub struct CPU {
   pub hl: u16,
   // ^ general purpose registers
   pub sp: u16,
-  pub pc: u16
+  pub pc: u16,
+  /// Interrupt master enable
+  pub ime: bool,
+  /// Set by EI, IME is only enabled once the instruction after it has executed
+  pub ime_scheduled: bool,
+  /// Set by HALT until an interrupt is pending
+  pub halted: bool,
+  /// Set by a HALT that was skipped because IME was clear with an interrupt already pending, the
+  /// byte after it is then read twice as PC fails to increment past it
+  pub halt_bug: bool,
+  /// Set by STOP until a joypad press
+  pub stopped: bool,
+  pub illegal_opcode_behavior: IllegalOpcodeBehavior,
+  /// Set once the CPU has frozen on an illegal opcode
+  pub locked: bool,
 }
 
 impl CPU {
@@ -22,44 +61,163 @@ impl CPU {
   const F_REGISTER_H_FLAG_BIT_N: u8 = 5;
   const F_REGISTER_C_FLAG_BIT_N: u8 = 4;
 
-  // TODO: what is this
-  const CARRY_BIT: u8 = 0;
-  const BORROW_BIT: u8 = 0;
-
-  const HALF_CARRY_BIT: u8 = 8;
-  const LOWER_HALF_CARRY_BIT: u8 = 3;
-  const UPPER_HALF_CARRY_BIT: u8 = Self::LOWER_HALF_CARRY_BIT + 8;
+  /// Register index of (HL) in the 3 bit register fields of an opcode
+  const HL_INDIRECT: u8 = 6;
+
+  /// F only has the four flag bits
+  const AF_WRITABLE_BITS: u16 = 0xFFF0;
+
+  /// Cycles that pass each step while the CPU is locked up
+  const LOCKED_CYCLES: u8 = 4;
+  /// Cycles that pass each step while the CPU is halted
+  const HALTED_CYCLES: u8 = 4;
+  /// Cycles that pass each step while the CPU is stopped
+  const STOPPED_CYCLES: u8 = 4;
+  /// Extra cycles taken to leave HALT once an interrupt is pending
+  const HALT_WAKE_CYCLES: u8 = 4;
+  /// Cycles taken to push PC and jump to an interrupt vector
+  const INTERRUPT_DISPATCH_CYCLES: u8 = 20;
+
+  const INTERRUPT_VECTOR_BASE_ADDRESS: u16 = 0x40;
+  const INTERRUPT_VECTOR_SIZE: u16 = 8;
+
+  /// Address execution starts from once the bios hands over to the cartridge
+  pub const ENTRY_POINT: u16 = 0x0100;
+
+  /// The registers as the bios of `model` leaves them when it jumps to the cartridge entry point
+  ///
+  /// Games tell a CGB apart by the 0x11 it leaves in A.
+  pub fn post_boot(model: Model) -> Self {
+    let (af, bc, de, hl) = match model {
+      Model::Cgb => (0x1180, 0x0000, 0xFF56, 0x000D),
+      Model::Dmg | Model::Sgb => (0x01B0, 0x0013, 0x00D8, 0x014D),
+    };
+    Self {
+      af,
+      bc,
+      de,
+      hl,
+      sp: 0xFFFE,
+      pc: Self::ENTRY_POINT,
+      ..Self::default()
+    }
+  }
 
-  const HALF_BORROW_BIT: u8 = 8;
-  const UPPER_HALF_BORROW_BIT: u8 = Self::LOWER_HALF_BORROW_BIT + 8;
-  const LOWER_HALF_BORROW_BIT: u8 = 4;
+  pub fn step(&mut self, mmu: &mut impl Memory) -> Result<u8, CpuError> {
+    if self.locked {
+      return Ok(Self::LOCKED_CYCLES);
+    }
+    if self.stopped {
+      // a press requests the joypad interrupt whether or not it is enabled
+      let flags = InterruptSet(mmu.read_internal(MMU::INTERRUPT_FLAG_REG_ADDRESS));
+      if !flags.contains(Interrupt::Joypad) {
+        return Ok(Self::STOPPED_CYCLES);
+      }
+      self.stopped = false;
+    }
 
-  const UPPER_BORROW_BIT: u8 = Self::LOWER_BORROW_BIT + 8;
-  const LOWER_BORROW_BIT: u8 = 0;
+    let pending = Self::highest_priority(self.pending_interrupts(mmu));
+    if self.halted {
+      let interrupt = match pending {
+        Some(interrupt) => interrupt,
+        None => return Ok(Self::HALTED_CYCLES),
+      };
+      self.halted = false;
+      // without IME the interrupt isn't serviced, execution resumes after the HALT
+      let dispatch_cycles = if self.ime { self.service_interrupt(interrupt, mmu) } else { 0 };
+      return Ok(Self::HALT_WAKE_CYCLES + dispatch_cycles);
+    }
+    if let (true, Some(interrupt)) = (self.ime, pending) {
+      return Ok(self.service_interrupt(interrupt, mmu));
+    }
 
-  pub fn step(&mut self, mmu: &mut MMU) -> u8 {
     let pc = self.pc;
     let opcode = mmu.read(pc);
-    self.exec(opcode, mmu)
+    if std::mem::replace(&mut self.halt_bug, false) {
+      // the opcode is fetched without moving PC, so it is read again as the first operand
+      self.pc = self.pc.wrapping_sub(1);
+    }
+    let enable_ime = self.ime_scheduled;
+    let n_cycles = self.exec(opcode, mmu)?;
+    // a DI straight after EI cancels the enable
+    if enable_ime && self.ime_scheduled {
+      self.ime = true;
+      self.ime_scheduled = false;
+    }
+    Ok(n_cycles)
   }
 
-  fn exec(&mut self, opcode: u8, mmu: &mut MMU) -> u8 {
+  /// Interrupts that are both requested in IF and enabled in IE
+  fn pending_interrupts(&self, mmu: &impl Memory) -> InterruptSet {
+    InterruptSet(mmu.read_internal(MMU::INTERRUPT_FLAG_REG_ADDRESS) & mmu.read_internal(MMU::INTERRUPT_ENABLE_REG_ADDRESS))
+  }
+
+  /// The address the CPU jumps to when servicing `interrupt`
+  pub fn interrupt_vector(interrupt: Interrupt) -> u16 {
+    Self::INTERRUPT_VECTOR_BASE_ADDRESS + Self::INTERRUPT_VECTOR_SIZE * interrupt as u16
+  }
+
+  /// The interrupt in `pending` that is serviced first, VBlank > STAT > Timer > Serial > Joypad
+  pub fn highest_priority(pending: InterruptSet) -> Option<Interrupt> {
+    Interrupt::ALL.iter().copied().find(|&x| pending.contains(x))
+  }
+
+  /// Jump to the vector of `interrupt`, acknowledging it in IF
+  ///
+  /// # Returns
+  /// the number of cycles the dispatch took
+  fn service_interrupt(&mut self, interrupt: Interrupt, mmu: &mut impl Memory) -> u8 {
+    let flags = mmu.read_internal(MMU::INTERRUPT_FLAG_REG_ADDRESS);
+    mmu.write_internal(MMU::INTERRUPT_FLAG_REG_ADDRESS, flags & !interrupt.mask());
+    self.ime = false;
+    self.push(self.pc, mmu);
+    self.pc = Self::interrupt_vector(interrupt);
+    Self::INTERRUPT_DISPATCH_CYCLES
+  }
+
+  /// Push `value` onto the stack, high byte first
+  ///
+  /// The high byte lands at SP-1 and the low byte at SP-2, so the value is little endian in memory
+  fn push(&mut self, value: u16, mmu: &mut impl Memory) {
+    let (upper, lower) = unpack_bytes_from_double(value);
+    self.sp = self.sp.wrapping_sub(1);
+    mmu.write(self.sp, upper);
+    self.sp = self.sp.wrapping_sub(1);
+    mmu.write(self.sp, lower);
+  }
+
+  /// Pop a value off the stack, low byte first
+  fn pop(&mut self, mmu: &impl Memory) -> u16 {
+    let lower = mmu.read(self.sp);
+    self.sp = self.sp.wrapping_add(1);
+    let upper = mmu.read(self.sp);
+    self.sp = self.sp.wrapping_add(1);
+    pack_bytes_into_double(upper, lower)
+  }
+
+  /// Step like `step`, also returning the instruction at PC decoded before it was executed
+  pub fn step_debug(&mut self, mmu: &mut impl Memory) -> Result<(u8, Instruction), CpuError> {
+    let (instruction, _) = disassemble(self.pc, mmu);
+    Ok((self.step(mmu)?, instruction))
+  }
+
+  fn exec(&mut self, opcode: u8, mmu: &mut impl Memory) -> Result<u8, CpuError> {
     let n_cycles = match opcode {
 
       // NOP
       // 1  4
       // - - - -
       0x00 => {
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         4
       }
 
-      // LD BC,d16
+      // LD BC,d16 | LD DE,d16 | LD HL,d16 | LD SP,d16
       // 3  12
       // - - - -
-      0x01 => {
-        self.bc = mmu.read_double(self.pc + 1);
-        self.pc += 3;
+      0x01 | 0x11 | 0x21 | 0x31 => {
+        self.set_register16(opcode, self.immediate16(mmu));
+        self.pc = self.pc.wrapping_add(3);
         12
       }
 
@@ -68,26 +226,62 @@ impl CPU {
       // - - - -
       0x02 => {
         mmu.write(self.bc, self.a());
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         8
       }
 
-      // INC BC
+      // INC BC | INC DE | INC HL | INC SP
       // 1  8
       // - - - -
-      0x03 => {
-        self.bc = self.bc.overflowing_add(1).0;
-        self.pc += 1;
+      0x03 | 0x13 | 0x23 | 0x33 => {
+        self.set_register16(opcode, self.register16(opcode).wrapping_add(1));
+        self.pc = self.pc.wrapping_add(1);
         8
       }
 
-      // DEC B
-      // 1  4
+      // INC B | INC C | INC D | INC E | INC H | INC L | INC (HL) | INC A
+      // 1  4 (12 for (HL))
+      // Z 0 H -
+      0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x34 | 0x3C => {
+        let index = opcode >> 3;
+        let (result, flags) = alu::inc8(self.register(index, mmu));
+        self.set_register(index, result, mmu);
+        self.apply_flags(flags);
+        self.pc = self.pc.wrapping_add(1);
+        if index == Self::HL_INDIRECT { 12 } else { 4 }
+      }
+
+      // DEC B | DEC C | DEC D | DEC E | DEC H | DEC L | DEC (HL) | DEC A
+      // 1  4 (12 for (HL))
       // Z 1 H -
-      0x05 => {
-        self.set_b(self.b().overflowing_sub(1).0);
-        self.set_flags(Some(self.b() == 0), Some(true), Some(get_bit(self.bc, Self::HALF_BORROW_BIT)), None);
-        self.pc += 1;
+      0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x35 | 0x3D => {
+        let index = opcode >> 3;
+        let (result, flags) = alu::dec8(self.register(index, mmu));
+        self.set_register(index, result, mmu);
+        self.apply_flags(flags);
+        self.pc = self.pc.wrapping_add(1);
+        if index == Self::HL_INDIRECT { 12 } else { 4 }
+      }
+
+      // LD B,d8 | LD C,d8 | LD D,d8 | LD E,d8 | LD H,d8 | LD L,d8 | LD (HL),d8 | LD A,d8
+      // 2  8 (12 for (HL))
+      // - - - -
+      0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => {
+        let index = opcode >> 3;
+        self.set_register(index, mmu.read(self.pc.wrapping_add(1)), mmu);
+        self.pc = self.pc.wrapping_add(2);
+        if index == Self::HL_INDIRECT { 12 } else { 8 }
+      }
+
+      // RLCA | RRCA | RLA | RRA
+      // 1  4
+      // 0 0 0 C
+      0x07 | 0x0F | 0x17 | 0x1F => {
+        let result = self.rotate_shift(opcode >> 3, self.a());
+        self.set_a(result);
+        // unlike the 0xCB prefixed rotates Z is always cleared
+        self.set_z_flag(false);
+        self.pc = self.pc.wrapping_add(1);
         4
       }
 
@@ -96,51 +290,52 @@ impl CPU {
       // - - - -
       // Put Stack Pointer (SP) at address n.
       0x08 => {
-        mmu.write_double(mmu.read_double(self.pc + 1), self.sp);
-        self.pc += 3;
+        mmu.write_u16_le(mmu.read_u16_le(self.pc.wrapping_add(1)), self.sp);
+        self.pc = self.pc.wrapping_add(3);
         20
       }
 
+      // ADD HL,BC | ADD HL,DE | ADD HL,HL | ADD HL,SP
+      // 1  8
+      // - 0 H C
+      0x09 | 0x19 | 0x29 | 0x39 => {
+        let (result, flags) = alu::add16(self.hl, self.register16(opcode));
+        self.hl = result;
+        self.apply_flags(flags);
+        self.pc = self.pc.wrapping_add(1);
+        8
+      }
+
       // LD A,(BC)
       // 1  8
       // - - - -
       0x0A => {
         self.set_a(mmu.read(self.bc));
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         8
       }
 
-      // INC C
-      // 1  4
-      // Z 0 H -
-      0x0C => {
-        self.set_c(self.c().overflowing_add(1).0);
-        self.set_flags(
-          Some(self.c() == 0),
-          Some(false),
-          Some(get_bit(self.bc, Self::LOWER_HALF_CARRY_BIT)),
-          None
-        );
-        self.pc += 1;
-        4
-      }
-
-      // LD C,d8
-      // 2  8
+      // DEC BC | DEC DE | DEC HL | DEC SP
+      // 1  8
       // - - - -
-      0x0E => {
-        self.set_c(mmu.read(self.pc + 1));
-        self.pc += 2;
+      0x0B | 0x1B | 0x2B | 0x3B => {
+        self.set_register16(opcode, self.register16(opcode).wrapping_sub(1));
+        self.pc = self.pc.wrapping_add(1);
         8
       }
 
-      // LD DE,d16
-      // 3  12
+      // STOP 0
+      // 2  4
       // - - - -
-      0x11 => {
-        self.de = mmu.read_double(self.pc + 1);
-        self.pc += 3;
-        12
+      0x10 => {
+        // a CGB with a speed switch armed switches speed rather than stopping, which `Gameboy::step`
+        // takes care of as the MMU's business
+        self.stopped = true;
+        // only a press from here on wakes it, not one already waiting in IF
+        let flags = mmu.read_internal(MMU::INTERRUPT_FLAG_REG_ADDRESS);
+        mmu.write_internal(MMU::INTERRUPT_FLAG_REG_ADDRESS, flags & !Interrupt::Joypad.mask());
+        self.pc = self.pc.wrapping_add(2);
+        4
       }
 
       // LD (DE),A
@@ -148,31 +343,7 @@ impl CPU {
       // - - - -
       0x12 => {
         mmu.write(self.de, self.a());
-        self.pc += 1;
-        8
-      }
-
-      // DEC D
-      // 1  4
-      // Z 1 H -
-      0x15 => {
-        self.set_d(self.d().overflowing_sub(1).0);
-        self.set_flags(
-          Some(self.d() == 0),
-          Some(true),
-          Some(get_bit(self.de, Self::UPPER_HALF_BORROW_BIT)),
-          None
-        );
-        self.pc += 1;
-        4
-      }
-
-      // LD D,d8
-      // 2  8
-      // - - - -
-      0x16 => {
-        self.set_d(mmu.read(self.pc + 1));
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(1);
         8
       }
 
@@ -180,87 +351,29 @@ impl CPU {
       // 2  12
       // - - - -
       0x18 => {
-        let offset = mmu.read(self.pc + 1) as i8;
-        self.pc = if offset < 0 {
-          self.pc.overflowing_sub(-offset as u16).0
-        } else {
-          self.pc.overflowing_add(offset as u16).0
-        };
-
-        self.pc += 2;
+        let offset = mmu.read(self.pc.wrapping_add(1)) as i8;
+        self.pc = self.pc.wrapping_add(2).wrapping_add(offset as u16);
         12
       }
 
-      // ADD HL,DE
+      // LD A,(DE)
       // 1  8
-      // - 0 H C
-      0x19 => {
-
-        self.set_flags(
-          None,
-          Some(false),
-          Some(get_bit(self.hl, Self::HALF_CARRY_BIT)),
-          Some(get_bit(self.hl, Self::CARRY_BIT))
-        );
-        self.pc += 1;
+      // - - - -
+      0x1A => {
+        self.set_a(mmu.read(self.de));
+        self.pc = self.pc.wrapping_add(1);
         8
       }
 
-      // INC E
-      // 1  4
-      // Z 0 H -
-      0x1C => {
-        self.set_e(self.e() + 1);
-        self.set_flags(
-          Some(self.e() == 0),
-          Some(false),
-          Some(get_bit(self.de, Self::LOWER_HALF_CARRY_BIT)),
-          None
-        );
-        self.pc += 1;
-        4
-      }
-
-      // DEC E
-      // 1  4
-      // Z 1 H -
-      0x1D => {
-        self.set_e(self.e().overflowing_sub(1).0);
-        self.pc += 1;
-        self.set_flags(
-          Some(self.e() == 0),
-          Some(true),
-          Some(get_bit(self.de, Self::LOWER_HALF_BORROW_BIT)),
-          None
-        );
-        4
-      }
-
-      // JR NZ,r8
+      // JR NZ,r8 | JR Z,r8 | JR NC,r8 | JR C,r8
       // 2  12/8
       // - - - -
-      0x20 => {
-        let offset = mmu.read(self.pc + 1) as i8;
-        if !self.get_z_flag() {
-          self.pc = if offset < 0 {
-            self.pc.overflowing_sub(-offset as u16).0
-          } else {
-            self.pc.overflowing_add(offset as u16).0
-          };
-          12
-        } else {
-          self.pc += 2;
-          8
-        }
-      }
-
-      // LD HL,d16
-      // 3  12
-      // - - - -
-      0x21 => {
-        self.hl = mmu.read_double(self.pc + 1);
-        self.pc += 3;
-        12
+      0x20 | 0x28 | 0x30 | 0x38 => {
+        let offset = mmu.read(self.pc.wrapping_add(1)) as i8;
+        let next = self.pc.wrapping_add(2);
+        let taken = self.condition(opcode);
+        self.pc = if taken { next.wrapping_add(offset as u16) } else { next };
+        Self::branch_cycles(opcode, taken)
       }
 
       // LD (HL+),A
@@ -268,66 +381,39 @@ impl CPU {
       // - - - -
       0x22 => {
         mmu.write(self.hl, self.a());
-        self.hl = self.hl.overflowing_add(1).0;
-        self.pc += 1;
-        8
-      }
-
-      // INC HL
-      // 1  8
-      // - - - -
-      0x23 => {
-        self.hl += 1;
-        self.pc += 1;
+        self.hl = self.hl.wrapping_add(1);
+        self.pc = self.pc.wrapping_add(1);
         8
       }
 
-      // DEC H
-      // 1  4
-      // Z 1 H -
-      0x25 => {
-        self.set_h(self.h().overflowing_sub(1).0);
-        self.set_flags(
-          Some(self.h() == 0),
-          Some(true),
-          Some(get_bit(self.hl, Self::UPPER_HALF_BORROW_BIT)),
-          None
-        );
-        self.pc += 1;
-        4
-      }
-
-      // INC L
+      // DAA
       // 1  4
-      // Z 0 H -
-      0x2c => {
-        self.set_l(self.l() + 1);
-
-        self.set_flags(
-          Some(self.l() == 0),
-          Some(false),
-          Some(get_bit(self.l() as u16, Self::LOWER_HALF_CARRY_BIT as u8)),
-          None
-        );
-        self.pc += 1;
-        4
-      }
-
-      // DEC L
-      // 1  4
-      // Z 1 H -
-      0x2D => {
-        self.set_l(self.l().overflowing_sub(1).0);
-        self.pc += 1;
+      // Z - 0 C
+      0x27 => {
+        let (a, n) = (self.a(), self.n_flag());
+        let mut adjust = 0;
+        let mut carry = self.c_flag();
+        if self.h_flag() || (!n && a & 0x0F > 0x09) {
+          adjust |= 0x06;
+        }
+        if carry || (!n && a > 0x99) {
+          adjust |= 0x60;
+          carry = true;
+        }
+        let result = if n { a.wrapping_sub(adjust) } else { a.wrapping_add(adjust) };
+        self.set_a(result);
+        self.set_flags(Some(result == 0), None, Some(false), Some(carry));
+        self.pc = self.pc.wrapping_add(1);
         4
       }
 
-      // LD L,d8
-      // 2  8
+      // LD A,(HL+)
+      // 1  8
       // - - - -
-      0x2E => {
-        self.set_l(mmu.read(self.pc + 1));
-        self.pc += 2;
+      0x2A => {
+        self.set_a(mmu.read(self.hl));
+        self.hl = self.hl.wrapping_add(1);
+        self.pc = self.pc.wrapping_add(1);
         8
       }
 
@@ -337,782 +423,563 @@ impl CPU {
       0x2F => {
         self.set_a(!self.a());
         self.set_flags(None, Some(true), Some(true), None);
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         4
       }
 
-      // LD SP,d16
-      // 3  12
-      // - - - -
-      0x31 => {
-        self.sp = mmu.read_double(self.pc + 1);
-        self.pc += 3;
-        12
-      }
-
       // LD (HL-),A
       // 1  8
       // - - - -
       0x32 => {
         mmu.write(self.hl, self.a());
-        self.hl -= 1;
-        self.pc += 1;
+        self.hl = self.hl.wrapping_sub(1);
+        self.pc = self.pc.wrapping_add(1);
         8
       }
 
-      // LD A,d8
-      // 2  8
-      // - - - -
-      0x3E => {
-        self.set_a(mmu.read(self.pc + 1));
-        self.pc += 2;
-        8
-      }
-
-      // LD B,A
-      // 1  4
-      // - - - -
-      0x47 => {
-        self.set_b(self.a());
-        self.pc += 1;
-        4
-      }
-
-      // LD C,B
+      // SCF
       // 1  4
-      // - - - -
-      0x48 => {
-        self.set_c(self.b());
-        self.pc += 1;
+      // - 0 0 1
+      0x37 => {
+        self.set_flags(None, Some(false), Some(false), Some(true));
+        self.pc = self.pc.wrapping_add(1);
         4
       }
 
-      // LD C,C
-      // 1  4
+      // LD A,(HL-)
+      // 1  8
       // - - - -
-      0x49 => {
-        self.set_c(self.c());
-        self.pc += 1;
-        4
+      0x3A => {
+        self.set_a(mmu.read(self.hl));
+        self.hl = self.hl.wrapping_sub(1);
+        self.pc = self.pc.wrapping_add(1);
+        8
       }
 
-      // LD C,D
+      // CCF
       // 1  4
-      // - - - -
-      0x4A => {
-        self.set_c(self.d());
-        self.pc += 1;
+      // - 0 0 C
+      0x3F => {
+        self.set_flags(None, Some(false), Some(false), Some(!self.c_flag()));
+        self.pc = self.pc.wrapping_add(1);
         4
       }
 
-      // LD C,E
+      // HALT
       // 1  4
       // - - - -
-      0x4b => {
-        self.set_c(self.e());
-        self.pc += 1;
+      0x76 => {
+        if !self.ime && !self.pending_interrupts(mmu).is_empty() {
+          self.halt_bug = true;
+        } else {
+          self.halted = true;
+        }
+        self.pc = self.pc.wrapping_add(1);
         4
       }
 
-      // CALL NZ,a16
-      // 3  24/12
+      // LD r,r' where r and r' are each one of B, C, D, E, H, L, (HL), A
+      // 1  4 (8 for (HL))
       // - - - -
-      0x4C => {
-        if self.get_z_flag() {
-          let address = mmu.read_double(self.pc + 1);
-          self.pc = address;
-          self.pc += 3;
-          24
-        } else {
-          self.pc += 3;
-          12
-        }
+      0x40..=0x7F => {
+        let (to, from) = ((opcode >> 3) & 0b111, opcode & 0b111);
+        self.set_register(to, self.register(from, mmu), mmu);
+        self.pc = self.pc.wrapping_add(1);
+        if to == Self::HL_INDIRECT || from == Self::HL_INDIRECT { 8 } else { 4 }
       }
 
-      // LD C,L
-      // 1  4
-      // - - - -
-      0x4D => {
-        self.set_c(self.l());
-        self.pc += 1;
-        4
+      // ADD A,r | ADC A,r | SUB r | SBC A,r | AND r | XOR r | OR r | CP r
+      // where r is one of B, C, D, E, H, L, (HL), A
+      // 1  4 (8 for (HL))
+      // Z N H C
+      0x80..=0xBF => {
+        self.alu(opcode >> 3, self.operand(opcode, mmu));
+        self.pc = self.pc.wrapping_add(1);
+        Self::operand_cycles(opcode)
       }
 
-      // LD C,(HL)
-      // 1  8
+      // RET NZ | RET Z | RET NC | RET C
+      // 1  20/8
       // - - - -
-      0x4E => {
-        self.set_c(mmu.read(self.hl));
-        self.pc += 1;
-        8
+      0xC0 | 0xC8 | 0xD0 | 0xD8 => {
+        let taken = self.condition(opcode);
+        self.pc = if taken { self.pop(mmu) } else { self.pc.wrapping_add(1) };
+        Self::branch_cycles(opcode, taken)
       }
 
-      // LD C,A
-      // 1  4
+      // POP BC
+      // 1  12
       // - - - -
-      0x4F => {
-        self.set_c(self.a());
-        self.pc += 1;
-        4
+      0xC1 => {
+        self.bc = self.pop(mmu);
+        self.pc = self.pc.wrapping_add(1);
+        12
       }
 
-      // LD D,B
-      // 1  4
+      // JP NZ,a16 | JP Z,a16 | JP NC,a16 | JP C,a16
+      // 3  16/12
       // - - - -
-      0x50 => {
-        self.set_d(self.b());
-        self.pc += 1;
-        4
+      0xC2 | 0xCA | 0xD2 | 0xDA => {
+        let taken = self.condition(opcode);
+        self.pc = if taken { self.immediate16(mmu) } else { self.pc.wrapping_add(3) };
+        Self::branch_cycles(opcode, taken)
       }
 
-      // LD D,C
-      // 1  4
+      // JP a16
+      // 3  16
       // - - - -
-      0x51 => {
-        self.set_d(self.c());
-        self.pc += 1;
-        4
+      0xC3 => {
+        self.pc = self.immediate16(mmu);
+        16
       }
 
-      // LD D,D
-      // 1  4
+      // CALL NZ,a16 | CALL Z,a16 | CALL NC,a16 | CALL C,a16
+      // 3  24/12
       // - - - -
-      0x52 => {
-        self.set_d(self.d());
-        self.pc += 1;
-        4
+      0xC4 | 0xCC | 0xD4 | 0xDC => {
+        let target = self.immediate16(mmu);
+        let next = self.pc.wrapping_add(3);
+        let taken = self.condition(opcode);
+        if taken {
+          self.push(next, mmu);
+        }
+        self.pc = if taken { target } else { next };
+        Self::branch_cycles(opcode, taken)
       }
 
-      // LD D,E
-      // 1  4
+      // PUSH BC
+      // 1  16
       // - - - -
-      0x53 => {
-        self.set_d(self.e());
-        self.pc += 1;
-        4
+      0xC5 => {
+        self.push(self.bc, mmu);
+        self.pc = self.pc.wrapping_add(1);
+        16
       }
 
-      // LD D,H
-      // 1  4
-      // - - - -
-      0x54 => {
-        self.set_d(self.h());
-        self.pc += 1;
-        4
+      // ADD A,d8 | ADC A,d8 | SUB d8 | SBC A,d8 | AND d8 | XOR d8 | OR d8 | CP d8
+      // 2  8
+      // Z N H C
+      0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => {
+        self.alu(opcode >> 3, mmu.read(self.pc.wrapping_add(1)));
+        self.pc = self.pc.wrapping_add(2);
+        8
       }
 
-      // LD D,L
-      // 1  4
+      // RST 00H | RST 08H | RST 10H | RST 18H | RST 20H | RST 28H | RST 30H | RST 38H
+      // 1  16
       // - - - -
-      0x55 => {
-        self.set_d(self.l());
-        self.pc += 1;
-        4
+      0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+        self.push(self.pc.wrapping_add(1), mmu);
+        self.pc = (opcode & 0b0011_1000) as u16;
+        16
       }
 
-      // LD D,(HL)
-      // 1  8
+      // RET
+      // 1  16
       // - - - -
-      0x56 => {
-        self.set_d(mmu.read(self.hl));
-        self.pc += 1;
-        8
+      0xC9 => {
+        self.pc = self.pop(mmu);
+        16
       }
 
-      // LD D,A
-      // 1  4
-      // - - - -
-      0x57 => {
-        self.set_d(self.a());
-        self.pc += 1;
-        4
+      // RLC r | RRC r | RL r | RR r | SLA r | SRA r | SWAP r | SRL r | BIT n,r | RES n,r | SET n,r
+      // where r is one of B, C, D, E, H, L, (HL), A
+      // 2  8 (16 for (HL), 12 for BIT n,(HL))
+      // Z 0 0 C for rotates and shifts, Z 0 1 - for BIT, - - - - for RES and SET
+      0xCB => {
+        let cb = mmu.read(self.pc.wrapping_add(1));
+        let (index, n) = (cb & 0b111, (cb >> 3) & 0b111);
+        let value = self.register(index, mmu);
+        match cb >> 6 {
+          0 => {
+            let result = self.rotate_shift(n, value);
+            self.set_register(index, result, mmu);
+          }
+          // Z is set when the bit is clear
+          1 => self.set_flags(Some(value & (1 << n) == 0), Some(false), Some(true), None),
+          2 => self.set_register(index, value & !(1 << n), mmu),
+          _ => self.set_register(index, value | (1 << n), mmu),
+        }
+        self.pc = self.pc.wrapping_add(2);
+        Self::cb_cycles(cb)
       }
 
-      // LD E,B
-      // 1  4
+      // CALL a16
+      // 3  24
       // - - - -
-      0x58 => {
-        self.set_e(self.b());
-        self.pc += 1;
-        4
+      0xCD => {
+        let target = self.immediate16(mmu);
+        self.push(self.pc.wrapping_add(3), mmu);
+        self.pc = target;
+        24
       }
 
-      // LD E,C
-      // 1  4
+      // POP DE
+      // 1  12
       // - - - -
-      0x59 => {
-        self.set_e(self.c());
-        self.pc += 1;
-        4
+      0xD1 => {
+        self.de = self.pop(mmu);
+        self.pc = self.pc.wrapping_add(1);
+        12
       }
 
-      // LD E,D
-      // 1  4
+      // PUSH DE
+      // 1  16
       // - - - -
-      0x5A => {
-        self.set_e(self.d());
-        self.pc += 1;
-        4
+      0xD5 => {
+        self.push(self.de, mmu);
+        self.pc = self.pc.wrapping_add(1);
+        16
       }
 
-      // LD E,E
-      // 1  4
+      // RETI
+      // 1  16
       // - - - -
-      0x5B => {
-        self.set_e(self.e());
-        self.pc += 1;
-        4
+      0xD9 => {
+        self.pc = self.pop(mmu);
+        // unlike EI the enable is immediate, an interrupt can be serviced before the next instruction
+        self.ime = true;
+        self.ime_scheduled = false;
+        16
       }
 
-      // LD E,H
-      // 1  4
+      // LDH (a8),A
+      // 2  12
       // - - - -
-      0x5C => {
-        self.set_e(self.h());
-        self.pc += 1;
-        4
+      0xE0 => {
+        mmu.write(MMU::IO_START_ADDRESS + mmu.read(self.pc.wrapping_add(1)) as u16, self.a());
+        self.pc = self.pc.wrapping_add(2);
+        12
       }
 
-      // LD H,B
-      // 1  4
+      // POP HL
+      // 1  12
       // - - - -
-      0x60 => {
-        self.set_h(self.b());
-        self.pc += 1;
-        4
+      0xE1 => {
+        self.hl = self.pop(mmu);
+        self.pc = self.pc.wrapping_add(1);
+        12
       }
 
-      // LD H,(HL)
+      // LD (C),A
       // 1  8
       // - - - -
-      0x66 => {
-        self.set_h(mmu.read(self.hl));
-        self.pc += 1;
+      0xE2 => {
+        mmu.write(MMU::IO_START_ADDRESS + self.c() as u16, self.a());
+        self.pc = self.pc.wrapping_add(1);
         8
       }
 
-      // LD L,E
-      // 1  4
+      // PUSH HL
+      // 1  16
       // - - - -
-      0x6B => {
-        self.set_l(self.e());
-        self.pc += 1;
-        4
+      0xE5 => {
+        self.push(self.hl, mmu);
+        self.pc = self.pc.wrapping_add(1);
+        16
+      }
+
+      // ADD SP,r8
+      // 2  16
+      // 0 0 H C
+      0xE8 => {
+        self.sp = self.sp_offset(mmu);
+        self.pc = self.pc.wrapping_add(2);
+        16
       }
 
-      // LD L,H
+      // JP (HL)
       // 1  4
       // - - - -
-      0x6c => {
-        self.set_l(self.h());
-        self.pc += 1;
+      0xE9 => {
+        self.pc = self.hl;
         4
       }
 
-      // LD L,(HL)
-      // 1  8
+      // LD (a16),A
+      // 3  16
       // - - - -
-      0x6E => {
-        self.set_l(mmu.read(self.hl));
-        self.pc += 1;
-        8
+      0xEA => {
+        mmu.write(self.immediate16(mmu), self.a());
+        self.pc = self.pc.wrapping_add(3);
+        16
       }
 
-      // LD L,L
-      // 1  4
+      // LDH A,(a8)
+      // 2  12
       // - - - -
-      0x6D => {
-        self.set_l(self.l());
-        self.pc += 1;
-        4
+      0xF0 => {
+        self.set_a(mmu.read(MMU::IO_START_ADDRESS + mmu.read(self.pc.wrapping_add(1)) as u16));
+        self.pc = self.pc.wrapping_add(2);
+        12
       }
 
-      // LD L,A
-      // 1  4
-      // - - - -
-      0x6F => {
-        self.set_l(self.a());
-        self.pc += 1;
-        4
+      // POP AF
+      // 1  12
+      // Z N H C
+      0xF1 => {
+        // the lower nibble of F doesn't exist
+        self.af = self.pop(mmu) & Self::AF_WRITABLE_BITS;
+        self.pc = self.pc.wrapping_add(1);
+        12
       }
 
-      // LD (HL),C
+      // LD A,(C)
       // 1  8
       // - - - -
-      0x71 => {
-        mmu.write(self.hl, self.c());
-        self.pc += 1;
+      0xF2 => {
+        self.set_a(mmu.read(MMU::IO_START_ADDRESS + self.c() as u16));
+        self.pc = self.pc.wrapping_add(1);
         8
       }
 
-      // LD (HL),D
-      // 1  8
+      // DI
+      // 1  4
       // - - - -
-      0x72 => {
-        mmu.write(self.hl, self.d());
-        self.pc += 1;
-        8
+      0xF3 => {
+        self.ime = false;
+        self.ime_scheduled = false;
+        self.pc = self.pc.wrapping_add(1);
+        4
       }
 
-      //LD (HL),E
-      // 1  8
+      // PUSH AF
+      // 1  16
       // - - - -
-      0x73 => {
-        mmu.write(self.hl, self.e());
-        self.pc += 1;
-        8
+      0xF5 => {
+        self.push(self.af, mmu);
+        self.pc = self.pc.wrapping_add(1);
+        16
       }
 
-      // LD (HL),H
-      // 1  8
-      // - - - -
-      0x74 => {
-        mmu.write(self.hl, self.h());
-        self.pc += 1;
-        8
+      // LD HL,SP+r8
+      // 2  12
+      // 0 0 H C
+      0xF8 => {
+        self.hl = self.sp_offset(mmu);
+        self.pc = self.pc.wrapping_add(2);
+        12
       }
 
-      // LD (HL),L
+      // LD SP,HL
       // 1  8
       // - - - -
-      0x75 => {
-        mmu.write(self.hl, self.l());
-        self.pc += 1;
+      0xF9 => {
+        self.sp = self.hl;
+        self.pc = self.pc.wrapping_add(1);
         8
       }
 
-      // LD (HL),A
-      // 1  8
+      // LD A,(a16)
+      // 3  16
       // - - - -
-      0x77 => {
-        mmu.write(self.hl, self.a());
-        self.pc += 1;
-        8
+      0xFA => {
+        self.set_a(mmu.read(self.immediate16(mmu)));
+        self.pc = self.pc.wrapping_add(3);
+        16
       }
 
-      // LD A,B
+      // EI
       // 1  4
       // - - - -
-      0x78 => {
-        self.set_a(self.b());
-        self.pc += 1;
+      0xFB => {
+        self.ime_scheduled = true;
+        self.pc = self.pc.wrapping_add(1);
         4
       }
 
-      // LD A,C
-      // 1  4
-      // - - - -
-      0x79 => {
-        self.set_a(self.c());
-        self.pc += 1;
-        4
+      // Illegal opcodes, these lock up the real CPU
+      0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
+        return self.illegal_opcode(opcode)
       }
+    };
 
-      // LD A,D
-      // 1  4
-      // - - - -
-      0x7A => {
-        self.set_a(self.d());
-        self.pc += 1;
-        4
-      }
+    Ok(n_cycles)
+  }
 
-      // LD A,(HL)
-      // 1  8
-      // - - - -
-      0x7E => {
-        self.set_a(mmu.read(self.hl));
-        self.pc += 1;
-        8
-      }
+  /// The little endian 16 bit immediate following the opcode at PC
+  fn immediate16(&self, mmu: &impl Memory) -> u16 {
+    mmu.read_u16_le(self.pc.wrapping_add(1))
+  }
 
-      // LD A,A
-      // 1  4
-      // - - - -
-      0x7F => {
-        self.set_a(self.a());
-        self.pc += 1;
-        4
-      }
-
-      // ADD A,(HL)
-      // 1  8
-      // Z 0 H C
-      0x86 => {
-        self.set_a(self.a().overflowing_add(mmu.read(self.hl)).0);
-        self.set_flags(
-          Some(self.a() == 0),
-          Some(false),
-          Some(get_bit(self.af, Self::HALF_CARRY_BIT)),
-          Some(get_bit(self.af, Self::CARRY_BIT))
-        );
-        self.pc += 1;
-        8
-      }
-
-      // SUB B
-      // 1  4
-      // Z 1 H C
-      0x90 => {
-        self.set_a(self.a().overflowing_sub(self.b()).0);
-        // FIXME: verify the correctness of this
-        self.set_flags(
-          Some(self.a() == 0),
-          Some(true),
-          Some(get_bit(self.bc, Self::UPPER_HALF_BORROW_BIT)),
-          Some(get_bit(self.bc, Self::UPPER_BORROW_BIT))
-        );
-        self.pc += 1;
-        4
-      }
-
-      // SUB C
-      // 1  4
-      // Z 1 H C
-      0x91 => {
-        self.set_a(self.a().overflowing_sub(self.c()).0);
-        // FIXME: verify the correctness of this
-        self.set_flags(
-          Some(self.a() == 0),
-          Some(true),
-          Some(get_bit(self.bc, Self::LOWER_HALF_BORROW_BIT)),
-          Some(get_bit(self.bc, Self::LOWER_BORROW_BIT))
-        );
-        self.pc += 1;
-        4
-      }
-
-      // SUB D
-      // 1  4
-      // Z 1 H C
-      0x92 => {
-        self.set_a(self.a().overflowing_sub(self.d()).0);
-        // FIXME: verify the correctness of this
-        self.set_flags(
-          Some(self.a() == 0),
-          Some(true),
-          Some(get_bit(self.de, Self::UPPER_HALF_BORROW_BIT)),
-          Some(get_bit(self.de, Self::UPPER_BORROW_BIT))
-        );
-        self.pc += 1;
-        4
-      }
-
-      // SUB E
-      // 1  4
-      // Z 1 H C
-      0x93 => {
-        self.set_a(self.a().overflowing_sub(self.e()).0);
-        // FIXME: verify the correctness of this
-        self.set_flags(
-          Some(self.a() == 0),
-          Some(true),
-          Some(get_bit(self.de, Self::LOWER_HALF_BORROW_BIT)),
-          Some(get_bit(self.de, Self::LOWER_BORROW_BIT))
-        );
-        self.pc += 1;
-        4
-      }
-
-      // SUB H
-      // 1  4
-      // Z 1 H C
-      0x94 => {
-        self.set_a(self.a().overflowing_sub(self.h()).0);
-        // FIXME: verify the correctness of this
-        self.set_flags(
-          Some(self.a() == 0),
-          Some(true),
-          Some(get_bit(self.hl, Self::UPPER_HALF_BORROW_BIT)),
-          Some(get_bit(self.hl, Self::UPPER_BORROW_BIT))
-        );
-        self.pc += 1;
-        4
-      }
-
-      // SUB L
-      // 1  4
-      // Z 1 H C
-      0x95 => {
-        self.set_a(self.a().overflowing_sub(self.l()).0);
-        // FIXME: verify the correctness of this
-        self.set_flags(
-          Some(self.a() == 0),
-          Some(true),
-          Some(get_bit(self.hl, Self::LOWER_HALF_BORROW_BIT)),
-          Some(get_bit(self.hl, Self::LOWER_BORROW_BIT))
-        );
-        self.pc += 1;
-        4
-      }
-
-      // SUB (HL)
-      // 1  8
-      // Z 1 H C
-      0x96 => {
-        self.set_a(self.a().overflowing_sub(self.l()).0);
-        // FIXME: verify the correctness of this
-        self.set_flags(
-          Some(self.a() == 0),
-          Some(true),
-          Some(get_bit(self.af, Self::LOWER_HALF_BORROW_BIT)),
-          Some(get_bit(self.af, Self::LOWER_BORROW_BIT))
-        );
-        self.pc += 1;
-        8
-      }
-
-      // SUB A
-      // 1  4
-      // Z 1 H C
-      0x97 => {
-        self.set_a(self.a().overflowing_sub(self.a()).0);
-        // FIXME: verify the correctness of this
-        self.set_flags(
-          Some(self.a() == 0),
-          Some(true),
-          Some(get_bit(self.af, Self::LOWER_HALF_BORROW_BIT)),
-          Some(get_bit(self.af, Self::LOWER_BORROW_BIT))
-        );
-        self.pc += 1;
-        4
-      }
-
-      // SBC A,B
-      // 1  4
-      // Z 1 H C
-      0x98 => {
-        let (rhs, _) = self.b().overflowing_add(if self.c_flag() { 1 } else { 0 });
-        self.set_a(self.a().overflowing_sub(rhs).0);
-        // FIXME: verify the correctness of this
-        self.set_flags(
-          Some(self.a() == 0),
-          Some(true),
-          Some(get_bit(self.af, Self::LOWER_HALF_BORROW_BIT)),
-          Some(get_bit(self.af, Self::LOWER_BORROW_BIT))
-        );
-        self.pc += 1;
-        4
-      }
-
-      // SBC A,C
-      // 1  4
-      // Z 1 H C
-      0x99 => {
-        let (rhs, _) = self.c().overflowing_add(if self.c_flag() { 1 } else { 0 });
-        self.set_a(self.a().overflowing_sub(rhs).0);
-        // FIXME: verify the correctness of this
-        self.set_flags(
-          Some(self.a() == 0),
-          Some(true),
-          Some(get_bit(self.af, Self::LOWER_HALF_BORROW_BIT)),
-          Some(get_bit(self.af, Self::LOWER_BORROW_BIT))
-        );
-        self.pc += 1;
-        4
-      }
-
-      // SBC A,D
-      // 1  4
-      // Z 1 H C
-      0x9A => {
-        let (rhs, _) = self.d().overflowing_add(if self.c_flag() { 1 } else { 0 });
-        self.set_a(self.a().overflowing_sub(rhs).0);
-        self.set_flags(
-          Some(self.a() == 0),
-          Some(true),
-          Some(get_bit(self.af, Self::UPPER_HALF_BORROW_BIT)),
-          Some(get_bit(self.af, Self::BORROW_BIT))
-        );
-        self.pc += 1;
-        4
-      }
-
-      // SBC A,E
-      // 1  4
-      // Z 1 H C
-      0x9B => {
-        let (rhs, _) = self.e().overflowing_add(if self.c_flag() { 1 } else { 0 });
-        self.set_a(self.a().overflowing_sub(rhs).0);
-        self.pc += 1;
-        4
-      }
+  /// SP plus the signed 8 bit immediate following the opcode at PC, setting the flags for
+  /// ADD SP,r8 and LD HL,SP+r8
+  ///
+  /// The carries are those of an unsigned add to the low byte of SP.
+  fn sp_offset(&mut self, mmu: &impl Memory) -> u16 {
+    let (result, flags) = alu::add_sp(self.sp, mmu.read(self.pc.wrapping_add(1)));
+    self.apply_flags(flags);
+    result
+  }
 
-      // SBC A,H
-      // 1  4
-      // Z 1 H C
-      0x9C => {
-        let (rhs, _) = self.h().overflowing_add(if self.c_flag() { 1 } else { 0 });
-        self.set_a(self.a().overflowing_sub(rhs).0);
-        self.set_flags(
-          Some(self.a() == 0),
-          Some(true),
-          Some(get_bit(self.af, Self::UPPER_HALF_BORROW_BIT)),
-          Some(get_bit(self.af, Self::BORROW_BIT))
-        );
-        self.pc += 1;
-        4
-      }
+  /// The 8 bit register selected by bits 0-2 of `index` (B, C, D, E, H, L, (HL), A)
+  fn register(&self, index: u8, mmu: &impl Memory) -> u8 {
+    match index & 0b111 {
+      0 => self.b(),
+      1 => self.c(),
+      2 => self.d(),
+      3 => self.e(),
+      4 => self.h(),
+      5 => self.l(),
+      6 => mmu.read(self.hl),
+      _ => self.a(),
+    }
+  }
 
-      // SBC A,L
-      // 1  4
-      // Z 1 H C
-      0x9D => {
-        let (rhs, _) = self.l().overflowing_add(if self.c_flag() { 1 } else { 0 });
-        self.set_a(self.a().overflowing_sub(rhs).0);
-        self.set_flags(
-          Some(self.a() == 0),
-          Some(true),
-          Some(get_bit(self.af, Self::UPPER_HALF_CARRY_BIT)),
-          Some(get_bit(self.af, Self::CARRY_BIT))
-        );
-        self.pc += 1;
-        4
-      }
+  /// Set the 8 bit register selected by bits 0-2 of `index` (B, C, D, E, H, L, (HL), A)
+  fn set_register(&mut self, index: u8, value: u8, mmu: &mut impl Memory) {
+    match index & 0b111 {
+      0 => self.set_b(value),
+      1 => self.set_c(value),
+      2 => self.set_d(value),
+      3 => self.set_e(value),
+      4 => self.set_h(value),
+      5 => self.set_l(value),
+      6 => mmu.write(self.hl, value),
+      _ => self.set_a(value),
+    }
+  }
 
-      // XOR C
-      // 1  4
-      // Z 0 0 0
-      0xA9 => {
-        self.set_c(self.a() ^ self.c());
-        self.set_flags(Some(self.a() == 0), Some(false), Some(false), Some(false));
-        self.pc += 1;
-        4
-      }
+  /// The register pair selected by bits 4-5 of `opcode` (BC, DE, HL, SP)
+  fn register16(&self, opcode: u8) -> u16 {
+    match (opcode >> 4) & 0b11 {
+      0 => self.bc,
+      1 => self.de,
+      2 => self.hl,
+      _ => self.sp,
+    }
+  }
 
-      // XOR D
-      // 1  4
-      // Z 0 0 0
-      0xAA => {
-        self.set_a(self.a() ^ self.d());
-        self.set_flags(Some(self.a() == 0), Some(false), Some(false), Some(false));
-        self.pc += 1;
-        4
-      }
+  /// Set the register pair selected by bits 4-5 of `opcode` (BC, DE, HL, SP)
+  fn set_register16(&mut self, opcode: u8, value: u16) {
+    match (opcode >> 4) & 0b11 {
+      0 => self.bc = value,
+      1 => self.de = value,
+      2 => self.hl = value,
+      _ => self.sp = value,
+    }
+  }
 
-      // XOR E
-      // 1  4
-      // Z 0 0 0
-      0xAB => {
-        self.set_a(self.a() ^ self.e());
-        self.set_flags(Some(self.a() == 0), Some(false), Some(false), Some(false));
-        self.pc += 1;
-        4
-      }
+  /// The 8 bit operand selected by bits 0-2 of an ALU `opcode` (B, C, D, E, H, L, (HL), A)
+  fn operand(&self, opcode: u8, mmu: &impl Memory) -> u8 {
+    self.register(opcode, mmu)
+  }
 
-      // XOR H
-      // 1  4
-      // Z 0 0 0
-      0xAC => {
-        self.set_a(self.a() ^ self.h());
-        self.set_flags(Some(self.a() == 0), Some(false), Some(false), Some(false));
-        self.pc += 1;
-        4
-      }
+  /// Cycles taken by an ALU `opcode`, reading through (HL) costs an extra memory access
+  fn operand_cycles(opcode: u8) -> u8 {
+    if opcode & 0b111 == Self::HL_INDIRECT { 8 } else { 4 }
+  }
 
-      // XOR A
-      // 1  4
-      // Z 0 0 0
-      0xAF => {
-        self.set_a(self.a() ^ self.a());
-        self.set_flags(Some(self.a() == 0), Some(false), Some(false), Some(false));
-        self.pc += 1;
-        4
-      }
+  /// Apply the ALU operation selected by bits 0-2 of `op` to A and `value`
+  /// (ADD, ADC, SUB, SBC, AND, XOR, OR, CP)
+  fn alu(&mut self, op: u8, value: u8) {
+    let a = self.a();
+    if op & 0b111 == 7 {
+      // CP only keeps the flags of the subtraction, A is never written
+      self.apply_flags(alu::sub8(a, value).1);
+      return;
+    }
+    let carry = self.c_flag();
+    let (result, flags) = match op & 0b111 {
+      0 => alu::add8(a, value),
+      1 => alu::adc8(a, value, carry),
+      2 => alu::sub8(a, value),
+      3 => alu::sbc8(a, value, carry),
+      4 => (a & value, Flags { z: Some(a & value == 0), n: Some(false), h: Some(true), c: Some(false) }),
+      5 => (a ^ value, Flags { z: Some(a ^ value == 0), n: Some(false), h: Some(false), c: Some(false) }),
+      _ => (a | value, Flags { z: Some(a | value == 0), n: Some(false), h: Some(false), c: Some(false) }),
+    };
+    self.set_a(result);
+    self.apply_flags(flags);
+  }
 
-      // JP a16
-      // 3  16
-      // - - - -
-      0xC3 => {
-        self.pc = mmu.read_double(self.pc + 1);
-        16
-      }
+  /// Rotate or shift `value` by the operation selected by bits 0-2 of `op`
+  /// (RLC, RRC, RL, RR, SLA, SRA, SWAP, SRL), setting C to the bit shifted out
+  fn rotate_shift(&mut self, op: u8, value: u8) -> u8 {
+    let carry_in = self.c_flag() as u8;
+    let (result, carry) = match op & 0b111 {
+      0 => (value.rotate_left(1), value & 0x80 != 0),
+      1 => (value.rotate_right(1), value & 0x01 != 0),
+      2 => (value << 1 | carry_in, value & 0x80 != 0),
+      3 => (value >> 1 | carry_in << 7, value & 0x01 != 0),
+      4 => (value << 1, value & 0x80 != 0),
+      // the sign bit is kept
+      5 => (value >> 1 | (value & 0x80), value & 0x01 != 0),
+      6 => (value.rotate_left(4), false),
+      _ => (value >> 1, value & 0x01 != 0),
+    };
+    self.set_flags(Some(result == 0), Some(false), Some(false), Some(carry));
+    result
+  }
 
-      0xCB => match mmu.read(self.pc + 1) {
-        // BIT 7,H
-        // 2  8
-        // Z 0 1 -
-        0x7C => {
-          self.set_flags(Some(get_bit(self.bc, 7 + 8)), Some(false), Some(true), None);
-          self.pc += 2;
-          8
-        }
-        b => unimplemented!("0xCB prefixed command not implemented 0x{:x}", b)
-      }
+  /// Cycles taken by the 0xCB prefixed opcode `cb`, (HL) costs a read and, unless it's only
+  /// tested by BIT, a write back
+  fn cb_cycles(cb: u8) -> u8 {
+    match (cb & 0b111 == Self::HL_INDIRECT, cb >> 6) {
+      (false, _) => 8,
+      (true, 1) => 12,
+      (true, _) => 16,
+    }
+  }
 
-      // CALL Z,a16
-      // 3  24/12
-      // - - - -
-      0xCC => {
-        if self.get_z_flag() {
-          mmu.write_double(self.sp, self.pc);
-          self.sp -= 2;
-          self.pc = mmu.read_double(self.pc + 1);
-          24
-        } else {
-          self.pc += 3;
-          12
-        }
-      }
+  /// Whether the condition encoded in bits 3-4 of a conditional `opcode` holds (NZ, Z, NC, C)
+  fn condition(&self, opcode: u8) -> bool {
+    match (opcode >> 3) & 0b11 {
+      0 => !self.get_z_flag(),
+      1 => self.get_z_flag(),
+      2 => !self.c_flag(),
+      _ => self.c_flag(),
+    }
+  }
 
-      // ADC A,d8
-      // 2  8
-      // Z 0 H C
-      0xCE => {
-        // let immediate = mmu.read(pc + 1) as i8;
-        // unsafe {
-        //   if immediate > 0 {
-        //     self.af.bytes.0 += immediate as u8
-        //   } else {
-        //     self.af.bytes.0 -= -immediate as u8
-        //   }
-        // }
-        // self.pc += 2;
-        // 8
-        unimplemented!()
-      },
+  /// Cycles taken by a conditional instruction as `(taken, not_taken)`
+  fn conditional_cycles(opcode: u8) -> (u8, u8) {
+    match opcode {
+      // JR cc,r8
+      0x20 | 0x28 | 0x30 | 0x38 => (12, 8),
+      // RET cc
+      0xC0 | 0xC8 | 0xD0 | 0xD8 => (20, 8),
+      // JP cc,a16
+      0xC2 | 0xCA | 0xD2 | 0xDA => (16, 12),
+      // CALL cc,a16
+      0xC4 | 0xCC | 0xD4 | 0xDC => (24, 12),
+      _ => unreachable!("opcode 0x{:02x} is not conditional", opcode),
+    }
+  }
 
-      // LDH (a8),A
-      // 2  12
-      // - - - -
-      0xE0 => {
-        mmu.write(MMU::IO_START_ADDRESS + mmu.read(self.pc + 1) as u16, self.a());
-        self.pc += 2;
-        12
-      }
+  /// Cycles taken by a conditional instruction for the path actually taken
+  fn branch_cycles(opcode: u8, taken: bool) -> u8 {
+    let (taken_cycles, not_taken_cycles) = Self::conditional_cycles(opcode);
+    if taken { taken_cycles } else { not_taken_cycles }
+  }
 
-      // LD (C),A
-      // 2  8
-      // - - - -
-      0xE2 => {
-        mmu.write(self.c() as u16, self.a());
-        self.pc += 2;
-        8
-      }
+  /// Whether the CPU can execute `opcode`, looked up among the 0xCB prefixed opcodes if `cb`
+  ///
+  /// Found by executing it on a scratch CPU, so the illegal opcodes, which only error or freeze,
+  /// aren't implemented.
+  pub fn is_opcode_implemented(opcode: u8, cb: bool) -> bool {
+    let program = if cb { [0xCB, opcode] } else { [opcode, 0x00] };
+    let mut memory = FlatMemory::with_program(0x0000, &program);
+    let mut cpu = CPU { pc: 0x0000, illegal_opcode_behavior: IllegalOpcodeBehavior::Error, ..CPU::default() };
+    cpu.exec(program[0], &mut memory).is_ok()
+  }
 
-      // PUSH HL
-      // 1  16
-      // - - - -
-      0xE5 => {
-        mmu.write_double(self.sp, self.hl);
-        self.sp -= 2;
-        self.pc += 1;
-        16
-      }
+  /// Number of implemented opcodes, prefixed and unprefixed, out of 512
+  pub fn implemented_opcode_count() -> usize {
+    (0..=0xFF)
+      .flat_map(|opcode| [(opcode, false), (opcode, true)])
+      .filter(|&(opcode, cb)| Self::is_opcode_implemented(opcode, cb))
+      .count()
+  }
 
-      // RST 38H
-      // 1  16
-      // - - - -
-      0xFF => {
-        mmu.write_double(self.sp, self.pc);
-        self.sp -= 2;
-        self.pc = 0x38;
-        16
+  fn illegal_opcode(&mut self, opcode: u8) -> Result<u8, CpuError> {
+    match self.illegal_opcode_behavior {
+      IllegalOpcodeBehavior::Error => Err(CpuError::IllegalOpcode { opcode, address: self.pc }),
+      IllegalOpcodeBehavior::Freeze => {
+        self.locked = true;
+        Ok(Self::LOCKED_CYCLES)
       }
-      b => unimplemented!("command not implemented 0x{:x}", b)
-    };
-
-    n_cycles
+    }
   }
 
   fn set_f_bit_n(&mut self, n: u8, value: bool) {
@@ -1141,27 +1008,22 @@ impl CPU {
     self.get_f_bit_n(Self::F_REGISTER_Z_FLAG_BIT_N)
   }
 
-  // This flag is set if a subtraction was performed in the last math instruction.
-  fn get_n_flag(&self) -> bool {
-    self.get_f_bit_n(Self::F_REGISTER_N_FLAG_BIT_N)
+  fn apply_flags(&mut self, flags: Flags) {
+    self.set_flags(flags.z, flags.n, flags.h, flags.c);
   }
 
   fn set_flags(&mut self, z: Option<bool>, n: Option<bool>, h: Option<bool>, c: Option<bool>) {
-    match z {
-      Some(z) => self.set_z_flag(z),
-      _ => {}
+    if let Some(z) = z {
+      self.set_z_flag(z);
     }
-    match n {
-      Some(n) => self.set_n_flag(n),
-      _ => {}
+    if let Some(n) = n {
+      self.set_n_flag(n);
     }
-    match h {
-      Some(h) => self.set_h_flag(h),
-      _ => {}
+    if let Some(h) = h {
+      self.set_h_flag(h);
     }
-    match c {
-      Some(c) => self.set_c_flag(c),
-      _ => {}
+    if let Some(c) = c {
+      self.set_c_flag(c);
     }
   }
 
@@ -1241,3 +1103,716 @@ impl CPU {
     self.de = set_lower(self.de, value);
   }
 }
+
+impl SaveState for CPU {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    for register in &[self.af, self.bc, self.de, self.hl, self.sp, self.pc] {
+      write_u16(w, *register)?;
+    }
+    write_bool(w, self.ime)?;
+    write_bool(w, self.ime_scheduled)?;
+    write_bool(w, self.halted)?;
+    write_bool(w, self.halt_bug)?;
+    write_bool(w, self.stopped)?;
+    write_bool(w, self.locked)
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    for register in &mut [&mut self.af, &mut self.bc, &mut self.de, &mut self.hl, &mut self.sp, &mut self.pc] {
+      **register = read_u16(r)?;
+    }
+    self.ime = read_bool(r)?;
+    self.ime_scheduled = read_bool(r)?;
+    self.halted = read_bool(r)?;
+    self.halt_bug = read_bool(r)?;
+    self.stopped = read_bool(r)?;
+    self.locked = read_bool(r)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use {
+    super::*,
+    crate::{
+      cartridge::Cartridge,
+      test_rom::RomBuilder,
+    },
+  };
+
+  /// An MMU with the bios disabled and `program` at the cartridge entry point
+  fn mmu_with_program(program: &[u8]) -> MMU {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + program.len()].copy_from_slice(program);
+    let mut mmu = MMU {
+      cartridge: Cartridge::maybe_from_bytes(&rom),
+      ..MMU::default()
+    };
+    mmu.write(MMU::BIOS_DISABLE_REGISTER_ADDRESS, 0x01);
+    mmu
+  }
+
+  #[test]
+  fn step_debug_returns_executed_instruction() {
+    let mut mmu = mmu_with_program(&[0x3E, 0xFF]);
+    let mut cpu = CPU { pc: 0x100, ..CPU::default() };
+
+    let (n_cycles, instruction) = cpu.step_debug(&mut mmu).unwrap();
+    assert_eq!(n_cycles, 8);
+    assert_eq!(instruction.address, 0x100);
+    assert_eq!(instruction.to_string(), "LD A,$FF");
+    assert_eq!(cpu.a(), 0xFF);
+    assert_eq!(cpu.pc, 0x102);
+  }
+
+  #[test]
+  fn pc_wraps_from_the_top_of_memory() {
+    // JP $FFFF, to IE holding a NOP
+    let mut mmu = mmu_with_program(&[0xC3, 0xFF, 0xFF]);
+    mmu.write(MMU::INTERRUPT_ENABLE_REG_ADDRESS, 0x00);
+    let mut cpu = CPU { pc: 0x100, ..CPU::default() };
+    cpu.step(&mut mmu).unwrap();
+    assert_eq!(cpu.pc, 0xFFFF);
+    cpu.step(&mut mmu).unwrap();
+    assert_eq!(cpu.pc, 0x0000);
+
+    // LD HL,d16 at the end of HRAM takes its operand from IE and the first byte of ROM
+    mmu.write(0xFFFE, 0x21);
+    cpu.pc = 0xFFFE;
+    cpu.step(&mut mmu).unwrap();
+    assert_eq!((cpu.pc, cpu.hl), (0x0001, 0x0000));
+  }
+
+  #[test]
+  fn opcode_status() {
+    // ADC A,d8, RLC B and SET 7,A
+    assert!(CPU::is_opcode_implemented(0xCE, false));
+    assert!(CPU::is_opcode_implemented(0x00, true));
+    assert!(CPU::is_opcode_implemented(0xFF, true));
+    // the illegal opcodes, though 0xD3 prefixed is SET 2,E
+    for &opcode in [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD].iter() {
+      assert!(!CPU::is_opcode_implemented(opcode, false), "{:02x}", opcode);
+    }
+    assert!(CPU::is_opcode_implemented(0xD3, true));
+    assert_eq!(CPU::implemented_opcode_count(), 512 - 11);
+  }
+
+  #[test]
+  fn every_opcode_executes() {
+    for opcode in 0..=0xFF {
+      for program in [[opcode, 0x00, 0x00], [0xCB, opcode, 0x00]].iter() {
+        let mut mmu = mmu_with_program(program);
+        let mut cpu = CPU { pc: 0x100, sp: 0xD000, hl: 0xC000, ..CPU::default() };
+        let _ = cpu.step(&mut mmu);
+      }
+    }
+  }
+
+  #[test]
+  fn illegal_opcode_is_an_error() {
+    let mut mmu = mmu_with_program(&[0xD3]);
+    let mut cpu = CPU { pc: 0x100, ..CPU::default() };
+
+    match cpu.step(&mut mmu) {
+      Err(CpuError::IllegalOpcode { opcode, address }) => assert_eq!((opcode, address), (0xD3, 0x100)),
+      result => panic!("expected an illegal opcode error, got {:?}", result),
+    }
+    assert!(!cpu.locked);
+  }
+
+  #[test]
+  fn illegal_opcode_freezes_in_hardware_accurate_mode() {
+    let mut mmu = mmu_with_program(&[0xD3, 0x00]);
+    let mut cpu = CPU {
+      pc: 0x100,
+      illegal_opcode_behavior: IllegalOpcodeBehavior::Freeze,
+      ..CPU::default()
+    };
+
+    assert_eq!(cpu.step(&mut mmu).unwrap(), 4);
+    assert!(cpu.locked);
+    for _ in 0..10 {
+      assert_eq!(cpu.step(&mut mmu).unwrap(), 4);
+      assert_eq!(cpu.pc, 0x100);
+    }
+  }
+
+  /// A halted CPU with the timer interrupt enabled and the stack in WRAM
+  fn halted_cpu(mmu: &mut MMU, ime: bool) -> CPU {
+    let mut cpu = CPU { pc: 0x100, sp: 0xD000, ime, ..CPU::default() };
+    mmu.write(MMU::INTERRUPT_ENABLE_REG_ADDRESS, Interrupt::Timer.mask());
+    assert_eq!(cpu.step(mmu).unwrap(), 4);
+    assert!(cpu.halted);
+    // nothing pending, the CPU idles in place
+    assert_eq!(cpu.step(mmu).unwrap(), 4);
+    assert_eq!(cpu.pc, 0x101);
+    cpu
+  }
+
+  #[test]
+  fn halt_with_ime_set_services_interrupt_on_wake() {
+    let mut mmu = mmu_with_program(&[0x76, 0x00]);
+    let mut cpu = halted_cpu(&mut mmu, true);
+
+    mmu.request_interrupt(Interrupt::Timer);
+    assert_eq!(cpu.step(&mut mmu).unwrap(), 24);
+    assert!(!cpu.halted);
+    assert!(!cpu.ime);
+    assert_eq!(cpu.pc, 0x50);
+    assert_eq!(mmu.read(MMU::INTERRUPT_FLAG_REG_ADDRESS) & Interrupt::Timer.mask(), 0);
+    // the return address is the instruction after the HALT
+    assert_eq!(cpu.sp, 0xCFFE);
+    assert_eq!((mmu.read(0xCFFF), mmu.read(0xCFFE)), (0x01, 0x01));
+  }
+
+  #[test]
+  fn halt_with_ime_clear_falls_through_on_wake() {
+    let mut mmu = mmu_with_program(&[0x76, 0x00]);
+    let mut cpu = halted_cpu(&mut mmu, false);
+
+    mmu.request_interrupt(Interrupt::Timer);
+    assert_eq!(cpu.step(&mut mmu).unwrap(), 4);
+    assert!(!cpu.halted);
+    assert_eq!(cpu.pc, 0x101);
+    assert_eq!(cpu.sp, 0xD000);
+    // the interrupt is left pending
+    assert_ne!(mmu.read(MMU::INTERRUPT_FLAG_REG_ADDRESS) & Interrupt::Timer.mask(), 0);
+
+    assert_eq!(cpu.step(&mut mmu).unwrap(), 4);
+    assert_eq!(cpu.pc, 0x102);
+  }
+
+  #[test]
+  fn halt_with_ime_clear_and_interrupt_pending_reads_next_byte_twice() {
+    // HALT; LD A,0x14; INC A
+    let mut mmu = mmu_with_program(&[0x76, 0x3E, 0x14, 0x3C]);
+    let mut cpu = CPU { pc: 0x100, ..CPU::default() };
+    mmu.write(MMU::INTERRUPT_ENABLE_REG_ADDRESS, Interrupt::Timer.mask());
+    mmu.request_interrupt(Interrupt::Timer);
+
+    cpu.step(&mut mmu).unwrap();
+    assert!(!cpu.halted);
+    assert!(cpu.halt_bug);
+    assert_eq!(cpu.pc, 0x101);
+    // executes LD A,0x3E, leaving 0x14 (INC D) as the next instruction
+    cpu.step(&mut mmu).unwrap();
+    assert!(!cpu.halt_bug);
+    assert_eq!((cpu.a(), cpu.pc), (0x3E, 0x102));
+  }
+
+  /// `program` with a timer interrupt pending and enabled, IME clear
+  fn cpu_with_pending_interrupt(program: &[u8]) -> (CPU, MMU) {
+    let mut mmu = mmu_with_program(program);
+    mmu.write(MMU::INTERRUPT_ENABLE_REG_ADDRESS, Interrupt::Timer.mask());
+    mmu.request_interrupt(Interrupt::Timer);
+    (CPU { pc: 0x100, sp: 0xD000, ..CPU::default() }, mmu)
+  }
+
+  #[test]
+  fn reti_enables_interrupts_immediately() {
+    // RETI, returning to a NOP at 0x0104
+    let (mut cpu, mut mmu) = cpu_with_pending_interrupt(&[0xD9, 0x00, 0x00, 0x00, 0x00]);
+    cpu.sp = 0xCFFE;
+    mmu.write(0xCFFE, 0x04);
+    mmu.write(0xCFFF, 0x01);
+
+    assert_eq!(cpu.step(&mut mmu).unwrap(), 16);
+    assert!(cpu.ime);
+    assert_eq!((cpu.pc, cpu.sp), (0x104, 0xD000));
+    // serviced before the instruction at the return address
+    assert_eq!(cpu.step(&mut mmu).unwrap(), 20);
+    assert_eq!(cpu.pc, 0x50);
+    assert_eq!((mmu.read(0xCFFF), mmu.read(0xCFFE)), (0x01, 0x04));
+  }
+
+  #[test]
+  fn ei_enables_interrupts_after_the_next_instruction() {
+    let (mut cpu, mut mmu) = cpu_with_pending_interrupt(RomBuilder::new().ei().nop().nop().program());
+
+    assert_eq!(cpu.step(&mut mmu).unwrap(), 4);
+    assert!(!cpu.ime);
+    // the NOP still runs
+    assert_eq!(cpu.step(&mut mmu).unwrap(), 4);
+    assert_eq!(cpu.pc, 0x102);
+    assert!(cpu.ime);
+    assert_eq!(cpu.step(&mut mmu).unwrap(), 20);
+    assert_eq!(cpu.pc, 0x50);
+    // the return address is the second NOP
+    assert_eq!((mmu.read(0xCFFF), mmu.read(0xCFFE)), (0x01, 0x02));
+  }
+
+  #[test]
+  fn di_after_ei_cancels_the_enable() {
+    let (mut cpu, mut mmu) = cpu_with_pending_interrupt(RomBuilder::new().ei().di().nop().program());
+
+    for _ in 0..3 {
+      assert_eq!(cpu.step(&mut mmu).unwrap(), 4);
+    }
+    assert!(!cpu.ime);
+    assert_eq!(cpu.pc, 0x103);
+  }
+
+  #[test]
+  fn stop_waits_for_joypad() {
+    // STOP; NOP
+    let mut mmu = mmu_with_program(&[0x10, 0x00, 0x00]);
+    let mut cpu = CPU { pc: 0x100, ..CPU::default() };
+
+    assert_eq!(cpu.step(&mut mmu).unwrap(), 4);
+    assert!(cpu.stopped);
+    for _ in 0..10 {
+      cpu.step(&mut mmu).unwrap();
+    }
+    assert_eq!(cpu.pc, 0x102);
+
+    // woken by a press even with the interrupt disabled
+    mmu.request_interrupt(Interrupt::Joypad);
+    assert_eq!(cpu.step(&mut mmu).unwrap(), 4);
+    assert!(!cpu.stopped);
+    assert_eq!(cpu.pc, 0x103);
+  }
+
+  #[test]
+  fn stop_waits_for_a_new_press_with_one_already_pending() {
+    // STOP; NOP
+    let mut mmu = mmu_with_program(&[0x10, 0x00, 0x00]);
+    mmu.request_interrupt(Interrupt::Joypad);
+    let mut cpu = CPU { pc: 0x100, ..CPU::default() };
+
+    cpu.step(&mut mmu).unwrap();
+    for _ in 0..10 {
+      cpu.step(&mut mmu).unwrap();
+    }
+    assert!(cpu.stopped);
+    assert_eq!(cpu.pc, 0x102);
+
+    mmu.request_interrupt(Interrupt::Joypad);
+    cpu.step(&mut mmu).unwrap();
+    assert!(!cpu.stopped);
+    assert_eq!(cpu.pc, 0x103);
+  }
+
+  #[test]
+  fn vblank_has_the_highest_priority() {
+    let pending = InterruptSet(Interrupt::Joypad.mask() | Interrupt::Timer.mask() | Interrupt::VBlank.mask());
+    let interrupt = CPU::highest_priority(pending);
+    assert_eq!(interrupt, Some(Interrupt::VBlank));
+    assert_eq!(CPU::interrupt_vector(interrupt.unwrap()), 0x40);
+
+    assert_eq!(CPU::highest_priority(InterruptSet(Interrupt::Joypad.mask() | Interrupt::Serial.mask())), Some(Interrupt::Serial));
+    assert_eq!(CPU::highest_priority(InterruptSet::default()), None);
+  }
+
+  #[test]
+  fn interrupt_vectors() {
+    let vectors: Vec<_> = Interrupt::ALL.iter().map(|&x| CPU::interrupt_vector(x)).collect();
+    assert_eq!(vectors, [0x40, 0x48, 0x50, 0x58, 0x60]);
+  }
+
+  #[test]
+  fn push_writes_high_byte_above_low_byte() {
+    // PUSH BC
+    let mut mmu = mmu_with_program(&[0xC5]);
+    let mut cpu = CPU { pc: 0x100, sp: 0xD000, bc: 0x1234, ..CPU::default() };
+
+    assert_eq!(cpu.step(&mut mmu).unwrap(), 16);
+    assert_eq!(cpu.sp, 0xCFFE);
+    assert_eq!(mmu.read(0xCFFF), 0x12);
+    assert_eq!(mmu.read(0xCFFE), 0x34);
+  }
+
+  #[test]
+  fn pop_reads_low_byte_first() {
+    // PUSH BC; POP DE; POP AF
+    let mut mmu = mmu_with_program(&[0xC5, 0xD1, 0xF1]);
+    mmu.write(0xD000, 0xFF);
+    mmu.write(0xD001, 0xAB);
+    let mut cpu = CPU { pc: 0x100, sp: 0xD000, bc: 0x1234, ..CPU::default() };
+
+    cpu.step(&mut mmu).unwrap();
+    assert_eq!(cpu.step(&mut mmu).unwrap(), 12);
+    assert_eq!(cpu.de, 0x1234);
+    assert_eq!(cpu.sp, 0xD000);
+
+    cpu.step(&mut mmu).unwrap();
+    assert_eq!(cpu.af, 0xABF0);
+    assert_eq!(cpu.sp, 0xD002);
+  }
+
+  /// A CB prefixed opcode run against a starting state, and the state it should leave behind
+  struct CbCase {
+    opcode: u8,
+    /// Value of the target register before and after
+    before: u8,
+    after: u8,
+    /// F before the op, so flags the op leaves alone can be checked
+    flags_before: u8,
+    flags_after: u8,
+  }
+
+  /// Run each case with the target register loaded, asserting the target's value and all of Z/N/H/C
+  fn run_cb_cases(cases: &[CbCase], set_target: fn(&mut CPU, u8), target: fn(&CPU) -> u8) {
+    for case in cases {
+      let mut mmu = mmu_with_program(&[0xCB, case.opcode]);
+      let mut cpu = CPU { pc: 0x100, af: case.flags_before as u16, ..CPU::default() };
+      set_target(&mut cpu, case.before);
+
+      assert_eq!(cpu.step(&mut mmu).unwrap(), 8, "cycles of CB {:02X}", case.opcode);
+      assert_eq!(target(&cpu), case.after, "value after CB {:02X} on {:02X}", case.opcode, case.before);
+      assert_eq!(cpu.af as u8, case.flags_after, "flags after CB {:02X} on {:02X}", case.opcode, case.before);
+      assert_eq!(cpu.pc, 0x102);
+    }
+  }
+
+  #[test]
+  fn bit_tests_h_register() {
+    let z = 0x80;
+    let h = 0x20;
+    let c = 0x10;
+    let cases = [
+      // BIT 7,H only looks at H, carry is left alone
+      CbCase { opcode: 0x7C, before: 0x80, after: 0x80, flags_before: 0x00, flags_after: h },
+      CbCase { opcode: 0x7C, before: 0x7F, after: 0x7F, flags_before: 0x00, flags_after: z | h },
+      CbCase { opcode: 0x7C, before: 0x00, after: 0x00, flags_before: c, flags_after: z | h | c },
+      CbCase { opcode: 0x7C, before: 0xFF, after: 0xFF, flags_before: z | c, flags_after: h | c },
+    ];
+    run_cb_cases(&cases, CPU::set_h, CPU::h);
+
+    // B shares no bits with H
+    let mut mmu = mmu_with_program(&[0xCB, 0x7C]);
+    let mut cpu = CPU { pc: 0x100, bc: 0xFF00, ..CPU::default() };
+    cpu.step(&mut mmu).unwrap();
+    assert!(cpu.get_z_flag());
+  }
+
+  #[test]
+  fn runs_against_flat_memory() {
+    let mut memory = FlatMemory::with_program(0x0000, &[
+      0x3E, 0x42, // LD A,$42
+      0x47,       // LD B,A
+      0x0E, 0x07, // LD C,$07
+      0x2E, 0x00, // LD L,$00
+      0x77,       // LD (HL),A
+      0xC5,       // PUSH BC
+    ]);
+    let mut cpu = CPU { hl: 0x8000, sp: 0xFFFE, ..CPU::default() };
+    for _ in 0..6 {
+      cpu.step(&mut memory).unwrap();
+    }
+
+    assert_eq!(cpu.a(), 0x42);
+    assert_eq!(cpu.bc, 0x4207);
+    assert_eq!(cpu.hl, 0x8000);
+    assert_eq!(cpu.sp, 0xFFFC);
+    assert_eq!(cpu.pc, 0x0009);
+    assert_eq!(memory.read(0x8000), 0x42);
+    assert_eq!(memory.0[0xFFFC..0xFFFE], [0x07, 0x42]);
+  }
+
+  #[test]
+  fn word_operands_are_little_endian() {
+    let mut memory = FlatMemory::with_program(0x0100, &[
+      0x01, 0x34, 0x12, // LD BC,$1234
+      0x31, 0xCD, 0xAB, // LD SP,$ABCD
+      0x08, 0x00, 0xC0, // LD ($C000),SP
+    ]);
+    let mut cpu = CPU { pc: 0x100, ..CPU::default() };
+    for _ in 0..3 {
+      cpu.step(&mut memory).unwrap();
+    }
+    assert_eq!(cpu.bc, 0x1234);
+    assert_eq!(cpu.sp, 0xABCD);
+    assert_eq!((memory.read(0xC000), memory.read(0xC001)), (0xCD, 0xAB));
+  }
+
+  #[test]
+  fn hl_increment_and_decrement_wrap() {
+    let mut memory = FlatMemory::with_program(0x0100, &[
+      0x21, 0x00, 0x00, // LD HL,$0000
+      0x3E, 0x42,       // LD A,$42
+      0x32,             // LD (HL-),A
+      0x2A,             // LD A,(HL+)
+      0x3A,             // LD A,(HL-)
+    ]);
+    memory.0[0xFFFF] = 0x99;
+    let mut cpu = CPU { pc: 0x100, ..CPU::default() };
+    for _ in 0..3 {
+      cpu.step(&mut memory).unwrap();
+    }
+    assert_eq!(memory.read(0x0000), 0x42);
+    assert_eq!(cpu.hl, 0xFFFF);
+
+    assert_eq!(cpu.step(&mut memory).unwrap(), 8);
+    assert_eq!(cpu.a(), 0x99);
+    assert_eq!(cpu.hl, 0x0000);
+
+    assert_eq!(cpu.step(&mut memory).unwrap(), 8);
+    assert_eq!(cpu.a(), 0x42);
+    assert_eq!(cpu.hl, 0xFFFF);
+  }
+
+  const Z: u16 = 0x80;
+  const C: u16 = 0x10;
+
+  /// Run the single instruction `program` at 0x100 with the flags in `flags`, returning the cycles it took
+  fn run_with_flags(program: &[u8], flags: u16) -> (u8, CPU) {
+    let mut mmu = mmu_with_program(program);
+    let mut cpu = CPU { pc: 0x100, sp: 0xD000, af: flags, ..CPU::default() };
+    mmu.write(0xD000, 0x34);
+    mmu.write(0xD001, 0x12);
+    let cycles = cpu.step(&mut mmu).unwrap();
+    (cycles, cpu)
+  }
+
+  #[test]
+  fn jr_cc_cycles_follow_branch() {
+    // JR NZ,-2
+    let (cycles, cpu) = run_with_flags(&[0x20, 0xFE], 0);
+    assert_eq!((cycles, cpu.pc), (12, 0x100));
+    let (cycles, cpu) = run_with_flags(&[0x20, 0xFE], Z);
+    assert_eq!((cycles, cpu.pc), (8, 0x102));
+    // JR C,+4
+    let (cycles, cpu) = run_with_flags(&[0x38, 0x04], C);
+    assert_eq!((cycles, cpu.pc), (12, 0x106));
+  }
+
+  #[test]
+  fn call_cc_cycles_follow_branch() {
+    // CALL NZ,$1234
+    let (cycles, cpu) = run_with_flags(&[0xC4, 0x34, 0x12], 0);
+    assert_eq!((cycles, cpu.pc, cpu.sp), (24, 0x1234, 0xCFFE));
+    let (cycles, cpu) = run_with_flags(&[0xC4, 0x34, 0x12], Z);
+    assert_eq!((cycles, cpu.pc, cpu.sp), (12, 0x103, 0xD000));
+  }
+
+  #[test]
+  fn ret_cc_cycles_follow_branch() {
+    // RET C
+    let (cycles, cpu) = run_with_flags(&[0xD8], C);
+    assert_eq!((cycles, cpu.pc, cpu.sp), (20, 0x1234, 0xD002));
+    let (cycles, cpu) = run_with_flags(&[0xD8], 0);
+    assert_eq!((cycles, cpu.pc, cpu.sp), (8, 0x101, 0xD000));
+  }
+
+  #[test]
+  fn jp_cc_cycles_follow_branch() {
+    // JP Z,$1234
+    let (cycles, cpu) = run_with_flags(&[0xCA, 0x34, 0x12], Z);
+    assert_eq!((cycles, cpu.pc), (16, 0x1234));
+    let (cycles, cpu) = run_with_flags(&[0xCA, 0x34, 0x12], 0);
+    assert_eq!((cycles, cpu.pc), (12, 0x103));
+  }
+
+  /// Run the single ALU instruction in `program` with A = `a` and both B and (HL) = `b`
+  fn run_alu(program: &[u8], a: u8, b: u8) -> CPU {
+    let mut mmu = mmu_with_program(program);
+    let mut cpu = CPU {
+      pc: 0x100,
+      af: (a as u16) << 8,
+      bc: (b as u16) << 8,
+      hl: 0xC000,
+      ..CPU::default()
+    };
+    mmu.write(0xC000, b);
+    cpu.step(&mut mmu).unwrap();
+    cpu
+  }
+
+  #[test]
+  fn cp_sets_sub_flags_without_touching_a() {
+    let pairs = [(0x3C, 0x2F), (0x3C, 0x3C), (0x3C, 0x40), (0x00, 0x01), (0x10, 0x01)];
+    // (CP, SUB) for B, (HL) and d8
+    let forms = |b: u8| [([0xB8, 0x00], [0x90, 0x00]), ([0xBE, 0x00], [0x96, 0x00]), ([0xFE, b], [0xD6, b])];
+    for &(a, b) in pairs.iter() {
+      for (cp, sub) in forms(b).iter() {
+        let compared = run_alu(cp, a, b);
+        let subtracted = run_alu(sub, a, b);
+        assert_eq!(compared.a(), a, "CP clobbered A for {:02x} - {:02x}", a, b);
+        assert_eq!(subtracted.a(), a.wrapping_sub(b));
+        assert_eq!(compared.af & 0xF0, subtracted.af & 0xF0, "flags differ for {:02x} - {:02x}", a, b);
+        assert_eq!(compared.pc, subtracted.pc);
+      }
+    }
+  }
+
+  #[test]
+  fn sub_flags() {
+    // 0x3C - 0x2F borrows from bit 4 only
+    assert_eq!(run_alu(&[0x90], 0x3C, 0x2F).af, 0x0D60);
+    // equal operands set Z
+    assert_eq!(run_alu(&[0x90], 0x3C, 0x3C).af, 0x00C0);
+    // 0x3C - 0x40 borrows out of bit 8 only
+    assert_eq!(run_alu(&[0x90], 0x3C, 0x40).af, 0xFC50);
+  }
+
+  /// Step through `program` one instruction at a time, starting from `cpu` with PC at the entry
+  /// point
+  fn run_program(program: &[u8], cpu: CPU, steps: usize) -> (CPU, MMU) {
+    let mut mmu = mmu_with_program(program);
+    let mut cpu = CPU { pc: 0x100, ..cpu };
+    for _ in 0..steps {
+      cpu.step(&mut mmu).unwrap();
+    }
+    (cpu, mmu)
+  }
+
+  #[test]
+  fn adc_and_sbc_include_carry() {
+    // 0xF0 + 0x0F + 1
+    assert_eq!(run_with_flags(&[0xCE, 0x0F], 0xF010).1.af, 0x00B0);
+    // 0x10 - 0x0F - 1
+    assert_eq!(run_with_flags(&[0xDE, 0x0F], 0x1010).1.af, 0x00E0);
+    // without the carry in
+    assert_eq!(run_with_flags(&[0xCE, 0x0F], 0xF000).1.af, 0xFF00);
+  }
+
+  #[test]
+  fn logic_ops_flags() {
+    // AND always sets H
+    assert_eq!(run_with_flags(&[0xE6, 0x0F], 0xF010).1.af, 0x00A0);
+    assert_eq!(run_with_flags(&[0xF6, 0x0F], 0xF070).1.af, 0xFF00);
+    // XOR A
+    assert_eq!(run_with_flags(&[0xAF], 0x3C70).1.af, 0x0080);
+  }
+
+  #[test]
+  fn inc_dec_preserve_carry() {
+    // INC A carrying out of bit 3
+    assert_eq!(run_with_flags(&[0x3C], 0x0F10).1.af, 0x1030);
+    // DEC A borrowing from bit 4
+    assert_eq!(run_with_flags(&[0x3D], 0x1010).1.af, 0x0F70);
+    // INC A wrapping to zero
+    assert_eq!(run_with_flags(&[0x3C], 0xFF00).1.af, 0x00A0);
+
+    // INC (HL)
+    let (cpu, mmu) = run_program(&[0x34], CPU { hl: 0xC000, ..CPU::default() }, 1);
+    assert_eq!(mmu.read(0xC000), 0x01);
+    assert_eq!(cpu.pc, 0x101);
+  }
+
+  #[test]
+  fn add_hl_carries_from_bits_11_and_15() {
+    let (cpu, _) = run_program(&[0x09], CPU { hl: 0x0FFF, bc: 0x0001, ..CPU::default() }, 1);
+    assert_eq!((cpu.hl, cpu.af), (0x1000, 0x0020));
+    // Z is left alone
+    let (cpu, _) = run_program(&[0x19], CPU { hl: 0xF000, de: 0x1000, af: 0x0080, ..CPU::default() }, 1);
+    assert_eq!((cpu.hl, cpu.af), (0x0000, 0x0090));
+  }
+
+  #[test]
+  fn daa_adjusts_to_bcd() {
+    // 45 + 38
+    let (cpu, _) = run_program(&[0xC6, 0x38, 0x27], CPU { af: 0x4500, ..CPU::default() }, 2);
+    assert_eq!(cpu.a(), 0x83);
+    // 83 - 38
+    let (cpu, _) = run_program(&[0xD6, 0x38, 0x27], CPU { af: 0x8300, ..CPU::default() }, 2);
+    assert_eq!(cpu.a(), 0x45);
+    // 99 + 01 carries out
+    let (cpu, _) = run_program(&[0xC6, 0x01, 0x27], CPU { af: 0x9900, ..CPU::default() }, 2);
+    assert_eq!(cpu.af, 0x0090);
+  }
+
+  #[test]
+  fn accumulator_rotates_clear_z() {
+    // RLCA
+    assert_eq!(run_with_flags(&[0x07], 0x8500).1.af, 0x0B10);
+    // RLA shifting out the only set bit
+    assert_eq!(run_with_flags(&[0x17], 0x8000).1.af, 0x0010);
+    // RRA shifting the carry in
+    assert_eq!(run_with_flags(&[0x1F], 0x0110).1.af, 0x8010);
+    // RRCA
+    assert_eq!(run_with_flags(&[0x0F], 0x0100).1.af, 0x8010);
+  }
+
+  #[test]
+  fn call_rst_and_ret() {
+    let (cycles, cpu) = run_with_flags(&[0xEF], 0);
+    assert_eq!((cycles, cpu.pc, cpu.sp), (16, 0x28, 0xCFFE));
+
+    // CALL 0x0200 then RET from there
+    let mut program = vec![0; 0x101];
+    program[..3].copy_from_slice(&[0xCD, 0x00, 0x02]);
+    program[0x100] = 0xC9;
+    let (cpu, mmu) = run_program(&program, CPU { sp: 0xD000, ..CPU::default() }, 1);
+    assert_eq!((cpu.pc, mmu.read_u16_le(cpu.sp)), (0x200, 0x103));
+    let (cpu, _) = run_program(&program, CPU { sp: 0xD000, ..CPU::default() }, 2);
+    assert_eq!((cpu.pc, cpu.sp), (0x103, 0xD000));
+  }
+
+  #[test]
+  fn register_to_register_loads() {
+    // LD D,B, LD (HL),D, LD A,(HL)
+    let cpu = CPU { bc: 0x4200, hl: 0xC000, ..CPU::default() };
+    let (cpu, mmu) = run_program(&[0x50, 0x72, 0x7E], cpu, 3);
+    assert_eq!((cpu.d(), cpu.a(), mmu.read(0xC000)), (0x42, 0x42, 0x42));
+  }
+
+  #[test]
+  fn high_page_loads() {
+    // LD (C),A then LD A,(C) back out of HRAM
+    let cpu = CPU { af: 0x5A00, bc: 0x0080, ..CPU::default() };
+    let (cpu, mmu) = run_program(&[0xE2, 0xAF, 0xF2], cpu, 3);
+    assert_eq!((mmu.read(0xFF80), cpu.a(), cpu.pc), (0x5A, 0x5A, 0x103));
+  }
+
+  #[test]
+  fn sp_offset_flags() {
+    // ADD SP,1 carrying out of the low byte
+    let (cpu, _) = run_program(&[0xE8, 0x01], CPU { sp: 0x00FF, af: 0x00C0, ..CPU::default() }, 1);
+    assert_eq!((cpu.sp, cpu.af), (0x0100, 0x0030));
+    // LD HL,SP-1
+    let (cpu, _) = run_program(&[0xF8, 0xFF], CPU { sp: 0x0000, ..CPU::default() }, 1);
+    assert_eq!((cpu.hl, cpu.af), (0xFFFF, 0x0000));
+  }
+
+  #[test]
+  fn cb_rotates_and_shifts() {
+    // (opcode on B, B before, flags before, B after, flags after)
+    let cases = [
+      // RLC B
+      (0x00, 0x85, 0x00, 0x0B, 0x10),
+      // RRC B to zero
+      (0x08, 0x00, 0x10, 0x00, 0x80),
+      // RL B shifting the carry in
+      (0x10, 0x80, 0x10, 0x01, 0x10),
+      // RR B
+      (0x18, 0x01, 0x00, 0x00, 0x90),
+      // SLA B
+      (0x20, 0xC1, 0x10, 0x82, 0x10),
+      // SRA B keeps the sign
+      (0x28, 0x81, 0x00, 0xC0, 0x10),
+      // SWAP B clears the carry
+      (0x30, 0xF1, 0x10, 0x1F, 0x00),
+      // SRL B
+      (0x38, 0x81, 0x00, 0x40, 0x10),
+    ];
+    for &(opcode, b, flags, result, result_flags) in cases.iter() {
+      let cpu = CPU { bc: (b as u16) << 8, af: flags, ..CPU::default() };
+      let (cpu, _) = run_program(&[0xCB, opcode], cpu, 1);
+      assert_eq!((cpu.b(), cpu.af), (result, result_flags), "CB {:02x} on {:02x}", opcode, b);
+      assert_eq!(cpu.pc, 0x102);
+    }
+  }
+
+  #[test]
+  fn cb_bit_res_set() {
+    // BIT 7,H with the bit clear keeps C
+    let (cpu, _) = run_program(&[0xCB, 0x7C], CPU { hl: 0x7F00, af: 0x0010, ..CPU::default() }, 1);
+    assert_eq!(cpu.af, 0x00B0);
+    // RES 0,A, SET 3,(HL) then BIT 3,(HL)
+    let cpu = CPU { af: 0xFF00, hl: 0xC000, ..CPU::default() };
+    let (cpu, mmu) = run_program(&[0xCB, 0x87, 0xCB, 0xDE, 0xCB, 0x5E], cpu, 3);
+    assert_eq!((cpu.a(), mmu.read(0xC000)), (0xFE, 0x08));
+    assert_eq!(cpu.af & 0xF0, 0x20);
+  }
+
+  #[test]
+  fn cb_cycles() {
+    assert_eq!(CPU::cb_cycles(0x00), 8);
+    // RLC (HL), BIT 0,(HL) and SET 7,(HL)
+    assert_eq!(CPU::cb_cycles(0x06), 16);
+    assert_eq!(CPU::cb_cycles(0x46), 12);
+    assert_eq!(CPU::cb_cycles(0xFE), 16);
+  }
+}
@@ -0,0 +1,102 @@
+use {
+  crate::{mmu::MMU, util::*, Gameboy},
+};
+
+/// A difference between two machines found by `Gameboy::diff`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryDiff {
+  /// The byte at `address` differs
+  Address { address: u16, left: u8, right: u8 },
+  /// A CPU register or flag differs
+  Register { name: &'static str, left: u16, right: u16 },
+}
+
+/// Compare the register state and every memory region of two machines
+///
+/// The echo region is skipped since it mirrors work RAM, and IO registers are compared through the
+/// MMU so peripheral registers are seen as the CPU sees them.
+pub fn diff(left: &Gameboy, right: &Gameboy) -> Vec<MemoryDiff> {
+  let mut diffs: Vec<_> = registers(left)
+    .iter()
+    .zip(registers(right).iter())
+    .filter(|((_, l), (_, r))| l != r)
+    .map(|(&(name, left), &(_, right))| MemoryDiff::Register { name, left, right })
+    .collect();
+
+  let regions: [(u16, &[u8], &[u8]); 4] = [
+    // bank 0 of VRAM, and banks 0 and 1 of work RAM as DMG maps them
+    (MMU::VRAM_START_ADDRESS, &left.mmu.vram[..MMU::VRAM_SIZE], &right.mmu.vram[..MMU::VRAM_SIZE]),
+    (MMU::WRAM_START_ADDRESS, &left.mmu.wram[..MMU::WRAM_SIZE], &right.mmu.wram[..MMU::WRAM_SIZE]),
+    (MMU::OAM_START_ADDRESS, &left.mmu.oam, &right.mmu.oam),
+    (MMU::HRAM_START_ADDRESS, &left.mmu.hram, &right.mmu.hram),
+  ];
+  for (start, l, r) in regions.iter() {
+    diffs.extend(
+      l.iter()
+        .zip(r.iter())
+        .enumerate()
+        .filter(|(_, (l, r))| l != r)
+        .map(|(i, (&left, &right))| MemoryDiff::Address { address: start + i as u16, left, right }),
+    );
+  }
+
+  let mapped = (MMU::IO_START_ADDRESS..=MMU::IO_END_ADDRESS).chain(std::iter::once(MMU::INTERRUPT_ENABLE_REG_ADDRESS));
+  diffs.extend(
+    mapped
+      .map(|address| (address, left.mmu.read(address), right.mmu.read(address)))
+      .filter(|(_, l, r)| l != r)
+      .map(|(address, left, right)| MemoryDiff::Address { address, left, right }),
+  );
+  diffs
+}
+
+fn registers(gameboy: &Gameboy) -> [(&'static str, u16); 9] {
+  let cpu = &gameboy.cpu;
+  [
+    ("AF", cpu.af),
+    ("BC", cpu.bc),
+    ("DE", cpu.de),
+    ("HL", cpu.hl),
+    ("SP", cpu.sp),
+    ("PC", cpu.pc),
+    ("IME", cpu.ime as u16),
+    ("HALTED", cpu.halted as u16),
+    ("LOCKED", cpu.locked as u16),
+  ]
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn identical_machines_have_no_diff() {
+    let gameboy = Gameboy::default();
+    assert_eq!(diff(&gameboy, &gameboy.clone()), vec![]);
+  }
+
+  #[test]
+  fn single_byte_change_is_one_diff() {
+    let left = Gameboy::default();
+    let mut right = left.clone();
+    right.mmu.write(0xC123, 0x42);
+
+    assert_eq!(diff(&left, &right), vec![MemoryDiff::Address { address: 0xC123, left: 0x00, right: 0x42 }]);
+  }
+
+  #[test]
+  fn register_changes_are_named() {
+    let left = Gameboy::default();
+    let mut right = left.clone();
+    right.cpu.pc = 0x100;
+    right.mmu.write(MMU::INTERRUPT_ENABLE_REG_ADDRESS, 0x01);
+
+    assert_eq!(
+      diff(&left, &right),
+      vec![
+        MemoryDiff::Register { name: "PC", left: 0x0000, right: 0x0100 },
+        MemoryDiff::Address { address: MMU::INTERRUPT_ENABLE_REG_ADDRESS, left: 0x00, right: 0x01 },
+      ]
+    );
+  }
+}
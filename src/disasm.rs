@@ -0,0 +1,435 @@
+use {
+  crate::util::*,
+  std::fmt,
+};
+
+/// Instruction templates for every unprefixed opcode, in the same notation as the CPU's opcode comments
+///
+/// Operand placeholders are replaced with the bytes following the opcode when decoding:
+/// `d8`/`d16` immediates, `a8`/`a16` addresses, `r8` a jump offset and `e8` a signed immediate.
+/// Illegal opcodes have an empty template.
+const TEMPLATES: [&str; 0x100] = [
+  // 0x00
+  "NOP", "LD BC,d16", "LD (BC),A", "INC BC", "INC B", "DEC B", "LD B,d8", "RLCA",
+  "LD (a16),SP", "ADD HL,BC", "LD A,(BC)", "DEC BC", "INC C", "DEC C", "LD C,d8", "RRCA",
+  // 0x10
+  "STOP", "LD DE,d16", "LD (DE),A", "INC DE", "INC D", "DEC D", "LD D,d8", "RLA",
+  "JR r8", "ADD HL,DE", "LD A,(DE)", "DEC DE", "INC E", "DEC E", "LD E,d8", "RRA",
+  // 0x20
+  "JR NZ,r8", "LD HL,d16", "LD (HL+),A", "INC HL", "INC H", "DEC H", "LD H,d8", "DAA",
+  "JR Z,r8", "ADD HL,HL", "LD A,(HL+)", "DEC HL", "INC L", "DEC L", "LD L,d8", "CPL",
+  // 0x30
+  "JR NC,r8", "LD SP,d16", "LD (HL-),A", "INC SP", "INC (HL)", "DEC (HL)", "LD (HL),d8", "SCF",
+  "JR C,r8", "ADD HL,SP", "LD A,(HL-)", "DEC SP", "INC A", "DEC A", "LD A,d8", "CCF",
+  // 0x40
+  "LD B,B", "LD B,C", "LD B,D", "LD B,E", "LD B,H", "LD B,L", "LD B,(HL)", "LD B,A",
+  "LD C,B", "LD C,C", "LD C,D", "LD C,E", "LD C,H", "LD C,L", "LD C,(HL)", "LD C,A",
+  // 0x50
+  "LD D,B", "LD D,C", "LD D,D", "LD D,E", "LD D,H", "LD D,L", "LD D,(HL)", "LD D,A",
+  "LD E,B", "LD E,C", "LD E,D", "LD E,E", "LD E,H", "LD E,L", "LD E,(HL)", "LD E,A",
+  // 0x60
+  "LD H,B", "LD H,C", "LD H,D", "LD H,E", "LD H,H", "LD H,L", "LD H,(HL)", "LD H,A",
+  "LD L,B", "LD L,C", "LD L,D", "LD L,E", "LD L,H", "LD L,L", "LD L,(HL)", "LD L,A",
+  // 0x70
+  "LD (HL),B", "LD (HL),C", "LD (HL),D", "LD (HL),E", "LD (HL),H", "LD (HL),L", "HALT", "LD (HL),A",
+  "LD A,B", "LD A,C", "LD A,D", "LD A,E", "LD A,H", "LD A,L", "LD A,(HL)", "LD A,A",
+  // 0x80
+  "ADD A,B", "ADD A,C", "ADD A,D", "ADD A,E", "ADD A,H", "ADD A,L", "ADD A,(HL)", "ADD A,A",
+  "ADC A,B", "ADC A,C", "ADC A,D", "ADC A,E", "ADC A,H", "ADC A,L", "ADC A,(HL)", "ADC A,A",
+  // 0x90
+  "SUB B", "SUB C", "SUB D", "SUB E", "SUB H", "SUB L", "SUB (HL)", "SUB A",
+  "SBC A,B", "SBC A,C", "SBC A,D", "SBC A,E", "SBC A,H", "SBC A,L", "SBC A,(HL)", "SBC A,A",
+  // 0xA0
+  "AND B", "AND C", "AND D", "AND E", "AND H", "AND L", "AND (HL)", "AND A",
+  "XOR B", "XOR C", "XOR D", "XOR E", "XOR H", "XOR L", "XOR (HL)", "XOR A",
+  // 0xB0
+  "OR B", "OR C", "OR D", "OR E", "OR H", "OR L", "OR (HL)", "OR A",
+  "CP B", "CP C", "CP D", "CP E", "CP H", "CP L", "CP (HL)", "CP A",
+  // 0xC0
+  "RET NZ", "POP BC", "JP NZ,a16", "JP a16", "CALL NZ,a16", "PUSH BC", "ADD A,d8", "RST 00H",
+  "RET Z", "RET", "JP Z,a16", "PREFIX CB", "CALL Z,a16", "CALL a16", "ADC A,d8", "RST 08H",
+  // 0xD0
+  "RET NC", "POP DE", "JP NC,a16", "", "CALL NC,a16", "PUSH DE", "SUB d8", "RST 10H",
+  "RET C", "RETI", "JP C,a16", "", "CALL C,a16", "", "SBC A,d8", "RST 18H",
+  // 0xE0
+  "LDH (a8),A", "POP HL", "LD (C),A", "", "", "PUSH HL", "AND d8", "RST 20H",
+  "ADD SP,e8", "JP (HL)", "LD (a16),A", "", "", "", "XOR d8", "RST 28H",
+  // 0xF0
+  "LDH A,(a8)", "POP AF", "LD A,(C)", "DI", "", "PUSH AF", "OR d8", "RST 30H",
+  "LD HL,SP+e8", "LD SP,HL", "LD A,(a16)", "EI", "", "", "CP d8", "RST 38H",
+];
+
+/// Targets of the 0xCB prefixed instructions, indexed by the lower 3 bits of the opcode
+const CB_REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+
+/// 0xCB prefixed rotate/shift mnemonics for opcodes 0x00-0x3F, indexed by bits 3-5 of the opcode
+const CB_SHIFT_MNEMONICS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+const CONDITIONS: [&str; 4] = ["NZ", "Z", "NC", "C"];
+
+/// Names of the memory mapped hardware registers, written in place of their address when disassembling
+/// with symbols
+const HARDWARE_REGISTERS: [(u16, &str); 42] = [
+  (0xFF00, "P1"), (0xFF01, "SB"), (0xFF02, "SC"), (0xFF04, "DIV"), (0xFF05, "TIMA"), (0xFF06, "TMA"),
+  (0xFF07, "TAC"), (0xFF0F, "IF"),
+  (0xFF10, "NR10"), (0xFF11, "NR11"), (0xFF12, "NR12"), (0xFF13, "NR13"), (0xFF14, "NR14"),
+  (0xFF16, "NR21"), (0xFF17, "NR22"), (0xFF18, "NR23"), (0xFF19, "NR24"),
+  (0xFF1A, "NR30"), (0xFF1B, "NR31"), (0xFF1C, "NR32"), (0xFF1D, "NR33"), (0xFF1E, "NR34"),
+  (0xFF20, "NR41"), (0xFF21, "NR42"), (0xFF22, "NR43"), (0xFF23, "NR44"),
+  (0xFF24, "NR50"), (0xFF25, "NR51"), (0xFF26, "NR52"),
+  (0xFF40, "LCDC"), (0xFF41, "STAT"), (0xFF42, "SCY"), (0xFF43, "SCX"), (0xFF44, "LY"), (0xFF45, "LYC"),
+  (0xFF46, "DMA"), (0xFF47, "BGP"), (0xFF48, "OBP0"), (0xFF49, "OBP1"), (0xFF4A, "WY"), (0xFF4B, "WX"),
+  (0xFFFF, "IE"),
+];
+
+const PREFIX_CB: u8 = 0xCB;
+const STOP: u8 = 0x10;
+
+/// An operand of a decoded instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+  /// A register or register indirect operand, e.g. `A`, `(HL+)` or `(C)`
+  Register(&'static str),
+  /// A branch condition
+  Condition(&'static str),
+  /// The bit index of a `BIT`, `RES` or `SET`
+  Bit(u8),
+  /// The target of a `RST`
+  Vector(u8),
+  Immediate8(u8),
+  Immediate16(u16),
+  SignedImmediate(i8),
+  /// The target of an absolute `JP` or `CALL`
+  Address(u16),
+  /// An absolute memory operand, `(a16)`
+  IndirectAddress(u16),
+  /// A memory operand in the 0xFF00 page, `(a8)`
+  HighAddress(u8),
+  /// The target of a `JR`
+  Relative { offset: i8, target: u16 },
+  /// `SP+e8`
+  StackOffset(i8),
+}
+
+/// A decoded instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+  /// Address the instruction was decoded from
+  pub address: u16,
+  /// The opcode, or the byte following the prefix for 0xCB prefixed instructions
+  pub opcode: u8,
+  pub prefixed: bool,
+  pub mnemonic: &'static str,
+  pub operands: Vec<Operand>,
+}
+
+impl Instruction {
+  /// Returns true if the opcode does not exist on the real CPU
+  pub fn is_illegal(&self) -> bool {
+    !self.prefixed && TEMPLATES[self.opcode as usize].is_empty()
+  }
+
+  /// Format the instruction writing numeric operands in `radix`, `Display` always uses hex
+  pub fn display(&self, radix: Radix) -> InstructionDisplay<'_> {
+    InstructionDisplay { instruction: self, radix, symbols: false }
+  }
+}
+
+/// The name of the hardware register at `address`, e.g. `LCDC` for 0xFF40
+pub fn register_name(address: u16) -> Option<&'static str> {
+  HARDWARE_REGISTERS.iter().find(|&&(x, _)| x == address).map(|&(_, name)| name)
+}
+
+/// Decode the instruction at `address`
+///
+/// # Returns
+/// the instruction and its length in bytes as `(instruction, len)`
+pub fn disassemble(address: u16, memory: &impl Memory) -> (Instruction, u16) {
+  let opcode = memory.read(address);
+  if opcode == PREFIX_CB {
+    return (disassemble_prefixed(address, memory.read(address.wrapping_add(1))), 2);
+  }
+
+  let template = TEMPLATES[opcode as usize];
+  if template.is_empty() {
+    let instruction = Instruction {
+      address,
+      opcode,
+      prefixed: false,
+      mnemonic: "DB",
+      operands: vec![Operand::Immediate8(opcode)],
+    };
+    return (instruction, 1);
+  }
+
+  let mut parts = template.splitn(2, ' ');
+  let mnemonic = parts.next().unwrap_or_default();
+  let tokens: Vec<&'static str> = parts.next().map(|x| x.split(',').collect()).unwrap_or_default();
+  let operands: Vec<_> = tokens
+    .iter()
+    .enumerate()
+    .map(|(i, token)| decode_operand(mnemonic, token, i == 0 && is_conditional(mnemonic, &tokens), address, memory))
+    .collect();
+
+  let len = if opcode == STOP {
+    // STOP is followed by an ignored byte
+    2
+  } else {
+    1 + tokens.iter().map(|x| operand_len(x)).sum::<u16>()
+  };
+
+  let instruction = Instruction {
+    address,
+    opcode,
+    prefixed: false,
+    mnemonic,
+    operands,
+  };
+  (instruction, len)
+}
+
+fn disassemble_prefixed(address: u16, opcode: u8) -> Instruction {
+  let register = Operand::Register(CB_REGISTERS[(opcode & 0b111) as usize]);
+  let n = (opcode >> 3) & 0b111;
+  let (mnemonic, operands) = match opcode >> 6 {
+    0b00 => (CB_SHIFT_MNEMONICS[n as usize], vec![register]),
+    0b01 => ("BIT", vec![Operand::Bit(n), register]),
+    0b10 => ("RES", vec![Operand::Bit(n), register]),
+    _ => ("SET", vec![Operand::Bit(n), register]),
+  };
+  Instruction {
+    address,
+    opcode,
+    prefixed: true,
+    mnemonic,
+    operands,
+  }
+}
+
+/// Returns true if the first operand of the instruction is a branch condition rather than a register
+fn is_conditional(mnemonic: &str, tokens: &[&str]) -> bool {
+  let is_branch = match mnemonic {
+    "JR" | "JP" | "CALL" => tokens.len() == 2,
+    "RET" => tokens.len() == 1,
+    _ => false,
+  };
+  is_branch && CONDITIONS.contains(&tokens[0])
+}
+
+/// Number of bytes following the opcode consumed by an operand
+fn operand_len(token: &str) -> u16 {
+  match token {
+    "d8" | "a8" | "(a8)" | "r8" | "e8" | "SP+e8" => 1,
+    "d16" | "a16" | "(a16)" => 2,
+    _ => 0,
+  }
+}
+
+fn decode_operand(mnemonic: &str, token: &'static str, is_condition: bool, address: u16, memory: &impl Memory) -> Operand {
+  let immediate8 = || memory.read(address.wrapping_add(1));
+  let immediate16 = || pack_bytes_into_double(memory.read(address.wrapping_add(2)), immediate8());
+  match token {
+    _ if is_condition => Operand::Condition(token),
+    "d8" => Operand::Immediate8(immediate8()),
+    "d16" => Operand::Immediate16(immediate16()),
+    "a16" => Operand::Address(immediate16()),
+    "(a16)" => Operand::IndirectAddress(immediate16()),
+    "(a8)" => Operand::HighAddress(immediate8()),
+    "e8" => Operand::SignedImmediate(immediate8() as i8),
+    "SP+e8" => Operand::StackOffset(immediate8() as i8),
+    "r8" => {
+      let offset = immediate8() as i8;
+      let target = address.wrapping_add(2).wrapping_add(offset as u16);
+      Operand::Relative { offset, target }
+    }
+    _ if mnemonic == "RST" => Operand::Vector(u8::from_str_radix(token.trim_end_matches('H'), 16).unwrap_or_default()),
+    _ => Operand::Register(token),
+  }
+}
+
+/// How numeric operands are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Radix {
+  /// `$`-prefixed uppercase hex, as most Game Boy assemblers write it
+  #[default]
+  Hex,
+  Decimal,
+}
+
+impl Radix {
+  /// Write `value`, padding hex to `width` digits
+  fn write(self, f: &mut fmt::Formatter, value: u16, width: usize) -> fmt::Result {
+    match self {
+      Radix::Hex => write!(f, "${:0width$X}", value, width = width),
+      Radix::Decimal => write!(f, "{}", value),
+    }
+  }
+
+  /// Write `value` after `prefix` with an explicit sign, `-` only when negative if there is no prefix
+  fn write_signed(self, f: &mut fmt::Formatter, prefix: &str, value: i8) -> fmt::Result {
+    let sign = match (value < 0, prefix.is_empty()) {
+      (true, _) => "-",
+      (false, true) => "",
+      (false, false) => "+",
+    };
+    write!(f, "{}{}", prefix, sign)?;
+    self.write(f, (value as i16).unsigned_abs(), 2)
+  }
+}
+
+/// Write the memory operand `address`, by name if `symbols` and it is a hardware register
+fn write_indirect(f: &mut fmt::Formatter, address: u16, radix: Radix, symbols: bool) -> fmt::Result {
+  write!(f, "(")?;
+  match register_name(address) {
+    Some(name) if symbols => write!(f, "{}", name)?,
+    _ => radix.write(f, address, 4)?,
+  }
+  write!(f, ")")
+}
+
+impl Operand {
+  fn write(&self, f: &mut fmt::Formatter, radix: Radix, symbols: bool) -> fmt::Result {
+    match *self {
+      Operand::Register(name) | Operand::Condition(name) => write!(f, "{}", name),
+      Operand::Bit(n) => write!(f, "{}", n),
+      Operand::Vector(value) | Operand::Immediate8(value) => radix.write(f, value as u16, 2),
+      Operand::Immediate16(value) | Operand::Address(value) => radix.write(f, value, 4),
+      Operand::IndirectAddress(address) => write_indirect(f, address, radix, symbols),
+      Operand::HighAddress(offset) => write_indirect(f, 0xFF00 | offset as u16, radix, symbols),
+      Operand::SignedImmediate(value) => radix.write_signed(f, "", value),
+      Operand::Relative { target, .. } => radix.write(f, target, 4),
+      Operand::StackOffset(offset) => radix.write_signed(f, "SP", offset),
+    }
+  }
+}
+
+impl fmt::Display for Operand {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    self.write(f, Radix::Hex, false)
+  }
+}
+
+/// An instruction formatted with a chosen `Radix`, see `Instruction::display`
+pub struct InstructionDisplay<'a> {
+  instruction: &'a Instruction,
+  radix: Radix,
+  symbols: bool,
+}
+
+impl InstructionDisplay<'_> {
+  /// Write memory operands that are hardware registers by name, e.g. `LD (LCDC),A`
+  pub fn with_symbols(self, symbols: bool) -> Self {
+    Self { symbols, ..self }
+  }
+}
+
+impl fmt::Display for InstructionDisplay<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.instruction.mnemonic)?;
+    for (i, operand) in self.instruction.operands.iter().enumerate() {
+      write!(f, "{}", if i == 0 { " " } else { "," })?;
+      operand.write(f, self.radix, self.symbols)?;
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Display for Instruction {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    self.display(Radix::Hex).fmt(f)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  struct Program(Vec<u8>);
+
+  impl Memory for Program {
+    fn read(&self, address: u16) -> u8 {
+      self.0.get(address as usize).cloned().unwrap_or(0)
+    }
+
+    fn write(&mut self, _address: u16, _value: u8) {}
+  }
+
+  fn disassemble_bytes(bytes: &[u8]) -> (String, u16) {
+    let (instruction, len) = disassemble(0, &Program(bytes.to_vec()));
+    (instruction.to_string(), len)
+  }
+
+  #[test]
+  fn immediates_are_decoded_little_endian() {
+    assert_eq!(disassemble_bytes(&[0x3E, 0xFF]), ("LD A,$FF".to_string(), 2));
+    assert_eq!(disassemble_bytes(&[0x01, 0x34, 0x12]), ("LD BC,$1234".to_string(), 3));
+    assert_eq!(disassemble_bytes(&[0xEA, 0x00, 0xC0]), ("LD ($C000),A".to_string(), 3));
+    assert_eq!(disassemble_bytes(&[0xE0, 0x40]), ("LDH ($FF40),A".to_string(), 2));
+  }
+
+  #[test]
+  fn branch_conditions_are_not_registers() {
+    assert_eq!(disassemble_bytes(&[0x38, 0xFE]), ("JR C,$0000".to_string(), 2));
+    assert_eq!(disassemble_bytes(&[0xD8]), ("RET C".to_string(), 1));
+    assert_eq!(disassemble_bytes(&[0xDC, 0x00, 0x01]), ("CALL C,$0100".to_string(), 3));
+    assert_eq!(disassemble_bytes(&[0x81]), ("ADD A,C".to_string(), 1));
+  }
+
+  #[test]
+  fn signed_operands_are_formatted_with_sign() {
+    assert_eq!(disassemble_bytes(&[0xE8, 0xFE]), ("ADD SP,-$02".to_string(), 2));
+    assert_eq!(disassemble_bytes(&[0xF8, 0x05]), ("LD HL,SP+$05".to_string(), 2));
+  }
+
+  #[test]
+  fn prefixed_instructions_are_decoded() {
+    assert_eq!(disassemble_bytes(&[0xCB, 0x7C]), ("BIT 7,H".to_string(), 2));
+    assert_eq!(disassemble_bytes(&[0xCB, 0x36]), ("SWAP (HL)".to_string(), 2));
+    assert_eq!(disassemble_bytes(&[0xCB, 0xFF]), ("SET 7,A".to_string(), 2));
+  }
+
+  #[test]
+  fn illegal_opcodes_are_data_bytes() {
+    let (instruction, len) = disassemble(0, &Program(vec![0xD3]));
+    assert!(instruction.is_illegal());
+    assert_eq!((instruction.to_string(), len), ("DB $D3".to_string(), 1));
+  }
+
+  #[test]
+  fn every_opcode_decodes() {
+    for opcode in 0..=0xFF {
+      let (_, len) = disassemble(0, &Program(vec![opcode, 0, 0]));
+      assert!((1..=3).contains(&len));
+    }
+  }
+
+  #[test]
+  fn operands_format_in_either_radix() {
+    let (instruction, _) = disassemble(0, &Program(vec![0x3E, 0xFF]));
+    assert_eq!(instruction.display(Radix::Hex).to_string(), "LD A,$FF");
+    assert_eq!(instruction.display(Radix::Decimal).to_string(), "LD A,255");
+
+    let (instruction, _) = disassemble(0, &Program(vec![0xF8, 0xFB]));
+    assert_eq!(instruction.display(Radix::Hex).to_string(), "LD HL,SP-$05");
+    assert_eq!(instruction.display(Radix::Decimal).to_string(), "LD HL,SP-5");
+
+    let (instruction, _) = disassemble(0, &Program(vec![0xE0, 0x40]));
+    assert_eq!(instruction.display(Radix::Decimal).to_string(), "LDH (65344),A");
+  }
+
+  #[test]
+  fn hardware_registers_are_named_with_symbols() {
+    let (instruction, _) = disassemble(0, &Program(vec![0xEA, 0x40, 0xFF]));
+    assert_eq!(instruction.display(Radix::Hex).with_symbols(true).to_string(), "LD (LCDC),A");
+    assert_eq!(instruction.display(Radix::Hex).to_string(), "LD ($FF40),A");
+
+    let (instruction, _) = disassemble(0, &Program(vec![0xF0, 0x44]));
+    assert_eq!(instruction.display(Radix::Hex).with_symbols(true).to_string(), "LDH A,(LY)");
+    // only memory operands are named
+    let (instruction, _) = disassemble(0, &Program(vec![0x21, 0x40, 0xFF]));
+    assert_eq!(instruction.display(Radix::Hex).with_symbols(true).to_string(), "LD HL,$FF40");
+    // addresses without a register are left as they are
+    let (instruction, _) = disassemble(0, &Program(vec![0xE0, 0x80]));
+    assert_eq!(instruction.display(Radix::Hex).with_symbols(true).to_string(), "LDH ($FF80),A");
+  }
+}
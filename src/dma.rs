@@ -0,0 +1,150 @@
+use {
+  crate::state::*,
+  std::{
+    io::{self, Read, Write},
+    ops::Range,
+  },
+};
+
+/// OAM DMA, started by writing the high byte of a source address to FF46
+///
+/// After a one machine cycle startup the transfer copies XX00-XX9F into OAM, one byte per machine
+/// cycle. While it runs the CPU is cut off from OAM and from whichever bus the source is on, see
+/// `Bus`.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Dma {
+  /// High byte of the source address, as last written to FF46
+  source: u8,
+  /// Cycles since the transfer was started, `None` once it has finished
+  elapsed: Option<u16>,
+}
+
+/// A bus the CPU shares with the DMA, accesses to the one the source is on conflict with the transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+  /// Cartridge ROM and RAM, and work RAM with its echo
+  External,
+  /// VRAM
+  Video,
+}
+
+impl Bus {
+  /// The bus `address` is on, `None` for OAM, IO and HRAM which the DMA never reads
+  pub fn of(address: u16) -> Option<Bus> {
+    match address {
+      0x8000..=0x9FFF => Some(Bus::Video),
+      0x0000..=0x7FFF | 0xA000..=0xFDFF => Some(Bus::External),
+      _ => None,
+    }
+  }
+}
+
+impl Dma {
+  pub const LENGTH: u16 = 0xA0;
+
+  const STARTUP_CYCLES: u16 = 4;
+  const BYTE_CYCLES: u16    = 4;
+
+  /// Start a new transfer from `source` << 8, cancelling any already running
+  pub fn start(&mut self, source: u8) {
+    self.source = source;
+    self.elapsed = Some(0);
+  }
+
+  /// Whether bytes are being copied, which is when the CPU is cut off from the buses
+  pub fn is_active(&self) -> bool {
+    self.elapsed.is_some_and(|elapsed| elapsed >= Self::STARTUP_CYCLES)
+  }
+
+  /// The address the next byte is read from, sources past DFFF read the work RAM echo below them
+  pub fn current_address(&self) -> u16 {
+    let offset = Self::copied(self.elapsed.unwrap_or(0)).min(Self::LENGTH - 1);
+    self.address(offset)
+  }
+
+  /// The bus the source is on
+  pub fn source_bus(&self) -> Option<Bus> {
+    Bus::of(self.current_address())
+  }
+
+  /// Where byte `offset` of the transfer is read from
+  pub fn address(&self, offset: u16) -> u16 {
+    let address = (self.source as u16) << 8 | offset;
+    if address >= 0xE000 {
+      address - 0x2000
+    } else {
+      address
+    }
+  }
+
+  /// Advance the transfer `n_cycles`
+  ///
+  /// # Returns
+  /// The offsets of the bytes to copy into OAM in that time
+  pub fn step(&mut self, n_cycles: u8) -> Range<u16> {
+    let elapsed = match self.elapsed {
+      Some(elapsed) => elapsed,
+      None => return 0..0,
+    };
+    let after = elapsed + n_cycles as u16;
+    let (start, end) = (Self::copied(elapsed), Self::copied(after));
+    self.elapsed = if end < Self::LENGTH { Some(after) } else { None };
+    start..end
+  }
+
+  /// Bytes copied after `elapsed` cycles
+  fn copied(elapsed: u16) -> u16 {
+    (elapsed.saturating_sub(Self::STARTUP_CYCLES) / Self::BYTE_CYCLES).min(Self::LENGTH)
+  }
+}
+
+impl SaveState for Dma {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    write_u8(w, self.source)?;
+    write_bool(w, self.elapsed.is_some())?;
+    write_u16(w, self.elapsed.unwrap_or(0))
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    self.source = read_u8(r)?;
+    let running = read_bool(r)?;
+    let elapsed = read_u16(r)?;
+    self.elapsed = if running { Some(elapsed) } else { None };
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn transfer_copies_a_byte_per_machine_cycle_after_startup() {
+    let mut dma = Dma::default();
+    dma.start(0xC0);
+    assert_eq!(dma.step(4), 0..0);
+    assert!(dma.is_active());
+    assert_eq!(dma.step(4), 0..1);
+    assert_eq!(dma.step(8), 1..3);
+    assert_eq!(dma.current_address(), 0xC003);
+
+    let mut copied = 3;
+    while dma.is_active() {
+      copied = dma.step(252).end;
+    }
+    assert_eq!(copied, Dma::LENGTH);
+    assert_eq!(dma.step(4), 0..0);
+  }
+
+  #[test]
+  fn echo_sources_read_work_ram() {
+    let mut dma = Dma::default();
+    dma.start(0xE1);
+    assert_eq!(dma.address(0x10), 0xC110);
+    dma.start(0xFE);
+    assert_eq!(dma.address(0x00), 0xDE00);
+    dma.start(0x80);
+    assert_eq!(dma.address(0x9F), 0x809F);
+  }
+}
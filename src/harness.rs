@@ -0,0 +1,192 @@
+//! Runs test ROMs headless and works out whether they passed, following the conventions of blargg's
+//! and Mooneye's test suites
+//!
+//! Blargg's ROMs print their results over the serial port, ending in "Passed" or "Failed". Mooneye's
+//! finish by executing `LD B,B` with B, C, D, E, H and L holding 3, 5, 8, 13, 21 and 34 if they
+//! passed, or all 0x42 if they failed, and send the same bytes over the serial port.
+
+use {
+  crate::{cartridge::{Cartridge, LoadMode}, cpu::CPU, Gameboy},
+  std::{
+    fmt,
+    fs,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+  },
+};
+
+/// About a minute of emulated time, long enough for the slowest of blargg's ROMs
+pub const DEFAULT_TIMEOUT_CYCLES: u64 = 60 * 4_194_304;
+
+const LD_B_B_OPCODE: u8          = 0x40;
+const MOONEYE_PASS: [u8; 6]      = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAIL: [u8; 6]      = [0x42; 6];
+const ROM_EXTENSIONS: [&str; 2]  = ["gb", "gbc"];
+
+/// How a test ROM finished
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+  Passed,
+  Failed,
+  /// Neither passed nor failed before the timeout
+  TimedOut,
+  /// The ROM couldn't be loaded or hit an emulation error
+  Error(String),
+}
+
+impl fmt::Display for Verdict {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Verdict::Passed => write!(f, "pass"),
+      Verdict::Failed => write!(f, "FAIL"),
+      Verdict::TimedOut => write!(f, "TIMEOUT"),
+      Verdict::Error(error) => write!(f, "ERROR {}", error),
+    }
+  }
+}
+
+/// The outcome of running a test ROM
+#[derive(Debug, Clone)]
+pub struct Report {
+  pub verdict: Verdict,
+  /// Everything the ROM sent over the serial port
+  pub serial: Vec<u8>,
+  /// Cycles run before the verdict
+  pub cycles: u64,
+}
+
+impl Report {
+  /// The serial output as text, which is how blargg's ROMs explain a failure
+  pub fn serial_text(&self) -> String {
+    String::from_utf8_lossy(&self.serial).into_owned()
+  }
+}
+
+/// Run `cartridge` from the entry point until it reports a result or `timeout_cycles` pass
+pub fn run(cartridge: Cartridge, timeout_cycles: u64) -> Report {
+  let mut gameboy = Gameboy::new_skip_bios(cartridge);
+  let serial = Arc::new(Mutex::new(vec![]));
+  let sink = serial.clone();
+  gameboy.set_serial_callback(move |byte| sink.lock().unwrap().push(byte));
+
+  let (mut cycles, mut seen) = (0, 0);
+  let verdict = loop {
+    let sent = serial.lock().unwrap().len();
+    if sent != seen {
+      seen = sent;
+      if let Some(verdict) = serial_verdict(&serial.lock().unwrap()) {
+        break verdict;
+      }
+    }
+    if cycles >= timeout_cycles {
+      break Verdict::TimedOut;
+    }
+    if !gameboy.cpu.halted && gameboy.peek(gameboy.cpu.pc) == LD_B_B_OPCODE {
+      if let Some(verdict) = register_verdict(&gameboy.cpu) {
+        break verdict;
+      }
+    }
+    match gameboy.step() {
+      Ok(n_cycles) => cycles += n_cycles as u64,
+      Err(error) => break Verdict::Error(error.to_string()),
+    }
+  };
+  let serial = serial.lock().unwrap().clone();
+  Report { verdict, serial, cycles }
+}
+
+/// Load and `run` the ROM at `path`
+pub fn run_file(path: &Path, timeout_cycles: u64) -> Report {
+  let error = |error: String| Report { verdict: Verdict::Error(error), serial: vec![], cycles: 0 };
+  match Cartridge::from_file(path, LoadMode::Strict) {
+    Ok(cartridge) => run(cartridge, timeout_cycles),
+    Err(e) => error(e.to_string()),
+  }
+}
+
+/// `run_file` every .gb and .gbc file in `dir`, in order of name
+pub fn run_dir(dir: &Path, timeout_cycles: u64) -> io::Result<Vec<(PathBuf, Report)>> {
+  let mut roms = vec![];
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    let extension = path.extension().and_then(|x| x.to_str()).map(str::to_lowercase);
+    if extension.is_some_and(|x| ROM_EXTENSIONS.contains(&x.as_str())) {
+      roms.push(path);
+    }
+  }
+  roms.sort();
+  Ok(roms.into_iter().map(|path| (path.clone(), run_file(&path, timeout_cycles))).collect())
+}
+
+/// A line per ROM with its verdict, followed by a count of those that passed
+pub fn matrix(results: &[(PathBuf, Report)]) -> String {
+  let mut out = String::new();
+  for (path, report) in results.iter() {
+    let name = path.file_name().map_or_else(|| path.display().to_string(), |x| x.to_string_lossy().into_owned());
+    out += &format!("{:<8} {} ({} cycles)\n", report.verdict.to_string(), name, report.cycles);
+  }
+  let passed = results.iter().filter(|(_, report)| report.verdict == Verdict::Passed).count();
+  out += &format!("{}/{} passed\n", passed, results.len());
+  out
+}
+
+/// The verdict in the serial output so far, if there is one yet
+fn serial_verdict(sent: &[u8]) -> Option<Verdict> {
+  let text = String::from_utf8_lossy(sent);
+  if text.contains("Passed") || sent.ends_with(&MOONEYE_PASS) {
+    Some(Verdict::Passed)
+  } else if text.contains("Failed") || sent.ends_with(&MOONEYE_FAIL) {
+    Some(Verdict::Failed)
+  } else {
+    None
+  }
+}
+
+/// The verdict a Mooneye ROM left in the registers, `None` if they hold neither pattern
+fn register_verdict(cpu: &CPU) -> Option<Verdict> {
+  let registers = [cpu.bc, cpu.de, cpu.hl].iter().flat_map(|&x| x.to_be_bytes().to_vec()).collect::<Vec<_>>();
+  if registers == MOONEYE_PASS {
+    Some(Verdict::Passed)
+  } else if registers == MOONEYE_FAIL {
+    Some(Verdict::Failed)
+  } else {
+    None
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use {super::*, crate::test_rom::RomBuilder};
+
+  /// LD B,d8 through LD L,d8 loading `values`, then LD B,B and a loop
+  fn mooneye_rom(values: [u8; 6]) -> Cartridge {
+    let mut rom = RomBuilder::new();
+    for (&opcode, &value) in [0x06, 0x0E, 0x16, 0x1E, 0x26, 0x2E].iter().zip(values.iter()) {
+      rom = rom.bytes(&[opcode, value]);
+    }
+    Cartridge::maybe_from_bytes(&rom.bytes(&[LD_B_B_OPCODE]).jr(-2).build()).unwrap()
+  }
+
+  #[test]
+  fn mooneye_registers_decide_verdict() {
+    assert_eq!(run(mooneye_rom(MOONEYE_PASS), 10_000).verdict, Verdict::Passed);
+    assert_eq!(run(mooneye_rom(MOONEYE_FAIL), 10_000).verdict, Verdict::Failed);
+  }
+
+  #[test]
+  fn rom_that_never_reports_times_out() {
+    let rom = Cartridge::maybe_from_bytes(&RomBuilder::new().bytes(&[LD_B_B_OPCODE]).jr(-3).build()).unwrap();
+    let report = run(rom, 10_000);
+    assert_eq!(report.verdict, Verdict::TimedOut);
+    assert!(report.cycles >= 10_000);
+  }
+
+  #[test]
+  fn serial_output_decides_verdict() {
+    assert_eq!(serial_verdict(b"cpu_instrs\n\nPassed all tests"), Some(Verdict::Passed));
+    assert_eq!(serial_verdict(b"01:ok 02:01\n\nFailed 1 tests"), Some(Verdict::Failed));
+    assert_eq!(serial_verdict(&[3, 5, 8, 13, 21, 34]), Some(Verdict::Passed));
+    assert_eq!(serial_verdict(b"01:ok "), None);
+  }
+}
@@ -0,0 +1,158 @@
+use {
+  crate::{state::*, util::get_bit},
+  std::io::{self, Read, Write},
+};
+
+/// How a VRAM DMA copies its blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HdmaMode {
+  /// Every block at once, as soon as HDMA5 is written
+  General,
+  /// One block at the start of each HBlank
+  HBlank,
+}
+
+/// The CGB's VRAM DMA, copying 16 byte blocks from ROM or RAM into the current VRAM bank
+///
+/// HDMA1-4 set the source and destination and writing the block count less one to HDMA5 starts the
+/// transfer, bit 7 choosing HBlank mode. The CPU isn't held off the bus while it runs.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Hdma {
+  source: u16,
+  /// Offset into VRAM
+  destination: u16,
+  /// Blocks left to copy less one, as HDMA5 reads
+  length: u8,
+  mode: Option<HdmaMode>,
+}
+
+impl Hdma {
+  pub const HDMA1_ADDRESS: u16 = 0xFF51;
+  pub const HDMA2_ADDRESS: u16 = 0xFF52;
+  pub const HDMA3_ADDRESS: u16 = 0xFF53;
+  pub const HDMA4_ADDRESS: u16 = 0xFF54;
+  pub const HDMA5_ADDRESS: u16 = 0xFF55;
+
+  pub const BLOCK_SIZE: u16 = 0x10;
+
+  const BLOCK_BITS: u16         = 0xFFF0;
+  const DESTINATION_BITS: u16   = 0x1FF0;
+  const LENGTH_BITS: u8         = 0b0111_1111;
+  const HBLANK_BIT_N: u8        = 7;
+
+  pub fn read(&self, address: u16) -> u8 {
+    match address {
+      // bit 7 reads clear while a transfer is running
+      Self::HDMA5_ADDRESS if self.mode.is_some() => self.length,
+      Self::HDMA5_ADDRESS => 0x80 | self.length,
+      _ => 0xFF,
+    }
+  }
+
+  /// Write one of HDMA1-5, a write to HDMA5 with bit 7 clear cancels an HBlank transfer in progress
+  pub fn write(&mut self, address: u16, value: u8) {
+    let (high, low) = ((value as u16) << 8, value as u16);
+    match address {
+      Self::HDMA1_ADDRESS => self.source = (self.source & 0x00FF) | high,
+      Self::HDMA2_ADDRESS => self.source = (self.source & 0xFF00) | (low & Self::BLOCK_BITS),
+      Self::HDMA3_ADDRESS => self.destination = (self.destination & 0x00FF) | (high & Self::DESTINATION_BITS),
+      Self::HDMA4_ADDRESS => self.destination = (self.destination & 0xFF00) | (low & Self::BLOCK_BITS),
+      Self::HDMA5_ADDRESS => {
+        let hblank = get_bit(value as u16, Self::HBLANK_BIT_N);
+        if self.mode == Some(HdmaMode::HBlank) && !hblank {
+          self.mode = None;
+          return;
+        }
+        self.length = value & Self::LENGTH_BITS;
+        self.mode = Some(if hblank { HdmaMode::HBlank } else { HdmaMode::General });
+      }
+      _ => {}
+    }
+  }
+
+  /// The running transfer's mode, `None` if there isn't one
+  pub fn mode(&self) -> Option<HdmaMode> {
+    self.mode
+  }
+
+  /// Take the next block of the running transfer, finishing it after the last
+  ///
+  /// # Returns
+  /// the address the block is copied from and its offset into VRAM
+  pub fn next_block(&mut self) -> Option<(u16, u16)> {
+    self.mode?;
+    let block = (self.source, self.destination);
+    self.source = self.source.wrapping_add(Self::BLOCK_SIZE);
+    self.destination = (self.destination + Self::BLOCK_SIZE) & Self::DESTINATION_BITS;
+    if self.length == 0 {
+      self.mode = None;
+    }
+    self.length = self.length.wrapping_sub(1) & Self::LENGTH_BITS;
+    Some(block)
+  }
+}
+
+impl SaveState for Hdma {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    write_u16(w, self.source)?;
+    write_u16(w, self.destination)?;
+    write_u8(w, self.length)?;
+    write_u8(w, match self.mode {
+      None => 0,
+      Some(HdmaMode::General) => 1,
+      Some(HdmaMode::HBlank) => 2,
+    })
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    self.source = read_u16(r)?;
+    self.destination = read_u16(r)?;
+    self.length = read_u8(r)?;
+    self.mode = match read_u8(r)? {
+      1 => Some(HdmaMode::General),
+      2 => Some(HdmaMode::HBlank),
+      _ => None,
+    };
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn hblank_transfer_counts_down_and_can_be_cancelled() {
+    let mut hdma = Hdma::default();
+    hdma.write(Hdma::HDMA1_ADDRESS, 0xC1);
+    hdma.write(Hdma::HDMA2_ADDRESS, 0x2F);
+    hdma.write(Hdma::HDMA3_ADDRESS, 0xE8);
+    hdma.write(Hdma::HDMA4_ADDRESS, 0x01);
+    hdma.write(Hdma::HDMA5_ADDRESS, 0x82);
+    assert_eq!(hdma.mode(), Some(HdmaMode::HBlank));
+    assert_eq!(hdma.read(Hdma::HDMA5_ADDRESS), 0x02);
+
+    // the low four bits of both addresses are ignored, and the destination is kept inside VRAM
+    assert_eq!(hdma.next_block(), Some((0xC120, 0x0800)));
+    assert_eq!(hdma.next_block(), Some((0xC130, 0x0810)));
+    assert_eq!(hdma.read(Hdma::HDMA5_ADDRESS), 0x00);
+
+    hdma.write(Hdma::HDMA5_ADDRESS, 0x00);
+    assert_eq!(hdma.mode(), None);
+    assert_eq!(hdma.read(Hdma::HDMA5_ADDRESS), 0x80);
+    assert_eq!(hdma.next_block(), None);
+  }
+
+  #[test]
+  fn finished_transfer_reads_ff() {
+    let mut hdma = Hdma::default();
+    hdma.write(Hdma::HDMA5_ADDRESS, 0x01);
+    assert_eq!(hdma.mode(), Some(HdmaMode::General));
+    assert!(hdma.next_block().is_some());
+    assert!(hdma.next_block().is_some());
+    assert_eq!(hdma.next_block(), None);
+    assert_eq!(hdma.read(Hdma::HDMA5_ADDRESS), 0xFF);
+  }
+}
@@ -0,0 +1,109 @@
+use {
+  crate::util::Memory,
+  std::cell::Cell,
+};
+
+/// Read and write counts of the CPU's memory accesses, bucketed by the high byte of the address
+///
+/// Meant for rendering a heatmap of what a game is touching. Only the CPU's accesses are counted,
+/// the PPU and other peripherals reading their registers don't show up.
+#[derive(Debug, Clone)]
+pub struct AccessHeatmap {
+  reads: [Cell<u64>; Self::BUCKETS],
+  writes: [Cell<u64>; Self::BUCKETS],
+}
+
+impl Default for AccessHeatmap {
+  fn default() -> Self {
+    Self {
+      reads: [(); Self::BUCKETS].map(|_| Cell::new(0)),
+      writes: [(); Self::BUCKETS].map(|_| Cell::new(0)),
+    }
+  }
+}
+
+impl AccessHeatmap {
+  /// One bucket per 256 byte page
+  pub const BUCKETS: usize = 0x100;
+
+  /// `(reads, writes)` of each page
+  pub fn counts(&self) -> [(u64, u64); Self::BUCKETS] {
+    let mut counts = [(0, 0); Self::BUCKETS];
+    for (count, (reads, writes)) in counts.iter_mut().zip(self.reads.iter().zip(self.writes.iter())) {
+      *count = (reads.get(), writes.get());
+    }
+    counts
+  }
+
+  /// Zero every count
+  pub fn clear(&mut self) {
+    *self = Self::default();
+  }
+
+  fn bump(counts: &[Cell<u64>; Self::BUCKETS], address: u16) {
+    let count = &counts[(address >> 8) as usize];
+    count.set(count.get().wrapping_add(1));
+  }
+}
+
+/// `memory` with each access tallied in `heatmap`
+pub(crate) struct Counted<'a, M> {
+  pub memory: &'a mut M,
+  pub heatmap: &'a AccessHeatmap,
+}
+
+impl<M: Memory> Memory for Counted<'_, M> {
+  fn read(&self, address: u16) -> u8 {
+    AccessHeatmap::bump(&self.heatmap.reads, address);
+    self.memory.read(address)
+  }
+
+  fn write(&mut self, address: u16, value: u8) {
+    AccessHeatmap::bump(&self.heatmap.writes, address);
+    self.memory.write(address, value)
+  }
+
+  fn read_internal(&self, address: u16) -> u8 {
+    self.memory.read_internal(address)
+  }
+
+  fn write_internal(&mut self, address: u16, value: u8) {
+    self.memory.write_internal(address, value)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use {
+    super::*,
+    crate::util::FlatMemory,
+  };
+
+  #[test]
+  fn reads_from_one_page_only_count_there() {
+    let mut memory = FlatMemory::default();
+    let heatmap = AccessHeatmap::default();
+    let counted = Counted { memory: &mut memory, heatmap: &heatmap };
+    for address in 0xC000..0xC100 {
+      counted.read(address);
+    }
+
+    let counts = heatmap.counts();
+    assert_eq!(counts[0xC0], (0x100, 0));
+    let mut elsewhere = counts.iter().enumerate().filter(|&(page, _)| page != 0xC0);
+    assert!(elsewhere.all(|(_, &count)| count == (0, 0)));
+  }
+
+  #[test]
+  fn writes_are_counted_separately() {
+    let mut memory = FlatMemory::default();
+    let mut heatmap = AccessHeatmap::default();
+    let mut counted = Counted { memory: &mut memory, heatmap: &heatmap };
+    counted.write(0x8010, 0x42);
+    assert_eq!(counted.read(0x8010), 0x42);
+    assert_eq!(heatmap.counts()[0x80], (1, 1));
+
+    heatmap.clear();
+    assert_eq!(heatmap.counts()[0x80], (0, 0));
+  }
+}
@@ -0,0 +1,185 @@
+use {
+  crate::{
+    peripheral::Peripheral,
+    state::*,
+    util::Memory,
+  },
+  derivative::Derivative,
+  std::{
+    io::{self, Read, Write},
+    sync::{
+      atomic::{AtomicBool, Ordering},
+      Arc,
+      Mutex,
+    },
+  },
+};
+
+/// Whatever faces the infrared LED and sensor, such as another machine's port
+pub trait IrTransceiver {
+  /// Called whenever the LED is switched on or off
+  fn set_led(&mut self, on: bool);
+
+  /// Whether light is falling on the sensor
+  fn receiving(&mut self) -> bool;
+}
+
+/// One end of an infrared link between two machines, see `ir_link`
+///
+/// The sensor sees light while the other end has its LED on. Like the real thing it only works if
+/// both machines run in step, e.g. as `LinkedGameboys`.
+#[derive(Debug, Clone)]
+pub struct IrEndpoint {
+  led: Arc<AtomicBool>,
+  other_led: Arc<AtomicBool>,
+}
+
+impl IrTransceiver for IrEndpoint {
+  fn set_led(&mut self, on: bool) {
+    self.led.store(on, Ordering::SeqCst);
+  }
+
+  fn receiving(&mut self) -> bool {
+    self.other_led.load(Ordering::SeqCst)
+  }
+}
+
+/// Two ends of an infrared link pointing at each other, to give to two machines
+pub fn ir_link() -> (IrEndpoint, IrEndpoint) {
+  let (left, right) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)));
+  (
+    IrEndpoint { led: left.clone(), other_led: right.clone() },
+    IrEndpoint { led: right, other_led: left },
+  )
+}
+
+/// The CGB infrared port (RP), and the port of a HuC1 cartridge which shares its transceiver
+#[derive(Derivative, Clone, Default)]
+#[derivative(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Infrared {
+  /// The LED and read enable bits
+  rp: u8,
+  /// The LED of a HuC1 cartridge
+  cartridge_led: bool,
+  #[derivative(Debug = "ignore")]
+  #[cfg_attr(feature = "serde", serde(skip))]
+  transceiver: Option<Arc<Mutex<dyn IrTransceiver + Send>>>,
+}
+
+impl Infrared {
+  pub const RP_ADDRESS: u16 = 0xFF56;
+
+  const RP_LED_BIT: u8          = 0b0000_0001;
+  /// Reads 0 while light is seen
+  const RP_RECEIVING_BIT: u8    = 0b0000_0010;
+  /// Both set to read the sensor, otherwise it reads as no light
+  const RP_READ_ENABLE_BITS: u8 = 0b1100_0000;
+  const RP_UNUSED_BITS: u8      = 0b0011_1100;
+  /// A HuC1 port reads this, with bit 0 set while light is seen
+  const CARTRIDGE_READ_VALUE: u8 = 0xC0;
+
+  /// Point the port at `transceiver` in place of whatever was there
+  pub fn set_transceiver(&mut self, transceiver: impl IrTransceiver + Send + 'static) {
+    let transceiver = Arc::new(Mutex::new(transceiver));
+    transceiver.lock().unwrap().set_led(self.led());
+    self.transceiver = Some(transceiver);
+  }
+
+  /// Take the transceiver away, the sensor then sees no light
+  pub fn clear_transceiver(&mut self) {
+    self.transceiver = None;
+  }
+
+  /// Whether the CGB's or the cartridge's LED is on
+  pub fn led(&self) -> bool {
+    self.rp & Self::RP_LED_BIT != 0 || self.cartridge_led
+  }
+
+  fn receiving(&self) -> bool {
+    self.transceiver.as_ref().is_some_and(|transceiver| transceiver.lock().unwrap().receiving())
+  }
+
+  fn update_led(&mut self) {
+    let led = self.led();
+    if let Some(transceiver) = self.transceiver.as_ref() {
+      transceiver.lock().unwrap().set_led(led);
+    }
+  }
+
+  /// Read the HuC1 cartridge's port, mapped at A000-BFFF in infrared mode
+  pub(crate) fn read_cartridge(&self) -> u8 {
+    Self::CARTRIDGE_READ_VALUE | self.receiving() as u8
+  }
+
+  /// Write the HuC1 cartridge's port, bit 0 switching its LED
+  pub(crate) fn write_cartridge(&mut self, value: u8) {
+    self.cartridge_led = value & 1 != 0;
+    self.update_led();
+  }
+}
+
+impl Memory for Infrared {
+  fn read(&self, address: u16) -> u8 {
+    debug_assert_eq!(address, Self::RP_ADDRESS);
+    let reading = self.rp & Self::RP_READ_ENABLE_BITS == Self::RP_READ_ENABLE_BITS;
+    let dark = if reading && self.receiving() { 0 } else { Self::RP_RECEIVING_BIT };
+    self.rp | Self::RP_UNUSED_BITS | dark
+  }
+
+  fn write(&mut self, address: u16, value: u8) {
+    debug_assert_eq!(address, Self::RP_ADDRESS);
+    self.rp = value & (Self::RP_LED_BIT | Self::RP_READ_ENABLE_BITS);
+    self.update_led();
+  }
+}
+
+impl Peripheral for Infrared {
+  fn claims(&self, address: u16) -> bool {
+    address == Self::RP_ADDRESS
+  }
+}
+
+impl SaveState for Infrared {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    write_u8(w, self.rp)?;
+    write_bool(w, self.cartridge_led)
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    self.rp = read_u8(r)?;
+    self.cartridge_led = read_bool(r)?;
+    self.update_led();
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn linked_ports_see_each_others_led() {
+    let (left_end, right_end) = ir_link();
+    let (mut left, mut right) = (Infrared::default(), Infrared::default());
+    left.set_transceiver(left_end);
+    right.set_transceiver(right_end);
+
+    right.write(Infrared::RP_ADDRESS, 0xC0);
+    assert_eq!(right.read(Infrared::RP_ADDRESS), 0xFE);
+    left.write(Infrared::RP_ADDRESS, 0x01);
+    assert_eq!(right.read(Infrared::RP_ADDRESS), 0xFC);
+    // not while reading is disabled
+    right.write(Infrared::RP_ADDRESS, 0x00);
+    assert_eq!(right.read(Infrared::RP_ADDRESS), 0x3E);
+    // nor by the LED's own sensor
+    assert_eq!(left.read(Infrared::RP_ADDRESS) & Infrared::RP_RECEIVING_BIT, Infrared::RP_RECEIVING_BIT);
+
+    // a HuC1 cartridge's port shares the transceiver
+    assert_eq!(right.read_cartridge(), 0xC1);
+    left.write(Infrared::RP_ADDRESS, 0x00);
+    assert_eq!(right.read_cartridge(), 0xC0);
+    right.write_cartridge(0x01);
+    assert_eq!(left.read_cartridge(), 0xC1);
+  }
+}
@@ -0,0 +1,275 @@
+use {
+  crate::{cpu::CpuError, joypad::Button, state::*, Gameboy},
+  failure::Fail,
+  std::io::{self, Read, Write},
+};
+
+/// Identifies a byte stream as a movie
+pub const MAGIC: [u8; 4] = *b"GBMV";
+/// Bumped whenever the layout of a movie changes
+pub const VERSION: u16 = 1;
+
+#[derive(Debug, Fail)]
+pub enum MovieError {
+  #[fail(display = "not a movie")]
+  BadMagic,
+  #[fail(display = "unsupported movie version {}", _0)]
+  UnsupportedVersion(u16),
+  #[fail(display = "movie is truncated")]
+  Truncated,
+  #[fail(display = "{}", _0)]
+  Io(#[cause] io::Error),
+}
+
+impl From<io::Error> for MovieError {
+  fn from(error: io::Error) -> Self {
+    match error.kind() {
+      io::ErrorKind::UnexpectedEof => MovieError::Truncated,
+      _ => MovieError::Io(error),
+    }
+  }
+}
+
+/// The buttons held on each frame from a save state, replayed to get the same frames back
+///
+/// A frame is one call to `Gameboy::run_frame`, and the buttons are a byte with the bit of each
+/// held `Button` set. Since the machine is deterministic, the starting state and the buttons are
+/// enough to reproduce a whole play session, which also makes a movie a regression test for a game.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+  /// Save state the first frame runs from
+  start: Vec<u8>,
+  frames: Vec<u8>,
+  /// Times recording went back to an earlier frame, see `rerecord_from`
+  rerecords: u32,
+}
+
+/// A point in a movie to go back to and record again from, see `Movie::checkpoint`
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+  frame: usize,
+  state: Vec<u8>,
+}
+
+impl Checkpoint {
+  /// Frames of the movie recorded before the checkpoint
+  pub fn frame(&self) -> usize {
+    self.frame
+  }
+}
+
+impl Movie {
+  /// Start an empty movie from the current state of `gameboy`
+  pub fn new(gameboy: &Gameboy) -> Self {
+    Self { start: gameboy.save_state(), frames: vec![], rerecords: 0 }
+  }
+
+  /// Number of frames recorded
+  pub fn len(&self) -> usize {
+    self.frames.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.frames.is_empty()
+  }
+
+  /// The buttons held on `frame`, a bit per `Button`
+  pub fn buttons(&self, frame: usize) -> Option<u8> {
+    self.frames.get(frame).copied()
+  }
+
+  pub fn rerecords(&self) -> u32 {
+    self.rerecords
+  }
+
+  /// Hold `buttons`, release the rest, and run a frame of `gameboy`, adding it to the movie
+  pub fn record_frame(&mut self, gameboy: &mut Gameboy, buttons: &[Button]) -> Result<(), CpuError> {
+    let held = buttons.iter().fold(0, |held, button| held | button.mask());
+    play(gameboy, held)?;
+    self.frames.push(held);
+    Ok(())
+  }
+
+  /// The state of `gameboy` after the frames recorded so far, to `rerecord_from` later
+  pub fn checkpoint(&self, gameboy: &Gameboy) -> Checkpoint {
+    Checkpoint { frame: self.frames.len(), state: gameboy.save_state() }
+  }
+
+  /// Load `checkpoint` into `gameboy` and drop the frames recorded after it, to record them again
+  pub fn rerecord_from(&mut self, gameboy: &mut Gameboy, checkpoint: &Checkpoint) -> Result<(), StateError> {
+    gameboy.load_state(&checkpoint.state)?;
+    self.frames.truncate(checkpoint.frame);
+    self.rerecords += 1;
+    Ok(())
+  }
+
+  /// Load the state the movie starts from into `gameboy`, then play it back frame by frame
+  pub fn play<'a>(&'a self, gameboy: &mut Gameboy) -> Result<Player<'a>, StateError> {
+    gameboy.load_state(&self.start)?;
+    Ok(Player { movie: self, frame: 0 })
+  }
+
+  /// Write the movie with the buttons run length encoded
+  pub fn write_to(&self, mut w: impl Write) -> io::Result<()> {
+    w.write_all(&MAGIC)?;
+    write_u16(&mut w, VERSION)?;
+    write_u32(&mut w, self.rerecords)?;
+    write_u32(&mut w, self.start.len() as u32)?;
+    w.write_all(&self.start)?;
+
+    let mut runs: Vec<(u16, u8)> = vec![];
+    for &held in self.frames.iter() {
+      match runs.last_mut() {
+        Some((length, buttons)) if *buttons == held && *length < u16::MAX => *length += 1,
+        _ => runs.push((1, held)),
+      }
+    }
+    write_u32(&mut w, runs.len() as u32)?;
+    for &(length, buttons) in runs.iter() {
+      write_u16(&mut w, length)?;
+      write_u8(&mut w, buttons)?;
+    }
+    Ok(())
+  }
+
+  /// Read a movie written by `write_to`
+  pub fn read_from(mut r: impl Read) -> Result<Movie, MovieError> {
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if magic != MAGIC {
+      return Err(MovieError::BadMagic);
+    }
+    match read_u16(&mut r)? {
+      VERSION => {}
+      version => return Err(MovieError::UnsupportedVersion(version)),
+    }
+    let rerecords = read_u32(&mut r)?;
+    let mut start = vec![0; read_u32(&mut r)? as usize];
+    r.read_exact(&mut start)?;
+
+    let mut frames = vec![];
+    for _ in 0..read_u32(&mut r)? {
+      let length = read_u16(&mut r)?;
+      let buttons = read_u8(&mut r)?;
+      frames.extend(std::iter::repeat_n(buttons, length as usize));
+    }
+    Ok(Movie { start, frames, rerecords })
+  }
+}
+
+/// Plays a `Movie` back, see `Movie::play`
+#[derive(Debug)]
+pub struct Player<'a> {
+  movie: &'a Movie,
+  frame: usize,
+}
+
+impl Player<'_> {
+  /// Frames played so far
+  pub fn frame(&self) -> usize {
+    self.frame
+  }
+
+  /// Hold the next frame's buttons and run it on `gameboy`
+  ///
+  /// # Returns
+  /// false without running anything once every frame has been played
+  pub fn play_frame(&mut self, gameboy: &mut Gameboy) -> Result<bool, CpuError> {
+    let held = match self.movie.buttons(self.frame) {
+      Some(held) => held,
+      None => return Ok(false),
+    };
+    play(gameboy, held)?;
+    self.frame += 1;
+    Ok(true)
+  }
+}
+
+/// Hold the buttons set in `held`, release the rest, and run a frame
+fn play(gameboy: &mut Gameboy, held: u8) -> Result<(), CpuError> {
+  for &button in Button::ALL.iter() {
+    if held & button.mask() != 0 {
+      gameboy.press_button(button);
+    } else {
+      gameboy.release_button(button);
+    }
+  }
+  gameboy.run_frame()?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use {super::*, crate::test_rom::RomBuilder};
+
+  /// A program that keeps adding the joypad register to a running total in HL, so the state depends
+  /// on every frame's buttons
+  fn joypad_rom() -> Vec<u8> {
+    RomBuilder::new()
+      // LD A,0x20 ; LDH (0x00),A to select the directions
+      .ld_a(0x20)
+      .bytes(&[0xE0, 0x00])
+      // LDH A,(0x00) ; LD B,A ; LD A,(HL) ; ADD A,B ; LD (HL),A
+      .bytes(&[0xF0, 0x00, 0x47, 0x7E])
+      .add_a_b()
+      .bytes(&[0x77])
+      .jr(-8)
+      .build()
+  }
+
+  fn recorded_movie(gameboy: &mut Gameboy) -> Movie {
+    let mut movie = Movie::new(gameboy);
+    for frame in 0..20 {
+      let buttons: &[Button] = if frame % 3 == 0 { &[Button::Right, Button::A] } else { &[Button::Down] };
+      movie.record_frame(gameboy, buttons).unwrap();
+    }
+    movie
+  }
+
+  #[test]
+  fn playback_reproduces_recording() {
+    let mut gameboy = Gameboy::new_for_testing(&joypad_rom());
+    gameboy.cpu.hl = 0xC000;
+    let movie = recorded_movie(&mut gameboy);
+    let recorded = gameboy.save_state();
+
+    let mut bytes = vec![];
+    movie.write_to(&mut bytes).unwrap();
+    let movie = Movie::read_from(&bytes[..]).unwrap();
+    assert_eq!(movie.len(), 20);
+    assert_eq!(movie.buttons(3), Some(0b0001_0001));
+
+    let mut replayed = Gameboy::new_for_testing(&joypad_rom());
+    let mut player = movie.play(&mut replayed).unwrap();
+    while player.play_frame(&mut replayed).unwrap() {}
+    assert_eq!(player.frame(), 20);
+    assert_eq!(replayed.save_state(), recorded);
+  }
+
+  #[test]
+  fn rerecording_replaces_frames_after_checkpoint() {
+    let mut gameboy = Gameboy::new_for_testing(&joypad_rom());
+    gameboy.cpu.hl = 0xC000;
+    let mut movie = Movie::new(&gameboy);
+    movie.record_frame(&mut gameboy, &[Button::Up]).unwrap();
+    let checkpoint = movie.checkpoint(&gameboy);
+    movie.record_frame(&mut gameboy, &[Button::Left]).unwrap();
+    movie.record_frame(&mut gameboy, &[Button::Left]).unwrap();
+
+    movie.rerecord_from(&mut gameboy, &checkpoint).unwrap();
+    assert_eq!((movie.len(), movie.rerecords()), (1, 1));
+    movie.record_frame(&mut gameboy, &[]).unwrap();
+    let recorded = gameboy.save_state();
+
+    let mut replayed = Gameboy::new_for_testing(&joypad_rom());
+    let mut player = movie.play(&mut replayed).unwrap();
+    while player.play_frame(&mut replayed).unwrap() {}
+    assert_eq!(replayed.save_state(), recorded);
+  }
+
+  #[test]
+  fn bad_movies_are_rejected() {
+    assert!(matches!(Movie::read_from(&b"GBST"[..]), Err(MovieError::BadMagic)));
+    assert!(matches!(Movie::read_from(&b"GBMV\x01\x00\x00"[..]), Err(MovieError::Truncated)));
+  }
+}
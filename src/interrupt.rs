@@ -0,0 +1,39 @@
+/// A source of interrupt requests, ordered from highest to lowest priority
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+  VBlank = 0,
+  LcdStat = 1,
+  Timer = 2,
+  Serial = 3,
+  Joypad = 4,
+}
+
+impl Interrupt {
+  /// Every interrupt, from highest to lowest priority
+  pub const ALL: [Interrupt; 5] = [
+    Interrupt::VBlank,
+    Interrupt::LcdStat,
+    Interrupt::Timer,
+    Interrupt::Serial,
+    Interrupt::Joypad,
+  ];
+
+  /// The bit corresponding to this interrupt in the IF and IE registers
+  pub fn mask(self) -> u8 {
+    1 << self as u8
+  }
+}
+
+/// A set of interrupts, using the bit layout of the IF and IE registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InterruptSet(pub u8);
+
+impl InterruptSet {
+  pub fn contains(self, interrupt: Interrupt) -> bool {
+    self.0 & interrupt.mask() != 0
+  }
+
+  pub fn is_empty(self) -> bool {
+    self.0 & 0x1F == 0
+  }
+}
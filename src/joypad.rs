@@ -0,0 +1,159 @@
+use {
+  crate::{
+    peripheral::Peripheral,
+    state::*,
+    util::*,
+  },
+  std::io::{self, Read, Write},
+};
+
+/// A button on the gameboy, in the order of the bits the joypad reports them in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+  Right = 0,
+  Left = 1,
+  Up = 2,
+  Down = 3,
+  A = 4,
+  B = 5,
+  Select = 6,
+  Start = 7,
+}
+
+impl Button {
+  /// Every button, directions first
+  pub const ALL: [Button; 8] = [
+    Button::Right,
+    Button::Left,
+    Button::Up,
+    Button::Down,
+    Button::A,
+    Button::B,
+    Button::Select,
+    Button::Start,
+  ];
+
+  pub(crate) fn mask(self) -> u8 {
+    1 << self as u8
+  }
+}
+
+/// The joypad register (P1)
+///
+/// The buttons are wired in a matrix: writing 0 to bit 4 selects the directions and writing 0 to
+/// bit 5 selects the action buttons, then bits 0-3 read 0 for each selected button held down.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Joypad {
+  /// The select lines, bits 4-5 as last written
+  select: u8,
+  /// One bit per `Button`, set while it is held down
+  pressed: u8,
+}
+
+impl Joypad {
+  pub const P1_ADDRESS: u16 = 0xFF00;
+
+  const SELECT_DIRECTIONS_BIT_N: u8 = 4;
+  const SELECT_ACTIONS_BIT_N: u8    = 5;
+  const SELECT_BITS: u8             = 0b0011_0000;
+  const UNUSED_BITS: u8             = 0b1100_0000;
+  const LINE_BITS: u8               = 0b0000_1111;
+
+  /// Press or release `button`
+  ///
+  /// # Returns
+  /// true if one of the input lines fell and a joypad interrupt should be requested, which only
+  /// happens if the button's group is selected
+  pub fn set_button(&mut self, button: Button, pressed: bool) -> bool {
+    let before = self.lines();
+    self.pressed = if pressed { self.pressed | button.mask() } else { self.pressed & !button.mask() };
+    before & !self.lines() != 0
+  }
+
+  pub fn is_pressed(&self, button: Button) -> bool {
+    self.pressed & button.mask() != 0
+  }
+
+  /// Input lines P10-P13, each low while a button in a selected group is held on it
+  fn lines(&self) -> u8 {
+    let mut held = 0;
+    if !get_bit(self.select as u16, Self::SELECT_DIRECTIONS_BIT_N) {
+      held |= self.pressed & Self::LINE_BITS;
+    }
+    if !get_bit(self.select as u16, Self::SELECT_ACTIONS_BIT_N) {
+      held |= self.pressed >> 4;
+    }
+    !held & Self::LINE_BITS
+  }
+}
+
+impl Memory for Joypad {
+  fn read(&self, address: u16) -> u8 {
+    match address {
+      Self::P1_ADDRESS => Self::UNUSED_BITS | self.select | self.lines(),
+      _ => unreachable!("address '0x{:x}' is not a joypad register", address),
+    }
+  }
+
+  fn write(&mut self, address: u16, value: u8) {
+    match address {
+      // the lines are read only
+      Self::P1_ADDRESS => self.select = value & Self::SELECT_BITS,
+      _ => unreachable!("address '0x{:x}' is not a joypad register", address),
+    }
+  }
+}
+
+/// Button presses request their interrupt through `MMU::set_button` rather than `tick`
+impl Peripheral for Joypad {
+  fn claims(&self, address: u16) -> bool {
+    address == Self::P1_ADDRESS
+  }
+}
+
+impl SaveState for Joypad {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(&[self.select, self.pressed])
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    self.select = read_u8(r)?;
+    self.pressed = read_u8(r)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn selected_group_reads_low() {
+    let mut joypad = Joypad::default();
+    joypad.set_button(Button::Down, true);
+    joypad.set_button(Button::A, true);
+
+    // directions
+    joypad.write(Joypad::P1_ADDRESS, 0x20);
+    assert_eq!(joypad.read(Joypad::P1_ADDRESS), 0xE7);
+    // actions
+    joypad.write(Joypad::P1_ADDRESS, 0x10);
+    assert_eq!(joypad.read(Joypad::P1_ADDRESS), 0xDE);
+    // neither
+    joypad.write(Joypad::P1_ADDRESS, 0x3F);
+    assert_eq!(joypad.read(Joypad::P1_ADDRESS), 0xFF);
+  }
+
+  #[test]
+  fn only_selected_presses_interrupt() {
+    let mut joypad = Joypad::default();
+    joypad.write(Joypad::P1_ADDRESS, 0x20);
+    assert!(!joypad.set_button(Button::Start, true));
+    assert!(joypad.set_button(Button::Up, true));
+    // already low
+    assert!(!joypad.set_button(Button::Up, true));
+    assert!(!joypad.set_button(Button::Up, false));
+    assert!(joypad.is_pressed(Button::Start));
+  }
+}
@@ -1,25 +1,178 @@
+// failure_derive emits its impls inside an anonymous const
+#![allow(non_local_definitions)]
+
+pub mod apu;
 pub mod cpu;
 pub mod mmu;
 pub mod ppu;
+pub mod camera;
 pub mod cartridge;
+pub mod cheats;
+pub mod dma;
+pub mod hdma;
+pub mod infrared;
+pub mod input_movie;
+pub mod interrupt;
+pub mod joypad;
+pub mod model;
+pub mod palette;
+pub mod timer;
+pub mod serial;
+pub mod sgb;
+pub mod disasm;
+pub mod state;
+pub mod diff;
+pub mod link;
+pub mod trace;
+pub mod harness;
+pub mod heatmap;
+pub mod pacer;
+pub mod peripheral;
+pub mod printer;
+pub mod rewind;
+pub mod rtc;
+pub mod watch;
+mod alu;
+mod bus;
 mod util;
+#[cfg(test)]
+mod test_rom;
 
 pub use {
-    cartridge::Cartridge,
+    cartridge::{Cartridge, CartridgeError, LoadMode, SaveError},
+    joypad::Button,
+    model::Model,
+    state::StateError,
+    util::{FlatMemory, Memory},
 };
 use {
-    util::*,
+    derivative::Derivative,
+    failure::Fail,
+    state::SaveState,
+    std::{
+        cell::Cell,
+        collections::HashSet,
+        io::{Read, Write},
+        ops::RangeInclusive,
+        sync::{Arc, Mutex},
+    },
 };
 
 
-#[derive(Debug, Default)]
+#[derive(Derivative, Default, Clone)]
+#[derivative(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Gameboy {
     pub mmu: mmu::MMU,
     pub cpu: cpu::CPU,
     pub ppu: ppu::PPU,
+    /// PCs that `step_or_break` stops at before executing
+    breakpoints: HashSet<u16>,
+    /// Memory accesses that `step_or_break` stops after
+    watchpoints: Vec<watch::Watchpoint>,
+    /// The first watched access made by the instruction last stepped
+    #[cfg_attr(feature = "serde", serde(skip))]
+    watch_hit: Cell<Option<watch::WatchHit>>,
+    /// Called back with the CPU's accesses, see `add_access_hook`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    access_hooks: Vec<watch::AccessHook>,
+    /// Cycles `run_frame` ran past its budget while the LCD was off, taken off the next frame's
+    frame_overshoot: u32,
+    /// Tally of the CPU's memory accesses by page
+    #[cfg_attr(feature = "serde", serde(skip))]
+    heatmap: heatmap::AccessHeatmap,
+    /// Given a snapshot before each instruction, see `set_trace_sink`
+    #[derivative(Debug = "ignore")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    trace_sink: Option<Arc<Mutex<dyn trace::TraceSink + Send>>>,
+    /// Snapshots taken as `run_frame` finishes frames, see `enable_rewind`
+    #[derivative(Debug = "ignore")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    rewind: Option<rewind::Rewind>,
+}
+
+/// What happened during a call to `Gameboy::step_or_break`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    /// An instruction was executed, taking this many cycles
+    Executed(u8),
+    /// Nothing was executed because the instruction at this PC has a breakpoint set
+    Breakpoint(u16),
+    /// An instruction was executed, taking this many cycles, and made an access a watchpoint is set on
+    Watchpoint(u8, watch::WatchHit),
+}
+
+/// Why `Gameboy::run_frame_or_break` stopped before the frame was finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The instruction at this PC has a breakpoint set and wasn't executed
+    Breakpoint(u16),
+    /// The last instruction executed made an access a watchpoint is set on
+    Watchpoint(watch::WatchHit),
+}
+
+/// Any of the errors the library reports, for a frontend to handle them all with `?`
+///
+/// Nothing a ROM does panics the emulator. Unmapped or disabled memory reads as 0xFF and ignores
+/// writes, and an illegal opcode stops `step` with `Error::Cpu` unless the CPU is set to freeze.
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "{}", _0)]
+    Cpu(#[cause] cpu::CpuError),
+    #[fail(display = "{}", _0)]
+    Cartridge(#[cause] CartridgeError),
+    #[fail(display = "{}", _0)]
+    Save(#[cause] SaveError),
+    #[fail(display = "{}", _0)]
+    State(#[cause] StateError),
+    #[fail(display = "{}", _0)]
+    Movie(#[cause] input_movie::MovieError),
+    #[fail(display = "{}", _0)]
+    Cheat(#[cause] cheats::CheatError),
+}
+
+impl From<cpu::CpuError> for Error {
+    fn from(error: cpu::CpuError) -> Self {
+        Error::Cpu(error)
+    }
+}
+
+impl From<CartridgeError> for Error {
+    fn from(error: CartridgeError) -> Self {
+        Error::Cartridge(error)
+    }
+}
+
+impl From<SaveError> for Error {
+    fn from(error: SaveError) -> Self {
+        Error::Save(error)
+    }
+}
+
+impl From<StateError> for Error {
+    fn from(error: StateError) -> Self {
+        Error::State(error)
+    }
+}
+
+impl From<input_movie::MovieError> for Error {
+    fn from(error: input_movie::MovieError) -> Self {
+        Error::Movie(error)
+    }
+}
+
+impl From<cheats::CheatError> for Error {
+    fn from(error: cheats::CheatError) -> Self {
+        Error::Cheat(error)
+    }
 }
 
 impl Gameboy {
+    /// Cycles that pass each step while the CPU is halted
+    const HALTED_STEP_CYCLES: u32 = 4;
+    /// Largest whole number of halted steps the peripherals can be ticked at once
+    const FAST_FORWARD_CHUNK_CYCLES: u8 = 252;
+
     pub fn new(bios: [u8; mmu::MMU::BIOS_SIZE]) -> Self {
         Gameboy {
             mmu: mmu::MMU {
@@ -30,28 +183,1252 @@ impl Gameboy {
         }
     }
     /// Create a new gameboy with a cartridge loaded
+    ///
+    /// Cartridges with the CGB flag set in their header run on a `Model::Cgb`, the rest on a
+    /// `Model::Dmg`. Use `with_model` to pick another.
     pub fn new_with_cartridge(cartridge: cartridge::Cartridge) -> Self {
+        let model = if cartridge.header().cgb() { Model::Cgb } else { Model::Dmg };
         Gameboy {
-            mmu: mmu::MMU {
-                cartridge: Some(cartridge),
-                ..mmu::MMU::default()
-            },
+            mmu: mmu::MMU::default().with_cartridge(cartridge).with_model(model),
             ..Gameboy::default()
         }
     }
 
+    /// Create a machine that starts `cartridge` from the entry point without a bios binary
+    ///
+    /// The registers and IO are set to the values the bios leaves them at, and the bios is disabled
+    /// through FF50 as if it had just handed over.
+    pub fn new_skip_bios(cartridge: cartridge::Cartridge) -> Self {
+        let mut gameboy = Gameboy::new_with_cartridge(cartridge);
+        gameboy.mmu.post_boot_io();
+        gameboy.cpu = cpu::CPU::post_boot(gameboy.mmu.model);
+        gameboy
+    }
+
+    /// Load `rom` and start it from the entry point like `new_skip_bios`
+    pub fn from_rom(rom: &[u8], mode: LoadMode) -> Result<Self, Error> {
+        Ok(Gameboy::new_skip_bios(cartridge::Cartridge::from_bytes(rom, mode)?))
+    }
+
+    /// Emulate `model` in place of the one chosen from the cartridge header
+    ///
+    /// Call before running anything, the registers a skipped bios left behind aren't redone.
+    pub fn with_model(self, model: Model) -> Self {
+        Gameboy {
+            mmu: self.mmu.with_model(model),
+            ..self
+        }
+    }
+
+    /// The hardware being emulated
+    pub fn model(&self) -> Model {
+        self.mmu.model
+    }
+
+    /// Create a machine running `rom` from the entry point as if the bios had just finished, for tests
+    ///
+    /// # Panics
+    /// if `rom` can't be parsed as a cartridge
+    pub fn new_for_testing(rom: &[u8]) -> Self {
+        let cartridge = cartridge::Cartridge::maybe_from_bytes(rom).expect("test rom should be a valid cartridge");
+        Gameboy::new_skip_bios(cartridge)
+    }
+
+    /// Create two machines with cartridges loaded, joined by a link cable
+    ///
+    /// Use `LinkedGameboys::step_linked` to run them together
+    pub fn with_serial_link(left: cartridge::Cartridge, right: cartridge::Cartridge) -> link::LinkedGameboys {
+        link::LinkedGameboys {
+            left: Gameboy::new_with_cartridge(left),
+            right: Gameboy::new_with_cartridge(right),
+            link: link::SerialLink::default(),
+        }
+    }
+
+    /// Read `address` as the CPU would, subject to the PPU locking VRAM and OAM
     pub fn read(&self, address: u16) -> u8 {
         self.mmu.read(address)
     }
 
+    /// Read `address` as it currently is without disturbing the machine, for debuggers and memory viewers
+    pub fn peek(&self, address: u16) -> u8 {
+        self.mmu.peek(address)
+    }
+
     /// Step the gameboy forward one instruction, returning the number of cycles the instruction took to execute
-    pub fn step(&mut self) -> u8 {
-        let n_cycles = self.cpu.step(&mut self.mmu);
+    pub fn step(&mut self) -> Result<u8, cpu::CpuError> {
+        self.watch_hit.set(None);
+        if let Some(sink) = self.trace_sink.as_ref() {
+            sink.lock().unwrap().trace(&trace::Snapshot::of(&self.cpu, &self.mmu));
+        }
+        let stopped = self.cpu.stopped;
+        let mut bus = bus::TickingBus::new(&mut self.mmu, &mut self.ppu);
+        let mut mmu = heatmap::Counted { memory: &mut bus, heatmap: &self.heatmap };
+        let n_cycles = if self.watchpoints.is_empty() && self.access_hooks.is_empty() {
+            self.cpu.step(&mut mmu)?
+        } else {
+            let mut watched = watch::Watched {
+                memory: &mut mmu,
+                watchpoints: &self.watchpoints,
+                hit: &self.watch_hit,
+                hooks: &self.access_hooks,
+            };
+            self.cpu.step(&mut watched)?
+        };
+        // the accesses have ticked their machine cycles, the rest were spent inside the CPU
+        let ticked = bus.ticked();
+        self.tick_peripherals(n_cycles.saturating_sub(ticked));
+        if self.cpu.stopped && !stopped {
+            // a STOP with a CGB speed switch armed switches speed instead
+            self.cpu.stopped = !self.mmu.stop();
+        }
+        Ok(n_cycles)
+    }
+
+    /// Step the gameboy forward one instruction like `step`, also returning the instruction that was executed
+    pub fn step_debug(&mut self) -> Result<(u8, disasm::Instruction), cpu::CpuError> {
+        // decoded outside of `step` so the disassembler's reads aren't counted in the heatmap
+        let (instruction, _) = disasm::disassemble(self.cpu.pc, &self.mmu);
+        Ok((self.step()?, instruction))
+    }
+
+    /// Step the gameboy forward one instruction like `step`, unless the instruction at PC has a breakpoint set
+    ///
+    /// Use `step` to execute the instruction under a breakpoint and carry on past it
+    pub fn step_or_break(&mut self) -> Result<StepResult, cpu::CpuError> {
+        if self.breakpoints.contains(&self.cpu.pc) {
+            return Ok(StepResult::Breakpoint(self.cpu.pc));
+        }
+        let n_cycles = self.step()?;
+        Ok(match self.watch_hit.take() {
+            Some(hit) => StepResult::Watchpoint(n_cycles, hit),
+            None => StepResult::Executed(n_cycles),
+        })
+    }
+
+    /// Stop `step_or_break` before executing the instruction at `pc`
+    pub fn set_breakpoint(&mut self, pc: u16) {
+        self.breakpoints.insert(pc);
+    }
+
+    /// Remove the breakpoint at `pc`, returning false if there wasn't one
+    pub fn clear_breakpoint(&mut self, pc: u16) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> &HashSet<u16> {
+        &self.breakpoints
+    }
+
+    /// Stop `step_or_break` after any instruction making an access `watchpoint` matches
+    pub fn set_watchpoint(&mut self, watchpoint: watch::Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    /// Remove every watchpoint on `address`, returning false if there weren't any
+    pub fn clear_watchpoints(&mut self, address: u16) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|x| x.address != address);
+        self.watchpoints.len() != before
+    }
+
+    pub fn watchpoints(&self) -> &[watch::Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Call `callback` with every access of `kind` the CPU makes to an address in `range`, to observe
+    /// memory without stopping emulation
+    ///
+    /// # Returns
+    /// the number of the hook, to `remove_access_hook` it later
+    pub fn add_access_hook(
+        &mut self,
+        range: RangeInclusive<u16>,
+        kind: watch::WatchKind,
+        callback: impl FnMut(watch::WatchHit) + Send + 'static,
+    ) -> usize {
+        self.access_hooks.push(watch::AccessHook::new(range, kind, callback));
+        self.access_hooks.len() - 1
+    }
+
+    /// Remove hook `n`, renumbering those after it, returning false if there wasn't one
+    pub fn remove_access_hook(&mut self, n: usize) -> bool {
+        if n >= self.access_hooks.len() {
+            return false;
+        }
+        self.access_hooks.remove(n);
+        true
+    }
+
+    pub fn access_hooks(&self) -> &[watch::AccessHook] {
+        &self.access_hooks
+    }
+
+    /// Apply a GameShark or Game Genie cheat, see `cheats`
+    ///
+    /// # Returns
+    /// the number of the cheat, to `remove_cheat` or `set_cheat_enabled` it later
+    pub fn add_cheat(&mut self, code: &str) -> Result<usize, cheats::CheatError> {
+        self.mmu.cheats.add(code)
+    }
+
+    /// Remove cheat `n`, renumbering those after it
+    pub fn remove_cheat(&mut self, n: usize) -> Result<cheats::Cheat, cheats::CheatError> {
+        self.mmu.cheats.remove(n)
+    }
+
+    pub fn set_cheat_enabled(&mut self, n: usize, enabled: bool) -> Result<(), cheats::CheatError> {
+        self.mmu.cheats.set_enabled(n, enabled)
+    }
+
+    pub fn cheats(&self) -> &[cheats::Cheat] {
+        self.mmu.cheats.list()
+    }
+
+    /// Step the gameboy until at least `n_cycles` have passed
+    ///
+    /// While the CPU is halted the peripherals are fast forwarded straight to the next interrupt that
+    /// would wake it, rather than stepping through the halt.
+    ///
+    /// # Returns
+    /// the number of cycles that actually passed, which overshoots `n_cycles` by at most one instruction
+    pub fn run_cycles(&mut self, n_cycles: u32) -> Result<u32, cpu::CpuError> {
+        Ok(self.run_cycles_or_break(n_cycles, false)?.0)
+    }
+
+    /// `run_cycles`, stopping early at breakpoints and watchpoints like `step_or_break` if `breaks` is set
+    fn run_cycles_or_break(&mut self, n_cycles: u32, breaks: bool) -> Result<(u32, Option<StopReason>), cpu::CpuError> {
+        let mut elapsed = 0;
+        while elapsed < n_cycles {
+            let halted_for = self.halted_cycles(n_cycles - elapsed);
+            if halted_for > 0 {
+                self.fast_forward(halted_for);
+                elapsed += halted_for;
+            } else if !breaks {
+                elapsed += self.step()? as u32;
+            } else {
+                match self.step_or_break()? {
+                    StepResult::Executed(n) => elapsed += n as u32,
+                    StepResult::Breakpoint(pc) => return Ok((elapsed, Some(StopReason::Breakpoint(pc)))),
+                    StepResult::Watchpoint(n, hit) => {
+                        return Ok((elapsed + n as u32, Some(StopReason::Watchpoint(hit))));
+                    }
+                }
+            }
+        }
+        Ok((elapsed, None))
+    }
+
+    /// Cycles the CPU will stay halted for, up to `max`, or 0 if it isn't halted
+    ///
+    /// Rounded up to whole halted steps so the peripherals see the same cycles they would when stepping.
+    fn halted_cycles(&self, max: u32) -> u32 {
+        let pending = interrupt::InterruptSet(self.mmu.read(mmu::MMU::INTERRUPT_FLAG_REG_ADDRESS) & self.mmu.ie);
+        if !self.cpu.halted || self.cpu.locked || !pending.is_empty() {
+            return 0;
+        }
+        let cycles = self.cycles_until_wake().map_or(max, |x| x.min(max));
+        cycles.div_ceil(Self::HALTED_STEP_CYCLES) * Self::HALTED_STEP_CYCLES
+    }
+
+    /// Cycles until a peripheral next requests an interrupt enabled in IE, or an attached one any
+    /// interrupt, or `None` if none will
+    fn cycles_until_wake(&self) -> Option<u32> {
+        let enabled = interrupt::InterruptSet(self.mmu.ie);
+        let timer = self.mmu.timer.cycles_until_overflow().filter(|_| enabled.contains(interrupt::Interrupt::Timer));
+        let serial = self.mmu.serial.cycles_until_complete().filter(|_| enabled.contains(interrupt::Interrupt::Serial));
+        let attached = self.mmu.attached.iter().filter_map(|x| x.cycles_until_interrupt());
+        self.ppu.dots_until_interrupt(&self.mmu, enabled).into_iter().chain(timer).chain(serial).chain(attached).min()
+    }
+
+    /// Advance the peripherals `n_cycles` without stepping the CPU
+    fn fast_forward(&mut self, mut n_cycles: u32) {
+        while n_cycles > 0 {
+            let chunk = n_cycles.min(Self::FAST_FORWARD_CHUNK_CYCLES as u32);
+            self.tick_peripherals(chunk as u8);
+            n_cycles -= chunk;
+        }
+    }
+
+    /// Run until the PPU finishes the next frame, returning it like `take_frame`
+    ///
+    /// With the LCD off no frame is ever finished, so a frame's worth of cycles is run instead. What
+    /// that overshoots by is carried into the next call, keeping a frontend calling this once per
+    /// frame in step with the real hardware.
+    pub fn run_frame(&mut self) -> Result<&[u8], cpu::CpuError> {
+        self.run_frame_inner(false)?;
+        Ok(self.ppu.framebuffer())
+    }
+
+    /// Run like `run_frame`, stopping early at breakpoints and watchpoints like `step_or_break`
+    ///
+    /// # Returns
+    /// why it stopped early, or `None` once the frame is finished and can be read with `frame`
+    pub fn run_frame_or_break(&mut self) -> Result<Option<StopReason>, cpu::CpuError> {
+        self.run_frame_inner(true)
+    }
+
+    fn run_frame_inner(&mut self, breaks: bool) -> Result<Option<StopReason>, cpu::CpuError> {
+        // only a frame finished during this call counts
+        self.ppu.take_frame();
+        let budget = pacer::FramePacer::FRAME_CYCLES.saturating_sub(self.frame_overshoot);
+        let vblank = interrupt::InterruptSet(interrupt::Interrupt::VBlank.mask());
+        let mut elapsed = 0;
+        while !self.ppu.is_frame_ready() && elapsed < budget {
+            let until_frame = self.ppu.dots_until_interrupt(&self.mmu, vblank).unwrap_or(u32::MAX);
+            let (ran, stop) = self.run_cycles_or_break((budget - elapsed).min(until_frame).max(1), breaks)?;
+            elapsed += ran;
+            if stop.is_some() {
+                self.frame_overshoot = 0;
+                return Ok(stop);
+            }
+        }
+        self.frame_overshoot = if self.ppu.is_frame_ready() { 0 } else { elapsed - budget };
+        self.ppu.take_frame();
+        if self.rewind.as_mut().is_some_and(rewind::Rewind::frame_finished) {
+            let state = self.save_state();
+            self.rewind.as_mut().unwrap().push(state);
+        }
+        Ok(None)
+    }
+
+    /// Keep up to `capacity` snapshots to `rewind` to, taken every
+    /// `rewind::Rewind::DEFAULT_INTERVAL` frames that `run_frame` or `run_frame_or_break` finish
+    ///
+    /// Any snapshots already taken are dropped.
+    pub fn enable_rewind(&mut self, capacity: usize) {
+        self.rewind = Some(rewind::Rewind::new(capacity));
+    }
+
+    /// Take a rewind snapshot every `frames` frames, once rewind is enabled
+    pub fn set_rewind_interval(&mut self, frames: u32) {
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.set_interval(frames);
+        }
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    /// Roll back `steps` snapshots, restoring the oldest of them and discarding the newer ones
+    ///
+    /// Each call goes further back, so a "hold to rewind" button can call this with 1 every frame.
+    ///
+    /// # Returns
+    /// how many snapshots were rolled back, 0 if there were none or rewind isn't enabled
+    pub fn rewind(&mut self, steps: usize) -> Result<usize, StateError> {
+        // taken out so loading the snapshot doesn't clone the rest of them
+        let mut rewind = match self.rewind.take() {
+            Some(rewind) => rewind,
+            None => return Ok(0),
+        };
+        let result = match rewind.pop(steps) {
+            Some((state, popped)) => self.load_state(&state).map(|_| popped),
+            None => Ok(0),
+        };
+        self.rewind = Some(rewind);
+        result
+    }
+
+    /// Step the gameboy until `pred` holds or `max` instructions have executed
+    ///
+    /// `pred` is checked before every instruction, so nothing is executed if it already holds
+    ///
+    /// # Returns
+    /// true if `pred` was met within the budget
+    pub fn step_until(&mut self, max: u64, pred: impl Fn(&Gameboy) -> bool) -> Result<bool, cpu::CpuError> {
+        for _ in 0..max {
+            if pred(self) {
+                return Ok(true);
+            }
+            self.step()?;
+        }
+        Ok(pred(self))
+    }
+
+    /// Give `sink` a snapshot of the registers before every instruction, e.g. a `trace::DoctorLog`
+    pub fn set_trace_sink(&mut self, sink: impl trace::TraceSink + Send + 'static) {
+        self.trace_sink = Some(Arc::new(Mutex::new(sink)));
+    }
+
+    pub fn clear_trace_sink(&mut self) {
+        self.trace_sink = None;
+    }
+
+    /// Call `callback` with each byte sent over the serial port, how most test ROMs report their results
+    pub fn set_serial_callback(&mut self, callback: impl FnMut(u8) + Send + 'static) {
+        self.mmu.serial.set_callback(callback);
+    }
+
+    /// Plug a device into the link cable port, see `serial::SerialConnector`
+    pub fn set_serial_connector(&mut self, connector: impl serial::SerialConnector + Send + 'static) {
+        self.mmu.serial.set_connector(connector);
+    }
+
+    /// Point the infrared port at a transceiver, see `infrared::IrTransceiver`
+    pub fn set_ir_transceiver(&mut self, transceiver: impl infrared::IrTransceiver + Send + 'static) {
+        self.mmu.infrared.set_transceiver(transceiver);
+    }
+
+    /// Attach a peripheral of the frontend's to the IO registers it claims, see `peripheral::Peripheral`
+    pub fn attach_peripheral(&mut self, peripheral: impl peripheral::Peripheral + Send + 'static) {
+        self.mmu.attach_peripheral(peripheral);
+    }
+
+    /// Call `callback` with each command packet the game sends to the Super Game Boy, only sent when
+    /// the model is `Model::Sgb`
+    pub fn set_sgb_callback(&mut self, callback: impl FnMut(sgb::SgbPacket) + Send + 'static) {
+        self.mmu.sgb.set_callback(callback);
+    }
+
+    /// Advance every peripheral `n_cycles` in a fixed order, merging the interrupts they raise into IF
+    /// so they are all visible before the next instruction executes
+    fn tick_peripherals(&mut self, n_cycles: u8) {
         self.ppu.step(&mut self.mmu, n_cycles);
-        n_cycles
+        // timer, serial then sound
+        self.mmu.step(n_cycles);
+    }
+
+    /// Move up to `out.len()` interleaved stereo samples at the rate set by `set_audio_sample_rate` into
+    /// `out`, for audio libraries that pull samples from a callback
+    ///
+    /// Whatever the APU hasn't produced yet is filled with silence.
+    ///
+    /// # Returns
+    /// the number of samples the APU produced that were written
+    pub fn drain_audio(&mut self, out: &mut [f32]) -> usize {
+        self.mmu.apu.drain(out)
+    }
+
+    /// Produce `sample_rate` stereo samples per second, `apu::Apu::DEFAULT_SAMPLE_RATE` unless set
+    pub fn set_audio_sample_rate(&mut self, sample_rate: u32) {
+        self.mmu.apu.set_sample_rate(sample_rate);
+    }
+
+    /// A snapshot of sound `channel` for debugging tools and visualizers, with its recent output
+    pub fn audio_channel_state(&self, channel: apu::Channel) -> apu::ChannelState {
+        self.mmu.apu.channel_state(channel)
+    }
+
+    /// Leave sound `channel` out of the audio, or put it back
+    pub fn set_audio_channel_muted(&mut self, channel: apu::Channel, muted: bool) {
+        self.mmu.apu.set_channel_muted(channel, muted);
+    }
+
+    /// Hold `button` down until `release_button`, requesting the joypad interrupt if the game is
+    /// watching its group
+    pub fn press_button(&mut self, button: Button) {
+        self.mmu.set_button(button, true);
+    }
+
+    pub fn release_button(&mut self, button: Button) {
+        self.mmu.set_button(button, false);
+    }
+
+    /// Shades (0-3) of the framebuffer, row major, as far as the PPU has drawn it
+    ///
+    /// Unlike `take_frame` this doesn't clear the ready flag, so it can be used to peek at the screen.
+    pub fn display(&self) -> &[u8] {
+        self.ppu.framebuffer()
+    }
+
+    /// The framebuffer as colors, complete once `is_frame_ready` or `run_frame` says a frame is done
+    ///
+    /// The BGP, OBP0 and OBP1 palettes, or the CGB color palettes, have already been applied. Use `ppu::Frame::to_rgba8888` for
+    /// something a frontend can draw directly.
+    pub fn frame(&self) -> ppu::Frame<'_> {
+        self.ppu.frame()
+    }
+
+    /// Whether the LCD is on, no frames finish while it's off and `frame` is blank white
+    pub fn lcd_enabled(&self) -> bool {
+        self.ppu.lcd_enabled()
     }
 
-    pub fn display(&self) -> impl Iterator<Item=&u8> {
-        self.mmu.vram()
+    /// Returns true if a frame has completed since the last `take_frame`
+    pub fn is_frame_ready(&self) -> bool {
+        self.ppu.is_frame_ready()
+    }
+
+    /// Number of frames completed since power on, counted at the start of each VBlank
+    ///
+    /// The frame `take_frame` returns is this one, so inputs and snapshots can be indexed by it.
+    pub fn elapsed_frames(&self) -> u64 {
+        self.ppu.elapsed_frames()
+    }
+
+    /// Returns the framebuffer if a new frame has completed since the last call, otherwise `None`
+    ///
+    /// This lets a frontend poll for frames with `while let Some(frame) = gameboy.take_frame()`
+    pub fn take_frame(&mut self) -> Option<&[u8]> {
+        self.ppu.take_frame()
+    }
+
+    /// A summary of the loaded cartridge's header, or `None` if no cartridge is loaded
+    pub fn cartridge_info(&self) -> Option<cartridge::CartridgeInfo> {
+        self.mmu.cartridge.as_ref().map(Cartridge::info)
+    }
+
+    /// `(reads, writes)` the CPU has made to each 256 byte page of memory, indexed by the high byte of
+    /// the address
+    pub fn access_heatmap(&self) -> [(u64, u64); heatmap::AccessHeatmap::BUCKETS] {
+        self.heatmap.counts()
+    }
+
+    /// Start the access heatmap over from zero
+    pub fn clear_access_heatmap(&mut self) {
+        self.heatmap.clear();
+    }
+
+    /// The ROM bank mapped at 4000-7FFF, or `None` if no cartridge is loaded
+    pub fn current_rom_bank(&self) -> Option<usize> {
+        self.mmu.cartridge.as_ref().map(Cartridge::current_rom_bank)
+    }
+
+    /// Whether the game has the cartridge's rumble motor switched on, for frontends to pass on to a
+    /// controller
+    pub fn rumble(&self) -> bool {
+        self.mmu.cartridge.as_ref().map(Cartridge::rumble).unwrap_or_default()
+    }
+
+    /// Feed the Game Boy Camera images from `source`, see `Cartridge::set_camera_source`
+    pub fn set_camera_source(&mut self, source: impl camera::ImageSource + Send + Sync + 'static) -> bool {
+        self.mmu.cartridge.as_mut().map(|x| x.set_camera_source(source)).unwrap_or(false)
+    }
+
+    /// The external RAM bank mapped at A000-BFFF, or `None` if no cartridge is loaded
+    pub fn current_ram_bank(&self) -> Option<usize> {
+        self.mmu.cartridge.as_ref().map(Cartridge::current_ram_bank)
+    }
+
+    /// Pin a ROM bank at 4000-7FFF for inspection, see `Cartridge::force_rom_bank`
+    pub fn force_rom_bank(&mut self, bank: Option<usize>) -> bool {
+        self.mmu.cartridge.as_mut().map(|x| x.force_rom_bank(bank)).unwrap_or(false)
+    }
+
+    /// Pin an external RAM bank at A000-BFFF for inspection, see `Cartridge::force_ram_bank`
+    pub fn force_ram_bank(&mut self, bank: Option<usize>) -> bool {
+        self.mmu.cartridge.as_mut().map(|x| x.force_ram_bank(bank)).unwrap_or(false)
+    }
+
+    /// Restore the cartridge's battery backed RAM, typically from a save file read when the game is loaded
+    pub fn load_save_ram(&mut self, bytes: &[u8]) -> Result<(), SaveError> {
+        self.mmu.load_save_ram(bytes)
+    }
+
+    /// A copy of the cartridge's battery backed RAM to write to a save file, or `None` if there's nothing to save
+    pub fn dump_save_ram(&self) -> Option<Vec<u8>> {
+        self.mmu.dump_save_ram()
+    }
+
+    /// The cartridge's battery backed RAM to write to a save file, marking it clean
+    ///
+    /// Frontends can poll `save_ram_dirty` to only flush the save file when the game has written to it.
+    pub fn save_ram(&mut self) -> Option<&[u8]> {
+        self.mmu.save_ram()
+    }
+
+    pub fn save_ram_dirty(&self) -> bool {
+        self.mmu.save_ram_dirty()
+    }
+
+    /// Compare every memory region and register of this machine with `other`
+    pub fn diff(&self, other: &Gameboy) -> Vec<diff::MemoryDiff> {
+        diff::diff(self, other)
+    }
+
+    /// Serialize the state of the machine into a versioned save state
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut buffer = vec![];
+        self.save_state_to(&mut buffer).expect("writing to a vec cannot fail");
+        buffer
+    }
+
+    /// Restore a save state created by `save_state`
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        self.load_state_from(bytes)
+    }
+
+    /// Stream a save state into `w`, in the same format as `save_state`
+    pub fn save_state_to<W: Write>(&self, mut w: W) -> Result<(), StateError> {
+        state::write_header(&mut w)?;
+        self.cpu.write_state(&mut w)?;
+        self.mmu.write_state(&mut w)?;
+        self.ppu.write_state(&mut w)?;
+        state::write_u32(&mut w, self.frame_overshoot)?;
+        Ok(())
+    }
+
+    /// Restore a save state streamed from `r`, in the same format as `load_state`
+    ///
+    /// The machine is left untouched if the state can't be read
+    pub fn load_state_from<R: Read>(&mut self, mut r: R) -> Result<(), StateError> {
+        state::read_header(&mut r)?;
+        let mut loaded = self.clone();
+        loaded.cpu.read_state(&mut r)?;
+        loaded.mmu.read_state(&mut r)?;
+        loaded.ppu.read_state(&mut r)?;
+        loaded.frame_overshoot = state::read_u32(&mut r)?;
+        *self = loaded;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn instruction_spanning_timer_overflow_and_serial_completion_sets_both_flags() {
+        let cartridge = Cartridge::maybe_from_bytes(&[0x00; 0x8000]).unwrap(); // NOPs
+        let mut gameboy = Gameboy::new_with_cartridge(cartridge);
+        // TIMA ticks every 16 cycles and overflows after 256 ticks, the same 4096 cycles a serial
+        // transfer takes on the internal clock
+        gameboy.mmu.write(timer::Timer::TAC_ADDRESS, 0b101);
+        gameboy.mmu.write(serial::Serial::SC_ADDRESS, 0x81);
+
+        let n_instructions = 4096 / 4;
+        for _ in 0..n_instructions - 1 {
+            gameboy.step().unwrap();
+        }
+        let flags = gameboy.read(mmu::MMU::INTERRUPT_FLAG_REG_ADDRESS);
+        assert_eq!(flags & interrupt::Interrupt::Timer.mask(), 0);
+        assert_eq!(flags & interrupt::Interrupt::Serial.mask(), 0);
+
+        gameboy.step().unwrap();
+        let flags = gameboy.read(mmu::MMU::INTERRUPT_FLAG_REG_ADDRESS);
+        assert_ne!(flags & interrupt::Interrupt::Timer.mask(), 0);
+        assert_ne!(flags & interrupt::Interrupt::Serial.mask(), 0);
+    }
+
+    #[test]
+    fn save_state_round_trips_through_a_stream() {
+        let cartridge = Cartridge::maybe_from_bytes(&[0x00; 0x8000]).unwrap();
+        let mut gameboy = Gameboy::new_with_cartridge(cartridge.clone());
+        gameboy.mmu.write(timer::Timer::TAC_ADDRESS, 0b101);
+        gameboy.mmu.write(serial::Serial::SC_ADDRESS, 0x81);
+        gameboy.mmu.write(mmu::MMU::RAM_START_ADDRESS, 0x42);
+        for _ in 0..100 {
+            gameboy.step().unwrap();
+        }
+
+        let mut stream = std::io::Cursor::new(vec![]);
+        gameboy.save_state_to(&mut stream).unwrap();
+        assert_eq!(stream.get_ref(), &gameboy.save_state());
+
+        stream.set_position(0);
+        let mut restored = Gameboy::new_with_cartridge(cartridge);
+        restored.load_state_from(&mut stream).unwrap();
+        assert_eq!(restored.diff(&gameboy), vec![]);
+        assert_eq!(restored.save_state(), gameboy.save_state());
+        assert_eq!(restored.cpu.pc, gameboy.cpu.pc);
+        assert_eq!(restored.read(mmu::MMU::RAM_START_ADDRESS), 0x42);
+
+        // both machines carry on identically
+        for _ in 0..1000 {
+            gameboy.step().unwrap();
+            restored.step().unwrap();
+        }
+        assert_eq!(restored.save_state(), gameboy.save_state());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trips_the_whole_machine() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0147] = 0x13; // MBC3+RAM+BATTERY
+        rom[0x0149] = 0x02;
+        let mut gameboy = Gameboy::new_with_cartridge(Cartridge::maybe_from_bytes(&rom).unwrap());
+        gameboy.mmu.write(0x0000, 0x0A);
+        gameboy.mmu.write(0xA000, 0x42);
+        gameboy.mmu.write(mmu::MMU::RAM_START_ADDRESS, 0x24);
+        for _ in 0..100 {
+            gameboy.step().unwrap();
+        }
+
+        let json = serde_json::to_string(&gameboy).unwrap();
+        let restored: Gameboy = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.diff(&gameboy), vec![]);
+        assert_eq!(restored.save_state(), gameboy.save_state());
+        assert_eq!(restored.read(0xA000), 0x42);
+    }
+
+    #[test]
+    fn invalid_save_states_are_rejected_without_modifying_the_machine() {
+        let mut gameboy = Gameboy::default();
+        gameboy.cpu.pc = 0x1234;
+        let state = gameboy.save_state();
+
+        let mut other = Gameboy::default();
+        match other.load_state(&state[..state.len() - 1]) {
+            Err(StateError::Truncated) => {}
+            result => panic!("expected a truncated state error, got {:?}", result),
+        }
+        match other.load_state(b"nope") {
+            Err(StateError::BadMagic) => {}
+            result => panic!("expected a bad magic error, got {:?}", result),
+        }
+        assert_eq!(other.cpu.pc, 0);
+    }
+
+    #[test]
+    fn take_frame_returns_each_frame_once() {
+        let cartridge = Cartridge::maybe_from_bytes(&[0x00; 0x8000]).unwrap(); // NOPs
+        let mut gameboy = Gameboy::new_with_cartridge(cartridge);
+        gameboy.mmu.write(ppu::PPU::LCDC_ADDRESS, 0x80);
+        assert!(gameboy.take_frame().is_none());
+
+        let mut n_cycles = 0;
+        while n_cycles < 70224 {
+            n_cycles += gameboy.step().unwrap() as u32;
+        }
+        assert!(gameboy.is_frame_ready());
+        // peeking leaves the frame to be taken
+        assert_eq!(gameboy.display().len(), ppu::PPU::SCREEN_WIDTH * ppu::PPU::SCREEN_HEIGHT);
+        assert!(gameboy.is_frame_ready());
+        let frame = gameboy.take_frame().expect("a frame should be ready");
+        assert_eq!(frame.len(), ppu::PPU::SCREEN_WIDTH * ppu::PPU::SCREEN_HEIGHT);
+        assert!(gameboy.take_frame().is_none());
+        assert!(!gameboy.is_frame_ready());
+    }
+
+    #[test]
+    fn save_ram_restores_into_a_fresh_machine() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0149] = 0x02; // 8KB of RAM
+        let cartridge = Cartridge::maybe_from_bytes(&rom).unwrap();
+        let mut gameboy = Gameboy::new_with_cartridge(cartridge.clone());
+        gameboy.mmu.write(0xA000, 0x12);
+        gameboy.mmu.write(0xBFFF, 0x34);
+
+        let save = gameboy.dump_save_ram().expect("cartridge has ram");
+        let mut fresh = Gameboy::new_with_cartridge(cartridge);
+        assert_eq!(fresh.read(0xA000), 0x00);
+        fresh.load_save_ram(&save).unwrap();
+        assert_eq!(fresh.read(0xA000), 0x12);
+        assert_eq!(fresh.read(0xBFFF), 0x34);
+
+        assert!(Gameboy::default().dump_save_ram().is_none());
+        assert!(Gameboy::default().load_save_ram(&save).is_err());
+    }
+
+    #[test]
+    fn serial_callback_receives_sent_bytes() {
+        let mut rom = vec![0x00; 0x8000];
+        let program = [
+            0x3E, b'A', // LD A,'A'
+            0xE0, 0x01, // LDH ($01),A
+            0x3E, 0x81, // LD A,$81
+            0xE0, 0x02, // LDH ($02),A
+            0x18, 0xFE, // JR -2
+        ];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        let mut gameboy = Gameboy::new_with_cartridge(Cartridge::maybe_from_bytes(&rom).unwrap());
+        gameboy.mmu.write(mmu::MMU::BIOS_DISABLE_REGISTER_ADDRESS, 0x01);
+        gameboy.cpu.pc = 0x100;
+
+        let sent = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let callback_sent = sent.clone();
+        gameboy.set_serial_callback(move |x| callback_sent.lock().unwrap().push(x));
+
+        let elapsed = gameboy.run_cycles(5000).unwrap();
+        assert!((5000..5000 + 24).contains(&elapsed));
+        assert_eq!(*sent.lock().unwrap(), b"A");
+    }
+
+    #[test]
+    fn loopback_connector_receives_sent_byte() {
+        let rom = test_rom::RomBuilder::new()
+            .ld_a(0x42)
+            .bytes(&[0xE0, 0x01]) // LDH ($01),A
+            .ld_a(0x81)
+            .bytes(&[0xE0, 0x02]) // LDH ($02),A
+            .jr(-2)
+            .build();
+        let mut gameboy = Gameboy::new_for_testing(&rom);
+        gameboy.set_serial_connector(serial::Loopback);
+        gameboy.run_cycles(5000).unwrap();
+        assert_eq!(gameboy.read(serial::Serial::SB_ADDRESS), 0x42);
+    }
+
+    /// A device that clocks one byte into a waiting gameboy and records what it got back
+    struct ClockingDevice {
+        to_send: Option<u8>,
+        received: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    }
+
+    impl serial::SerialConnector for ClockingDevice {
+        fn exchange(&mut self, _sent: u8) -> u8 {
+            0xFF
+        }
+
+        fn poll(&mut self, outgoing: u8) -> Option<u8> {
+            let incoming = self.to_send.take()?;
+            self.received.lock().unwrap().push(outgoing);
+            Some(incoming)
+        }
+    }
+
+    #[test]
+    fn connector_clocks_waiting_transfer() {
+        let rom = test_rom::RomBuilder::new()
+            .ld_a(0x99)
+            .bytes(&[0xE0, 0x01]) // LDH ($01),A
+            .ld_a(0x80)
+            .bytes(&[0xE0, 0x02]) // LDH ($02),A
+            .jr(-2)
+            .build();
+        let mut gameboy = Gameboy::new_for_testing(&rom);
+        let received = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        gameboy.set_serial_connector(ClockingDevice { to_send: Some(0x42), received: received.clone() });
+        gameboy.run_cycles(100).unwrap();
+
+        assert_eq!(*received.lock().unwrap(), [0x99]);
+        assert_eq!(gameboy.read(serial::Serial::SB_ADDRESS), 0x42);
+        assert!(!gameboy.mmu.serial.is_transferring());
+        let flags = gameboy.read(mmu::MMU::INTERRUPT_FLAG_REG_ADDRESS);
+        assert_ne!(flags & interrupt::Interrupt::Serial.mask(), 0);
+    }
+
+    #[test]
+    fn step_until_stops_when_predicate_is_met() {
+        // an empty bios runs straight through to the cartridge entry point
+        let cartridge = Cartridge::maybe_from_bytes(&[0x00; 0x8000]).unwrap();
+        let at_entry_point = |gameboy: &Gameboy| gameboy.cpu.pc == 0x0100;
+
+        let mut gameboy = Gameboy::new_with_cartridge(cartridge.clone());
+        assert!(gameboy.step_until(1000, at_entry_point).unwrap());
+        assert_eq!(gameboy.cpu.pc, 0x0100);
+
+        let mut gameboy = Gameboy::new_with_cartridge(cartridge);
+        assert!(!gameboy.step_until(100, at_entry_point).unwrap());
+        assert_eq!(gameboy.cpu.pc, 100);
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn any_rom_runs_or_errors_without_panicking(program: Vec<u8>, cartridge_type: u8, rom_size: u8) -> bool {
+        let mut rom = vec![0x00; 0x8000];
+        for (byte, &x) in rom[0x100..].iter_mut().zip(program.iter().cycle()) {
+            *byte = x;
+        }
+        rom[0x147] = cartridge_type;
+        rom[0x148] = rom_size;
+        if let Ok(mut gameboy) = Gameboy::from_rom(&rom, LoadMode::Strict) {
+            let _ = gameboy.run_cycles(20_000);
+        }
+        true
+    }
+
+    /// Random ROMs rarely reach the top of the address space, so run random code placed in HRAM and
+    /// IE, from where it runs off the end into ROM
+    #[quickcheck_macros::quickcheck]
+    fn code_at_the_top_of_memory_runs_or_errors_without_panicking(program: Vec<u8>, start: u8) -> bool {
+        let mut gameboy = Gameboy::new_for_testing(&test_rom::RomBuilder::new().build());
+        let top = mmu::MMU::HRAM_START_ADDRESS..=mmu::MMU::INTERRUPT_ENABLE_REG_ADDRESS;
+        for (address, &x) in top.clone().zip(program.iter().cycle()) {
+            gameboy.mmu.write(address, x);
+        }
+        gameboy.cpu.pc = (*top.end()).wrapping_sub(start as u16 % 8);
+        let _ = gameboy.run_cycles(1_000);
+        true
+    }
+
+    #[test]
+    fn errors_convert_to_the_library_error() {
+        fn load() -> Result<Gameboy, Error> {
+            let mut gameboy = Gameboy::from_rom(&test_rom::RomBuilder::new().build(), LoadMode::Strict)?;
+            gameboy.load_state(b"GBMV")?;
+            Ok(gameboy)
+        }
+        assert!(matches!(load(), Err(Error::State(StateError::BadMagic))));
+        let mut rom = test_rom::RomBuilder::new().build();
+        rom[0x147] = 0xEE;
+        assert!(matches!(Gameboy::from_rom(&rom, LoadMode::Strict), Err(Error::Cartridge(_))));
+    }
+
+    #[test]
+    fn new_for_testing_starts_at_entry_point() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0x00, 0x18, 0xFE]); // NOP; NOP; JR -2
+        let mut gameboy = Gameboy::new_for_testing(&rom);
+        assert_eq!(gameboy.cpu.pc, 0x0100);
+        assert_eq!(gameboy.cpu.sp, 0xFFFE);
+
+        let pcs: Vec<_> = (0..5).map(|_| {
+            gameboy.step().unwrap();
+            gameboy.cpu.pc
+        }).collect();
+        assert_eq!(pcs, [0x0101, 0x0102, 0x0102, 0x0102, 0x0102]);
+    }
+
+    #[test]
+    fn cgb_flag_in_header_selects_cgb_model() {
+        let mut rom = test_rom::RomBuilder::new().build();
+        assert_eq!(Gameboy::new_for_testing(&rom).model(), Model::Dmg);
+
+        rom[0x0143] = 0x80;
+        let gameboy = Gameboy::new_for_testing(&rom);
+        assert_eq!(gameboy.model(), Model::Cgb);
+        // games check for the value the CGB bios leaves in A
+        assert_eq!(gameboy.cpu.af >> 8, 0x11);
+    }
+
+    #[test]
+    fn stop_switches_speed_on_a_cgb_with_the_switch_armed() {
+        // STOP; NOP
+        let mut rom = test_rom::RomBuilder::new().bytes(&[0x10, 0x00]).nop().build();
+        // arming a speed switch does nothing on DMG
+        let mut gameboy = Gameboy::new_for_testing(&rom);
+        gameboy.mmu.write(mmu::MMU::KEY1_ADDRESS, 0x01);
+        gameboy.step().unwrap();
+        assert!(gameboy.cpu.stopped);
+        assert!(!gameboy.mmu.double_speed());
+
+        rom[0x0143] = 0x80;
+        // with and without the CPU's accesses being watched
+        for hooked in [false, true] {
+            let mut gameboy = Gameboy::new_for_testing(&rom);
+            if hooked {
+                gameboy.add_access_hook(0x0000..=0xFFFF, watch::WatchKind::ReadWrite, |_| {});
+            }
+            gameboy.mmu.write(mmu::MMU::KEY1_ADDRESS, 0x01);
+            gameboy.step().unwrap();
+            assert!(!gameboy.cpu.stopped);
+            assert!(gameboy.mmu.double_speed());
+            // the switch disarms itself
+            assert_eq!(gameboy.mmu.read(mmu::MMU::KEY1_ADDRESS), 0xFE);
+            gameboy.step().unwrap();
+            assert_eq!(gameboy.cpu.pc, 0x0103);
+
+            // without the switch armed a CGB stops like a DMG
+            gameboy.cpu.pc = 0x0100;
+            gameboy.step().unwrap();
+            assert!(gameboy.cpu.stopped);
+            assert!(gameboy.mmu.double_speed());
+        }
+    }
+
+    #[test]
+    fn skip_bios_starts_with_post_boot_state() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0000] = 0x5A;
+        let gameboy = Gameboy::new_skip_bios(cartridge::Cartridge::maybe_from_bytes(&rom).unwrap());
+        assert_eq!((gameboy.cpu.af, gameboy.cpu.bc, gameboy.cpu.de, gameboy.cpu.hl), (0x01B0, 0x0013, 0x00D8, 0x014D));
+        assert_eq!((gameboy.cpu.sp, gameboy.cpu.pc), (0xFFFE, 0x0100));
+        // the cartridge is visible below 0x100 with no bios loaded
+        assert_eq!(gameboy.read(0x0000), 0x5A);
+        assert_eq!(gameboy.read(ppu::PPU::LCDC_ADDRESS), 0x91);
+    }
+
+    #[test]
+    fn step_or_break_stops_at_breakpoint() {
+        let mut gameboy = Gameboy::new_for_testing(&[0x00; 0x8000]);
+        gameboy.set_breakpoint(0x0102);
+        assert!(gameboy.breakpoints().contains(&0x0102));
+
+        assert_eq!(gameboy.step_or_break().unwrap(), StepResult::Executed(4));
+        assert_eq!(gameboy.step_or_break().unwrap(), StepResult::Executed(4));
+        assert_eq!(gameboy.step_or_break().unwrap(), StepResult::Breakpoint(0x0102));
+        assert_eq!(gameboy.step_or_break().unwrap(), StepResult::Breakpoint(0x0102));
+        assert_eq!(gameboy.cpu.pc, 0x0102);
+
+        // stepping carries on past it
+        gameboy.step().unwrap();
+        assert_eq!(gameboy.cpu.pc, 0x0103);
+
+        assert!(gameboy.clear_breakpoint(0x0102));
+        assert!(!gameboy.clear_breakpoint(0x0102));
+        assert!(gameboy.breakpoints().is_empty());
+    }
+
+    #[test]
+    fn cheats_patch_rom_reads_and_write_ram_at_vblank() {
+        // LD A,1; LD HL,0xC000; LD (HL+),A; JR -2
+        let rom = test_rom::RomBuilder::new().ld_a(1).ld_hl(0xC000).ld_hli_a().jr(-2).build();
+        let mut gameboy = Gameboy::new_for_testing(&rom);
+        // the LD A operand at 0x0101 reads as 7, and 0x42 is written to 0xC100
+        gameboy.add_cheat("071-01F").unwrap();
+        let shark = gameboy.add_cheat("014200C1").unwrap();
+        assert!(gameboy.add_cheat("not a code").is_err());
+        assert_eq!(gameboy.cheats().len(), 2);
+
+        gameboy.run_frame().unwrap();
+        assert_eq!(gameboy.peek(0x0101), 7);
+        assert_eq!(gameboy.peek(0xC000), 7);
+        assert_eq!(gameboy.peek(0xC100), 0x42);
+
+        gameboy.set_cheat_enabled(shark, false).unwrap();
+        gameboy.mmu.write(0xC100, 0);
+        gameboy.run_frame().unwrap();
+        assert_eq!(gameboy.peek(0xC100), 0);
+        gameboy.remove_cheat(0).unwrap();
+        assert_eq!(gameboy.peek(0x0101), 1);
+    }
+
+    #[test]
+    fn access_hooks_observe_without_stopping() {
+        // LD HL,0xC000; LD A,1; LD (HL+),A; LD A,2; LD (HL+),A; JR -2
+        let rom = test_rom::RomBuilder::new().ld_hl(0xC000).ld_a(1).ld_hli_a().ld_a(2).ld_hli_a().jr(-2).build();
+        let mut gameboy = Gameboy::new_for_testing(&rom);
+        let seen = Arc::new(Mutex::new(vec![]));
+        let sink = seen.clone();
+        let n = gameboy.add_access_hook(0xC000..=0xC001, watch::WatchKind::Write, move |x| sink.lock().unwrap().push(x));
+
+        for _ in 0..6 {
+            assert!(matches!(gameboy.step_or_break().unwrap(), StepResult::Executed(_)));
+        }
+        let values: Vec<_> = seen.lock().unwrap().iter().map(|x| (x.address, x.value)).collect();
+        assert_eq!(values, [(0xC000, 1), (0xC001, 2)]);
+
+        assert!(gameboy.remove_access_hook(n));
+        assert!(!gameboy.remove_access_hook(n));
+    }
+
+    #[test]
+    fn step_or_break_reports_watched_accesses() {
+        // LD HL,0xC000; LD A,1; LD (HL+),A; LD A,2; LD (HL+),A; JR -2
+        let rom = test_rom::RomBuilder::new().ld_hl(0xC000).ld_a(1).ld_hli_a().ld_a(2).ld_hli_a().jr(-2).build();
+        let mut gameboy = Gameboy::new_for_testing(&rom);
+        gameboy.set_watchpoint(watch::Watchpoint { address: 0xC001, kind: watch::WatchKind::Write, value: Some(2) });
+        gameboy.set_watchpoint(watch::Watchpoint { address: 0xC000, kind: watch::WatchKind::Read, value: None });
+
+        let hit = loop {
+            if let StepResult::Watchpoint(_, hit) = gameboy.step_or_break().unwrap() {
+                break hit;
+            }
+        };
+        let write = watch::WatchHit { address: 0xC001, access: watch::Access::Write, value: 2 };
+        assert_eq!(hit, write);
+        // stopped after the instruction that made the access
+        assert_eq!(gameboy.peek(0xC001), 2);
+
+        gameboy.set_breakpoint(gameboy.cpu.pc);
+        assert_eq!(gameboy.run_frame_or_break().unwrap(), Some(StopReason::Breakpoint(gameboy.cpu.pc)));
+        assert!(gameboy.clear_watchpoints(0xC001));
+        assert!(!gameboy.clear_watchpoints(0xC001));
+        assert_eq!(gameboy.watchpoints().len(), 1);
+    }
+
+    #[test]
+    fn run_frame_or_break_finishes_frame_without_hits() {
+        let mut gameboy = Gameboy::new_for_testing(&test_rom::RomBuilder::new().jr(-2).build());
+        gameboy.set_watchpoint(watch::Watchpoint { address: 0xC000, kind: watch::WatchKind::ReadWrite, value: None });
+        assert_eq!(gameboy.run_frame_or_break().unwrap(), None);
+        assert_eq!(gameboy.elapsed_frames(), 1);
+    }
+
+    #[test]
+    fn drain_audio_returns_samples_after_running() {
+        let mut gameboy = Gameboy::new_for_testing(&[0x00; 0x8000]);
+        let mut out = [1.0; 1024];
+        assert_eq!(gameboy.drain_audio(&mut out), 0);
+        assert!(out.iter().all(|&x| x == 0.0));
+
+        gameboy.run_cycles(10_000).unwrap();
+        let n = gameboy.drain_audio(&mut out);
+        assert!(n > 0);
+        assert_eq!(n % apu::Apu::CHANNELS, 0);
+        assert_eq!(gameboy.drain_audio(&mut out), 0);
+    }
+
+    /// Run `gameboy` for `n_cycles` with `run_cycles` and a copy of it one instruction at a time,
+    /// checking they end up identical
+    fn assert_fast_forward_matches_stepping(mut gameboy: Gameboy, n_cycles: u32) {
+        let mut stepped = gameboy.clone();
+        let mut stepped_cycles = 0;
+        while stepped_cycles < n_cycles {
+            stepped_cycles += stepped.step().unwrap() as u32;
+        }
+        assert_eq!(gameboy.run_cycles(n_cycles).unwrap(), stepped_cycles);
+        assert_eq!(gameboy.diff(&stepped), vec![]);
+    }
+
+    /// A machine halted at 0x100 with only `interrupt` enabled, which loops back into HALT when woken
+    fn halted_gameboy(interrupt: interrupt::Interrupt) -> Gameboy {
+        let rom = test_rom::RomBuilder::new().halt().jr(-3).build();
+        let mut gameboy = Gameboy::new_for_testing(&rom);
+        gameboy.mmu.ie = interrupt.mask();
+        // the bios leaves VBlank requested
+        gameboy.mmu.write(mmu::MMU::INTERRUPT_FLAG_REG_ADDRESS, 0x00);
+        gameboy.step().unwrap();
+        assert!(gameboy.cpu.halted);
+        gameboy
+    }
+
+    #[test]
+    fn halt_fast_forwards_to_vblank_in_one_jump() {
+        let mut gameboy = halted_gameboy(interrupt::Interrupt::VBlank);
+        gameboy.step().unwrap();
+        // the bios leaves the LCD on, 8 dots into the shortened first line, then 143 more lines
+        let to_vblank = 452 - 8 + 143 * 456;
+        assert_eq!(gameboy.cycles_until_wake(), Some(to_vblank));
+        assert_eq!(gameboy.halted_cycles(u32::MAX), to_vblank);
+
+        let mut jumped = gameboy.clone();
+        jumped.fast_forward(to_vblank);
+        assert_eq!(jumped.mmu.read(ppu::PPU::LY_ADDRESS), 144);
+        assert!(jumped.is_frame_ready());
+
+        assert_fast_forward_matches_stepping(gameboy, to_vblank + 100);
+    }
+
+    #[test]
+    fn halt_fast_forwards_to_timer_overflow() {
+        let mut gameboy = halted_gameboy(interrupt::Interrupt::Timer);
+        gameboy.mmu.write(timer::Timer::TAC_ADDRESS, 0b110);
+        gameboy.mmu.write(timer::Timer::TIMA_ADDRESS, 0xF0);
+        assert_fast_forward_matches_stepping(gameboy, 2000);
+    }
+
+    #[test]
+    fn halt_fast_forwards_with_the_unused_ie_bits_set() {
+        let mut gameboy = halted_gameboy(interrupt::Interrupt::VBlank);
+        // as left by LD A,$FF; LDH (IE),A, IF reads back with the same bits set
+        gameboy.mmu.ie = 0xFF;
+        let to_wake = gameboy.cycles_until_wake().unwrap();
+        let halted = gameboy.halted_cycles(u32::MAX);
+        assert!(halted >= to_wake && halted < to_wake + Gameboy::HALTED_STEP_CYCLES, "{} for {}", halted, to_wake);
+    }
+
+    /// Raises the joypad interrupt once, `cycles` after it's attached
+    #[derive(Clone)]
+    struct Alarm {
+        cycles: u32,
+    }
+
+    impl util::Memory for Alarm {
+        fn read(&self, _address: u16) -> u8 {
+            0xFF
+        }
+
+        fn write(&mut self, _address: u16, _value: u8) {}
+    }
+
+    impl peripheral::Peripheral for Alarm {
+        fn claims(&self, _address: u16) -> bool {
+            false
+        }
+
+        fn tick(&mut self, n_cycles: u8, _double_speed: bool) -> Option<interrupt::Interrupt> {
+            let fired = self.cycles == 0;
+            self.cycles = self.cycles.saturating_sub(n_cycles as u32);
+            Some(interrupt::Interrupt::Joypad).filter(|_| !fired && self.cycles == 0)
+        }
+
+        fn cycles_until_interrupt(&self) -> Option<u32> {
+            Some(self.cycles).filter(|&x| x > 0)
+        }
+    }
+
+    #[test]
+    fn halt_fast_forwards_to_an_attached_peripheral() {
+        let mut gameboy = halted_gameboy(interrupt::Interrupt::Joypad);
+        gameboy.attach_peripheral(Alarm { cycles: 100 });
+        assert_eq!(gameboy.cycles_until_wake(), Some(100));
+        assert_fast_forward_matches_stepping(gameboy, 1000);
+    }
+
+    #[test]
+    fn halt_without_enabled_interrupts_runs_out_the_budget() {
+        let mut gameboy = halted_gameboy(interrupt::Interrupt::Joypad);
+        assert_eq!(gameboy.run_cycles(10_001).unwrap(), 10_004);
+        assert!(gameboy.cpu.halted);
+    }
+
+    #[test]
+    fn run_frame_stops_at_vblank() {
+        let mut gameboy = Gameboy::new_for_testing(&test_rom::RomBuilder::new().jr(-2).build());
+        for frame in 1..=3 {
+            gameboy.run_frame().unwrap();
+            assert_eq!(gameboy.elapsed_frames(), frame);
+            assert_eq!(gameboy.read(ppu::PPU::LY_ADDRESS), 144);
+            assert!(!gameboy.is_frame_ready());
+        }
+    }
+
+    #[test]
+    fn rewind_restores_snapshots_newest_first() {
+        let mut gameboy = Gameboy::new_for_testing(&test_rom::RomBuilder::new().jr(-2).build());
+        assert_eq!(gameboy.rewind(1).unwrap(), 0);
+        gameboy.enable_rewind(8);
+        gameboy.set_rewind_interval(2);
+        let mut snapshots = vec![];
+        for _ in 0..3 {
+            gameboy.run_frame().unwrap();
+            gameboy.run_frame().unwrap();
+            snapshots.push(gameboy.save_state());
+        }
+
+        gameboy.run_frame().unwrap();
+        assert_eq!(gameboy.rewind(2).unwrap(), 2);
+        assert_eq!(gameboy.save_state(), snapshots[1]);
+        assert_eq!(gameboy.elapsed_frames(), 4);
+        assert_eq!(gameboy.rewind(5).unwrap(), 1);
+        assert_eq!(gameboy.save_state(), snapshots[0]);
+        assert_eq!(gameboy.rewind(1).unwrap(), 0);
+    }
+
+    #[test]
+    fn run_frame_with_lcd_off_carries_overshoot() {
+        let mut gameboy = Gameboy::new_for_testing(&test_rom::RomBuilder::new().jr(-2).build());
+        gameboy.mmu.write(ppu::PPU::LCDC_ADDRESS, 0x00);
+        gameboy.run_frame().unwrap();
+        assert_eq!(gameboy.elapsed_frames(), 0);
+        // JR takes 12 cycles, 70224 is a whole number of them
+        assert_eq!(gameboy.frame_overshoot, 0);
+
+        // a budget 20 short is run out by one fewer JR, overshooting by 8
+        gameboy.frame_overshoot = 20;
+        gameboy.run_frame().unwrap();
+        assert_eq!(gameboy.frame_overshoot, 8);
+    }
+
+    #[test]
+    fn button_press_wakes_from_halt() {
+        let mut gameboy = halted_gameboy(interrupt::Interrupt::Joypad);
+        // watch the action buttons
+        gameboy.mmu.write(mmu::MMU::JOYPAD_ADDRESS, 0x10);
+        gameboy.step().unwrap();
+        assert!(gameboy.cpu.halted);
+
+        gameboy.press_button(Button::Start);
+        assert_eq!(gameboy.read(mmu::MMU::JOYPAD_ADDRESS) & 0x0F, 0x07);
+        gameboy.step().unwrap();
+        assert!(!gameboy.cpu.halted);
+
+        gameboy.release_button(Button::Start);
+        assert_eq!(gameboy.read(mmu::MMU::JOYPAD_ADDRESS) & 0x0F, 0x0F);
+    }
+
+    #[test]
+    fn peek_sees_vram_locked_during_drawing() {
+        let mut gameboy = Gameboy::new_for_testing(&[0x00; 0x8000]);
+        gameboy.mmu.write(mmu::MMU::VRAM_START_ADDRESS, 0x42);
+        gameboy.mmu.write(ppu::PPU::LCDC_ADDRESS, 0x80);
+        assert!(gameboy.step_until(1000, |x| x.ppu.mode() == ppu::Mode::Drawing).unwrap());
+
+        assert_eq!(gameboy.read(mmu::MMU::VRAM_START_ADDRESS), 0xFF);
+        assert_eq!(gameboy.peek(mmu::MMU::VRAM_START_ADDRESS), 0x42);
+
+        assert!(gameboy.step_until(1000, |x| x.ppu.mode() == ppu::Mode::HBlank).unwrap());
+        assert_eq!(gameboy.read(mmu::MMU::VRAM_START_ADDRESS), 0x42);
+    }
+
+    #[test]
+    fn elapsed_frames_counts_vblanks() {
+        let mut gameboy = Gameboy::new_for_testing(&[0x00; 0x8000]);
+        gameboy.mmu.write(ppu::PPU::LCDC_ADDRESS, 0x80);
+        assert_eq!(gameboy.elapsed_frames(), 0);
+
+        let frames = 5;
+        gameboy.run_cycles(frames * 154 * 456).unwrap();
+        assert_eq!(gameboy.elapsed_frames(), frames as u64);
+
+        let mut restored = Gameboy::new_for_testing(&[0x00; 0x8000]);
+        restored.load_state(&gameboy.save_state()).unwrap();
+        assert_eq!(restored.elapsed_frames(), frames as u64);
     }
 }
@@ -0,0 +1,132 @@
+use {
+  crate::{cpu::CpuError, interrupt::Interrupt, mmu::MMU, Gameboy},
+};
+
+/// A link cable joining the serial ports of two machines
+///
+/// When a transfer clocked by one side completes while the other side is waiting for a clock, the two
+/// sides swap SB and both raise the serial interrupt. Otherwise the clocking side reads 0xFF as if
+/// nothing were plugged in.
+#[derive(Debug, Default, Clone)]
+pub struct SerialLink {
+  /// Cycles the left machine has run ahead of the right
+  skew: i64,
+}
+
+impl SerialLink {
+  /// Deliver the bytes of any transfer that completed on either side since the last call
+  pub fn exchange(&mut self, left: &mut MMU, right: &mut MMU) {
+    Self::deliver(left, right);
+    Self::deliver(right, left);
+  }
+
+  fn deliver(from: &mut MMU, to: &mut MMU) {
+    if let Some(sent) = from.serial.take_shifted_out() {
+      if to.serial.is_waiting_for_clock() {
+        from.serial.sb = to.serial.receive(sent);
+        to.request_interrupt(Interrupt::Serial);
+      }
+    }
+  }
+}
+
+/// Two machines sharing a `SerialLink`, see `Gameboy::with_serial_link`
+#[derive(Debug, Clone)]
+pub struct LinkedGameboys {
+  pub left: Gameboy,
+  pub right: Gameboy,
+  pub link: SerialLink,
+}
+
+impl LinkedGameboys {
+  /// Step whichever machine is behind forward one instruction and exchange any serial bytes,
+  /// keeping the two machines within an instruction of each other
+  ///
+  /// # Returns
+  /// the number of cycles the stepped machine ran
+  pub fn step_linked(&mut self) -> Result<u8, CpuError> {
+    let n_cycles = if self.link.skew <= 0 {
+      let n_cycles = self.left.step()?;
+      self.link.skew += n_cycles as i64;
+      n_cycles
+    } else {
+      let n_cycles = self.right.step()?;
+      self.link.skew -= n_cycles as i64;
+      n_cycles
+    };
+    self.link.exchange(&mut self.left.mmu, &mut self.right.mmu);
+    Ok(n_cycles)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use {
+    super::*,
+    crate::{cartridge::Cartridge, serial::{ClockRole, Serial}, util::Memory},
+  };
+
+  /// Load `value` into SB, start a transfer with the control value `sc`, then spin
+  fn send_byte_rom(value: u8, sc: u8) -> Cartridge {
+    let mut rom = vec![0; 0x8000];
+    let program = [
+      0x3E, value, // LD A,value
+      0xE0, 0x01,  // LDH ($01),A
+      0x3E, sc,    // LD A,sc
+      0xE0, 0x02,  // LDH ($02),A
+      0x18, 0xFE,  // JR -2
+    ];
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+    Cartridge::maybe_from_bytes(&rom).unwrap()
+  }
+
+  #[test]
+  fn linked_machines_swap_bytes() {
+    let mut linked = Gameboy::with_serial_link(send_byte_rom(0x42, 0x81), send_byte_rom(0x99, 0x80));
+    let mut elapsed = 0u32;
+    while elapsed < 2 * 8192 {
+      elapsed += linked.step_linked().unwrap() as u32;
+    }
+
+    assert_eq!(linked.left.read(Serial::SB_ADDRESS), 0x99);
+    assert_eq!(linked.right.read(Serial::SB_ADDRESS), 0x42);
+    for gameboy in [&linked.left, &linked.right].iter() {
+      assert_eq!(gameboy.read(Serial::SC_ADDRESS) & 0x80, 0);
+      assert_ne!(gameboy.read(MMU::INTERRUPT_FLAG_REG_ADDRESS) & Interrupt::Serial.mask(), 0);
+    }
+  }
+
+  /// Spin forever
+  fn idle_rom() -> Cartridge {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+    Cartridge::maybe_from_bytes(&rom).unwrap()
+  }
+
+  fn run(linked: &mut LinkedGameboys, n_cycles: u32) {
+    let mut elapsed = 0u32;
+    while elapsed < 2 * n_cycles {
+      elapsed += linked.step_linked().unwrap() as u32;
+    }
+  }
+
+  #[test]
+  fn slave_waits_for_master_clock() {
+    let mut linked = Gameboy::with_serial_link(idle_rom(), send_byte_rom(0x99, 0x80));
+    run(&mut linked, 4 * 8192);
+    let slave = &linked.right.mmu.serial;
+    assert_eq!(slave.role(), ClockRole::Slave);
+    assert!(slave.is_transferring());
+    assert_eq!(slave.sb, 0x99);
+    assert_eq!(linked.right.read(MMU::INTERRUPT_FLAG_REG_ADDRESS) & Interrupt::Serial.mask(), 0);
+
+    linked.left.mmu.write(Serial::SB_ADDRESS, 0x42);
+    linked.left.mmu.write(Serial::SC_ADDRESS, 0x81);
+    assert_eq!(linked.left.mmu.serial.role(), ClockRole::Master);
+    run(&mut linked, 5000);
+    assert!(!linked.right.mmu.serial.is_transferring());
+    assert_eq!(linked.right.read(Serial::SB_ADDRESS), 0x42);
+    assert_eq!(linked.left.read(Serial::SB_ADDRESS), 0x99);
+    assert_ne!(linked.right.read(MMU::INTERRUPT_FLAG_REG_ADDRESS) & Interrupt::Serial.mask(), 0);
+  }
+}
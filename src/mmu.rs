@@ -1,44 +1,124 @@
 use {
-  crate::{cartridge::Cartridge, util::Memory},
+  crate::{
+    apu::Apu,
+    cartridge::{Cartridge, SaveError},
+    cheats::Cheats,
+    dma::{Bus, Dma},
+    hdma::{Hdma, HdmaMode},
+    infrared::Infrared,
+    interrupt::Interrupt,
+    joypad::{Button, Joypad},
+    model::Model,
+    palette::ColorPalettes,
+    peripheral::Peripheral,
+    ppu::{Mode, PPU},
+    serial::Serial,
+    sgb::SgbCapture,
+    state::*,
+    timer::Timer,
+    util::{get_bit, set_bit, Memory},
+  },
   derivative::Derivative,
+  std::io::{self, Read, Write},
 };
 
-#[derive(Derivative)]
+#[derive(Derivative, Clone)]
 #[derivative(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MMU {
   #[derivative(Debug = "ignore")]
   pub cartridge: Option<Cartridge>,
   #[derivative(Debug = "ignore")]
-  pub bios: [u8; Self::BIOS_SIZE],
+  #[cfg_attr(feature = "serde", serde(with = "crate::state::byte_array"))]
+  pub bios: [u8; MMU::BIOS_SIZE],
   #[derivative(Debug = "ignore")]
-  pub vram: [u8; Self::VRAM_SIZE], // video ram
+  #[cfg_attr(feature = "serde", serde(with = "crate::state::byte_array::boxed"))]
+  pub vram: Box<[u8; MMU::VRAM_SIZE * MMU::VRAM_BANK_COUNT]>, // video ram, bank 1 after bank 0
   #[derivative(Debug = "ignore")]
-  pub oam: [u8; Self::OAM_SIZE], // sprite attrib memory
+  #[cfg_attr(feature = "serde", serde(with = "crate::state::byte_array"))]
+  pub oam: [u8; MMU::OAM_SIZE], // sprite attrib memory
   #[derivative(Debug = "ignore")]
-  pub iom: [u8; Self::IO_SIZE], // IO memory
+  #[cfg_attr(feature = "serde", serde(with = "crate::state::byte_array"))]
+  pub iom: [u8; MMU::IO_SIZE], // IO memory
   #[derivative(Debug = "ignore")]
-  pub ram: [u8; Self::RAM_SIZE], // internal ram
+  #[cfg_attr(feature = "serde", serde(with = "crate::state::byte_array::boxed"))]
+  pub wram: Box<[u8; MMU::WRAM_BANK_SIZE * MMU::WRAM_BANK_COUNT]>, // work ram, every bank in order
   #[derivative(Debug = "ignore")]
-  pub sram: [u8; Self::SRAM_SIZE], // switchable ram
-  #[derivative(Debug = "ignore")]
-  pub hram: [u8; Self::HRAM_SIZE],
+  #[cfg_attr(feature = "serde", serde(with = "crate::state::byte_array"))]
+  pub hram: [u8; MMU::HRAM_SIZE],
   /// Interrupt enable register
   pub ie: u8,
+  pub timer: Timer,
+  pub serial: Serial,
+  /// CGB infrared port, also reached through a HuC1 cartridge's
+  pub infrared: Infrared,
+  pub joypad: Joypad,
+  pub apu: Apu,
+  /// OAM DMA started through FF46
+  pub dma: Dma,
+  /// CGB VRAM DMA started through HDMA5
+  pub hdma: Hdma,
+  /// CGB background palettes, reached through BCPS and BCPD
+  pub bg_palettes: ColorPalettes,
+  /// CGB sprite palettes, reached through OCPS and OCPD
+  pub obj_palettes: ColorPalettes,
+  pub model: Model,
+  /// Super Game Boy packets sent over the joypad register, only fed on `Model::Sgb`
+  pub sgb: SgbCapture,
+  /// Set by a STAT write on DMG until the PPU has seen it
+  pub(crate) stat_written: bool,
+  /// Game Genie codes patch ROM reads, GameShark codes write RAM every VBlank
+  #[derivative(Debug = "ignore")]
+  #[cfg_attr(feature = "serde", serde(skip))]
+  pub cheats: Cheats,
+  /// Peripherals attached by the frontend, copied for clones and left out of save states
+  #[derivative(Debug = "ignore")]
+  #[cfg_attr(feature = "serde", serde(skip))]
+  pub(crate) attached: Vec<Box<dyn Peripheral + Send>>,
+  /// The peripheral each IO register is routed to, worked out from what they claim by `route_io`
+  #[derivative(Debug = "ignore")]
+  #[cfg_attr(feature = "serde", serde(skip, default = "MMU::default_io_routes"))]
+  pub(crate) io_routes: [Option<IoRoute>; MMU::IO_SIZE],
+}
+
+/// Where the MMU sends accesses to an IO register claimed by a peripheral
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IoRoute {
+  /// One of `MMU::peripherals`
+  Peripheral(usize),
+  /// One of the peripherals attached by the frontend
+  Attached(usize),
 }
 
 impl Default for MMU {
   fn default() -> Self {
-    Self {
+    let mut mmu = Self {
       cartridge: None,
       bios: [0; Self::BIOS_SIZE],
-      vram: [0; Self::VRAM_SIZE], // video ram
+      vram: Box::new([0; Self::VRAM_SIZE * Self::VRAM_BANK_COUNT]), // video ram
       oam: [0; Self::OAM_SIZE],   // sprite attrib memory
       iom: [0; Self::IO_SIZE],    // IO memory
-      ram: [0; Self::RAM_SIZE],   // internal ram
-      sram: [0; Self::SRAM_SIZE], // switchable ram
+      wram: Box::new([0; Self::WRAM_BANK_SIZE * Self::WRAM_BANK_COUNT]), // work ram
       hram: [0; Self::HRAM_SIZE],
       ie: 0, // interrupt enable register
-    }
+      timer: Timer::default(),
+      serial: Serial::default(),
+      infrared: Infrared::default(),
+      joypad: Joypad::default(),
+      apu: Apu::default(),
+      dma: Dma::default(),
+      hdma: Hdma::default(),
+      bg_palettes: ColorPalettes::default(),
+      obj_palettes: ColorPalettes::default(),
+      model: Model::default(),
+      sgb: SgbCapture::default(),
+      stat_written: false,
+      cheats: Cheats::default(),
+      attached: vec![],
+      io_routes: [None; Self::IO_SIZE],
+    };
+    mmu.route_io();
+    mmu
   }
 }
 
@@ -47,34 +127,43 @@ impl MMU {
   // #region Memory Map
   //=================================================================================
   // 0000-3FFF   16KB ROM Bank 00     (in cartridge, fixed at bank 00)
-  //    0000-00FF bios
+  pub const ROM_BANK_0_START_ADDRESS: u16 = 0x0000;
+  pub const ROM_BANK_0_END_ADDRESS: u16   = 0x3FFF;
+
+  //    0000-00FF bios (overlays ROM bank 00 until disabled)
   pub const BIOS_START_ADDRESS: u16 = 0x0000;
   pub const BIOS_END_ADDRESS: u16   = 0x00FF;
   pub const BIOS_SIZE: usize        = (Self::BIOS_END_ADDRESS - Self::BIOS_START_ADDRESS + 1) as usize;
 
   // 4000-7FFF   16KB ROM Bank 01..NN (in cartridge, switchable bank number)
-  pub const CARTRIDGE_START_ADDRESS: u16 = 0x0000;
-  pub const CARTRIDGE_END_ADDRESS: u16   = 0x7FFF;
+  pub const ROM_BANK_N_START_ADDRESS: u16 = 0x4000;
+  pub const ROM_BANK_N_END_ADDRESS: u16   = 0x7FFF;
   pub const CARTRIDGE_EMPTY_READ_VALUE : u8 = 0xFF;
 
   // 8000-9FFF   8KB Video RAM (VRAM) (switchable bank 0-1 in CGB Mode)
   pub const VRAM_START_ADDRESS: u16 = 0x8000;
   pub const VRAM_END_ADDRESS: u16   = 0x9FFF;
   pub const VRAM_SIZE: usize        = (Self::VRAM_END_ADDRESS - Self::VRAM_START_ADDRESS + 1) as usize;
+  pub const VRAM_BANK_COUNT: usize  = 2;
 
   // A000-BFFF   8KB External RAM     (in cartridge, switchable bank, if any)
   pub const EXTRAM_START_ADDRESS: u16 = 0xA000;
   pub const EXTRAM_END_ADDRESS: u16   = 0xBFFF;
 
-  // C000-CFFF   4KB Work RAM Bank 0 (WRAM)
+  // C000-DFFF   8KB Work RAM (WRAM), both banks
+  pub const WRAM_START_ADDRESS: u16 = 0xC000;
+  pub const WRAM_END_ADDRESS: u16   = 0xDFFF;
+  pub const WRAM_SIZE: usize        = (Self::WRAM_END_ADDRESS - Self::WRAM_START_ADDRESS + 1) as usize;
+  pub const WRAM_BANK_SIZE: usize   = 0x1000;
+  pub const WRAM_BANK_COUNT: usize  = 8;
+
+  //    C000-CFFF 4KB Work RAM Bank 0
   pub const RAM_START_ADDRESS: u16 = 0xC000;
   pub const RAM_END_ADDRESS: u16   = 0xCFFF;
-  pub const RAM_SIZE: usize        = (Self::RAM_END_ADDRESS - Self::RAM_START_ADDRESS + 1) as usize;
 
-  // D000-DFFF   4KB Work RAM Bank 1 (WRAM)  (switchable bank 1-7 in CGB Mode)
+  //    D000-DFFF 4KB Work RAM Bank 1 (switchable bank 1-7 in CGB Mode)
   pub const SRAM_START_ADDRESS: u16 = 0xD000;
   pub const SRAM_END_ADDRESS: u16   = 0xDFFF;
-  pub const SRAM_SIZE: usize        = (Self::SRAM_END_ADDRESS - Self::SRAM_START_ADDRESS + 1) as usize;
 
   // E000-FDFF   Same as C000-DDFF (ECHO)    (typically not used)
   pub const ERAM_START_ADDRESS: u16 = 0xE000;
@@ -89,10 +178,45 @@ impl MMU {
   pub const UNUSABLE_START_ADDRESS: u16 = 0xFEA0;
   pub const UNUSABLE_END_ADDRESS: u16   = 0xFEFF;
   pub const UNUSABLE_READ_VALUE: u8     = 0xFF;
+  /// Read by the CPU from VRAM or OAM while the PPU has it locked
+  pub const LOCKED_READ_VALUE: u8       = 0xFF;
 
   // FF00-FF7F   I/O Ports
   pub const IO_START_ADDRESS: u16              = 0xFF00;
+  pub const JOYPAD_ADDRESS: u16                = 0xFF00;
+  pub const SERIAL_START_ADDRESS: u16          = Serial::SB_ADDRESS;
+  pub const SERIAL_END_ADDRESS: u16            = Serial::SC_ADDRESS;
+  pub const TIMER_START_ADDRESS: u16           = Timer::DIV_ADDRESS;
+  pub const TIMER_END_ADDRESS: u16             = Timer::TAC_ADDRESS;
+  pub const INTERRUPT_FLAG_REG_ADDRESS: u16    = 0xFF0F;
+  pub const STAT_ADDRESS: u16                  = PPU::STAT_ADDRESS;
+  /// Mode and coincidence bits of STAT, only written by the PPU
+  const STAT_STATUS_BITS: u8                   = 0b0000_0111;
+  const STAT_MODE_BITS: u8                     = 0b0000_0011;
+  pub const APU_START_ADDRESS: u16             = Apu::START_ADDRESS;
+  pub const APU_END_ADDRESS: u16               = Apu::END_ADDRESS;
+  pub const DMA_ADDRESS: u16                   = 0xFF46;
+  /// CGB speed switch, bit 7 the current speed and bit 0 arming a switch on the next STOP
+  pub const KEY1_ADDRESS: u16                  = 0xFF4D;
+  const KEY1_PREPARE_BIT_N: u8                 = 0;
+  const KEY1_SPEED_BIT_N: u8                   = 7;
+  const KEY1_UNUSED_BITS: u8                   = 0b0111_1110;
+  /// CGB VRAM bank select, bit 0
+  pub const VBK_ADDRESS: u16                   = 0xFF4F;
   pub const BIOS_DISABLE_REGISTER_ADDRESS: u16 = 0xFF50;
+  pub const HDMA_START_ADDRESS: u16            = Hdma::HDMA1_ADDRESS;
+  pub const HDMA_END_ADDRESS: u16              = Hdma::HDMA5_ADDRESS;
+  /// CGB infrared port
+  pub const RP_ADDRESS: u16                    = Infrared::RP_ADDRESS;
+  /// CGB background palette index and data
+  pub const BCPS_ADDRESS: u16                  = 0xFF68;
+  pub const BCPD_ADDRESS: u16                  = 0xFF69;
+  /// CGB sprite palette index and data
+  pub const OCPS_ADDRESS: u16                  = 0xFF6A;
+  pub const OCPD_ADDRESS: u16                  = 0xFF6B;
+  /// CGB work RAM bank select for D000-DFFF, bits 0-2 with 0 selecting bank 1
+  pub const SVBK_ADDRESS: u16                  = 0xFF70;
+  const SVBK_BANK_BITS: u8                     = 0b0000_0111;
   pub const IO_END_ADDRESS: u16                = 0xFF7F;
   pub const IO_SIZE: usize                     = (Self::IO_END_ADDRESS - Self::IO_START_ADDRESS + 1) as usize;
 
@@ -108,106 +232,538 @@ impl MMU {
 }
 
 impl MMU {
+  /// Load `cartridge`
+  pub fn with_cartridge(self, cartridge: Cartridge) -> Self {
+    Self { cartridge: Some(cartridge), ..self }
+  }
+
+  /// Behave like `model` where the models differ
+  pub fn with_model(self, model: Model) -> Self {
+    Self { model, ..self }
+  }
+
+  /// Route the timer registers to `timer` in place of a fresh one, e.g. one preloaded by a test
+  pub fn with_timer(self, timer: Timer) -> Self {
+    Self { timer, ..self }
+  }
+
+  /// Route the serial registers to `serial` in place of a fresh one, e.g. one with a callback recording
+  /// what is sent
+  pub fn with_serial(self, serial: Serial) -> Self {
+    Self { serial, ..self }
+  }
+
+  /// IO registers as the DMG bios leaves them when it jumps to the cartridge entry point
+  ///
+  /// LY and the mode and coincidence bits of STAT are left out, the PPU owns them, as are the sound
+  /// registers, see `Apu::post_boot`.
+  const POST_BOOT_IO: [(u16, u8); 15] = [
+    (0xFF00, 0xCF), // P1
+    (0xFF01, 0x00), // SB
+    (0xFF02, 0x7E), // SC
+    (0xFF04, 0xAB), // DIV
+    (0xFF05, 0x00), // TIMA
+    (0xFF06, 0x00), // TMA
+    (0xFF07, 0xF8), // TAC
+    (0xFF0F, 0xE1), // IF
+    (0xFF40, 0x91), // LCDC
+    (0xFF42, 0x00), // SCY
+    (0xFF43, 0x00), // SCX
+    (0xFF45, 0x00), // LYC
+    (0xFF46, 0xFF), // DMA
+    (0xFF47, 0xFC), // BGP
+    (0xFF50, 0x01), // bios disabled
+  ];
+
+  /// Set the IO registers to the values the DMG bios leaves them at, for starting a cartridge without
+  /// running the bios
+  pub fn post_boot_io(&mut self) {
+    for &(address, value) in Self::POST_BOOT_IO.iter() {
+      match address {
+        Self::JOYPAD_ADDRESS => self.joypad.write(address, value),
+        Self::SERIAL_START_ADDRESS..=Self::SERIAL_END_ADDRESS => self.serial.write(address, value),
+        // DIV can only be reset by a write, the timer is replaced whole below
+        Self::TIMER_START_ADDRESS..=Self::TIMER_END_ADDRESS => {}
+        _ => self.iom[Self::region_offset(address, Self::IO_START_ADDRESS, Self::IO_SIZE)] = value,
+      }
+    }
+    self.timer = Timer::post_boot();
+    self.apu.post_boot();
+  }
+
   pub fn vram(&self) -> impl Iterator<Item = &u8> {
     self.vram.iter()
   }
 
+  /// The VRAM bank selected through VBK, always 0 on DMG
+  pub fn vram_bank(&self) -> usize {
+    if self.model != Model::Cgb {
+      return 0;
+    }
+    (self.iom[Self::region_offset(Self::VBK_ADDRESS, Self::IO_START_ADDRESS, Self::IO_SIZE)] & 1) as usize
+  }
+
+  /// The work RAM bank at D000-DFFF selected through SVBK, always 1 on DMG
+  pub fn wram_bank(&self) -> usize {
+    if self.model != Model::Cgb {
+      return 1;
+    }
+    let svbk = self.iom[Self::region_offset(Self::SVBK_ADDRESS, Self::IO_START_ADDRESS, Self::IO_SIZE)];
+    ((svbk & Self::SVBK_BANK_BITS) as usize).max(1)
+  }
+
+  /// Offset into `vram` of `address` in the current VRAM bank
+  fn vram_offset(&self, address: u16) -> usize {
+    self.vram_bank() * Self::VRAM_SIZE + Self::region_offset(address, Self::VRAM_START_ADDRESS, Self::VRAM_SIZE)
+  }
+
+  /// Offset into `wram` of `address` in work RAM or its echo
+  ///
+  /// The echo at E000-FDFF mirrors C000-DDFF. C000-CFFF is always bank 0 and D000-DFFF the bank
+  /// selected by `wram_bank`.
+  fn wram_offset(&self, address: u16) -> usize {
+    let address = match address {
+      Self::ERAM_START_ADDRESS..=Self::ERAM_END_ADDRESS => address - (Self::ERAM_START_ADDRESS - Self::WRAM_START_ADDRESS),
+      _ => address,
+    };
+    let offset = Self::region_offset(address, Self::WRAM_START_ADDRESS, Self::WRAM_SIZE);
+    if offset < Self::WRAM_BANK_SIZE {
+      offset
+    } else {
+      offset + (self.wram_bank() - 1) * Self::WRAM_BANK_SIZE
+    }
+  }
+
+  /// Offset of `address` into the region of `size` bytes starting at `start`
+  ///
+  /// Debug builds assert that `address` actually lies inside the region, catching bad region constants
+  fn region_offset(address: u16, start: u16, size: usize) -> usize {
+    let offset = address.wrapping_sub(start) as usize;
+    debug_assert!(
+      address >= start && offset < size,
+      "address '0x{:x}' is outside of the region at 0x{:x} of size 0x{:x}", address, start, size
+    );
+    offset
+  }
+
   fn bios_enabled(&self) -> bool {
-    self.read(Self::BIOS_DISABLE_REGISTER_ADDRESS) == 0
+    self.iom[Self::region_offset(Self::BIOS_DISABLE_REGISTER_ADDRESS, Self::IO_START_ADDRESS, Self::IO_SIZE)] == 0
   }
-}
 
-impl Memory for MMU {
-  fn read(&self, address: u16) -> u8 {
+  /// Returns true if reads from `address` are served by the bios rather than the cartridge
+  fn is_bios_mapped(&self, address: u16) -> bool {
+    (Self::BIOS_START_ADDRESS..=Self::BIOS_END_ADDRESS).contains(&address) && self.bios_enabled()
+  }
+
+  fn read_cartridge(&self, address: u16) -> u8 {
+    let value = self.cartridge.as_ref().map(|x| x.read(address)).unwrap_or(Self::CARTRIDGE_EMPTY_READ_VALUE);
+    if self.cheats.is_empty() { value } else { self.cheats.patch_rom(address, value) }
+  }
+
+  /// Restore the cartridge's battery backed RAM from a save created by `dump_save_ram`
+  pub fn load_save_ram(&mut self, bytes: &[u8]) -> Result<(), SaveError> {
+    self.cartridge.as_mut().ok_or(SaveError::NoCartridge)?.load_ram(bytes)
+  }
+
+  /// A copy of the cartridge's battery backed RAM, or `None` if there is no cartridge or it has no RAM
+  pub fn dump_save_ram(&self) -> Option<Vec<u8>> {
+    self.cartridge.as_ref().and_then(Cartridge::dump_ram)
+  }
+
+  /// The cartridge's battery backed RAM to write to a save file, see `Cartridge::save_ram`
+  pub fn save_ram(&mut self) -> Option<&[u8]> {
+    self.cartridge.as_mut().and_then(Cartridge::save_ram)
+  }
+
+  /// Whether the cartridge's RAM has changed since it was last saved or loaded
+  pub fn save_ram_dirty(&self) -> bool {
+    self.cartridge.as_ref().map(Cartridge::ram_dirty).unwrap_or(false)
+  }
+
+  /// Set the read only mode and coincidence bits of STAT, which the CPU can't write
+  pub(crate) fn set_stat_status(&mut self, status: u8) {
+    let offset = Self::region_offset(Self::STAT_ADDRESS, Self::IO_START_ADDRESS, Self::IO_SIZE);
+    self.iom[offset] = (self.iom[offset] & !Self::STAT_STATUS_BITS) | (status & Self::STAT_STATUS_BITS);
+  }
+
+  /// Returns true if STAT was written on DMG since the last call, see `PPU::step`
+  pub(crate) fn take_stat_write(&mut self) -> bool {
+    std::mem::replace(&mut self.stat_written, false)
+  }
+
+  /// Whether the PPU has `address` to itself in its current mode, locking the CPU out of it
+  ///
+  /// VRAM and the CGB palette data registers are locked while drawing, and OAM during both the OAM
+  /// scan and drawing. The mode is taken from STAT, which reports HBlank while the LCD is off.
+  fn is_locked_by_ppu(&self, address: u16) -> bool {
+    let mode = self.iom[Self::region_offset(Self::STAT_ADDRESS, Self::IO_START_ADDRESS, Self::IO_SIZE)] & Self::STAT_MODE_BITS;
+    match address {
+      Self::VRAM_START_ADDRESS..=Self::VRAM_END_ADDRESS => mode == Mode::Drawing as u8,
+      Self::OAM_START_ADDRESS..=Self::OAM_END_ADDRESS => mode == Mode::OamScan as u8 || mode == Mode::Drawing as u8,
+      Self::BCPD_ADDRESS | Self::OCPD_ADDRESS => mode == Mode::Drawing as u8,
+      _ => false,
+    }
+  }
+
+  /// The value a CPU read of `address` sees while an OAM DMA holds its bus, `None` if it is free
+  ///
+  /// OAM reads as FF, and the bus the source is on returns the byte being transferred to every
+  /// address. HRAM and the IO registers are never touched by the DMA.
+  fn dma_conflict(&self, address: u16) -> Option<u8> {
+    if !self.dma.is_active() {
+      return None;
+    }
+    match address {
+      Self::OAM_START_ADDRESS..=Self::OAM_END_ADDRESS => Some(Self::LOCKED_READ_VALUE),
+      _ if Bus::of(address).is_some() && Bus::of(address) == self.dma.source_bus() => {
+        Some(self.peek(self.dma.current_address()))
+      }
+      _ => None,
+    }
+  }
+
+  /// Attach `peripheral` to the bus, routing the IO registers it claims to it ahead of the built in
+  /// peripherals and registers, and ticking it with the others
+  ///
+  /// Only FF00-FF7F are asked about, and only as it is attached.
+  pub fn attach_peripheral(&mut self, peripheral: impl Peripheral + Send + 'static) {
+    self.attached.push(Box::new(peripheral));
+    self.route_io();
+  }
+
+  /// Detach every peripheral attached with `attach_peripheral`
+  pub fn detach_peripherals(&mut self) {
+    self.attached.clear();
+    self.route_io();
+  }
+
+  /// The peripherals registered on the bus, in the order they are ticked
+  fn peripherals(&self) -> [&dyn Peripheral; 5] {
+    [&self.joypad, &self.serial, &self.timer, &self.apu, &self.infrared]
+  }
+
+  fn peripherals_mut(&mut self) -> [&mut dyn Peripheral; 5] {
+    [&mut self.joypad, &mut self.serial, &mut self.timer, &mut self.apu, &mut self.infrared]
+  }
+
+  /// Route each IO register to the peripheral claiming it, the attached ones first
+  ///
+  /// The PPU reads its registers every dot, so the routes are worked out once rather than asking every
+  /// peripheral on each access.
+  fn route_io(&mut self) {
+    let mut routes = [None; Self::IO_SIZE];
+    for (offset, route) in routes.iter_mut().enumerate() {
+      let address = Self::IO_START_ADDRESS + offset as u16;
+      *route = self
+        .attached
+        .iter()
+        .position(|peripheral| peripheral.claims(address))
+        .map(IoRoute::Attached)
+        .or_else(|| self.peripherals().iter().position(|peripheral| peripheral.claims(address)).map(IoRoute::Peripheral));
+    }
+    self.io_routes = routes;
+  }
+
+  #[cfg(feature = "serde")]
+  fn default_io_routes() -> [Option<IoRoute>; Self::IO_SIZE] {
+    Self::default().io_routes
+  }
+
+  /// Read `address` as it currently is, without the side effects or access restrictions of a CPU read
+  pub fn peek(&self, address: u16) -> u8 {
     match address {
       // 0000-3FFF   16KB ROM Bank 00     (in cartridge, fixed at bank 00)
       //    0000-00FF bios
-      Self::BIOS_START_ADDRESS..=Self::BIOS_END_ADDRESS if self.bios_enabled() => {
-        self.bios[(address - Self::BIOS_START_ADDRESS) as usize]
+      Self::ROM_BANK_0_START_ADDRESS..=Self::ROM_BANK_0_END_ADDRESS => {
+        if self.is_bios_mapped(address) {
+          self.bios[Self::region_offset(address, Self::BIOS_START_ADDRESS, Self::BIOS_SIZE)]
+        } else {
+          self.read_cartridge(address)
+        }
       }
       // 4000-7FFF   16KB ROM Bank 01..NN (in cartridge, switchable bank number)
-      Self::CARTRIDGE_START_ADDRESS..=Self::CARTRIDGE_END_ADDRESS => {
-        self.cartridge.as_ref().map(|x| x.read(address)).unwrap_or(Self::CARTRIDGE_EMPTY_READ_VALUE)
-      }
+      Self::ROM_BANK_N_START_ADDRESS..=Self::ROM_BANK_N_END_ADDRESS => self.read_cartridge(address),
       // 8000-9FFF   8KB Video RAM (VRAM) (switchable bank 0-1 in CGB Mode)
-      Self::VRAM_START_ADDRESS..=Self::VRAM_END_ADDRESS => {
-        self.vram[(address - Self::VRAM_START_ADDRESS) as usize]
-      }
+      Self::VRAM_START_ADDRESS..=Self::VRAM_END_ADDRESS => self.vram[self.vram_offset(address)],
       // A000-BFFF   8KB External RAM     (in cartridge, switchable bank, if any)
-      Self::EXTRAM_START_ADDRESS..=Self::EXTRAM_END_ADDRESS => self
-        .cartridge
-        .as_ref()
-        .map(|x| x.read_ram(address - Self::EXTRAM_START_ADDRESS))
-        .unwrap_or(Self::CARTRIDGE_EMPTY_READ_VALUE),
-      // C000-CFFF   4KB Work RAM Bank 0 (WRAM)
-      Self::RAM_START_ADDRESS..=Self::RAM_END_ADDRESS => {
-        self.ram[(address - Self::RAM_START_ADDRESS) as usize]
-      }
-      // D000-DFFF   4KB Work RAM Bank 1 (WRAM)  (switchable bank 1-7 in CGB Mode)
-      Self::SRAM_START_ADDRESS..=Self::SRAM_END_ADDRESS => {
-        self.sram[(address - Self::SRAM_START_ADDRESS) as usize]
-      }
+      Self::EXTRAM_START_ADDRESS..=Self::EXTRAM_END_ADDRESS => match self.cartridge.as_ref() {
+        Some(cartridge) if cartridge.infrared_mapped() => self.infrared.read_cartridge(),
+        Some(cartridge) => cartridge.read_ram(address - Self::EXTRAM_START_ADDRESS),
+        None => Self::CARTRIDGE_EMPTY_READ_VALUE,
+      },
+      // C000-DFFF   8KB Work RAM (WRAM)
       // E000-FDFF   Same as C000-DDFF (ECHO)    (typically not used)
-      Self::ERAM_START_ADDRESS..=Self::ERAM_END_ADDRESS => {
-        self.ram[(address - Self::ERAM_START_ADDRESS) as usize]
+      Self::WRAM_START_ADDRESS..=Self::WRAM_END_ADDRESS | Self::ERAM_START_ADDRESS..=Self::ERAM_END_ADDRESS => {
+        self.wram[self.wram_offset(address)]
       }
       // FE00-FE9F   Sprite Attribute Table (OAM)
       Self::OAM_START_ADDRESS..=Self::OAM_END_ADDRESS => {
-        self.oam[(address - Self::OAM_START_ADDRESS) as usize]
+        self.oam[Self::region_offset(address, Self::OAM_START_ADDRESS, Self::OAM_SIZE)]
       }
       // FEA0-FEFF   Not Usable
       Self::UNUSABLE_START_ADDRESS..=Self::UNUSABLE_END_ADDRESS => Self::UNUSABLE_READ_VALUE,
       // FF00-FF7F   I/O Ports
-      Self::IO_START_ADDRESS..=Self::IO_END_ADDRESS => {
-        self.iom[(address - Self::IO_START_ADDRESS) as usize]
-      }
+      Self::IO_START_ADDRESS..=Self::IO_END_ADDRESS => self.peek_io(address),
       // FF80-FFFE   High RAM (HRAM)
       Self::HRAM_START_ADDRESS..=Self::HRAM_END_ADDRESS => {
-        self.hram[(address - Self::HRAM_START_ADDRESS) as usize]
+        self.hram[Self::region_offset(address, Self::HRAM_START_ADDRESS, Self::HRAM_SIZE)]
       }
       // FFFF        Interrupt Enable Register
       Self::INTERRUPT_ENABLE_REG_ADDRESS => self.ie,
     }
   }
 
+  /// Read the IO register at `address`, from the peripheral claiming it if there is one
+  fn peek_io(&self, address: u16) -> u8 {
+    match self.io_routes[Self::region_offset(address, Self::IO_START_ADDRESS, Self::IO_SIZE)] {
+      Some(IoRoute::Attached(i)) => return self.attached[i].read(address),
+      // FF4D-FF70   CGB registers, unmapped on DMG
+      _ if Self::is_cgb_register(address) && self.model != Model::Cgb => return 0xFF,
+      // FF00 Joypad, FF01-FF02 Serial, FF04-FF07 Timer, FF10-FF3F Sound, FF56 CGB Infrared
+      Some(IoRoute::Peripheral(i)) => return self.peripherals()[i].read(address),
+      None => {}
+    }
+    match address {
+      // FF4D        CGB Speed Switch
+      Self::KEY1_ADDRESS => {
+        self.iom[Self::region_offset(address, Self::IO_START_ADDRESS, Self::IO_SIZE)] | Self::KEY1_UNUSED_BITS
+      }
+      // FF51-FF55   CGB VRAM DMA
+      Self::HDMA_START_ADDRESS..=Self::HDMA_END_ADDRESS => self.hdma.read(address),
+      // FF68-FF6B   CGB Palettes
+      Self::BCPS_ADDRESS => self.bg_palettes.read_index(),
+      Self::BCPD_ADDRESS => self.bg_palettes.read_data(),
+      Self::OCPS_ADDRESS => self.obj_palettes.read_index(),
+      Self::OCPD_ADDRESS => self.obj_palettes.read_data(),
+      _ => self.iom[Self::region_offset(address, Self::IO_START_ADDRESS, Self::IO_SIZE)] | Self::io_read_mask(address),
+    }
+  }
+
+  /// Write the IO register at `address`, to the peripheral claiming it if there is one
+  fn write_io(&mut self, address: u16, value: u8) {
+    match self.io_routes[Self::region_offset(address, Self::IO_START_ADDRESS, Self::IO_SIZE)] {
+      Some(IoRoute::Attached(i)) => return self.attached[i].write(address, value),
+      // FF4D-FF70   CGB registers, unmapped on DMG
+      _ if Self::is_cgb_register(address) && self.model != Model::Cgb => return,
+      // FF00 Joypad, FF01-FF02 Serial, FF04-FF07 Timer, FF10-FF3F Sound, FF56 CGB Infrared
+      Some(IoRoute::Peripheral(i)) => {
+        self.peripherals_mut()[i].write(address, value);
+        // the Super Game Boy listens in on the joypad register for its packets
+        if address == Self::JOYPAD_ADDRESS && self.model == Model::Sgb {
+          self.sgb.write(value);
+        }
+        return;
+      }
+      None => {}
+    }
+    match address {
+      // FF41        LCD Status
+      Self::STAT_ADDRESS => {
+        let status = self.read(Self::STAT_ADDRESS);
+        self.iom[Self::region_offset(address, Self::IO_START_ADDRESS, Self::IO_SIZE)] = value;
+        self.set_stat_status(status);
+        self.stat_written = self.model.is_dmg();
+      }
+      // FF46        OAM DMA
+      Self::DMA_ADDRESS => {
+        self.iom[Self::region_offset(address, Self::IO_START_ADDRESS, Self::IO_SIZE)] = value;
+        self.dma.start(value);
+      }
+      // FF4D        CGB Speed Switch
+      Self::KEY1_ADDRESS => {
+        // only the prepare bit is writable, the speed changes on STOP
+        let key1 = &mut self.iom[Self::region_offset(address, Self::IO_START_ADDRESS, Self::IO_SIZE)];
+        *key1 = set_bit(*key1 as u16, Self::KEY1_PREPARE_BIT_N, get_bit(value as u16, Self::KEY1_PREPARE_BIT_N)) as u8;
+      }
+      // FF51-FF55   CGB VRAM DMA
+      Self::HDMA_START_ADDRESS..=Self::HDMA_END_ADDRESS => {
+        self.hdma.write(address, value);
+        if self.hdma.mode() == Some(HdmaMode::General) {
+          while self.copy_hdma_block() {}
+        }
+      }
+      // FF68-FF6B   CGB Palettes
+      Self::BCPS_ADDRESS => self.bg_palettes.write_index(value),
+      Self::BCPD_ADDRESS => self.bg_palettes.write_data(value),
+      Self::OCPS_ADDRESS => self.obj_palettes.write_index(value),
+      Self::OCPD_ADDRESS => self.obj_palettes.write_data(value),
+      _ => self.iom[Self::region_offset(address, Self::IO_START_ADDRESS, Self::IO_SIZE)] = value,
+    }
+  }
+
+  /// Bits of the IO register at `address` that always read as 1 whatever was last written, because
+  /// they are write-only or unused
+  fn io_read_mask(address: u16) -> u8 {
+    match address {
+      Self::DMA_ADDRESS | Self::BIOS_DISABLE_REGISTER_ADDRESS => 0xFF,
+      Self::VBK_ADDRESS => !1,
+      Self::SVBK_ADDRESS => !Self::SVBK_BANK_BITS,
+      // only the five interrupt request bits exist
+      Self::INTERRUPT_FLAG_REG_ADDRESS => 0xE0,
+      _ => 0x00,
+    }
+  }
+
+  /// Whether `address` is one of the registers only a CGB has
+  fn is_cgb_register(address: u16) -> bool {
+    matches!(
+      address,
+      Self::KEY1_ADDRESS
+        | Self::VBK_ADDRESS
+        | Self::HDMA_START_ADDRESS..=Self::HDMA_END_ADDRESS
+        | Self::RP_ADDRESS
+        | Self::BCPS_ADDRESS..=Self::OCPD_ADDRESS
+        | Self::SVBK_ADDRESS
+    )
+  }
+
+  /// Whether a CGB has switched to double speed
+  pub fn double_speed(&self) -> bool {
+    let key1 = self.iom[Self::region_offset(Self::KEY1_ADDRESS, Self::IO_START_ADDRESS, Self::IO_SIZE)];
+    get_bit(key1 as u16, Self::KEY1_SPEED_BIT_N)
+  }
+
+  /// Called once the CPU executes STOP, switching speed if a CGB game armed it through KEY1, either
+  /// way DIV is reset
+  ///
+  /// # Returns
+  /// true if the STOP was taken as a speed switch, false if the CPU should stop until a joypad press
+  pub(crate) fn stop(&mut self) -> bool {
+    self.timer.write(Timer::DIV_ADDRESS, 0);
+    let offset = Self::region_offset(Self::KEY1_ADDRESS, Self::IO_START_ADDRESS, Self::IO_SIZE);
+    let key1 = self.iom[offset] as u16;
+    if self.model != Model::Cgb || !get_bit(key1, Self::KEY1_PREPARE_BIT_N) {
+      return false;
+    }
+    let double_speed = get_bit(key1, Self::KEY1_SPEED_BIT_N);
+    let key1 = set_bit(key1, Self::KEY1_SPEED_BIT_N, !double_speed);
+    self.iom[offset] = set_bit(key1, Self::KEY1_PREPARE_BIT_N, false) as u8;
+    true
+  }
+
+  /// Copy the block an HBlank VRAM DMA is due, called by the PPU as it enters HBlank
+  pub(crate) fn hblank(&mut self) {
+    if self.hdma.mode() == Some(HdmaMode::HBlank) {
+      self.copy_hdma_block();
+    }
+  }
+
+  /// Make the GameShark cheats' writes, called by the PPU as it enters VBlank
+  pub(crate) fn vblank(&mut self) {
+    if self.cheats.is_empty() {
+      return;
+    }
+    let writes: Vec<_> = self.cheats.ram_writes().collect();
+    for (bank, address, value) in writes {
+      match bank {
+        Some(bank) if self.model == Model::Cgb => {
+          self.wram[bank * Self::WRAM_BANK_SIZE + (address as usize - Self::SRAM_START_ADDRESS as usize)] = value;
+        }
+        _ => self.write(address, value),
+      }
+    }
+  }
+
+  /// Copy the next block of the VRAM DMA into the current VRAM bank
+  ///
+  /// # Returns
+  /// false if there was nothing left to copy
+  fn copy_hdma_block(&mut self) -> bool {
+    let (source, destination) = match self.hdma.next_block() {
+      Some(block) => block,
+      None => return false,
+    };
+    let bank = self.vram_bank() * Self::VRAM_SIZE;
+    for i in 0..Hdma::BLOCK_SIZE {
+      self.vram[bank + (destination + i) as usize] = self.peek(source.wrapping_add(i));
+    }
+    true
+  }
+
+  /// Set the IF bit for `interrupt`
+  pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+    let flags = self.read(Self::INTERRUPT_FLAG_REG_ADDRESS);
+    self.write(Self::INTERRUPT_FLAG_REG_ADDRESS, flags | interrupt.mask());
+  }
+
+  /// Press or release `button`, requesting the joypad interrupt if that pulls a selected input line low
+  pub fn set_button(&mut self, button: Button, pressed: bool) {
+    if self.joypad.set_button(button, pressed) {
+      self.request_interrupt(Interrupt::Joypad);
+    }
+  }
+
+  /// Advance the peripherals on the bus `n_cycles`, requesting any interrupts they raise
+  pub fn step(&mut self, n_cycles: u8) {
+    let double_speed = self.double_speed();
+    let mut raised = vec![];
+    for peripheral in self.peripherals_mut().iter_mut() {
+      raised.extend(peripheral.tick(n_cycles, double_speed));
+    }
+    for peripheral in self.attached.iter_mut() {
+      raised.extend(peripheral.tick(n_cycles, double_speed));
+    }
+    for interrupt in raised {
+      self.request_interrupt(interrupt);
+    }
+    for offset in self.dma.step(n_cycles) {
+      self.oam[offset as usize] = self.peek(self.dma.address(offset));
+    }
+  }
+}
+
+impl Memory for MMU {
+  fn read(&self, address: u16) -> u8 {
+    if let Some(value) = self.dma_conflict(address) {
+      value
+    } else if self.is_locked_by_ppu(address) {
+      Self::LOCKED_READ_VALUE
+    } else {
+      self.peek(address)
+    }
+  }
+
   fn write(&mut self, address: u16, value: u8) {
+    if self.dma_conflict(address).is_some() || self.is_locked_by_ppu(address) {
+      return;
+    }
     match address {
       // 0000-3FFF   16KB ROM Bank 00     (in cartridge, fixed at bank 00)
-      //    0000-00FF bios
-      Self::BIOS_START_ADDRESS..=Self::BIOS_END_ADDRESS if self.bios_enabled() => {}
       // 4000-7FFF   16KB ROM Bank 01..NN (in cartridge, switchable bank number)
-      Self::CARTRIDGE_START_ADDRESS..=Self::CARTRIDGE_END_ADDRESS => {}
+      // writes to either go to the cartridge's mapper
+      Self::ROM_BANK_0_START_ADDRESS..=Self::ROM_BANK_N_END_ADDRESS => {
+        if let Some(cartridge) = self.cartridge.as_mut() {
+          cartridge.write(address, value);
+        }
+      }
       // 8000-9FFF   8KB Video RAM (VRAM) (switchable bank 0-1 in CGB Mode)
       Self::VRAM_START_ADDRESS..=Self::VRAM_END_ADDRESS => {
-        self.vram[(address - Self::VRAM_START_ADDRESS) as usize] = value;
+        let offset = self.vram_offset(address);
+        self.vram[offset] = value;
       }
       // A000-BFFF   8KB External RAM     (in cartridge, switchable bank, if any)
-      Self::EXTRAM_START_ADDRESS..=Self::EXTRAM_END_ADDRESS => unimplemented!(),
-      // C000-CFFF   4KB Work RAM Bank 0 (WRAM)
-      Self::RAM_START_ADDRESS..=Self::RAM_END_ADDRESS => {
-        self.ram[(address - Self::RAM_START_ADDRESS) as usize] = value;
-      }
-      // D000-DFFF   4KB Work RAM Bank 1 (WRAM)  (switchable bank 1-7 in CGB Mode)
-      Self::SRAM_START_ADDRESS..=Self::SRAM_END_ADDRESS => {
-        self.sram[(address - Self::SRAM_START_ADDRESS) as usize] = value;
-      }
+      Self::EXTRAM_START_ADDRESS..=Self::EXTRAM_END_ADDRESS => match self.cartridge.as_mut() {
+        Some(cartridge) if cartridge.infrared_mapped() => self.infrared.write_cartridge(value),
+        Some(cartridge) => cartridge.write_ram(address - Self::EXTRAM_START_ADDRESS, value),
+        None => {}
+      },
+      // C000-DFFF   8KB Work RAM (WRAM)
       // E000-FDFF   Same as C000-DDFF (ECHO)    (typically not used)
-      Self::ERAM_START_ADDRESS..=Self::ERAM_END_ADDRESS => {
-        self.ram[(address - Self::ERAM_START_ADDRESS) as usize] = value;
+      Self::WRAM_START_ADDRESS..=Self::WRAM_END_ADDRESS | Self::ERAM_START_ADDRESS..=Self::ERAM_END_ADDRESS => {
+        let offset = self.wram_offset(address);
+        self.wram[offset] = value;
       }
       // FE00-FE9F   Sprite Attribute Table (OAM)
       Self::OAM_START_ADDRESS..=Self::OAM_END_ADDRESS => {
-        self.oam[(address - Self::OAM_START_ADDRESS) as usize] = value;
+        self.oam[Self::region_offset(address, Self::OAM_START_ADDRESS, Self::OAM_SIZE)] = value;
       }
       // FEA0-FEFF   Not Usable
       Self::UNUSABLE_START_ADDRESS..=Self::UNUSABLE_END_ADDRESS => {}
       // FF00-FF7F   I/O Ports
-      Self::IO_START_ADDRESS..=Self::IO_END_ADDRESS => {
-        self.iom[(address - Self::IO_START_ADDRESS) as usize] = value;
-      }
+      Self::IO_START_ADDRESS..=Self::IO_END_ADDRESS => self.write_io(address, value),
       // FF80-FFFE   High RAM (HRAM)
       Self::HRAM_START_ADDRESS..=Self::HRAM_END_ADDRESS => {
-        self.hram[(address - Self::HRAM_START_ADDRESS) as usize] = value;
+        self.hram[Self::region_offset(address, Self::HRAM_START_ADDRESS, Self::HRAM_SIZE)] = value;
       }
       // FFFF        Interrupt Enable Register
       Self::INTERRUPT_ENABLE_REG_ADDRESS => self.ie = value,
@@ -215,6 +771,53 @@ impl Memory for MMU {
   }
 }
 
+impl SaveState for MMU {
+  /// Writes everything but the bios and cartridge ROM, which are supplied when the gameboy is created
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(&self.vram[..])?;
+    w.write_all(&self.oam)?;
+    w.write_all(&self.iom)?;
+    w.write_all(&self.wram[..])?;
+    w.write_all(&self.hram)?;
+    write_u8(w, self.ie)?;
+    self.timer.write_state(w)?;
+    self.serial.write_state(w)?;
+    self.infrared.write_state(w)?;
+    self.joypad.write_state(w)?;
+    self.apu.write_state(w)?;
+    self.dma.write_state(w)?;
+    self.hdma.write_state(w)?;
+    self.bg_palettes.write_state(w)?;
+    self.obj_palettes.write_state(w)?;
+    if let Some(cartridge) = self.cartridge.as_ref() {
+      cartridge.write_state(w)?;
+    }
+    Ok(())
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    r.read_exact(&mut self.vram[..])?;
+    r.read_exact(&mut self.oam)?;
+    r.read_exact(&mut self.iom)?;
+    r.read_exact(&mut self.wram[..])?;
+    r.read_exact(&mut self.hram)?;
+    self.ie = read_u8(r)?;
+    self.timer.read_state(r)?;
+    self.serial.read_state(r)?;
+    self.infrared.read_state(r)?;
+    self.joypad.read_state(r)?;
+    self.apu.read_state(r)?;
+    self.dma.read_state(r)?;
+    self.hdma.read_state(r)?;
+    self.bg_palettes.read_state(r)?;
+    self.obj_palettes.read_state(r)?;
+    if let Some(cartridge) = self.cartridge.as_mut() {
+      cartridge.read_state(r)?;
+    }
+    Ok(())
+  }
+}
+
 #[cfg(test)]
 mod test {
   use super::*;
@@ -235,11 +838,9 @@ mod test {
     assert_eq!(mmu.read(MMU::BIOS_END_ADDRESS + 1), cartridge_value);
 
     // but after we disable the bios
-    assert_eq!(mmu.bios_enabled(), true);
-    assert_eq!(mmu.read(MMU::BIOS_DISABLE_REGISTER_ADDRESS), 0x00);
+    assert!(mmu.bios_enabled());
     mmu.write(MMU::BIOS_DISABLE_REGISTER_ADDRESS, 0x01);
-    assert_eq!(mmu.read(MMU::BIOS_DISABLE_REGISTER_ADDRESS), 0x01);
-    assert_eq!(mmu.bios_enabled(), false);
+    assert!(!mmu.bios_enabled());
 
     // we should be reading from the cartridge
     assert_eq!(mmu.read(MMU::BIOS_START_ADDRESS), cartridge_value);
@@ -247,6 +848,25 @@ mod test {
     assert_eq!(mmu.read(MMU::BIOS_END_ADDRESS + 1), cartridge_value);
   }
 
+  #[test]
+  fn cartridge_is_read_past_bios_regardless_of_bios_state() {
+    let cartridge_value = 0x01;
+    let mut mmu = MMU {
+      cartridge: Cartridge::maybe_from_bytes(&[cartridge_value; 0x8000]),
+      bios: [0x02; MMU::BIOS_SIZE],
+      ..MMU::default()
+    };
+
+    assert!(mmu.bios_enabled());
+    assert_eq!(mmu.read(0x0100), cartridge_value);
+    assert_eq!(mmu.read(0x4000), cartridge_value);
+
+    mmu.write(MMU::BIOS_DISABLE_REGISTER_ADDRESS, 0x01);
+    assert!(!mmu.bios_enabled());
+    assert_eq!(mmu.read(0x0100), cartridge_value);
+    assert_eq!(mmu.read(0x4000), cartridge_value);
+  }
+
   #[test]
   fn address_is_read_from_correct_region() {
     let cartridge_value = 0x1;
@@ -254,16 +874,14 @@ mod test {
     let oam_value = 0x3;
     let iom_value = 0x4;
     let ram_value = 0x5;
-    let sram_value = 0x6;
     let hram_value = 0x7;
     let ie_value = 0x8;
     let mmu = MMU {
       cartridge: Cartridge::maybe_from_bytes(&[cartridge_value; 0xFFFF]),
-      vram: [vram_value; MMU::VRAM_SIZE],
+      vram: Box::new([vram_value; MMU::VRAM_SIZE * MMU::VRAM_BANK_COUNT]),
       oam: [oam_value; MMU::OAM_SIZE],
       iom: [iom_value; MMU::IO_SIZE],
-      ram: [ram_value; MMU::RAM_SIZE],
-      sram: [sram_value; MMU::SRAM_SIZE],
+      wram: Box::new([ram_value; MMU::WRAM_BANK_SIZE * MMU::WRAM_BANK_COUNT]),
       hram: [hram_value; MMU::HRAM_SIZE],
       ie: ie_value,
       ..MMU::default()
@@ -274,13 +892,325 @@ mod test {
       assert_eq!(mmu.read(end), value);
     };
 
-    test(cartridge_value, MMU::CARTRIDGE_START_ADDRESS, MMU::CARTRIDGE_END_ADDRESS);
+    test(cartridge_value, MMU::ROM_BANK_0_START_ADDRESS, MMU::ROM_BANK_0_END_ADDRESS);
+    test(cartridge_value, MMU::ROM_BANK_N_START_ADDRESS, MMU::ROM_BANK_N_END_ADDRESS);
     test(vram_value, MMU::VRAM_START_ADDRESS, MMU::VRAM_END_ADDRESS);
     test(oam_value, MMU::OAM_START_ADDRESS, MMU::OAM_END_ADDRESS);
-    test(iom_value, MMU::IO_START_ADDRESS, MMU::IO_END_ADDRESS);
-    test(ram_value, MMU::RAM_START_ADDRESS, MMU::RAM_END_ADDRESS);
-    test(sram_value, MMU::SRAM_START_ADDRESS, MMU::SRAM_END_ADDRESS);
+    // FF00-FF02 are the joypad and serial registers
+    test(iom_value, MMU::IO_START_ADDRESS + 3, MMU::IO_END_ADDRESS);
+    test(ram_value, MMU::WRAM_START_ADDRESS, MMU::WRAM_END_ADDRESS);
+    test(ram_value, MMU::ERAM_START_ADDRESS, MMU::ERAM_END_ADDRESS);
     test(hram_value, MMU::HRAM_START_ADDRESS, MMU::HRAM_END_ADDRESS);
     test(ie_value, MMU::INTERRUPT_ENABLE_REG_ADDRESS, MMU::INTERRUPT_ENABLE_REG_ADDRESS);
   }
+
+  #[test]
+  fn region_offset_is_relative_to_region_start() {
+    assert_eq!(MMU::region_offset(MMU::VRAM_START_ADDRESS, MMU::VRAM_START_ADDRESS, MMU::VRAM_SIZE), 0);
+    assert_eq!(MMU::region_offset(MMU::VRAM_END_ADDRESS, MMU::VRAM_START_ADDRESS, MMU::VRAM_SIZE), MMU::VRAM_SIZE - 1);
+  }
+
+  #[test]
+  #[cfg(debug_assertions)]
+  #[should_panic(expected = "outside of the region")]
+  fn region_offset_past_region_end_trips_assertion() {
+    MMU::region_offset(MMU::VRAM_END_ADDRESS + 1, MMU::VRAM_START_ADDRESS, MMU::VRAM_SIZE);
+  }
+
+  #[test]
+  #[cfg(debug_assertions)]
+  #[should_panic(expected = "outside of the region")]
+  fn region_offset_before_region_start_trips_assertion() {
+    MMU::region_offset(MMU::VRAM_START_ADDRESS - 1, MMU::VRAM_START_ADDRESS, MMU::VRAM_SIZE);
+  }
+
+  fn mmu_in_mode(mode: Mode) -> MMU {
+    let mut mmu = MMU::default();
+    mmu.vram[0] = 0x12;
+    mmu.oam[0] = 0x34;
+    mmu.set_stat_status(mode as u8);
+    mmu
+  }
+
+  #[test]
+  fn ppu_locks_vram_and_oam_by_mode() {
+    let cases = [
+      (Mode::HBlank, 0x12, 0x34),
+      (Mode::VBlank, 0x12, 0x34),
+      (Mode::OamScan, 0x12, 0xFF),
+      (Mode::Drawing, 0xFF, 0xFF),
+    ];
+    for &(mode, vram, oam) in cases.iter() {
+      let mut mmu = mmu_in_mode(mode);
+      assert_eq!(mmu.read(MMU::VRAM_START_ADDRESS), vram, "{:?}", mode);
+      assert_eq!(mmu.read(MMU::OAM_START_ADDRESS), oam, "{:?}", mode);
+      assert_eq!((mmu.peek(MMU::VRAM_START_ADDRESS), mmu.peek(MMU::OAM_START_ADDRESS)), (0x12, 0x34));
+
+      // writes are dropped the same way
+      mmu.write(MMU::VRAM_START_ADDRESS, 0x56);
+      assert_eq!(mmu.vram[0] == 0x56, vram != 0xFF, "{:?}", mode);
+    }
+  }
+
+  #[test]
+  fn write_only_registers_read_as_ff() {
+    let mut mmu = MMU::default();
+    for &address in [MMU::DMA_ADDRESS, MMU::BIOS_DISABLE_REGISTER_ADDRESS, 0xFF13, 0xFF1D].iter() {
+      for &value in [0x00, 0x5A].iter() {
+        mmu.write(address, value);
+        assert_eq!(mmu.read(address), 0xFF, "0x{:04x} after writing 0x{:02x}", address, value);
+      }
+    }
+    assert!(!mmu.bios_enabled());
+
+    // only the write-only bits of NR11 read as 1, the duty bits read back once the APU is on
+    mmu.write(0xFF26, 0x80);
+    mmu.write(0xFF11, 0x80);
+    assert_eq!(mmu.read(0xFF11), 0xBF);
+    // wave RAM reads back whole
+    mmu.write(0xFF30, 0x12);
+    assert_eq!(mmu.read(0xFF30), 0x12);
+  }
+
+  #[test]
+  fn oam_dma_copies_source_and_holds_its_bus() {
+    let mut mmu = MMU::default();
+    for i in 0..Dma::LENGTH {
+      mmu.wram[i as usize] = i as u8 ^ 0x5A;
+    }
+    mmu.vram[0] = 0x12;
+    mmu.hram[0] = 0x34;
+    mmu.write(MMU::DMA_ADDRESS, 0xC0);
+
+    // nothing is held during startup
+    assert_eq!(mmu.read(MMU::OAM_START_ADDRESS), 0x00);
+    mmu.step(8);
+    assert_eq!(mmu.oam[0], 0x5A);
+    // OAM reads FF, the external bus sees the byte being transferred, the rest is free
+    assert_eq!(mmu.read(MMU::OAM_START_ADDRESS), 0xFF);
+    assert_eq!(mmu.read(0x0150), 0x01 ^ 0x5A);
+    assert_eq!(mmu.read(0xD000), 0x01 ^ 0x5A);
+    assert_eq!(mmu.read(MMU::VRAM_START_ADDRESS), 0x12);
+    assert_eq!(mmu.read(MMU::HRAM_START_ADDRESS), 0x34);
+    mmu.write(0xC100, 0x77);
+    mmu.write(MMU::OAM_START_ADDRESS, 0x77);
+    assert_eq!((mmu.wram[0x100], mmu.oam[0]), (0x00, 0x5A));
+
+    for _ in 0..Dma::LENGTH {
+      mmu.step(4);
+    }
+    assert!(!mmu.dma.is_active());
+    assert_eq!(&mmu.oam[..], &mmu.wram[..Dma::LENGTH as usize]);
+    assert_eq!(mmu.read(MMU::OAM_START_ADDRESS), 0x5A);
+  }
+
+  #[test]
+  fn echo_mirrors_both_work_ram_banks() {
+    let mut mmu = MMU::default();
+    mmu.write(0xE123, 0x12);
+    mmu.write(0xF123, 0x34);
+    mmu.write(MMU::ERAM_END_ADDRESS, 0x56);
+    assert_eq!(mmu.read(0xC123), 0x12);
+    assert_eq!(mmu.read(0xD123), 0x34);
+    assert_eq!(mmu.read(0xDDFF), 0x56);
+
+    mmu.write(0xDDFE, 0x78);
+    assert_eq!(mmu.read(0xFDFE), 0x78);
+  }
+
+  #[test]
+  fn cgb_banks_vram_and_work_ram() {
+    let mut mmu = MMU { model: Model::Cgb, ..MMU::default() };
+    mmu.write(0x8000, 0x10);
+    mmu.write(0xD000, 0x20);
+    mmu.write(MMU::VBK_ADDRESS, 0x01);
+    mmu.write(MMU::SVBK_ADDRESS, 0x05);
+    assert_eq!((mmu.read(MMU::VBK_ADDRESS), mmu.read(MMU::SVBK_ADDRESS)), (0xFF, 0xFD));
+    assert_eq!((mmu.read(0x8000), mmu.read(0xD000)), (0x00, 0x00));
+    mmu.write(0x8000, 0x11);
+    mmu.write(0xF000, 0x25);
+    // bank 0 stays at C000 whatever is selected
+    mmu.write(0xC000, 0x30);
+    assert_eq!((mmu.vram[MMU::VRAM_SIZE], mmu.wram[5 * MMU::WRAM_BANK_SIZE]), (0x11, 0x25));
+
+    // bank 0 selects bank 1
+    mmu.write(MMU::VBK_ADDRESS, 0x00);
+    mmu.write(MMU::SVBK_ADDRESS, 0x00);
+    assert_eq!((mmu.read(0x8000), mmu.read(0xD000), mmu.read(0xC000)), (0x10, 0x20, 0x30));
+  }
+
+  /// Counts up every 16 cycles at FF03, and over TIMA, requesting the joypad interrupt each time
+  #[derive(Clone, Default)]
+  struct Counter {
+    value: u8,
+    cycles: u32,
+  }
+
+  impl Memory for Counter {
+    fn read(&self, _address: u16) -> u8 {
+      self.value
+    }
+
+    fn write(&mut self, _address: u16, value: u8) {
+      self.value = value;
+    }
+  }
+
+  impl Peripheral for Counter {
+    fn claims(&self, address: u16) -> bool {
+      address == 0xFF03 || address == Timer::TIMA_ADDRESS
+    }
+
+    fn tick(&mut self, n_cycles: u8, _double_speed: bool) -> Option<Interrupt> {
+      self.cycles += n_cycles as u32;
+      if self.cycles < 16 {
+        return None;
+      }
+      self.cycles -= 16;
+      self.value = self.value.wrapping_add(1);
+      Some(Interrupt::Joypad)
+    }
+  }
+
+  #[test]
+  fn attached_peripherals_take_their_registers_and_are_ticked() {
+    let mut mmu = MMU::default();
+    mmu.attach_peripheral(Counter::default());
+    mmu.write(0xFF03, 0x10);
+    assert_eq!((mmu.read(0xFF03), mmu.read(Timer::TIMA_ADDRESS)), (0x10, 0x10));
+    assert_eq!(mmu.timer.tima, 0x00);
+
+    mmu.write(MMU::INTERRUPT_FLAG_REG_ADDRESS, 0x00);
+    mmu.step(8);
+    mmu.step(8);
+    assert_eq!(mmu.read(0xFF03), 0x11);
+    assert_eq!(mmu.read(MMU::INTERRUPT_FLAG_REG_ADDRESS) & Interrupt::Joypad.mask(), Interrupt::Joypad.mask());
+    // clones get their own copy
+    let mut clone = mmu.clone();
+    clone.step(16);
+    assert_eq!((clone.read(0xFF03), mmu.read(0xFF03)), (0x12, 0x11));
+
+    mmu.detach_peripherals();
+    mmu.timer.tima = 0x42;
+    assert_eq!(mmu.read(Timer::TIMA_ADDRESS), 0x42);
+  }
+
+  #[test]
+  fn huc1_infrared_port_shares_the_transceiver() {
+    let (near, far) = crate::infrared::ir_link();
+    let mut rom = vec![0; 0x8000];
+    rom[0x0147] = 0xFF; // HuC1+RAM+BATTERY
+    rom[0x0149] = 0x02;
+    let mut mmu = MMU { model: Model::Cgb, ..MMU::default() }
+      .with_cartridge(Cartridge::maybe_from_bytes(&rom).unwrap());
+    mmu.infrared.set_transceiver(near);
+    let mut other = MMU { model: Model::Cgb, ..MMU::default() };
+    other.infrared.set_transceiver(far);
+
+    mmu.write(0x0000, 0x0E);
+    assert_eq!(mmu.read(MMU::EXTRAM_START_ADDRESS), 0xC0);
+    other.write(MMU::RP_ADDRESS, 0x01);
+    assert_eq!(mmu.read(MMU::EXTRAM_START_ADDRESS), 0xC1);
+    mmu.write(MMU::EXTRAM_START_ADDRESS, 0x01);
+    other.write(MMU::RP_ADDRESS, 0xC0);
+    assert_eq!(other.read(MMU::RP_ADDRESS), 0xFC);
+
+    // back in RAM mode the LED stays as it was
+    mmu.write(0x0000, 0x0A);
+    mmu.write(MMU::EXTRAM_START_ADDRESS, 0x12);
+    assert_eq!(mmu.read(MMU::EXTRAM_START_ADDRESS), 0x12);
+    assert!(mmu.infrared.led());
+  }
+
+  #[test]
+  fn cgb_registers_are_unmapped_on_dmg() {
+    let mut mmu = MMU::default();
+    mmu.write(MMU::SVBK_ADDRESS, 0x02);
+    mmu.write(MMU::VBK_ADDRESS, 0x01);
+    mmu.write(MMU::BCPS_ADDRESS, 0x80);
+    mmu.write(MMU::BCPD_ADDRESS, 0x00);
+    mmu.write(0xD000, 0x12);
+    assert_eq!(mmu.wram[MMU::WRAM_BANK_SIZE], 0x12);
+    for &address in [MMU::VBK_ADDRESS, MMU::SVBK_ADDRESS, MMU::BCPS_ADDRESS, MMU::HDMA_END_ADDRESS, MMU::RP_ADDRESS].iter() {
+      assert_eq!(mmu.read(address), 0xFF, "0x{:04x}", address);
+    }
+    assert_eq!(mmu.bg_palettes.rgb555(0, 0), 0xFFFF);
+  }
+
+  #[test]
+  fn vram_dma_copies_into_the_current_bank() {
+    let mut mmu = MMU { model: Model::Cgb, ..MMU::default() };
+    for i in 0..0x40 {
+      mmu.wram[i] = i as u8;
+    }
+    mmu.write(MMU::VBK_ADDRESS, 0x01);
+    mmu.write(Hdma::HDMA1_ADDRESS, 0xC0);
+    mmu.write(Hdma::HDMA2_ADDRESS, 0x00);
+    mmu.write(Hdma::HDMA3_ADDRESS, 0x01);
+    mmu.write(Hdma::HDMA4_ADDRESS, 0x00);
+
+    // a general purpose transfer copies everything straight away
+    mmu.write(Hdma::HDMA5_ADDRESS, 0x01);
+    assert_eq!(&mmu.vram[MMU::VRAM_SIZE + 0x100..MMU::VRAM_SIZE + 0x120], &mmu.wram[..0x20]);
+    assert_eq!(mmu.read(Hdma::HDMA5_ADDRESS), 0xFF);
+
+    // an HBlank transfer copies a block each HBlank, carrying on from where the last left off
+    mmu.write(Hdma::HDMA5_ADDRESS, 0x80);
+    assert_eq!(mmu.vram[MMU::VRAM_SIZE + 0x120], 0x00);
+    mmu.hblank();
+    assert_eq!(&mmu.vram[MMU::VRAM_SIZE + 0x120..MMU::VRAM_SIZE + 0x130], &mmu.wram[0x20..0x30]);
+    assert_eq!(mmu.read(Hdma::HDMA5_ADDRESS), 0xFF);
+  }
+
+  #[test]
+  fn interrupt_flag_unused_bits_read_as_set() {
+    let mut mmu = MMU::default();
+    mmu.write(MMU::INTERRUPT_FLAG_REG_ADDRESS, 0x00);
+    assert_eq!(mmu.read(MMU::INTERRUPT_FLAG_REG_ADDRESS), 0xE0);
+    mmu.request_interrupt(Interrupt::Timer);
+    assert_eq!(mmu.read(MMU::INTERRUPT_FLAG_REG_ADDRESS), 0xE4);
+  }
+
+  #[test]
+  fn injected_peripherals_are_routed_to() {
+    let mut timer = Timer::default();
+    timer.tima = 0xAB;
+    let mut serial = Serial::default();
+    let sent = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let callback_sent = sent.clone();
+    serial.set_callback(move |x| callback_sent.lock().unwrap().push(x));
+    let mut mmu = MMU::default().with_timer(timer).with_serial(serial);
+
+    assert_eq!(mmu.read(Timer::TIMA_ADDRESS), 0xAB);
+    mmu.write(Timer::TIMA_ADDRESS, 0xCD);
+    assert_eq!(mmu.timer.tima, 0xCD);
+
+    mmu.write(Serial::SB_ADDRESS, 0x42);
+    mmu.write(Serial::SC_ADDRESS, 0x81);
+    for _ in 0..32 {
+      mmu.step(128);
+    }
+    assert_eq!(*sent.lock().unwrap(), [0x42]);
+  }
+
+  #[test]
+  fn post_boot_io_registers() {
+    let mut mmu = MMU::default();
+    mmu.post_boot_io();
+    let expected = [
+      (0xFF00, 0xCF),
+      (0xFF02, 0x7E),
+      (0xFF04, 0xAB),
+      (0xFF07, 0xF8),
+      (0xFF0F, 0xE1),
+      (0xFF10, 0x80),
+      (0xFF26, 0xF1),
+      (0xFF40, 0x91),
+      (0xFF47, 0xFC),
+    ];
+    for &(address, value) in expected.iter() {
+      assert_eq!(mmu.read(address), value, "0x{:04x}", address);
+    }
+    assert!(!mmu.bios_enabled());
+  }
 }
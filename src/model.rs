@@ -0,0 +1,19 @@
+/// The hardware revision being emulated, for behaviour that differs between them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Model {
+  /// The original Game Boy
+  #[default]
+  Dmg,
+  /// A Game Boy running in the Super Game Boy, which takes commands over the joypad register
+  Sgb,
+  /// The Game Boy Color
+  Cgb,
+}
+
+impl Model {
+  /// Whether this model is built around the original Game Boy hardware, sharing its quirks
+  pub fn is_dmg(self) -> bool {
+    matches!(self, Model::Dmg | Model::Sgb)
+  }
+}
@@ -0,0 +1,215 @@
+use std::{
+  thread,
+  time::{Duration, Instant},
+};
+
+/// How fast emulation runs relative to the real hardware
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+  /// Real time scaled by a factor, 2.0 runs twice as fast and 0.5 in slow motion at half speed
+  Multiplier(f64),
+  /// As fast as the host can emulate
+  Uncapped,
+}
+
+impl Default for Speed {
+  fn default() -> Self {
+    Speed::Multiplier(1.0)
+  }
+}
+
+/// Pacing by the host's audio queue, see `FramePacer::sync_to_audio`
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct AudioSync {
+  sample_rate: u32,
+  /// How much queued audio to keep ahead of the host
+  latency: Duration,
+}
+
+/// Keeps a frontend running frames at the speed of the real hardware, or a multiple of it
+///
+/// Call `wait` once a frame has been emulated. Holding a turbo key is modelled by `toggle_turbo`, which
+/// swaps between the normal speed and the turbo speed.
+///
+/// A frontend playing the sound can instead call `wait_for_audio` with the samples it has queued, so
+/// the host's audio clock sets the pace and the queue neither runs dry nor grows without bound.
+#[derive(Debug, Clone)]
+pub struct FramePacer {
+  speed: Speed,
+  turbo_speed: Speed,
+  turbo: bool,
+  /// When the last frame finished, None before the first
+  last_frame: Option<Instant>,
+  audio: Option<AudioSync>,
+}
+
+impl Default for FramePacer {
+  fn default() -> Self {
+    Self {
+      speed: Speed::default(),
+      turbo_speed: Speed::Uncapped,
+      turbo: false,
+      last_frame: None,
+      audio: None,
+    }
+  }
+}
+
+impl FramePacer {
+  /// Cycles in one frame, 154 lines of 456 dots
+  pub const FRAME_CYCLES: u32 = 70224;
+  pub const CPU_CLOCK_HZ: u32 = 4_194_304;
+
+  /// Run at `speed` while turbo is off
+  ///
+  /// # Panics
+  /// if a multiplier isn't positive
+  pub fn set_speed(&mut self, speed: Speed) {
+    Self::check(speed);
+    self.speed = speed;
+  }
+
+  /// Run at `speed` while turbo is on, uncapped by default
+  ///
+  /// # Panics
+  /// if a multiplier isn't positive
+  pub fn set_turbo_speed(&mut self, speed: Speed) {
+    Self::check(speed);
+    self.turbo_speed = speed;
+  }
+
+  fn check(speed: Speed) {
+    if let Speed::Multiplier(multiplier) = speed {
+      assert!(multiplier > 0.0, "speed multiplier {} isn't positive", multiplier);
+    }
+  }
+
+  pub fn toggle_turbo(&mut self) {
+    self.turbo = !self.turbo;
+  }
+
+  pub fn turbo(&self) -> bool {
+    self.turbo
+  }
+
+  /// The speed frames are currently paced at
+  pub fn current_speed(&self) -> Speed {
+    if self.turbo { self.turbo_speed } else { self.speed }
+  }
+
+  /// How long a frame lasts on the real hardware
+  pub fn frame_duration() -> Duration {
+    Duration::from_secs_f64(Self::FRAME_CYCLES as f64 / Self::CPU_CLOCK_HZ as f64)
+  }
+
+  /// How long to sleep after a frame that took `elapsed` to emulate
+  pub fn sleep_duration(&self, elapsed: Duration) -> Duration {
+    match self.current_speed() {
+      Speed::Uncapped => Duration::ZERO,
+      Speed::Multiplier(multiplier) => Self::frame_duration().div_f64(multiplier).saturating_sub(elapsed),
+    }
+  }
+
+  /// Sleep out the rest of the frame that started when the last call returned
+  pub fn wait(&mut self) {
+    if let Some(last_frame) = self.last_frame {
+      thread::sleep(self.sleep_duration(last_frame.elapsed()));
+    }
+    self.last_frame = Some(Instant::now());
+  }
+
+  /// Pace `wait_for_audio` by the audio queue, keeping `latency` of sound queued for a host playing
+  /// `sample_rate` stereo samples per second
+  ///
+  /// The APU should produce samples at the same rate, see `Gameboy::set_audio_sample_rate`.
+  pub fn sync_to_audio(&mut self, sample_rate: u32, latency: Duration) {
+    self.audio = Some(AudioSync { sample_rate, latency });
+  }
+
+  /// Pace `wait_for_audio` by the clock, the same as `wait`
+  pub fn sync_to_clock(&mut self) {
+    self.audio = None;
+  }
+
+  /// How long to sleep with `queued` interleaved samples waiting to be played, for the queue to drain
+  /// down to the latency
+  ///
+  /// # Returns
+  /// `None` unless syncing to audio at normal speed, as sped up or slowed down sound can't set the pace
+  pub fn audio_sleep_duration(&self, queued: usize) -> Option<Duration> {
+    let audio = self.audio?;
+    if self.current_speed() != Speed::Multiplier(1.0) || audio.sample_rate == 0 {
+      return None;
+    }
+    let queued = Duration::from_secs_f64(queued as f64 / 2.0 / audio.sample_rate as f64);
+    Some(queued.saturating_sub(audio.latency))
+  }
+
+  /// Sleep until the `queued` interleaved samples drain down to the latency if syncing to audio,
+  /// otherwise `wait`
+  pub fn wait_for_audio(&mut self, queued: usize) {
+    match self.audio_sleep_duration(queued) {
+      Some(duration) => {
+        thread::sleep(duration);
+        self.last_frame = Some(Instant::now());
+      }
+      None => self.wait(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn sleep_scales_with_speed() {
+    let mut pacer = FramePacer::default();
+    let normal = pacer.sleep_duration(Duration::ZERO);
+    assert_eq!(normal, FramePacer::frame_duration());
+
+    pacer.set_speed(Speed::Multiplier(2.0));
+    assert_eq!(pacer.sleep_duration(Duration::ZERO), normal / 2);
+    // time spent emulating comes off the sleep
+    assert_eq!(pacer.sleep_duration(Duration::from_millis(5)), normal / 2 - Duration::from_millis(5));
+    assert_eq!(pacer.sleep_duration(normal), Duration::ZERO);
+
+    pacer.set_speed(Speed::Uncapped);
+    assert_eq!(pacer.sleep_duration(Duration::ZERO), Duration::ZERO);
+  }
+
+  #[test]
+  fn turbo_toggles_between_speeds() {
+    let mut pacer = FramePacer::default();
+    pacer.toggle_turbo();
+    assert_eq!(pacer.current_speed(), Speed::Uncapped);
+    assert_eq!(pacer.sleep_duration(Duration::ZERO), Duration::ZERO);
+
+    pacer.set_turbo_speed(Speed::Multiplier(4.0));
+    assert_eq!(pacer.sleep_duration(Duration::ZERO), FramePacer::frame_duration() / 4);
+    pacer.toggle_turbo();
+    assert_eq!(pacer.current_speed(), Speed::Multiplier(1.0));
+  }
+
+  #[test]
+  fn audio_sync_sleeps_off_queue_above_latency() {
+    let mut pacer = FramePacer::default();
+    assert_eq!(pacer.audio_sleep_duration(44100), None);
+
+    pacer.sync_to_audio(44100, Duration::from_millis(50));
+    // 100ms of stereo audio queued
+    assert_eq!(pacer.audio_sleep_duration(8820), Some(Duration::from_millis(50)));
+    assert_eq!(pacer.audio_sleep_duration(1000), Some(Duration::ZERO));
+
+    // fast forward and slow motion go back to the clock
+    pacer.toggle_turbo();
+    assert_eq!(pacer.audio_sleep_duration(8820), None);
+    pacer.toggle_turbo();
+    pacer.set_speed(Speed::Multiplier(0.5));
+    assert_eq!(pacer.audio_sleep_duration(8820), None);
+
+    pacer.set_speed(Speed::default());
+    pacer.sync_to_clock();
+    assert_eq!(pacer.audio_sleep_duration(8820), None);
+  }
+}
@@ -0,0 +1,116 @@
+use {
+  crate::{state::*, util::get_bit},
+  std::io::{self, Read, Write},
+};
+
+/// One of the CGB's color palette RAMs, eight palettes of four RGB555 colors
+///
+/// The RAM is reached a byte at a time through an index register (BCPS or OCPS) and a data register
+/// (BCPD or OCPD). Bits 0-5 of the index pick the byte, and with bit 7 set every write to the data
+/// register moves it on to the next one.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ColorPalettes {
+  index: u8,
+  #[cfg_attr(feature = "serde", serde(with = "crate::state::byte_array"))]
+  data: [u8; ColorPalettes::SIZE],
+}
+
+impl Default for ColorPalettes {
+  /// Every color white, as the CGB bios leaves the background palettes
+  fn default() -> Self {
+    Self { index: 0, data: [0xFF; Self::SIZE] }
+  }
+}
+
+impl ColorPalettes {
+  pub const SIZE: usize = 64;
+
+  const INDEX_BITS: u8              = 0b0011_1111;
+  const INDEX_UNUSED_BITS: u8       = 0b0100_0000;
+  const INDEX_INCREMENT_BIT_N: u8   = 7;
+  const PALETTE_BITS: u8            = 0b0000_0111;
+  const BYTES_PER_PALETTE: usize    = 8;
+  const BYTES_PER_COLOR: usize      = 2;
+
+  pub fn read_index(&self) -> u8 {
+    self.index | Self::INDEX_UNUSED_BITS
+  }
+
+  pub fn write_index(&mut self, value: u8) {
+    self.index = value & !Self::INDEX_UNUSED_BITS;
+  }
+
+  pub fn read_data(&self) -> u8 {
+    self.data[(self.index & Self::INDEX_BITS) as usize]
+  }
+
+  /// Write the byte the index points at, moving the index on if auto increment is set
+  pub fn write_data(&mut self, value: u8) {
+    self.data[(self.index & Self::INDEX_BITS) as usize] = value;
+    if get_bit(self.index as u16, Self::INDEX_INCREMENT_BIT_N) {
+      self.index = (self.index & !Self::INDEX_BITS) | (self.index.wrapping_add(1) & Self::INDEX_BITS);
+    }
+  }
+
+  /// Color `color` (0-3) of palette `palette` (0-7) as RGB555, red in the lowest five bits
+  pub fn rgb555(&self, palette: u8, color: u8) -> u16 {
+    let offset = (palette & Self::PALETTE_BITS) as usize * Self::BYTES_PER_PALETTE
+      + (color & 0b11) as usize * Self::BYTES_PER_COLOR;
+    u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+  }
+}
+
+/// An RGB555 color as RGBA8888, each five bit channel scaled up to fill eight bits
+pub fn rgb555_to_rgba(color: u16) -> [u8; 4] {
+  let channel = |shift: u16| {
+    let value = ((color >> shift) & 0x1F) as u8;
+    (value << 3) | (value >> 2)
+  };
+  [channel(0), channel(5), channel(10), 0xFF]
+}
+
+impl SaveState for ColorPalettes {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    write_u8(w, self.index)?;
+    w.write_all(&self.data)
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    self.index = read_u8(r)?;
+    r.read_exact(&mut self.data)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn data_writes_auto_increment_within_the_ram() {
+    let mut palettes = ColorPalettes::default();
+    // palette 7 color 3, the last two bytes
+    palettes.write_index(0x80 | 0x3E);
+    palettes.write_data(0x1F);
+    palettes.write_data(0x7C);
+    assert_eq!(palettes.rgb555(7, 3), 0x7C1F);
+    // wraps back to the first byte, keeping auto increment set
+    assert_eq!(palettes.read_index(), 0xC0);
+    palettes.write_data(0x00);
+    assert_eq!(palettes.rgb555(0, 0), 0xFF00);
+
+    // without auto increment the index stays put
+    palettes.write_index(0x02);
+    palettes.write_data(0x12);
+    palettes.write_data(0x34);
+    assert_eq!((palettes.read_index(), palettes.read_data()), (0x42, 0x34));
+  }
+
+  #[test]
+  fn rgb555_channels_scale_to_full_range() {
+    assert_eq!(rgb555_to_rgba(0x7FFF), [0xFF, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(rgb555_to_rgba(0x001F), [0xFF, 0x00, 0x00, 0xFF]);
+    assert_eq!(rgb555_to_rgba(0x03E0), [0x00, 0xFF, 0x00, 0xFF]);
+    assert_eq!(rgb555_to_rgba(0x4000), [0x00, 0x00, 0x84, 0xFF]);
+  }
+}
@@ -0,0 +1,49 @@
+use crate::{interrupt::Interrupt, util::Memory};
+
+/// A device on the memory bus, answering reads and writes of the IO registers it claims
+///
+/// The MMU routes its IO registers through the peripherals registered with it: the joypad, serial
+/// port, timer, APU and infrared port. A frontend can attach its own with `MMU::attach_peripheral`,
+/// e.g. to emulate extra hardware or watch a register, which then take precedence over the others.
+/// Cloning the machine clones its attached peripherals too, so deriving `Clone` is enough to get
+/// `PeripheralClone`.
+pub trait Peripheral: Memory + PeripheralClone {
+  /// Whether the IO register at `address`, FF00-FF7F, is the peripheral's, routing reads and writes
+  /// of it here
+  ///
+  /// Asked once as the peripheral is registered, so the answer mustn't change.
+  fn claims(&self, address: u16) -> bool;
+
+  /// Advance the peripheral `n_cycles`, counted at double speed if `double_speed` is set
+  ///
+  /// # Returns
+  /// the interrupt to request, if the peripheral raised one
+  fn tick(&mut self, _n_cycles: u8, _double_speed: bool) -> Option<Interrupt> {
+    None
+  }
+
+  /// Cycles until `tick` next raises an interrupt, or `None` if it won't
+  ///
+  /// A halted CPU is fast forwarded to the nearest interrupt, so a peripheral raising them has to say
+  /// when for them to wake it in `Gameboy::run_cycles`.
+  fn cycles_until_interrupt(&self) -> Option<u32> {
+    None
+  }
+}
+
+/// Clones an attached peripheral for a clone of the machine, implemented for every `Clone` peripheral
+pub trait PeripheralClone {
+  fn box_clone(&self) -> Box<dyn Peripheral + Send>;
+}
+
+impl<T: Peripheral + Clone + Send + 'static> PeripheralClone for T {
+  fn box_clone(&self) -> Box<dyn Peripheral + Send> {
+    Box::new(self.clone())
+  }
+}
+
+impl Clone for Box<dyn Peripheral + Send> {
+  fn clone(&self) -> Self {
+    self.box_clone()
+  }
+}
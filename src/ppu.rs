@@ -1,18 +1,1530 @@
-
 use {
-  crate::mmu::MMU,
+  crate::{
+    interrupt::{Interrupt, InterruptSet},
+    mmu::MMU,
+    model::Model,
+    palette::rgb555_to_rgba,
+    state::*,
+    util::*,
+  },
+  derivative::Derivative,
+  std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+  },
 };
 
+/// The mode the PPU is in, as reported in the lower bits of STAT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Mode {
+  HBlank = 0,
+  VBlank = 1,
+  OamScan = 2,
+  Drawing = 3,
+}
 
-/// A pixel processing unit
+/// One of the tile maps, chosen the way LCDC chooses it for the background or the window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BgMap {
+  Background,
+  Window,
+}
+
+/// How the PPU turns VRAM into pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Renderer {
+  /// Draw each line in one go on entering HBlank, fast but blind to register writes made during the line
+  #[default]
+  Scanline,
+  /// Shift pixels out dot by dot through background and sprite FIFOs like the hardware does in mode 3
+  Fifo,
+}
+
+/// A shade of the DMG's screen, what a color index comes out as through BGP, OBP0 or OBP1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+  White = 0,
+  LightGray = 1,
+  DarkGray = 2,
+  Black = 3,
+}
+
+impl Color {
+  /// The color of shade `shade` (0-3) as the palettes produce it, only the lower two bits are used
+  pub fn from_shade(shade: u8) -> Color {
+    match shade & 0b11 {
+      0 => Color::White,
+      1 => Color::LightGray,
+      2 => Color::DarkGray,
+      _ => Color::Black,
+    }
+  }
+
+  /// The color as gray RGBA8888
+  pub fn rgba(self) -> [u8; 4] {
+    let level = 0xFF - 0x55 * self as u8;
+    [level, level, level, 0xFF]
+  }
+}
+
+/// A view of the PPU's framebuffer as colors, `Frame::WIDTH` by `Frame::HEIGHT` in row major order
+#[derive(Debug, Clone, Copy)]
+pub struct Frame<'a> {
+  shades: &'a [u8],
+  /// RGB555 colors of a frame drawn in CGB mode
+  colors: Option<&'a [u16]>,
+}
+
+impl<'a> Frame<'a> {
+  pub const WIDTH: usize  = PPU::SCREEN_WIDTH;
+  pub const HEIGHT: usize = PPU::SCREEN_HEIGHT;
+
+  /// The color of pixel (`x`, `y`), counted from the top left
+  ///
+  /// In CGB mode this is the pixel's color index in its palette, see `rgb555` for its actual color.
+  ///
+  /// # Panics
+  /// if the pixel is off the screen
+  pub fn pixel(&self, x: usize, y: usize) -> Color {
+    assert!(x < Self::WIDTH && y < Self::HEIGHT, "pixel ({}, {}) is off the screen", x, y);
+    Color::from_shade(self.shades[y * Self::WIDTH + x])
+  }
+
+  /// The RGB555 color of pixel (`x`, `y`) if the frame was drawn in CGB mode
+  ///
+  /// # Panics
+  /// if the pixel is off the screen
+  pub fn rgb555(&self, x: usize, y: usize) -> Option<u16> {
+    assert!(x < Self::WIDTH && y < Self::HEIGHT, "pixel ({}, {}) is off the screen", x, y);
+    self.colors.map(|colors| colors[y * Self::WIDTH + x])
+  }
+
+  /// Every pixel, row major
+  pub fn pixels(&self) -> impl Iterator<Item = Color> + 'a {
+    self.shades.iter().map(|&shade| Color::from_shade(shade))
+  }
+
+  /// The frame as RGBA8888, 4 bytes a pixel row major, ready to upload as a texture
+  ///
+  /// Gray unless the frame was drawn in CGB mode.
+  pub fn to_rgba8888(&self) -> Vec<u8> {
+    match self.colors {
+      Some(colors) => colors.iter().flat_map(|&color| rgb555_to_rgba(color).to_vec()).collect(),
+      None => self.pixels().flat_map(|color| color.rgba().to_vec()).collect(),
+    }
+  }
+}
+
+/// An entry of the sprite attribute table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Sprite {
+  /// Position of the entry in OAM
+  pub index: u8,
+  /// Vertical position plus 16
+  pub y: u8,
+  /// Horizontal position plus 8
+  pub x: u8,
+  pub tile: u8,
+  pub flags: u8,
+}
+
+impl Sprite {
+  const BG_PRIORITY_BIT_N: u8 = 7;
+  const Y_FLIP_BIT_N: u8      = 6;
+  const X_FLIP_BIT_N: u8      = 5;
+  const PALETTE_BIT_N: u8     = 4;
+  /// CGB only, the VRAM bank of the tile
+  const BANK_BIT_N: u8        = 3;
+  /// CGB only, the palette in OCPD
+  const CGB_PALETTE_BITS: u8  = 0b0000_0111;
+
+  fn flag(&self, n: u8) -> bool {
+    get_bit(self.flags as u16, n)
+  }
+}
+
+/// A background or window pixel waiting to be drawn
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct BgPixel {
+  color: u8,
+  /// CGB only, the palette in BCPD from the tile's attributes
+  palette: u8,
+  /// CGB only, set by the tile's attributes to draw it over sprites
+  priority: bool,
+}
+
+/// A sprite pixel waiting to be drawn
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct ObjPixel {
+  /// Color index, 0 is transparent
+  color: u8,
+  /// OBP0 or OBP1 as 0 or 1 on DMG, the palette in OCPD on CGB
+  palette: u8,
+  bg_priority: bool,
+  /// Position of the sprite in OAM, which decides between overlapping sprites on CGB
+  index: u8,
+}
+
+/// The parts of a background or window tile the fetcher has read, each on its own step
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct FetchedTile {
+  number: u8,
+  /// CGB attributes from VRAM bank 1, 0 on DMG
+  attributes: u8,
+  /// Row of the tile on this line, after any vertical flip
+  row: u8,
+  low: u8,
+  high: u8,
+}
+
+/// State of the pixel FIFO renderer for the line being drawn
 #[derive(Debug, Clone, Default)]
-pub struct PPU {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct PixelFifo {
+  bg: VecDeque<BgPixel>,
+  obj: VecDeque<ObjPixel>,
+  /// Tile column the fetcher pushes next, counted from the left of the line or the window
+  fetcher_x: u8,
+  /// Dots spent fetching the next tile
+  fetch_dots: u8,
+  /// What the fetcher has read of the next tile so far
+  tile: FetchedTile,
+  /// Background pixels still to drop for fine scroll
+  discard: u8,
+  /// Dots left before pixels shift out again after fetching a sprite
+  stall: u8,
+  /// Pixels shifted out to the screen so far
+  x: u8,
+  /// Set once the window has started on this line
+  window: bool,
+  /// Bit n is set once `line_sprites[n]` has been fetched
+  fetched_sprites: u16,
+}
 
+/// A pixel processing unit
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PPU {
+  /// Dots elapsed on the current line
+  dots: u16,
+  mode: Mode,
+  /// Set when a frame completes, cleared by `take_frame`
+  frame_ready: bool,
+  /// Frames completed since power on, counted at the start of each VBlank
+  elapsed_frames: u64,
+  /// Whether the LCD was on when last stepped, to catch it being switched on or off
+  lcd_on: bool,
+  /// Set while drawing the shortened first line after the LCD is switched on
+  first_line: bool,
+  /// The STAT interrupt is requested when this goes high, see `update_stat`
+  stat_line: bool,
+  /// Sprites selected by the OAM scan of the current line, in OAM order
+  line_sprites: Vec<Sprite>,
+  /// Row of the window drawn next, only advanced on lines the window was drawn on
+  window_line: u8,
+  /// Set once LY has matched WY this frame, the window can't show before then
+  window_y_reached: bool,
+  /// Shade indices of the frame being drawn, row major, or color indices in CGB mode
+  #[derivative(Debug = "ignore")]
+  framebuffer: Vec<u8>,
+  /// RGB555 colors of the frame being drawn in CGB mode, empty until a CGB line is drawn
+  #[derivative(Debug = "ignore")]
+  colors: Vec<u16>,
+  renderer: Renderer,
+  #[derivative(Debug = "ignore")]
+  fifo: PixelFifo,
 }
 
+impl Default for PPU {
+  fn default() -> Self {
+    Self {
+      dots: 0,
+      mode: Mode::OamScan,
+      frame_ready: false,
+      elapsed_frames: 0,
+      lcd_on: false,
+      first_line: false,
+      stat_line: false,
+      line_sprites: Vec::with_capacity(Self::MAX_SPRITES_PER_LINE),
+      window_line: 0,
+      window_y_reached: false,
+      framebuffer: vec![0; Self::SCREEN_WIDTH * Self::SCREEN_HEIGHT],
+      colors: Vec::new(),
+      renderer: Renderer::default(),
+      fifo: PixelFifo::default(),
+    }
+  }
+}
 
 impl PPU {
+  pub const SCREEN_WIDTH: usize  = 160;
+  pub const SCREEN_HEIGHT: usize = 144;
+
+  pub const LCDC_ADDRESS: u16 = 0xFF40;
+  pub const STAT_ADDRESS: u16 = 0xFF41;
+  pub const SCY_ADDRESS: u16  = 0xFF42;
+  pub const SCX_ADDRESS: u16  = 0xFF43;
+  pub const LY_ADDRESS: u16   = 0xFF44;
+  pub const LYC_ADDRESS: u16  = 0xFF45;
+
+  pub const BGP_ADDRESS: u16  = 0xFF47;
+  pub const OBP0_ADDRESS: u16 = 0xFF48;
+  pub const OBP1_ADDRESS: u16 = 0xFF49;
+  pub const WY_ADDRESS: u16   = 0xFF4A;
+  pub const WX_ADDRESS: u16   = 0xFF4B;
+
+  const LCDC_ENABLE_BIT_N: u8           = 7;
+  const LCDC_WINDOW_MAP_BIT_N: u8       = 6;
+  const LCDC_WINDOW_ENABLE_BIT_N: u8    = 5;
+  const LCDC_TILE_DATA_BIT_N: u8        = 4;
+  const LCDC_BG_MAP_BIT_N: u8           = 3;
+  const LCDC_BG_WINDOW_ENABLE_BIT_N: u8 = 0;
+  const LCDC_OBJ_SIZE_BIT_N: u8         = 2;
+  const LCDC_OBJ_ENABLE_BIT_N: u8       = 1;
+  const STAT_COINCIDENCE_BIT_N: u8      = 2;
+  const STAT_HBLANK_SOURCE_BIT_N: u8    = 3;
+  const STAT_VBLANK_SOURCE_BIT_N: u8    = 4;
+  const STAT_OAM_SOURCE_BIT_N: u8       = 5;
+  const STAT_LYC_SOURCE_BIT_N: u8       = 6;
+  const STAT_SOURCE_BITS: u8            = 0b0111_1000;
+
+  pub const MAX_SPRITES_PER_LINE: usize = 10;
+  const SPRITE_COUNT: usize             = 40;
+  const SPRITE_ATTRIBUTE_SIZE: usize    = 4;
+  const SPRITE_WIDTH: u8                = 8;
+  const SPRITE_Y_OFFSET: u8             = 16;
+  const SPRITE_X_OFFSET: u8             = 8;
+  const TILE_SIZE: usize                = 16;
+  const WINDOW_X_OFFSET: u8             = 7;
+
+  /// Offsets of the two tile maps and the signed tile data base into VRAM
+  const TILE_MAP_0_OFFSET: usize       = 0x1800;
+  const TILE_MAP_1_OFFSET: usize       = 0x1C00;
+  const TILE_MAP_WIDTH: usize          = 32;
+  const SIGNED_TILE_DATA_OFFSET: isize = 0x1000;
+
+  /// Bits of the CGB tile attributes kept in VRAM bank 1 at the same offset as the tile number
+  const ATTRIBUTE_PALETTE_BITS: u8   = 0b0000_0111;
+  const ATTRIBUTE_BANK_BIT_N: u8     = 3;
+  const ATTRIBUTE_X_FLIP_BIT_N: u8   = 5;
+  const ATTRIBUTE_Y_FLIP_BIT_N: u8   = 6;
+  const ATTRIBUTE_PRIORITY_BIT_N: u8 = 7;
+
+  /// Dots into a tile fetch at which the fetcher reads the tile number, then the low and high bytes
+  /// of its row, taking all three to fetch a tile
+  const FETCH_NUMBER_DOTS: u8 = 2;
+  const FETCH_LOW_DOTS: u8    = 4;
+  const TILE_FETCH_DOTS: u8   = 6;
+  /// Dots pixels stop shifting out for while a sprite is fetched
+  const SPRITE_FETCH_DOTS: u8 = 6;
+  /// The fetcher only pushes a tile once the background FIFO has room for it
+  const FIFO_PUSH_THRESHOLD: usize = 8;
+
+  const OAM_SCAN_DOTS: u16    = 80;
+  const DRAWING_DOTS: u16     = 172;
+  const LINE_DOTS: u16        = 456;
+  const LINES_PER_FRAME: u8   = 154;
+  const WHITE_RGB555: u16 = 0x7FFF;
+  /// The first line after the LCD is switched on is this many dots shorter than the rest
+  const FIRST_LINE_SHORTENED_DOTS: u16 = 4;
+
+  /// Advance `n_cycles` of the CPU's clock, which is a dot each at normal speed and half a dot at
+  /// CGB double speed
   pub fn step(&mut self, mmu: &mut MMU, n_cycles: u8) {
+    let enabled = get_bit(mmu.read(Self::LCDC_ADDRESS) as u16, Self::LCDC_ENABLE_BIT_N);
+    if enabled != self.lcd_on {
+      self.switch_lcd(mmu, enabled);
+    }
+    // writing STAT on DMG enables every source for an instant, which can raise a spurious interrupt
+    if mmu.take_stat_write() && enabled && !self.stat_line && self.stat_condition(mmu, Self::STAT_SOURCE_BITS) {
+      mmu.request_interrupt(Interrupt::LcdStat);
+    }
+    if !enabled {
+      return;
+    }
+    let n_dots = if mmu.double_speed() { n_cycles / 2 } else { n_cycles };
+    for _ in 0..n_dots {
+      self.tick(mmu);
+    }
+  }
+
+  /// Restart from the top of the screen when the LCD is switched on or off
+  ///
+  /// Switching on starts a shortened first line which skips the OAM scan, reporting HBlank in its place.
+  /// Switching off blanks the screen to white, which it stays until the first frame after switching on.
+  fn switch_lcd(&mut self, mmu: &mut MMU, enabled: bool) {
+    if !enabled {
+      self.framebuffer.iter_mut().for_each(|shade| *shade = 0);
+      self.colors.iter_mut().for_each(|color| *color = Self::WHITE_RGB555);
+    }
+    self.lcd_on = enabled;
+    self.first_line = enabled;
+    self.window_line = 0;
+    self.window_y_reached = false;
+    self.dots = 0;
+    self.mode = Mode::HBlank;
+    self.stat_line = false;
+    mmu.write(Self::LY_ADDRESS, 0);
+    mmu.set_stat_status(self.mode as u8);
+  }
+
+  /// Returns true if any of the STAT interrupt sources in `sources` currently holds
+  fn stat_condition(&self, mmu: &MMU, sources: u8) -> bool {
+    let enabled = |n| get_bit(sources as u16, n);
+    let coincidence = mmu.read(Self::LY_ADDRESS) == mmu.read(Self::LYC_ADDRESS);
+    (enabled(Self::STAT_HBLANK_SOURCE_BIT_N) && self.mode == Mode::HBlank)
+      || (enabled(Self::STAT_VBLANK_SOURCE_BIT_N) && self.mode == Mode::VBlank)
+      || (enabled(Self::STAT_OAM_SOURCE_BIT_N) && self.mode == Mode::OamScan)
+      || (enabled(Self::STAT_LYC_SOURCE_BIT_N) && coincidence)
+  }
+
+  /// Refresh the mode and coincidence bits of STAT, requesting the STAT interrupt when the
+  /// enabled sources go from none holding to any holding
+  fn update_stat(&mut self, mmu: &mut MMU) {
+    let coincidence = mmu.read(Self::LY_ADDRESS) == mmu.read(Self::LYC_ADDRESS);
+    mmu.set_stat_status(((coincidence as u8) << Self::STAT_COINCIDENCE_BIT_N) | self.mode as u8);
+    let stat_line = self.stat_condition(mmu, mmu.read(Self::STAT_ADDRESS));
+    if stat_line && !self.stat_line {
+      mmu.request_interrupt(Interrupt::LcdStat);
+    }
+    self.stat_line = stat_line;
+  }
+
+  /// Advance a single dot
+  fn tick(&mut self, mmu: &mut MMU) {
+    self.dots += 1;
+    let mut ly = mmu.read(Self::LY_ADDRESS);
+    let line_dots = if self.first_line { Self::LINE_DOTS - Self::FIRST_LINE_SHORTENED_DOTS } else { Self::LINE_DOTS };
+    if self.dots == line_dots {
+      self.dots = 0;
+      self.first_line = false;
+      ly = (ly + 1) % Self::LINES_PER_FRAME;
+      mmu.write(Self::LY_ADDRESS, ly);
+    }
+
+    let mode = if ly as usize >= Self::SCREEN_HEIGHT {
+      Mode::VBlank
+    } else if self.dots < Self::OAM_SCAN_DOTS && self.first_line {
+      Mode::HBlank
+    } else if self.dots < Self::OAM_SCAN_DOTS {
+      Mode::OamScan
+    } else if self.dots < Self::OAM_SCAN_DOTS + Self::DRAWING_DOTS {
+      Mode::Drawing
+    } else {
+      Mode::HBlank
+    };
+    if mode != self.mode {
+      self.enter_mode(mode, mmu);
+    }
+    if self.mode == Mode::Drawing && self.renderer == Renderer::Fifo {
+      self.fifo_tick(mmu, ly);
+    }
+    self.update_stat(mmu);
+  }
+
+  fn enter_mode(&mut self, mode: Mode, mmu: &mut MMU) {
+    self.mode = mode;
+    let ly = mmu.read(Self::LY_ADDRESS);
+    match mode {
+      Mode::Drawing => {
+        self.window_y_reached |= ly == mmu.read(Self::WY_ADDRESS);
+        self.scan_oam(mmu, ly);
+        self.fifo = PixelFifo {
+          discard: mmu.read(Self::SCX_ADDRESS) % 8,
+          ..PixelFifo::default()
+        };
+      }
+      Mode::HBlank => {
+        match self.renderer {
+          Renderer::Scanline => self.render_line(mmu, ly),
+          Renderer::Fifo => self.finish_fifo_line(mmu, ly),
+        }
+        mmu.hblank();
+      }
+      Mode::VBlank => {
+        self.window_line = 0;
+        self.window_y_reached = false;
+        self.frame_ready = true;
+        self.elapsed_frames += 1;
+        mmu.request_interrupt(Interrupt::VBlank);
+        mmu.vblank();
+      }
+      Mode::OamScan => {}
+    }
+  }
+
+  fn sprite_height(mmu: &MMU) -> u8 {
+    if get_bit(mmu.read(Self::LCDC_ADDRESS) as u16, Self::LCDC_OBJ_SIZE_BIT_N) { 16 } else { 8 }
+  }
+
+  /// Select the sprites overlapping line `ly`, taking at most 10 in OAM order
+  ///
+  /// Horizontal position plays no part, a sprite off the side of the screen still uses up a slot.
+  fn scan_oam(&mut self, mmu: &MMU, ly: u8) {
+    let height = Self::sprite_height(mmu);
+    let line = ly as u16 + Self::SPRITE_Y_OFFSET as u16;
+    self.line_sprites.clear();
+    self.line_sprites.extend(
+      mmu.oam
+        .chunks(Self::SPRITE_ATTRIBUTE_SIZE)
+        .take(Self::SPRITE_COUNT)
+        .enumerate()
+        .map(|(i, attributes)| Sprite {
+          index: i as u8,
+          y: attributes[0],
+          x: attributes[1],
+          tile: attributes[2],
+          flags: attributes[3],
+        })
+        .filter(|sprite| (sprite.y as u16..sprite.y as u16 + height as u16).contains(&line))
+        .take(Self::MAX_SPRITES_PER_LINE),
+    );
+  }
+
+  /// Color index (0-3) of pixel (`x`, `y`) of the tile at `tile_address` in VRAM
+  fn tile_color(mmu: &MMU, tile_address: usize, x: u8, y: u8) -> u8 {
+    let row = tile_address + y as usize * 2;
+    let (lower, upper) = (mmu.vram[row], mmu.vram[row + 1]);
+    let bit = 7 - x;
+    (((upper >> bit) & 1) << 1) | ((lower >> bit) & 1)
+  }
+
+  fn apply_palette(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0b11
+  }
+
+  /// VRAM offset of tile `tile` using the addressing mode selected by LCDC
+  fn tile_data_offset(lcdc: u8, tile: u8) -> usize {
+    if get_bit(lcdc as u16, Self::LCDC_TILE_DATA_BIT_N) {
+      tile as usize * Self::TILE_SIZE
+    } else {
+      (Self::SIGNED_TILE_DATA_OFFSET + tile as i8 as isize * Self::TILE_SIZE as isize) as usize
+    }
+  }
+
+  /// Size in pixels of each side of a tile map
+  pub const BG_MAP_SIZE: usize = 256;
+
+  /// Render the whole of tile map `which` to shades through BGP, ignoring scroll, for debugging
+  ///
+  /// # Returns
+  /// `BG_MAP_SIZE * BG_MAP_SIZE` shades, row major
+  pub fn render_background_map(mmu: &MMU, which: BgMap) -> Vec<u8> {
+    let lcdc = mmu.read(Self::LCDC_ADDRESS);
+    let map = Self::bg_map_offset(lcdc, which);
+    let bgp = mmu.read(Self::BGP_ADDRESS);
+
+    let mut pixels = vec![0; Self::BG_MAP_SIZE * Self::BG_MAP_SIZE];
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+      let (x, y) = (i % Self::BG_MAP_SIZE, i / Self::BG_MAP_SIZE);
+      *pixel = Self::apply_palette(bgp, Self::map_pixel(mmu, lcdc, map, x as u8, y as u8).color);
+    }
+    pixels
+  }
+
+  fn bg_map_offset(lcdc: u8, which: BgMap) -> usize {
+    let map_bit = match which {
+      BgMap::Background => Self::LCDC_BG_MAP_BIT_N,
+      BgMap::Window => Self::LCDC_WINDOW_MAP_BIT_N,
+    };
+    if get_bit(lcdc as u16, map_bit) { Self::TILE_MAP_1_OFFSET } else { Self::TILE_MAP_0_OFFSET }
+  }
+
+  /// Pixel (`x`, `y`) of tile map `map`, flipped and colored by the tile's attributes in CGB mode
+  fn map_pixel(mmu: &MMU, lcdc: u8, map: usize, x: u8, y: u8) -> BgPixel {
+    let index = map + (y as usize / 8) * Self::TILE_MAP_WIDTH + x as usize / 8;
+    let attributes = if mmu.model == Model::Cgb { mmu.vram[MMU::VRAM_SIZE + index] } else { 0 };
+    let attribute = |n| get_bit(attributes as u16, n);
+    let (mut column, mut row) = (x % 8, y % 8);
+    if attribute(Self::ATTRIBUTE_X_FLIP_BIT_N) {
+      column = 7 - column;
+    }
+    if attribute(Self::ATTRIBUTE_Y_FLIP_BIT_N) {
+      row = 7 - row;
+    }
+    let bank = if attribute(Self::ATTRIBUTE_BANK_BIT_N) { MMU::VRAM_SIZE } else { 0 };
+    BgPixel {
+      color: Self::tile_color(mmu, bank + Self::tile_data_offset(lcdc, mmu.vram[index]), column, row),
+      palette: attributes & Self::ATTRIBUTE_PALETTE_BITS,
+      priority: attribute(Self::ATTRIBUTE_PRIORITY_BIT_N),
+    }
+  }
+
+  /// Whether the background and window are drawn, which on CGB they always are
+  fn bg_enabled(mmu: &MMU) -> bool {
+    mmu.model == Model::Cgb || get_bit(mmu.read(Self::LCDC_ADDRESS) as u16, Self::LCDC_BG_WINDOW_ENABLE_BIT_N)
+  }
+
+  /// Draw the background, scrolled by SCX and SCY, into `pixels`
+  fn render_background(mmu: &MMU, ly: u8, pixels: &mut [BgPixel]) {
+    let lcdc = mmu.read(Self::LCDC_ADDRESS);
+    let map = Self::bg_map_offset(lcdc, BgMap::Background);
+    let y = ly.wrapping_add(mmu.read(Self::SCY_ADDRESS));
+    let scx = mmu.read(Self::SCX_ADDRESS);
+    for (screen_x, pixel) in pixels.iter_mut().enumerate() {
+      *pixel = Self::map_pixel(mmu, lcdc, map, scx.wrapping_add(screen_x as u8), y);
+    }
+  }
+
+  /// Whether the window covers any of the current line, which also needs the background enabled on DMG
+  ///
+  /// LY has to have matched WY at the start of a line earlier in the frame, so moving WY above the
+  /// current line doesn't bring the window in until the next frame. WX 166 leaves the last column.
+  fn window_visible(&self, mmu: &MMU) -> bool {
+    let lcdc = mmu.read(Self::LCDC_ADDRESS);
+    get_bit(lcdc as u16, Self::LCDC_WINDOW_ENABLE_BIT_N)
+      && Self::bg_enabled(mmu)
+      && self.window_y_reached
+      && (mmu.read(Self::WX_ADDRESS) as usize) < Self::SCREEN_WIDTH + Self::WINDOW_X_OFFSET as usize
+  }
+
+  /// Color index of column `column` of `sprite` on line `ly`, before flipping
+  fn sprite_color(mmu: &MMU, sprite: &Sprite, ly: u8, column: u8, height: u8) -> u8 {
+    let mut column = column;
+    let mut row = ly + Self::SPRITE_Y_OFFSET - sprite.y;
+    if sprite.flag(Sprite::X_FLIP_BIT_N) {
+      column = Self::SPRITE_WIDTH - 1 - column;
+    }
+    if sprite.flag(Sprite::Y_FLIP_BIT_N) {
+      row = height - 1 - row;
+    }
+    let tile = if height == 16 { sprite.tile & !1 } else { sprite.tile };
+    let bank = if mmu.model == Model::Cgb && sprite.flag(Sprite::BANK_BIT_N) { MMU::VRAM_SIZE } else { 0 };
+    Self::tile_color(mmu, bank + tile as usize * Self::TILE_SIZE, column, row)
+  }
+
+  /// Column `column` of `sprite` on line `ly` as a pixel ready to draw
+  fn sprite_pixel(mmu: &MMU, sprite: &Sprite, ly: u8, column: u8, height: u8) -> ObjPixel {
+    let palette = if mmu.model == Model::Cgb {
+      sprite.flags & Sprite::CGB_PALETTE_BITS
+    } else {
+      sprite.flag(Sprite::PALETTE_BIT_N) as u8
+    };
+    ObjPixel {
+      color: Self::sprite_color(mmu, sprite, ly, column, height),
+      palette,
+      bg_priority: sprite.flag(Sprite::BG_PRIORITY_BIT_N),
+      index: sprite.index,
+    }
+  }
+
+  /// Draw pixel `x` of line `ly`, the sprite pixel `obj` going over the background pixel `bg` unless
+  /// it is transparent or the background has priority
+  ///
+  /// In CGB mode either the tile's attributes or the sprite can give the background priority, and
+  /// clearing LCDC bit 0 takes it away from both.
+  fn draw_pixel(&mut self, mmu: &MMU, ly: u8, x: usize, bg: BgPixel, obj: ObjPixel) {
+    let i = ly as usize * Self::SCREEN_WIDTH + x;
+    if mmu.model != Model::Cgb {
+      self.framebuffer[i] = if obj.color == 0 || (obj.bg_priority && bg.color != 0) {
+        Self::apply_palette(mmu.read(Self::BGP_ADDRESS), bg.color)
+      } else {
+        Self::apply_palette(mmu.read(Self::OBP0_ADDRESS + obj.palette as u16), obj.color)
+      };
+      return;
+    }
+
+    let master_priority = get_bit(mmu.read(Self::LCDC_ADDRESS) as u16, Self::LCDC_BG_WINDOW_ENABLE_BIT_N);
+    let bg_wins = obj.color == 0 || (master_priority && (bg.priority || obj.bg_priority) && bg.color != 0);
+    let (color, rgb) = if bg_wins {
+      (bg.color, mmu.bg_palettes.rgb555(bg.palette, bg.color))
+    } else {
+      (obj.color, mmu.obj_palettes.rgb555(obj.palette, obj.color))
+    };
+    if self.colors.is_empty() {
+      self.colors = vec![0; Self::SCREEN_WIDTH * Self::SCREEN_HEIGHT];
+    }
+    self.framebuffer[i] = color;
+    self.colors[i] = rgb;
+  }
+
+  /// Draw the window into `pixels` if it covers the current line
+  ///
+  /// The window keeps its own line counter rather than using `LY - WY`, so hiding it for a few lines
+  /// picks back up at the row it left off on.
+  fn render_window(&mut self, mmu: &MMU, pixels: &mut [BgPixel]) {
+    if !self.window_visible(mmu) {
+      return;
+    }
+
+    let lcdc = mmu.read(Self::LCDC_ADDRESS);
+    let wx = mmu.read(Self::WX_ADDRESS);
+    let map = Self::bg_map_offset(lcdc, BgMap::Window);
+    let start = wx.saturating_sub(Self::WINDOW_X_OFFSET) as usize;
+    for (screen_x, pixel) in pixels.iter_mut().enumerate().skip(start) {
+      let x = screen_x + Self::WINDOW_X_OFFSET as usize - wx as usize;
+      *pixel = Self::map_pixel(mmu, lcdc, map, x as u8, self.window_line);
+    }
+    self.window_line += 1;
+  }
+
+  fn render_line(&mut self, mmu: &MMU, ly: u8) {
+    let mut bg_pixels = [BgPixel::default(); Self::SCREEN_WIDTH];
+    if Self::bg_enabled(mmu) {
+      Self::render_background(mmu, ly, &mut bg_pixels);
+      self.render_window(mmu, &mut bg_pixels);
+    }
+
+    let mut by_priority = self.line_sprites.clone();
+    if !get_bit(mmu.read(Self::LCDC_ADDRESS) as u16, Self::LCDC_OBJ_ENABLE_BIT_N) {
+      by_priority.clear();
+    }
+    // where sprites overlap the one with the smaller X wins, then the one earlier in OAM, which is all
+    // that counts on CGB
+    if mmu.model != Model::Cgb {
+      by_priority.sort_by_key(|sprite| (sprite.x, sprite.index));
+    }
+
+    let height = Self::sprite_height(mmu);
+    for (x, &bg) in bg_pixels.iter().enumerate() {
+      let screen_x = x as u16 + Self::SPRITE_X_OFFSET as u16;
+      let obj = by_priority
+        .iter()
+        .filter(|sprite| (sprite.x as u16..sprite.x as u16 + Self::SPRITE_WIDTH as u16).contains(&screen_x))
+        .map(|sprite| Self::sprite_pixel(mmu, sprite, ly, (screen_x - sprite.x as u16) as u8, height))
+        .find(|pixel| pixel.color != 0)
+        .unwrap_or_default();
+      self.draw_pixel(mmu, ly, x, bg, obj);
+    }
+  }
+
+  /// Advance the pixel FIFO renderer a single dot of mode 3, shifting out at most one pixel
+  ///
+  /// Registers are read as the pixels they affect are fetched, so writes made partway through the
+  /// line show up partway across it.
+  fn fifo_tick(&mut self, mmu: &MMU, ly: u8) {
+    if self.fifo.x as usize >= Self::SCREEN_WIDTH {
+      return;
+    }
+    if self.fifo.stall > 0 {
+      self.fifo.stall -= 1;
+      return;
+    }
+
+    // the window restarts the fetcher when its left edge is reached
+    let wx = mmu.read(Self::WX_ADDRESS);
+    if !self.fifo.window && self.window_visible(mmu) && self.fifo.x + Self::WINDOW_X_OFFSET >= wx {
+      self.fifo.window = true;
+      self.fifo.bg.clear();
+      self.fifo.fetcher_x = 0;
+      self.fifo.fetch_dots = 0;
+      self.fifo.discard = Self::WINDOW_X_OFFSET.saturating_sub(wx);
+    }
+
+    self.fifo.fetch_dots += 1;
+    self.fetch_tile(mmu, ly);
+    let Some(&bg) = self.fifo.bg.front() else {
+      return;
+    };
+    if self.fifo.discard > 0 {
+      self.fifo.bg.pop_front();
+      self.fifo.discard -= 1;
+      return;
+    }
+
+    if self.fetch_sprites(mmu, ly) {
+      return;
+    }
+
+    self.fifo.bg.pop_front();
+    let obj = self.fifo.obj.pop_front().unwrap_or_default();
+    self.draw_pixel(mmu, ly, self.fifo.x as usize, bg, obj);
+    self.fifo.x += 1;
+  }
+
+  /// Run the background fetcher for a dot, pushing the tile once it's fetched and there's room
+  ///
+  /// Each step reads its registers and VRAM on the dot it finishes, so a write landing between the
+  /// tile number and data reads affects only the data.
+  fn fetch_tile(&mut self, mmu: &MMU, ly: u8) {
+    match self.fifo.fetch_dots {
+      Self::FETCH_NUMBER_DOTS => self.fetch_tile_number(mmu, ly),
+      Self::FETCH_LOW_DOTS => self.fifo.tile.low = Self::fetch_tile_data(mmu, self.fifo.tile, 0),
+      Self::TILE_FETCH_DOTS => self.fifo.tile.high = Self::fetch_tile_data(mmu, self.fifo.tile, 1),
+      _ => {}
+    }
+    if self.fifo.fetch_dots >= Self::TILE_FETCH_DOTS && self.fifo.bg.len() <= Self::FIFO_PUSH_THRESHOLD {
+      self.push_tile(mmu);
+    }
+  }
+
+  /// Read the number and attributes of the next background or window tile from its map
+  fn fetch_tile_number(&mut self, mmu: &MMU, ly: u8) {
+    let lcdc = mmu.read(Self::LCDC_ADDRESS);
+    let fetcher_x = self.fifo.fetcher_x.wrapping_mul(8);
+    let (map, x, y) = if self.fifo.window {
+      (Self::bg_map_offset(lcdc, BgMap::Window), fetcher_x, self.window_line)
+    } else {
+      // only the coarse part of SCX is read here, the fine part was dropped at the start of the line
+      let scx = mmu.read(Self::SCX_ADDRESS) & !0b111;
+      let y = ly.wrapping_add(mmu.read(Self::SCY_ADDRESS));
+      (Self::bg_map_offset(lcdc, BgMap::Background), scx.wrapping_add(fetcher_x), y)
+    };
+    let index = map + (y as usize / 8) * Self::TILE_MAP_WIDTH + x as usize / 8;
+    let attributes = if mmu.model == Model::Cgb { mmu.vram[MMU::VRAM_SIZE + index] } else { 0 };
+    let row = if get_bit(attributes as u16, Self::ATTRIBUTE_Y_FLIP_BIT_N) { 7 - y % 8 } else { y % 8 };
+    self.fifo.tile = FetchedTile { number: mmu.vram[index], attributes, row, ..FetchedTile::default() };
+  }
+
+  /// Byte `byte` (0 low, 1 high) of the fetched tile's row, addressed as LCDC selects now
+  fn fetch_tile_data(mmu: &MMU, tile: FetchedTile, byte: usize) -> u8 {
+    let lcdc = mmu.read(Self::LCDC_ADDRESS);
+    let bank = if get_bit(tile.attributes as u16, Self::ATTRIBUTE_BANK_BIT_N) { MMU::VRAM_SIZE } else { 0 };
+    mmu.vram[bank + Self::tile_data_offset(lcdc, tile.number) + tile.row as usize * 2 + byte]
+  }
+
+  /// Push the fetched tile's row onto the background FIFO
+  fn push_tile(&mut self, mmu: &MMU) {
+    let tile = self.fifo.tile;
+    let enabled = Self::bg_enabled(mmu);
+    let x_flip = get_bit(tile.attributes as u16, Self::ATTRIBUTE_X_FLIP_BIT_N);
+    for column in 0..8 {
+      let bit = if x_flip { column } else { 7 - column };
+      let pixel = if enabled {
+        BgPixel {
+          color: (((tile.high >> bit) & 1) << 1) | ((tile.low >> bit) & 1),
+          palette: tile.attributes & Self::ATTRIBUTE_PALETTE_BITS,
+          priority: get_bit(tile.attributes as u16, Self::ATTRIBUTE_PRIORITY_BIT_N),
+        }
+      } else {
+        BgPixel::default()
+      };
+      self.fifo.bg.push_back(pixel);
+    }
+    self.fifo.fetcher_x = self.fifo.fetcher_x.wrapping_add(1);
+    self.fifo.fetch_dots = 0;
+  }
+
+  /// Merge the sprites starting at the next pixel into the sprite FIFO, stalling while they're fetched
+  ///
+  /// Pixels already in the FIFO win over the ones merged in, which gives priority to the sprite with
+  /// the smaller X and then the one earlier in OAM. On CGB the one earlier in OAM wins outright.
+  ///
+  /// # Returns
+  /// true if a sprite was fetched and no pixel should be shifted out this dot
+  fn fetch_sprites(&mut self, mmu: &MMU, ly: u8) -> bool {
+    if !get_bit(mmu.read(Self::LCDC_ADDRESS) as u16, Self::LCDC_OBJ_ENABLE_BIT_N) {
+      return false;
+    }
+    let screen_x = self.fifo.x + Self::SPRITE_X_OFFSET;
+    let height = Self::sprite_height(mmu);
+    let cgb = mmu.model == Model::Cgb;
+    let mut fetched = false;
+    for (i, sprite) in self.line_sprites.iter().enumerate() {
+      if get_bit(self.fifo.fetched_sprites, i as u8) || sprite.x > screen_x {
+        continue;
+      }
+      self.fifo.fetched_sprites |= 1 << i;
+      fetched = true;
+      // sprites hanging off the left edge start partway through
+      let start = screen_x - sprite.x;
+      for column in start..Self::SPRITE_WIDTH {
+        let i = (column - start) as usize;
+        if self.fifo.obj.len() <= i {
+          self.fifo.obj.push_back(ObjPixel::default());
+        }
+        let pixel = Self::sprite_pixel(mmu, sprite, ly, column, height);
+        let queued = self.fifo.obj[i];
+        if queued.color == 0 || (cgb && pixel.color != 0 && pixel.index < queued.index) {
+          self.fifo.obj[i] = pixel;
+        }
+      }
+    }
+    if fetched {
+      self.fifo.stall = Self::SPRITE_FETCH_DOTS;
+    }
+    fetched
+  }
+
+  /// Shift out whatever mode 3 didn't have time for, so the line is complete on entering HBlank
+  fn finish_fifo_line(&mut self, mmu: &MMU, ly: u8) {
+    while (self.fifo.x as usize) < Self::SCREEN_WIDTH {
+      self.fifo_tick(mmu, ly);
+    }
+    if self.fifo.window {
+      self.window_line += 1;
+    }
+  }
+
+  pub fn renderer(&self) -> Renderer {
+    self.renderer
+  }
+
+  /// Switch between the scanline and pixel FIFO renderers, taking effect from the next line
+  pub fn set_renderer(&mut self, renderer: Renderer) {
+    self.renderer = renderer;
+  }
+
+  /// Dots until the PPU next requests one of the interrupts in `enabled`, or `None` if it never will
+  ///
+  /// The STAT interrupt can only be raised when the mode or LY changes, so for it this is the next
+  /// such change rather than the one that actually raises it.
+  pub fn dots_until_interrupt(&self, mmu: &MMU, enabled: InterruptSet) -> Option<u32> {
+    let lcd_enabled = get_bit(mmu.read(Self::LCDC_ADDRESS) as u16, Self::LCDC_ENABLE_BIT_N);
+    if lcd_enabled != self.lcd_on {
+      // switching the LCD takes effect on the next step
+      return Some(0);
+    }
+    if !lcd_enabled {
+      return None;
+    }
+
+    let ly = mmu.read(Self::LY_ADDRESS) as u32;
+    let line_dots = if self.first_line { Self::LINE_DOTS - Self::FIRST_LINE_SHORTENED_DOTS } else { Self::LINE_DOTS };
+    let to_line_end = (line_dots - self.dots) as u32;
+    let (visible_lines, lines) = (Self::SCREEN_HEIGHT as u32, Self::LINES_PER_FRAME as u32);
+
+    let vblank = if ly < visible_lines {
+      to_line_end + (visible_lines - 1 - ly) * Self::LINE_DOTS as u32
+    } else {
+      to_line_end + (lines - 1 - ly + visible_lines) * Self::LINE_DOTS as u32
+    };
+    let stat_sources = mmu.read(Self::STAT_ADDRESS) & Self::STAT_SOURCE_BITS;
+    let mode_change = if ly < visible_lines {
+      [Self::OAM_SCAN_DOTS, Self::OAM_SCAN_DOTS + Self::DRAWING_DOTS, line_dots]
+        .iter()
+        .find(|&&boundary| boundary > self.dots)
+        .map(|&boundary| (boundary - self.dots) as u32)
+    } else {
+      Some(to_line_end)
+    };
+
+    let vblank = Some(vblank).filter(|_| enabled.contains(Interrupt::VBlank));
+    let stat = mode_change.filter(|_| enabled.contains(Interrupt::LcdStat) && stat_sources != 0);
+    vblank.into_iter().chain(stat).min()
+  }
+
+  /// Sprites selected for the current line, in OAM order
+  pub fn line_sprites(&self) -> &[Sprite] {
+    &self.line_sprites
+  }
+
+  pub fn mode(&self) -> Mode {
+    self.mode
+  }
+
+  /// Whether the LCD was on when last stepped, while it's off the screen shows nothing but white
+  pub fn lcd_enabled(&self) -> bool {
+    self.lcd_on
+  }
+
+  /// Returns true if a frame has completed since the last `take_frame`
+  pub fn is_frame_ready(&self) -> bool {
+    self.frame_ready
+  }
+
+  /// Number of frames completed since power on, the last one being the one `take_frame` returns
+  pub fn elapsed_frames(&self) -> u64 {
+    self.elapsed_frames
+  }
+
+  /// Shades of the frame being drawn, row major, complete from the start of VBlank
+  pub fn framebuffer(&self) -> &[u8] {
+    &self.framebuffer
+  }
+
+  /// The framebuffer as colors, see `framebuffer`
+  pub fn frame(&self) -> Frame<'_> {
+    let colors = Some(&self.colors[..]).filter(|colors| !colors.is_empty());
+    Frame { shades: &self.framebuffer, colors }
+  }
+
+  /// Returns the framebuffer if a new frame has completed since the last call, clearing the ready flag
+  pub fn take_frame(&mut self) -> Option<&[u8]> {
+    if self.frame_ready {
+      self.frame_ready = false;
+      Some(&self.framebuffer)
+    } else {
+      None
+    }
+  }
+}
+
+impl SaveState for PPU {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    write_u16(w, self.dots)?;
+    write_u8(w, self.mode as u8)?;
+    write_bool(w, self.lcd_on)?;
+    write_bool(w, self.first_line)?;
+    write_bool(w, self.stat_line)?;
+    write_u8(w, self.window_line)?;
+    write_bool(w, self.window_y_reached)?;
+    write_u64(w, self.elapsed_frames)
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    self.dots = read_u16(r)?;
+    self.mode = match read_u8(r)? {
+      0 => Mode::HBlank,
+      1 => Mode::VBlank,
+      2 => Mode::OamScan,
+      _ => Mode::Drawing,
+    };
+    self.lcd_on = read_bool(r)?;
+    self.first_line = read_bool(r)?;
+    self.stat_line = read_bool(r)?;
+    self.window_line = read_u8(r)?;
+    self.window_y_reached = read_bool(r)?;
+    self.elapsed_frames = read_u64(r)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use {super::*, crate::model::Model};
+
+  fn step_dots(ppu: &mut PPU, mmu: &mut MMU, n_dots: usize) {
+    for _ in 0..n_dots / 4 {
+      ppu.step(mmu, 4);
+    }
+  }
+
+  fn lcd_on_mmu() -> MMU {
+    let mut mmu = MMU::default();
+    mmu.write(PPU::LCDC_ADDRESS, 0x80);
+    mmu
+  }
+
+  #[test]
+  fn modes_follow_line_timing() {
+    let mut mmu = lcd_on_mmu();
+    let mut ppu = PPU::default();
+    // skip the shortened first line
+    step_dots(&mut ppu, &mut mmu, 452);
+    assert_eq!(mmu.read(PPU::LY_ADDRESS), 1);
+    assert_eq!(ppu.mode(), Mode::OamScan);
+
+    step_dots(&mut ppu, &mut mmu, 80);
+    assert_eq!(ppu.mode(), Mode::Drawing);
+    step_dots(&mut ppu, &mut mmu, 172);
+    assert_eq!(ppu.mode(), Mode::HBlank);
+    assert_eq!(mmu.read(PPU::STAT_ADDRESS) & 0b11, Mode::HBlank as u8);
+    step_dots(&mut ppu, &mut mmu, 204);
+    assert_eq!(ppu.mode(), Mode::OamScan);
+    assert_eq!(mmu.read(PPU::LY_ADDRESS), 2);
+  }
+
+  #[test]
+  fn vblank_starts_after_visible_lines() {
+    let mut mmu = lcd_on_mmu();
+    let mut ppu = PPU::default();
+
+    // the first frame is 4 dots short after the LCD is switched on
+    step_dots(&mut ppu, &mut mmu, 144 * 456 - 8);
+    assert!(!ppu.is_frame_ready());
+    step_dots(&mut ppu, &mut mmu, 4);
+    assert_eq!(ppu.mode(), Mode::VBlank);
+    assert!(ppu.is_frame_ready());
+    assert_ne!(mmu.read(MMU::INTERRUPT_FLAG_REG_ADDRESS) & Interrupt::VBlank.mask(), 0);
+
+    // LY wraps back to the first line after the 10 vblank lines
+    step_dots(&mut ppu, &mut mmu, 10 * 456);
+    assert_eq!(mmu.read(PPU::LY_ADDRESS), 0);
+    assert_eq!(ppu.mode(), Mode::OamScan);
+  }
+
+  #[test]
+  fn first_line_after_lcd_enable_is_shortened() {
+    let mut mmu = lcd_on_mmu();
+    let mut ppu = PPU::default();
+    step_dots(&mut ppu, &mut mmu, 50 * 456 + 100);
+    assert_eq!(mmu.read(PPU::LY_ADDRESS), 50);
+
+    mmu.write(PPU::LCDC_ADDRESS, 0x00);
+    step_dots(&mut ppu, &mut mmu, 4);
+    assert_eq!(mmu.read(PPU::LY_ADDRESS), 0);
+    mmu.write(PPU::LCDC_ADDRESS, 0x80);
+
+    // no OAM scan, HBlank is reported until drawing starts
+    step_dots(&mut ppu, &mut mmu, 76);
+    assert_eq!(ppu.mode(), Mode::HBlank);
+    assert_eq!(mmu.read(PPU::STAT_ADDRESS) & 0b11, Mode::HBlank as u8);
+    step_dots(&mut ppu, &mut mmu, 4);
+    assert_eq!(ppu.mode(), Mode::Drawing);
+
+    step_dots(&mut ppu, &mut mmu, 452 - 80 - 4);
+    assert_eq!(mmu.read(PPU::LY_ADDRESS), 0);
+    step_dots(&mut ppu, &mut mmu, 4);
+    assert_eq!(mmu.read(PPU::LY_ADDRESS), 1);
+    assert_eq!(ppu.mode(), Mode::OamScan);
+
+    // later lines are the full length
+    step_dots(&mut ppu, &mut mmu, 452);
+    assert_eq!(mmu.read(PPU::LY_ADDRESS), 1);
+    step_dots(&mut ppu, &mut mmu, 4);
+    assert_eq!(mmu.read(PPU::LY_ADDRESS), 2);
+  }
+
+  fn stat_interrupt_requested(mmu: &MMU) -> bool {
+    mmu.read(MMU::INTERRUPT_FLAG_REG_ADDRESS) & Interrupt::LcdStat.mask() != 0
+  }
+
+  #[test]
+  fn stat_interrupt_on_lyc_match() {
+    let mut mmu = lcd_on_mmu();
+    mmu.write(PPU::LYC_ADDRESS, 2);
+    mmu.write(PPU::STAT_ADDRESS, 1 << PPU::STAT_LYC_SOURCE_BIT_N);
+    let mut ppu = PPU::default();
+    // the STAT write itself raises a spurious interrupt on DMG
+    step_dots(&mut ppu, &mut mmu, 4);
+    mmu.write(MMU::INTERRUPT_FLAG_REG_ADDRESS, 0);
+
+    step_dots(&mut ppu, &mut mmu, 452 + 448);
+    assert!(!stat_interrupt_requested(&mmu));
+    step_dots(&mut ppu, &mut mmu, 4);
+    assert_eq!(mmu.read(PPU::LY_ADDRESS), 2);
+    assert!(stat_interrupt_requested(&mmu));
+    assert_ne!(mmu.read(PPU::STAT_ADDRESS) & (1 << PPU::STAT_COINCIDENCE_BIT_N), 0);
+  }
+
+  fn stat_write_during_hblank(model: Model) -> bool {
+    let mut mmu = lcd_on_mmu();
+    mmu.model = model;
+    mmu.write(PPU::LYC_ADDRESS, 0xFF);
+    let mut ppu = PPU::default();
+    step_dots(&mut ppu, &mut mmu, 452 + 300);
+    assert_eq!(ppu.mode(), Mode::HBlank);
+    assert!(!stat_interrupt_requested(&mmu));
+
+    mmu.write(PPU::STAT_ADDRESS, 0x00);
+    step_dots(&mut ppu, &mut mmu, 4);
+    stat_interrupt_requested(&mmu)
+  }
+
+  #[test]
+  fn stat_write_raises_spurious_interrupt_on_dmg_only() {
+    assert!(stat_write_during_hblank(Model::Dmg));
+    assert!(!stat_write_during_hblank(Model::Cgb));
+  }
+
+  #[test]
+  fn lcd_off_does_not_advance() {
+    let mut mmu = MMU::default();
+    let mut ppu = PPU::default();
+
+    step_dots(&mut ppu, &mut mmu, 1000);
+    assert_eq!(mmu.read(PPU::LY_ADDRESS), 0);
+  }
+
+  #[test]
+  fn lcd_off_blanks_the_screen_and_resets_ly() {
+    let mut mmu = lcd_on_mmu();
+    // BGP maps color 0 to black so the blank screen can't be mistaken for a drawn one
+    mmu.write(PPU::BGP_ADDRESS, 0b1110_0111);
+    let mut ppu = PPU::default();
+    step_dots(&mut ppu, &mut mmu, 20 * 456);
+    assert!(ppu.lcd_enabled());
+    assert_eq!(first_line(&ppu)[0], 3);
+
+    mmu.write(PPU::LCDC_ADDRESS, 0x00);
+    step_dots(&mut ppu, &mut mmu, 4);
+    assert!(!ppu.lcd_enabled());
+    assert_eq!((mmu.read(PPU::LY_ADDRESS), ppu.mode()), (0, Mode::HBlank));
+    assert!(ppu.framebuffer().iter().all(|&shade| shade == 0));
+  }
+
+  /// Fill tile 0 with color 3 and put a sprite using it at each of `positions` in OAM order
+  fn place_sprites(mmu: &mut MMU, positions: &[(u8, u8)]) {
+    for byte in mmu.vram[..PPU::TILE_SIZE].iter_mut() {
+      *byte = 0xFF;
+    }
+    for (i, &(y, x)) in positions.iter().enumerate() {
+      mmu.oam[i * 4..i * 4 + 4].copy_from_slice(&[y, x, 0, 0]);
+    }
+    mmu.write(PPU::OBP0_ADDRESS, 0b1110_0100);
+    mmu.write(PPU::LCDC_ADDRESS, 0x82);
+  }
+
+  fn first_line(ppu: &PPU) -> &[u8] {
+    &ppu.framebuffer[..PPU::SCREEN_WIDTH]
+  }
+
+  #[test]
+  fn only_first_ten_sprites_in_oam_order_are_drawn() {
+    let mut mmu = MMU::default();
+    // 12 sprites on the first line, placed right to left so OAM order differs from X order
+    let positions: Vec<_> = (0..12).map(|i| (16, 8 + 12 * (11 - i))).collect();
+    place_sprites(&mut mmu, &positions);
+    let mut ppu = PPU::default();
+
+    step_dots(&mut ppu, &mut mmu, 80 + 172);
+    assert_eq!(ppu.line_sprites().len(), 10);
+    assert_eq!(ppu.line_sprites().iter().map(|x| x.index).collect::<Vec<_>>(), (0..10).collect::<Vec<_>>());
+
+    let line = first_line(&ppu);
+    for (i, &(_, x)) in positions.iter().enumerate() {
+      let screen_x = (x - 8) as usize;
+      let expected = if i < 10 { 3 } else { 0 };
+      assert_eq!(line[screen_x..screen_x + 8], [expected; 8], "sprite {}", i);
+    }
+  }
+
+  #[test]
+  fn offscreen_sprites_use_up_a_slot() {
+    let mut mmu = MMU::default();
+    let mut positions = vec![(16, 0); 10];
+    positions.push((16, 8));
+    place_sprites(&mut mmu, &positions);
+    let mut ppu = PPU::default();
+
+    step_dots(&mut ppu, &mut mmu, 80 + 172);
+    assert!(first_line(&ppu).iter().all(|&x| x == 0));
+  }
+
+  #[test]
+  fn overlapping_sprites_prefer_smaller_x_then_oam_order() {
+    let mut mmu = MMU::default();
+    place_sprites(&mut mmu, &[(16, 12), (16, 8), (16, 8)]);
+    // each sprite uses its own palette shade so the winner is visible
+    mmu.oam[3] = 1 << Sprite::PALETTE_BIT_N;
+    mmu.oam[11] = 1 << Sprite::PALETTE_BIT_N;
+    mmu.write(PPU::OBP0_ADDRESS, 0b0100_0000);
+    mmu.write(PPU::OBP1_ADDRESS, 0b1000_0000);
+    let mut ppu = PPU::default();
+
+    step_dots(&mut ppu, &mut mmu, 80 + 172);
+    // sprite 1 (x = 8, OBP0) beats sprite 0 (x = 12) and sprite 2 (x = 8, later in OAM)
+    assert_eq!(first_line(&ppu)[..8], [1; 8]);
+    assert_eq!(first_line(&ppu)[8..12], [2; 4]);
+  }
+
+  /// Check the top left of `frame` against `golden`, a row of shades per line with `.` for 0
+  fn assert_golden(frame: &[u8], golden: &[&str]) {
+    for (y, row) in golden.iter().enumerate() {
+      let actual: String = frame[y * PPU::SCREEN_WIDTH..][..row.len()]
+        .iter()
+        .map(|&shade| if shade == 0 { '.' } else { (b'0' + shade) as char })
+        .collect();
+      assert_eq!(&actual, row, "line {}", y);
+    }
+  }
+
+  /// Two 8x16 sprites side by side, the right one flipped and behind the background, over a
+  /// background tile whose left half is color 1
+  fn tall_sprites_mmu() -> MMU {
+    let mut mmu = MMU::default();
+    for row in 0..8 {
+      // tile 2 is a diagonal of color 3, tile 3 has color 2 down both edges
+      mmu.vram[PPU::TILE_SIZE * 2 + row * 2..][..2].copy_from_slice(&[0x80 >> row, 0x80 >> row]);
+      mmu.vram[PPU::TILE_SIZE * 3 + row * 2 + 1] = 0x81;
+      mmu.vram[PPU::TILE_SIZE * 4 + row * 2] = 0xF0;
+    }
+    mmu.vram[PPU::TILE_MAP_0_OFFSET + 1] = 4;
+    // the odd tile number is rounded down in 8x16 mode
+    mmu.oam[..8].copy_from_slice(&[16, 8, 3, 0, 16, 16, 2, 0b1010_0000]);
+    mmu.write(PPU::BGP_ADDRESS, 0b1110_0100);
+    mmu.write(PPU::OBP0_ADDRESS, 0b1110_0100);
+    mmu.write(PPU::LCDC_ADDRESS, 0b1001_0111);
+    mmu
+  }
+
+  #[test]
+  fn tall_flipped_and_background_priority_sprites_match_golden() {
+    let golden = [
+      "3.......1111...3",
+      ".3......1111..3.",
+      "..3.....1111.3..",
+      "...3....11113...",
+      "....3...1111....",
+      ".....3..1111....",
+      "......3.1111....",
+      ".......31111....",
+      "2......22......2",
+      "2......22......2",
+      "2......22......2",
+      "2......22......2",
+      "2......22......2",
+      "2......22......2",
+      "2......22......2",
+      "2......22......2",
+      "................",
+    ];
+    for &renderer in [Renderer::Scanline, Renderer::Fifo].iter() {
+      assert_golden(&render_frame(tall_sprites_mmu(), renderer), &golden);
+    }
+  }
+
+  #[test]
+  fn window_resumes_at_the_row_it_left_off() {
+    let mut mmu = MMU::default();
+    // tile 0 has a single color 1 pixel on each row, at the column matching the row
+    for y in 0..8 {
+      mmu.vram[y * 2] = 0x80 >> y;
+    }
+    // the background is left blank with tile 1, the window uses map 1
+    for tile in mmu.vram[PPU::TILE_MAP_0_OFFSET..PPU::TILE_MAP_1_OFFSET].iter_mut() {
+      *tile = 1;
+    }
+    mmu.write(PPU::BGP_ADDRESS, 0b1110_0100);
+    mmu.write(PPU::WY_ADDRESS, 0);
+    mmu.write(PPU::WX_ADDRESS, 7);
+    let window_on = 0b1111_0001;
+    let window_off = 0b1101_0001;
+    let mut ppu = PPU::default();
+
+    let mut lines = vec![];
+    for ly in 0..8 {
+      mmu.write(PPU::LCDC_ADDRESS, if (2..4).contains(&ly) { window_off } else { window_on });
+      step_dots(&mut ppu, &mut mmu, PPU::LINE_DOTS as usize);
+      let row = &ppu.framebuffer[ly * PPU::SCREEN_WIDTH..(ly + 1) * PPU::SCREEN_WIDTH];
+      lines.push(row[..8].iter().position(|&x| x == 1));
+    }
+
+    assert_eq!(lines, [Some(0), Some(1), None, None, Some(2), Some(3), Some(4), Some(5)]);
+  }
+
+  /// Solid color 3 window over a blank background, WY past the top of the screen and WX at `wx`
+  fn solid_window_mmu(wx: u8) -> MMU {
+    let mut mmu = MMU::default();
+    for byte in mmu.vram[..PPU::TILE_SIZE].iter_mut() {
+      *byte = 0xFF;
+    }
+    for tile in mmu.vram[PPU::TILE_MAP_0_OFFSET..PPU::TILE_MAP_1_OFFSET].iter_mut() {
+      *tile = 1;
+    }
+    mmu.write(PPU::BGP_ADDRESS, 0b1110_0100);
+    mmu.write(PPU::WY_ADDRESS, 0xFF);
+    mmu.write(PPU::WX_ADDRESS, wx);
+    mmu.write(PPU::LCDC_ADDRESS, 0b1111_0001);
+    mmu
+  }
+
+  #[test]
+  fn window_waits_for_ly_to_match_wy() {
+    for &renderer in [Renderer::Scanline, Renderer::Fifo].iter() {
+      let mut mmu = solid_window_mmu(7);
+      let mut ppu = PPU::default();
+      ppu.set_renderer(renderer);
+      step_dots(&mut ppu, &mut mmu, 4 * PPU::LINE_DOTS as usize);
+
+      // WY is moved above LY after the line it names has gone by
+      mmu.write(PPU::WY_ADDRESS, 2);
+      step_dots(&mut ppu, &mut mmu, 140 * PPU::LINE_DOTS as usize);
+      assert!(ppu.framebuffer().iter().all(|&x| x == 0), "{:?}", renderer);
+
+      // the next frame matches it on line 2
+      step_dots(&mut ppu, &mut mmu, 20 * PPU::LINE_DOTS as usize);
+      let lines: Vec<_> = (0..4).map(|ly| ppu.framebuffer()[ly * PPU::SCREEN_WIDTH]).collect();
+      assert_eq!(lines, [0, 0, 3, 3], "{:?}", renderer);
+    }
+  }
+
+  #[test]
+  fn window_at_wx_166_covers_only_the_last_column() {
+    for &renderer in [Renderer::Scanline, Renderer::Fifo].iter() {
+      let mut mmu = solid_window_mmu(166);
+      mmu.write(PPU::WY_ADDRESS, 0);
+      let line = render_frame(mmu, renderer)[..PPU::SCREEN_WIDTH].to_vec();
+      assert_eq!(line.iter().position(|&x| x == 3), Some(PPU::SCREEN_WIDTH - 1), "{:?}", renderer);
+    }
+  }
+
+  #[test]
+  fn background_map_renders_every_tile() {
+    let mut mmu = MMU::default();
+    // tile 1 is color 1 on the left half and color 2 on the right
+    for y in 0..8 {
+      mmu.vram[PPU::TILE_SIZE + y * 2] = 0xF0;
+      mmu.vram[PPU::TILE_SIZE + y * 2 + 1] = 0x0F;
+    }
+    for tile in mmu.vram[PPU::TILE_MAP_1_OFFSET..PPU::TILE_MAP_1_OFFSET + 0x400].iter_mut() {
+      *tile = 1;
+    }
+    // unsigned tile data, background on map 1, window on map 0
+    mmu.write(PPU::LCDC_ADDRESS, 0b0001_1000);
+    mmu.write(PPU::BGP_ADDRESS, 0b1110_0100);
+
+    let map = PPU::render_background_map(&mmu, BgMap::Background);
+    assert_eq!(map.len(), 256 * 256);
+    for (i, &shade) in map.iter().enumerate() {
+      assert_eq!(shade, if i % 8 < 4 { 1 } else { 2 }, "pixel {}", i);
+    }
+
+    let window = PPU::render_background_map(&mmu, BgMap::Window);
+    assert!(window.iter().all(|&x| x == 0));
+  }
+
+  /// A busy scene using the background, the window and sprites with every flag
+  fn busy_scene_mmu() -> MMU {
+    let mut mmu = MMU::default();
+    for (i, byte) in mmu.vram[..PPU::TILE_MAP_0_OFFSET].iter_mut().enumerate() {
+      *byte = (i * 37 + i / 16 * 11) as u8;
+    }
+    for (i, tile) in mmu.vram[PPU::TILE_MAP_0_OFFSET..].iter_mut().enumerate() {
+      *tile = (i * 7) as u8;
+    }
+    let sprites = [
+      (16, 0, 3, 0x00),
+      (20, 4, 5, 0x20),
+      (30, 40, 9, 0x40),
+      (30, 44, 10, 0x10),
+      (30, 44, 11, 0x80),
+      (60, 100, 12, 0xF0),
+      (100, 160, 13, 0x00),
+      (150, 80, 14, 0x30),
+    ];
+    for (i, &(y, x, tile, flags)) in sprites.iter().enumerate() {
+      mmu.oam[i * 4..i * 4 + 4].copy_from_slice(&[y, x, tile, flags]);
+    }
+    mmu.write(PPU::SCX_ADDRESS, 3);
+    mmu.write(PPU::SCY_ADDRESS, 5);
+    mmu.write(PPU::WX_ADDRESS, 50);
+    mmu.write(PPU::WY_ADDRESS, 20);
+    mmu.write(PPU::BGP_ADDRESS, 0b1110_0100);
+    mmu.write(PPU::OBP0_ADDRESS, 0b1101_0010);
+    mmu.write(PPU::OBP1_ADDRESS, 0b0011_1001);
+    // window on map 1, unsigned tile data, background on map 0, 8x8 sprites
+    mmu.write(PPU::LCDC_ADDRESS, 0b1111_0011);
+    mmu
+  }
+
+  fn render_frame(mut mmu: MMU, renderer: Renderer) -> Vec<u8> {
+    let mut ppu = PPU::default();
+    ppu.set_renderer(renderer);
+    step_dots(&mut ppu, &mut mmu, 144 * 456);
+    ppu.take_frame().expect("a frame should be ready").to_vec()
+  }
+
+  /// Set color `color` of palette `palette` through the index and data registers at `index_address`
+  fn write_color(mmu: &mut MMU, index_address: u16, palette: u8, color: u8, rgb555: u16) {
+    // auto increment takes the index on to the upper byte
+    mmu.write(index_address, 0x80 | (palette * 8 + color * 2));
+    mmu.write(index_address + 1, rgb555 as u8);
+    mmu.write(index_address + 1, (rgb555 >> 8) as u8);
+  }
+
+  #[test]
+  fn cgb_tiles_use_their_attributes_and_color_palettes() {
+    let mut mmu = MMU { model: Model::Cgb, ..MMU::default() };
+    // tile 0 is solid color 1 in bank 0 and solid color 2 in bank 1, tile 1 solid color 3 in bank 0
+    for row in 0..8 {
+      mmu.vram[row * 2] = 0xFF;
+      mmu.vram[MMU::VRAM_SIZE + row * 2 + 1] = 0xFF;
+      mmu.vram[PPU::TILE_SIZE + row * 2..PPU::TILE_SIZE + row * 2 + 2].copy_from_slice(&[0xFF, 0xFF]);
+    }
+    // the first tile of the map comes from bank 1 with palette 3, the second has priority over sprites
+    mmu.vram[MMU::VRAM_SIZE + PPU::TILE_MAP_0_OFFSET] = 0b0000_1011;
+    mmu.vram[MMU::VRAM_SIZE + PPU::TILE_MAP_0_OFFSET + 1] = 0b1000_0000;
+    write_color(&mut mmu, MMU::BCPS_ADDRESS, 3, 2, 0x7C00);
+    write_color(&mut mmu, MMU::BCPS_ADDRESS, 0, 1, 0x03E0);
+    write_color(&mut mmu, MMU::OCPS_ADDRESS, 2, 3, 0x001F);
+    // a sprite with tile 1 and palette 2 across x 12-19, half of it over the second tile
+    mmu.oam[..4].copy_from_slice(&[16, 20, 1, 0x02]);
+    mmu.write(PPU::LCDC_ADDRESS, 0b1001_0011);
+
+    for &renderer in [Renderer::Scanline, Renderer::Fifo].iter() {
+      let mut mmu = mmu.clone();
+      let mut ppu = PPU::default();
+      ppu.set_renderer(renderer);
+      step_dots(&mut ppu, &mut mmu, 144 * 456);
+      let frame = ppu.frame();
+      let colors: Vec<_> = [0, 11, 12, 16, 24].iter().map(|&x| frame.rgb555(x, 0).unwrap()).collect();
+      assert_eq!(colors, [0x7C00, 0x03E0, 0x03E0, 0x001F, 0x03E0], "{:?}", renderer);
+      assert_eq!(frame.to_rgba8888()[..4], [0x00, 0x00, 0xFF, 0xFF]);
+    }
+  }
+
+  #[test]
+  fn fifo_renderer_matches_scanline_on_static_frame() {
+    let scanline = render_frame(busy_scene_mmu(), Renderer::Scanline);
+    let fifo = render_frame(busy_scene_mmu(), Renderer::Fifo);
+    // make sure the scene isn't trivially blank
+    assert!((0..4).all(|shade| scanline.contains(&shade)));
+    for (i, (expected, actual)) in scanline.iter().zip(fifo.iter()).enumerate() {
+      assert_eq!(expected, actual, "pixel ({}, {})", i % PPU::SCREEN_WIDTH, i / PPU::SCREEN_WIDTH);
+    }
+  }
+
+  /// The first line drawn with SCX moved from 0 to 96 partway through mode 3
+  fn line_with_mid_line_scx_change(renderer: Renderer) -> Vec<u8> {
+    let mut mmu = MMU::default();
+    // tile 1 is solid color 3, used from column 12 of the background map onwards
+    for byte in mmu.vram[PPU::TILE_SIZE..PPU::TILE_SIZE * 2].iter_mut() {
+      *byte = 0xFF;
+    }
+    for column in 12..PPU::TILE_MAP_WIDTH {
+      mmu.vram[PPU::TILE_MAP_0_OFFSET + column] = 1;
+    }
+    mmu.write(PPU::BGP_ADDRESS, 0b1110_0100);
+    mmu.write(PPU::LCDC_ADDRESS, 0b1001_0001);
+    let mut ppu = PPU::default();
+    ppu.set_renderer(renderer);
+
+    step_dots(&mut ppu, &mut mmu, 80 + 40);
+    assert_eq!(ppu.mode(), Mode::Drawing);
+    mmu.write(PPU::SCX_ADDRESS, 96);
+    step_dots(&mut ppu, &mut mmu, 172 - 40);
+    assert_eq!(ppu.mode(), Mode::HBlank);
+    first_line(&ppu).to_vec()
+  }
+
+  #[test]
+  fn fifo_renderer_sees_mid_line_scx_change() {
+    // the scanline renderer draws the whole line with the final SCX
+    let scanline = line_with_mid_line_scx_change(Renderer::Scanline);
+    assert!(scanline.iter().all(|&x| x == 3));
+
+    // the FIFO draws the left of the line before the write, and fetches the rest after it
+    let fifo = line_with_mid_line_scx_change(Renderer::Fifo);
+    assert_eq!(fifo[..32], [0; 32]);
+    assert!(fifo[64..].iter().all(|&x| x == 3));
+  }
+
+  #[test]
+  fn fetcher_reads_tile_number_before_tile_data() {
+    let mut mmu = MMU::default();
+    // every map entry is tile 1, solid color 3, and tile 2 is solid color 1
+    for byte in mmu.vram[PPU::TILE_SIZE..PPU::TILE_SIZE * 2].iter_mut() {
+      *byte = 0xFF;
+    }
+    for row in 0..8 {
+      mmu.vram[PPU::TILE_SIZE * 2 + row * 2] = 0xFF;
+    }
+    for entry in mmu.vram[PPU::TILE_MAP_0_OFFSET..PPU::TILE_MAP_1_OFFSET].iter_mut() {
+      *entry = 1;
+    }
+    mmu.write(PPU::BGP_ADDRESS, 0b1110_0100);
+    mmu.write(PPU::LCDC_ADDRESS, 0b1001_0001);
+    let mut ppu = PPU::default();
+    ppu.set_renderer(Renderer::Fifo);
+
+    while ppu.mode() != Mode::Drawing {
+      ppu.step(&mut mmu, 1);
+    }
+    // the first tile's number has been read but none of its data
+    ppu.step(&mut mmu, 1);
+    mmu.vram[PPU::TILE_MAP_0_OFFSET] = 2;
+    for byte in mmu.vram[PPU::TILE_SIZE..PPU::TILE_SIZE * 2].iter_mut().step_by(2) {
+      *byte = 0;
+    }
+    step_dots(&mut ppu, &mut mmu, 456);
+
+    // the map write came too late for the first tile, but the new data was fetched for it
+    assert_eq!(first_line(&ppu)[..8], [2; 8]);
+  }
+
+  #[test]
+  fn frame_decodes_shades_to_colors() {
+    let mut ppu = PPU::default();
+    ppu.framebuffer[0] = 3;
+    ppu.framebuffer[Frame::WIDTH + 1] = 1;
+    let frame = ppu.frame();
+    assert_eq!(frame.pixel(0, 0), Color::Black);
+    assert_eq!(frame.pixel(1, 1), Color::LightGray);
+    assert_eq!(frame.pixel(Frame::WIDTH - 1, Frame::HEIGHT - 1), Color::White);
+    assert_eq!(frame.pixels().count(), Frame::WIDTH * Frame::HEIGHT);
 
+    let rgba = frame.to_rgba8888();
+    assert_eq!(rgba.len(), Frame::WIDTH * Frame::HEIGHT * 4);
+    assert_eq!(rgba[..8], [0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(Color::DarkGray.rgba(), [0x55, 0x55, 0x55, 0xFF]);
   }
 }
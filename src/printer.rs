@@ -0,0 +1,259 @@
+use {
+  crate::serial::SerialConnector,
+  std::sync::{Arc, Mutex},
+};
+
+/// A strip of paper printed by the Game Boy Printer, in shades 0 (white) to 3 (black) row by row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrintedImage {
+  pub width: usize,
+  pub height: usize,
+  pub pixels: Vec<u8>,
+}
+
+/// The Game Boy Printer, plugged into the serial port with `Gameboy::set_serial_connector`
+///
+/// Clones share the same printer, so one can be kept to collect what is printed. The game sends
+/// packets of `88 33`, a command, a compression flag, a little endian length, the data and a little
+/// endian checksum of everything after the magic bytes, then two zeros the printer answers with 0x81
+/// and its status. Image data is sent in bands of 2 rows of 20 tiles, and printed in one strip once a
+/// print command follows. Printing finishes after the game has seen the printer busy for a few status
+/// checks.
+#[derive(Debug, Clone, Default)]
+pub struct Printer {
+  state: Arc<Mutex<PrinterState>>,
+}
+
+#[derive(Debug, Default)]
+struct PrinterState {
+  /// The packet being received, from its magic bytes on
+  packet: Vec<u8>,
+  /// Tile data received since the last print
+  image_data: Vec<u8>,
+  printed: Vec<PrintedImage>,
+  status: u8,
+  /// Status checks left to answer busy for the last print
+  busy_checks: u8,
+}
+
+impl Printer {
+  pub const WIDTH: usize = 160;
+
+  const MAGIC: [u8; 2]              = [0x88, 0x33];
+  const HEADER_SIZE: usize          = 6;
+  /// The checksum, then the two bytes the printer answers
+  const TRAILER_SIZE: usize         = 4;
+  const ALIVE_VALUE: u8             = 0x81;
+  const COMMAND_INIT: u8            = 0x01;
+  const COMMAND_PRINT: u8           = 0x02;
+  const COMMAND_DATA: u8            = 0x04;
+  const STATUS_CHECKSUM_ERROR: u8   = 0b0000_0001;
+  const STATUS_BUSY: u8             = 0b0000_0010;
+  const STATUS_FULL: u8             = 0b0000_0100;
+  const STATUS_UNPROCESSED: u8      = 0b0000_1000;
+  const BUSY_STATUS_CHECKS: u8      = 2;
+  const BAND_SIZE: usize            = 0x280;
+  const MAX_BANDS: usize            = 9;
+  const PRINT_PALETTE_INDEX: usize  = 2;
+  /// A palette of 0 prints as the usual one
+  const DEFAULT_PALETTE: u8         = 0xE4;
+  const RUN_BIT: u8                 = 0x80;
+  const TILE_BYTES: usize           = 16;
+  const TILES_WIDE: usize           = Self::WIDTH / 8;
+
+  /// Take the strips printed since the last call, oldest first
+  pub fn take_printed(&self) -> Vec<PrintedImage> {
+    std::mem::take(&mut self.state.lock().unwrap().printed)
+  }
+}
+
+impl SerialConnector for Printer {
+  fn exchange(&mut self, sent: u8) -> u8 {
+    self.state.lock().unwrap().receive(sent)
+  }
+}
+
+impl PrinterState {
+  /// Take the next byte of a packet
+  ///
+  /// # Returns
+  /// the byte the printer sends back
+  fn receive(&mut self, value: u8) -> u8 {
+    let position = self.packet.len();
+    if position < Printer::MAGIC.len() && value != Printer::MAGIC[position] {
+      // out of sync, start over at this byte if it could begin a packet
+      self.packet.clear();
+      if value == Printer::MAGIC[0] {
+        self.packet.push(value);
+      }
+      return 0x00;
+    }
+    self.packet.push(value);
+    if self.packet.len() < Printer::HEADER_SIZE {
+      return 0x00;
+    }
+
+    let data_length = u16::from_le_bytes([self.packet[4], self.packet[5]]) as usize;
+    let alive = Printer::HEADER_SIZE + data_length + Printer::TRAILER_SIZE - 1;
+    match self.packet.len() {
+      length if length == alive => Printer::ALIVE_VALUE,
+      length if length == alive + 1 => {
+        let packet = std::mem::take(&mut self.packet);
+        self.process(&packet[Printer::MAGIC.len()..]);
+        self.report_status()
+      }
+      _ => 0x00,
+    }
+  }
+
+  /// Run the command in `packet`, the bytes after the magic bytes
+  fn process(&mut self, packet: &[u8]) {
+    let body = &packet[..packet.len() - Printer::TRAILER_SIZE];
+    let checksum = u16::from_le_bytes([packet[body.len()], packet[body.len() + 1]]);
+    if body.iter().fold(0u16, |sum, &x| sum.wrapping_add(x as u16)) != checksum {
+      self.status |= Printer::STATUS_CHECKSUM_ERROR;
+      return;
+    }
+    self.status &= !Printer::STATUS_CHECKSUM_ERROR;
+
+    let (command, compressed, data) = (body[0], body[1] != 0, &body[Printer::HEADER_SIZE - Printer::MAGIC.len()..]);
+    match command {
+      Printer::COMMAND_INIT => {
+        self.image_data.clear();
+        self.status = 0;
+        self.busy_checks = 0;
+      }
+      Printer::COMMAND_DATA if compressed => {
+        let data = Self::decompress(data);
+        self.store(&data);
+      }
+      Printer::COMMAND_DATA => self.store(data),
+      Printer::COMMAND_PRINT => {
+        let palette = data.get(Printer::PRINT_PALETTE_INDEX).cloned().unwrap_or_default();
+        self.print(palette);
+      }
+      // anything else just asks for the status
+      _ => {}
+    }
+  }
+
+  fn store(&mut self, data: &[u8]) {
+    let room = (Printer::BAND_SIZE * Printer::MAX_BANDS).saturating_sub(self.image_data.len());
+    self.image_data.extend_from_slice(&data[..data.len().min(room)]);
+    if !self.image_data.is_empty() {
+      self.status |= Printer::STATUS_UNPROCESSED;
+    }
+    if room <= data.len() {
+      self.status |= Printer::STATUS_FULL;
+    }
+  }
+
+  /// Expand runs: a control byte with bit 7 set repeats the next byte (control & 0x7F) + 2 times,
+  /// without it the next (control + 1) bytes are copied
+  fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut bytes = data.iter().cloned();
+    while let Some(control) = bytes.next() {
+      if control & Printer::RUN_BIT != 0 {
+        let value = bytes.next().unwrap_or_default();
+        out.extend(std::iter::repeat_n(value, (control & !Printer::RUN_BIT) as usize + 2));
+      } else {
+        out.extend(bytes.by_ref().take(control as usize + 1));
+      }
+    }
+    out
+  }
+
+  /// Print the tile data received so far as one strip, mapping colors to shades through `palette` as
+  /// BGP does
+  fn print(&mut self, palette: u8) {
+    let palette = if palette == 0 { Printer::DEFAULT_PALETTE } else { palette };
+    let tile_rows = self.image_data.len() / (Printer::TILE_BYTES * Printer::TILES_WIDE);
+    let (width, height) = (Printer::WIDTH, tile_rows * 8);
+    let mut pixels = vec![0; width * height];
+    for (y, row) in pixels.chunks_mut(width).enumerate() {
+      for (x, pixel) in row.iter_mut().enumerate() {
+        let tile = (y / 8) * Printer::TILES_WIDE + x / 8;
+        let offset = tile * Printer::TILE_BYTES + (y % 8) * 2;
+        let bit = 7 - (x % 8);
+        let color = ((self.image_data[offset] >> bit) & 1) | (((self.image_data[offset + 1] >> bit) & 1) << 1);
+        *pixel = (palette >> (color * 2)) & 0b11;
+      }
+    }
+    if height > 0 {
+      self.printed.push(PrintedImage { width, height, pixels });
+    }
+    self.image_data.clear();
+    self.status &= !(Printer::STATUS_UNPROCESSED | Printer::STATUS_FULL);
+    self.busy_checks = Printer::BUSY_STATUS_CHECKS;
+  }
+
+  fn report_status(&mut self) -> u8 {
+    let busy = if self.busy_checks > 0 { Printer::STATUS_BUSY } else { 0 };
+    self.busy_checks = self.busy_checks.saturating_sub(1);
+    self.status | busy
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  /// Send a packet, returning the alive and status bytes
+  fn send(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+    let mut body = vec![command, compressed as u8];
+    body.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    body.extend_from_slice(data);
+    let checksum = body.iter().fold(0u16, |sum, &x| sum.wrapping_add(x as u16));
+
+    let mut replies = vec![];
+    for &x in Printer::MAGIC.iter().chain(&body).chain(&checksum.to_le_bytes()).chain(&[0, 0]) {
+      replies.push(printer.exchange(x));
+    }
+    let (_, trailer) = replies.split_at(replies.len() - 2);
+    assert!(replies[..replies.len() - 2].iter().all(|&x| x == 0));
+    (trailer[0], trailer[1])
+  }
+
+  #[test]
+  fn prints_bands_through_the_palette() {
+    let mut printer = Printer::default();
+    let handle = printer.clone();
+    assert_eq!(send(&mut printer, Printer::COMMAND_INIT, false, &[]), (0x81, 0x00));
+
+    // a band with the top row of tiles color 3 and the bottom color 1, the bottom row compressed
+    let top = vec![0xFF; Printer::BAND_SIZE / 2];
+    assert_eq!(send(&mut printer, Printer::COMMAND_DATA, false, &top).1, Printer::STATUS_UNPROCESSED);
+    let compressed: Vec<u8> = (0..Printer::BAND_SIZE / 4).flat_map(|_| vec![0x01, 0xFF, 0x00]).collect();
+    send(&mut printer, Printer::COMMAND_DATA, true, &compressed);
+    send(&mut printer, Printer::COMMAND_DATA, false, &[]);
+
+    // color 3 prints as shade 1, color 1 as shade 2
+    let status = send(&mut printer, Printer::COMMAND_PRINT, false, &[0x01, 0x13, 0b0100_1000, 0x40]).1;
+    assert_eq!(status, Printer::STATUS_BUSY);
+    assert_eq!(send(&mut printer, 0x0F, false, &[]).1, Printer::STATUS_BUSY);
+    assert_eq!(send(&mut printer, 0x0F, false, &[]).1, 0x00);
+
+    let printed = handle.take_printed();
+    assert_eq!(printed.len(), 1);
+    let image = &printed[0];
+    assert_eq!((image.width, image.height), (160, 16));
+    assert!(image.pixels[..160 * 8].iter().all(|&x| x == 1));
+    assert!(image.pixels[160 * 8..].iter().all(|&x| x == 2));
+    assert!(handle.take_printed().is_empty());
+  }
+
+  #[test]
+  fn bad_checksum_is_reported_and_ignored() {
+    let mut printer = Printer::default();
+    for &x in &[0x88, 0x33, 0x04, 0x00, 0x01, 0x00, 0xAA, 0x00, 0x00] {
+      printer.exchange(x);
+    }
+    assert_eq!(printer.exchange(0x00), 0x81);
+    assert_eq!(printer.exchange(0x00), Printer::STATUS_CHECKSUM_ERROR);
+
+    // noise before the magic bytes is skipped
+    printer.exchange(0x12);
+    assert_eq!(send(&mut printer, 0x0F, false, &[]), (0x81, 0x00));
+  }
+}
@@ -0,0 +1,186 @@
+use std::collections::VecDeque;
+
+/// A ring buffer of save states taken every few frames, for rolling a machine back
+///
+/// Only the newest snapshot is kept whole. Each older one is stored as a delta that rebuilds it from
+/// the snapshot taken after it, which is small since little changes in a few frames.
+#[derive(Debug, Clone)]
+pub struct Rewind {
+  /// Most snapshots kept, counting the newest
+  capacity: usize,
+  /// Frames between snapshots
+  interval: u32,
+  /// Frames finished since the last snapshot
+  frames: u32,
+  newest: Option<Vec<u8>>,
+  /// Newest first, each rebuilding the snapshot before the one it follows
+  deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+  pub const DEFAULT_INTERVAL: u32 = 4;
+
+  pub fn new(capacity: usize) -> Self {
+    Self {
+      capacity,
+      interval: Self::DEFAULT_INTERVAL,
+      frames: 0,
+      newest: None,
+      deltas: VecDeque::with_capacity(capacity),
+    }
+  }
+
+  /// Take a snapshot every `frames` frames, at least 1
+  pub fn set_interval(&mut self, frames: u32) {
+    self.interval = frames.max(1);
+  }
+
+  /// Count a finished frame
+  ///
+  /// # Returns
+  /// true if a snapshot is due
+  pub fn frame_finished(&mut self) -> bool {
+    self.frames += 1;
+    if self.frames < self.interval {
+      return false;
+    }
+    self.frames = 0;
+    true
+  }
+
+  /// Number of snapshots held
+  pub fn len(&self) -> usize {
+    self.newest.as_ref().map_or(0, |_| 1 + self.deltas.len())
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.newest.is_none()
+  }
+
+  /// Add `state` as the newest snapshot, dropping the oldest once over capacity
+  pub fn push(&mut self, state: Vec<u8>) {
+    if self.capacity == 0 {
+      return;
+    }
+    if let Some(previous) = self.newest.replace(state) {
+      let delta = encode_delta(self.newest.as_ref().unwrap(), &previous);
+      self.deltas.push_front(delta);
+      self.deltas.truncate(self.capacity - 1);
+    }
+  }
+
+  /// Remove the newest `steps` snapshots, at least one
+  ///
+  /// # Returns
+  /// the oldest snapshot removed and how many were, or `None` if there were none
+  pub fn pop(&mut self, steps: usize) -> Option<(Vec<u8>, usize)> {
+    let mut state = self.newest.take()?;
+    let mut popped = 1;
+    while popped < steps {
+      match self.deltas.pop_front() {
+        Some(delta) => state = apply_delta(&state, &delta),
+        None => break,
+      }
+      popped += 1;
+    }
+    self.newest = self.deltas.pop_front().map(|delta| apply_delta(&state, &delta));
+    self.frames = 0;
+    Some((state, popped))
+  }
+
+  pub fn clear(&mut self) {
+    self.newest = None;
+    self.deltas.clear();
+    self.frames = 0;
+  }
+}
+
+/// Encode `target` as a delta against `base`
+///
+/// The delta is the length of `target` followed by runs of the two xored together: a count of zero
+/// bytes, which `base` already has right, then a count of bytes to xor in and the bytes themselves.
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+  let xored: Vec<u8> = target
+    .iter()
+    .enumerate()
+    .map(|(i, &byte)| byte ^ base.get(i).copied().unwrap_or(0))
+    .collect();
+
+  let mut delta = (target.len() as u32).to_le_bytes().to_vec();
+  let max_run = u16::MAX as usize;
+  let mut i = 0;
+  while i < xored.len() {
+    let unchanged = xored[i..].iter().take(max_run).take_while(|&&x| x == 0).count();
+    i += unchanged;
+    let changed = xored[i..].iter().take(max_run).take_while(|&&x| x != 0).count();
+    delta.extend_from_slice(&(unchanged as u16).to_le_bytes());
+    delta.extend_from_slice(&(changed as u16).to_le_bytes());
+    delta.extend_from_slice(&xored[i..i + changed]);
+    i += changed;
+  }
+  delta
+}
+
+/// Rebuild the target of `delta` from `base`, see `encode_delta`
+fn apply_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+  let read_u16 = |at: usize| u16::from_le_bytes([delta[at], delta[at + 1]]) as usize;
+  let length = u32::from_le_bytes([delta[0], delta[1], delta[2], delta[3]]) as usize;
+  let mut target = base.to_vec();
+  target.resize(length, 0);
+
+  let (mut i, mut at) = (0, 4);
+  while at < delta.len() {
+    let (unchanged, changed) = (read_u16(at), read_u16(at + 2));
+    at += 4;
+    i += unchanged;
+    for (byte, &xor) in target[i..i + changed].iter_mut().zip(delta[at..at + changed].iter()) {
+      *byte ^= xor;
+    }
+    i += changed;
+    at += changed;
+  }
+  target
+}
+
+#[cfg(test)]
+mod test {
+  use {super::*, quickcheck_macros::quickcheck};
+
+  #[quickcheck]
+  fn delta_rebuilds_target(base: Vec<u8>, target: Vec<u8>) -> bool {
+    apply_delta(&base, &encode_delta(&base, &target)) == target
+  }
+
+  #[test]
+  fn delta_of_similar_states_is_small() {
+    let base = vec![0x5A; 0x10000];
+    let mut target = base.clone();
+    target[0x1234] = 0;
+    target[0xFFFF] = 1;
+    assert_eq!(apply_delta(&base, &encode_delta(&base, &target)), target);
+    assert!(encode_delta(&base, &target).len() < 32);
+  }
+
+  #[test]
+  fn pop_rolls_back_and_oldest_are_dropped() {
+    let mut rewind = Rewind::new(3);
+    assert_eq!(rewind.pop(1), None);
+    for i in 0..5u8 {
+      rewind.push(vec![i; 8]);
+    }
+    assert_eq!(rewind.len(), 3);
+    assert_eq!(rewind.pop(2), Some((vec![3; 8], 2)));
+    assert_eq!(rewind.len(), 1);
+    // asking for more than is left stops at the oldest
+    assert_eq!(rewind.pop(5), Some((vec![2; 8], 1)));
+    assert!(rewind.is_empty());
+  }
+
+  #[test]
+  fn snapshots_are_due_every_interval() {
+    let mut rewind = Rewind::new(1);
+    rewind.set_interval(3);
+    let due: Vec<_> = (0..6).map(|_| rewind.frame_finished()).collect();
+    assert_eq!(due, [false, false, true, false, false, true]);
+  }
+}
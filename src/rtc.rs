@@ -0,0 +1,258 @@
+use {
+  crate::state::*,
+  derivative::Derivative,
+  std::{
+    io::{self, Read, Write},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+  },
+};
+
+/// A source of wall clock time for a cartridge's real time clock, so it can be replaced in tests
+pub trait Clock {
+  /// Seconds since some fixed point in the past, only the difference between calls matters
+  fn now(&self) -> u64;
+}
+
+/// The host's clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or_default()
+  }
+}
+
+/// The real time clock of an MBC3 cartridge
+///
+/// Time is counted from the host clock rather than emulated cycles, as the battery keeps the real
+/// one running while the gameboy is off. The game reads a copy of the registers latched by writing
+/// 0 then 1 to 6000-7FFF.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rtc {
+  /// Seconds counted as of `anchor`, days included
+  seconds: u64,
+  /// The clock's time when `seconds` was last brought up to date
+  anchor: u64,
+  /// Set through bit 6 of DH, stopping the clock
+  halted: bool,
+  /// Set when the day counter overflows, until the game clears it through bit 7 of DH
+  day_carry: bool,
+  /// S, M, H, DL and DH as of the last latch
+  latched: [u8; Rtc::REGISTER_COUNT],
+  /// Whether 0 was the last value written to the latch register
+  latch_armed: bool,
+  /// Not serialized, a deserialized clock counts from the host clock
+  #[derivative(Debug = "ignore")]
+  #[cfg_attr(feature = "serde", serde(skip, default = "system_clock"))]
+  clock: Arc<dyn Clock + Send + Sync>,
+}
+
+#[cfg(feature = "serde")]
+fn system_clock() -> Arc<dyn Clock + Send + Sync> {
+  Arc::new(SystemClock)
+}
+
+impl Default for Rtc {
+  fn default() -> Self {
+    Self::new(SystemClock)
+  }
+}
+
+impl Rtc {
+  /// Value written to the RAM bank register to map S, the first of the clock registers
+  pub const FIRST_REGISTER: u8 = 0x08;
+  pub const LAST_REGISTER: u8  = 0x0C;
+
+  const REGISTER_COUNT: usize = 5;
+  const DAYS: u64             = 512;
+  const SECONDS_PER_DAY: u64  = 24 * 60 * 60;
+  const DH_DAY_BIT: u8        = 0b0000_0001;
+  const DH_HALT_BIT: u8       = 0b0100_0000;
+  const DH_CARRY_BIT: u8      = 0b1000_0000;
+
+  pub fn new(clock: impl Clock + Send + Sync + 'static) -> Self {
+    let clock: Arc<dyn Clock + Send + Sync> = Arc::new(clock);
+    Self {
+      seconds: 0,
+      anchor: clock.now(),
+      halted: false,
+      day_carry: false,
+      latched: [0; Self::REGISTER_COUNT],
+      latch_armed: false,
+      clock,
+    }
+  }
+
+  /// Count time from `clock` from now on, keeping the time counted so far
+  pub fn set_clock(&mut self, clock: impl Clock + Send + Sync + 'static) {
+    self.update();
+    self.clock = Arc::new(clock);
+    self.anchor = self.clock.now();
+  }
+
+  /// Bring `seconds` up to date with the clock, wrapping the day counter into the carry
+  fn update(&mut self) {
+    let now = self.clock.now();
+    if !self.halted {
+      self.seconds += now.saturating_sub(self.anchor);
+    }
+    self.anchor = now;
+    if self.seconds >= Self::DAYS * Self::SECONDS_PER_DAY {
+      self.seconds %= Self::DAYS * Self::SECONDS_PER_DAY;
+      self.day_carry = true;
+    }
+  }
+
+  /// The live S, M, H, DL and DH
+  fn registers(&mut self) -> [u8; Self::REGISTER_COUNT] {
+    self.update();
+    let days = self.seconds / Self::SECONDS_PER_DAY;
+    let mut dh = (days >> 8) as u8 & Self::DH_DAY_BIT;
+    if self.halted {
+      dh |= Self::DH_HALT_BIT;
+    }
+    if self.day_carry {
+      dh |= Self::DH_CARRY_BIT;
+    }
+    [
+      (self.seconds % 60) as u8,
+      (self.seconds / 60 % 60) as u8,
+      (self.seconds / 3600 % 24) as u8,
+      days as u8,
+      dh,
+    ]
+  }
+
+  /// Observe a write of `value` to 6000-7FFF, latching the registers on a 0 followed by a 1
+  pub fn write_latch(&mut self, value: u8) {
+    if self.latch_armed && value == 1 {
+      self.latched = self.registers();
+    }
+    self.latch_armed = value == 0;
+  }
+
+  /// Read the latched value of clock register `register`, 08-0C
+  pub fn read(&self, register: u8) -> u8 {
+    self.latched[(register - Self::FIRST_REGISTER) as usize]
+  }
+
+  /// Set the live value of clock register `register`, 08-0C
+  pub fn write(&mut self, register: u8, value: u8) {
+    let mut registers = self.registers();
+    registers[(register - Self::FIRST_REGISTER) as usize] = value;
+    let [s, m, h, dl, dh] = registers;
+    let days = ((dh & Self::DH_DAY_BIT) as u64) << 8 | dl as u64;
+    self.seconds = days * Self::SECONDS_PER_DAY + h as u64 * 3600 + m as u64 * 60 + s as u64;
+    self.halted = dh & Self::DH_HALT_BIT != 0;
+    self.day_carry = dh & Self::DH_CARRY_BIT != 0;
+  }
+}
+
+impl SaveState for Rtc {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    write_u64(w, self.seconds)?;
+    write_u64(w, self.anchor)?;
+    write_bool(w, self.halted)?;
+    write_bool(w, self.day_carry)?;
+    w.write_all(&self.latched)?;
+    write_bool(w, self.latch_armed)
+  }
+
+  /// Restores the time as of the save, so the time since counts towards it as if the battery kept it running
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    self.seconds = read_u64(r)?;
+    self.anchor = read_u64(r)?;
+    self.halted = read_bool(r)?;
+    self.day_carry = read_bool(r)?;
+    r.read_exact(&mut self.latched)?;
+    self.latch_armed = read_bool(r)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use {
+    super::*,
+    std::sync::atomic::{AtomicU64, Ordering},
+  };
+
+  /// A clock that only moves when told to
+  #[derive(Clone, Default)]
+  struct FakeClock(Arc<AtomicU64>);
+
+  impl FakeClock {
+    fn advance(&self, seconds: u64) {
+      self.0.fetch_add(seconds, Ordering::SeqCst);
+    }
+  }
+
+  impl Clock for FakeClock {
+    fn now(&self) -> u64 {
+      self.0.load(Ordering::SeqCst)
+    }
+  }
+
+  fn latch(rtc: &mut Rtc) -> [u8; Rtc::REGISTER_COUNT] {
+    rtc.write_latch(0);
+    rtc.write_latch(1);
+    let mut registers = [0; Rtc::REGISTER_COUNT];
+    for (i, x) in registers.iter_mut().enumerate() {
+      *x = rtc.read(Rtc::FIRST_REGISTER + i as u8);
+    }
+    registers
+  }
+
+  #[test]
+  fn counts_clock_time_once_latched() {
+    let clock = FakeClock::default();
+    let mut rtc = Rtc::new(clock.clone());
+    // 1 day, 2 hours, 3 minutes and 4 seconds
+    clock.advance(86400 + 2 * 3600 + 3 * 60 + 4);
+    assert_eq!(rtc.read(Rtc::FIRST_REGISTER), 0);
+    assert_eq!(latch(&mut rtc), [4, 3, 2, 1, 0]);
+
+    // the latched copy holds still
+    clock.advance(10);
+    assert_eq!(rtc.read(Rtc::FIRST_REGISTER), 4);
+    // a 1 without a 0 before it doesn't latch
+    rtc.write_latch(1);
+    assert_eq!(rtc.read(Rtc::FIRST_REGISTER), 4);
+  }
+
+  #[test]
+  fn halt_stops_the_clock() {
+    let clock = FakeClock::default();
+    let mut rtc = Rtc::new(clock.clone());
+    rtc.write(Rtc::LAST_REGISTER, Rtc::DH_HALT_BIT);
+    rtc.write(Rtc::FIRST_REGISTER, 30);
+    clock.advance(100);
+    assert_eq!(latch(&mut rtc), [30, 0, 0, 0, Rtc::DH_HALT_BIT]);
+
+    rtc.write(Rtc::LAST_REGISTER, 0);
+    clock.advance(5);
+    assert_eq!(latch(&mut rtc)[0], 35);
+  }
+
+  #[test]
+  fn day_counter_overflow_sets_carry() {
+    let clock = FakeClock::default();
+    let mut rtc = Rtc::new(clock.clone());
+    // day 511
+    rtc.write(Rtc::FIRST_REGISTER + 3, 0xFF);
+    rtc.write(Rtc::LAST_REGISTER, Rtc::DH_DAY_BIT);
+    assert_eq!(latch(&mut rtc)[3..], [0xFF, Rtc::DH_DAY_BIT]);
+
+    clock.advance(Rtc::SECONDS_PER_DAY);
+    assert_eq!(latch(&mut rtc)[3..], [0x00, Rtc::DH_CARRY_BIT]);
+    // it sticks until cleared
+    clock.advance(Rtc::SECONDS_PER_DAY);
+    assert_eq!(latch(&mut rtc)[3..], [0x01, Rtc::DH_CARRY_BIT]);
+    rtc.write(Rtc::LAST_REGISTER, 0);
+    assert_eq!(latch(&mut rtc)[3..], [0x01, 0x00]);
+  }
+}
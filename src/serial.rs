@@ -0,0 +1,232 @@
+use {
+  crate::{
+    interrupt::Interrupt,
+    peripheral::Peripheral,
+    state::*,
+    util::*,
+  },
+  derivative::Derivative,
+  std::{
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+  },
+};
+
+/// Called with each byte the serial port sends
+pub type SerialCallback = Arc<Mutex<dyn FnMut(u8) + Send>>;
+
+/// A device plugged into the other end of the link cable, such as a printer or a network transport
+/// to another machine
+pub trait SerialConnector {
+  /// Swap bytes for a transfer clocked by the gameboy, `sent` is the byte shifted out
+  ///
+  /// # Returns
+  /// the byte shifted in
+  fn exchange(&mut self, sent: u8) -> u8;
+
+  /// Called while the gameboy waits for the other end to clock a transfer, with the byte it has
+  /// ready to send
+  ///
+  /// # Returns
+  /// the byte shifted in if the device clocked the transfer, completing it, or `None` to keep waiting
+  fn poll(&mut self, _outgoing: u8) -> Option<u8> {
+    None
+  }
+}
+
+/// A cable looped back into the same port, every byte sent is received back
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Loopback;
+
+impl SerialConnector for Loopback {
+  fn exchange(&mut self, sent: u8) -> u8 {
+    sent
+  }
+}
+
+/// Which end of the link cable provides the clock for a transfer, selected by SC bit 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ClockRole {
+  /// Clocks the transfer from the internal 8192Hz clock and drives the exchange
+  Master,
+  /// Waits for the other end of the cable to clock the transfer
+  Slave,
+}
+
+/// The serial port (SB, SC)
+#[derive(Derivative, Clone, Default)]
+#[derivative(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Serial {
+  /// Serial transfer data
+  pub sb: u8,
+  /// Serial transfer control
+  pub sc: u8,
+  /// Cycles until the in-flight transfer completes, if there is one
+  remaining_cycles: Option<u16>,
+  /// Byte shifted out by the last transfer this port clocked, until a link collects it
+  shifted_out: Option<u8>,
+  #[derivative(Debug = "ignore")]
+  #[cfg_attr(feature = "serde", serde(skip))]
+  callback: Option<SerialCallback>,
+  #[derivative(Debug = "ignore")]
+  #[cfg_attr(feature = "serde", serde(skip))]
+  connector: Option<Arc<Mutex<dyn SerialConnector + Send>>>,
+}
+
+impl Serial {
+  pub const SB_ADDRESS: u16 = 0xFF01;
+  pub const SC_ADDRESS: u16 = 0xFF02;
+
+  const SC_TRANSFER_START_BIT_N: u8 = 7;
+  const SC_CLOCK_SELECT_BIT_N: u8   = 0;
+  const SC_UNUSED_BITS: u8          = 0b0111_1110;
+
+  /// Cycles to shift out all 8 bits using the 8192Hz internal clock
+  const TRANSFER_CYCLES: u16 = 8 * 512;
+  /// Value shifted in when nothing is connected to the other end of the cable
+  const DISCONNECTED_VALUE: u8 = 0xFF;
+
+  /// Advance the serial port `n_cycles`
+  ///
+  /// # Returns
+  /// true if a transfer completed and a serial interrupt should be requested
+  pub fn step(&mut self, n_cycles: u8) -> bool {
+    match self.remaining_cycles {
+      Some(remaining) if remaining <= n_cycles as u16 => {
+        let sent = self.sb;
+        self.remaining_cycles = None;
+        self.shifted_out = Some(sent);
+        if let Some(callback) = self.callback.as_ref() {
+          (callback.lock().unwrap())(sent);
+        }
+        self.sb = match self.connector.as_ref() {
+          Some(connector) => connector.lock().unwrap().exchange(sent),
+          None => Self::DISCONNECTED_VALUE,
+        };
+        self.sc = set_bit(self.sc as u16, Self::SC_TRANSFER_START_BIT_N, false) as u8;
+        true
+      }
+      Some(remaining) => {
+        self.remaining_cycles = Some(remaining - n_cycles as u16);
+        false
+      }
+      None if self.is_waiting_for_clock() => {
+        let received = self.connector.as_ref().and_then(|connector| connector.lock().unwrap().poll(self.sb));
+        match received {
+          Some(value) => {
+            self.receive(value);
+            true
+          }
+          None => false,
+        }
+      }
+      None => false,
+    }
+  }
+
+  /// Call `callback` with each byte sent by a transfer this port clocks, e.g. to collect test ROM output
+  pub fn set_callback(&mut self, callback: impl FnMut(u8) + Send + 'static) {
+    self.callback = Some(Arc::new(Mutex::new(callback)));
+  }
+
+  /// Plug `connector` into the other end of the cable in place of whatever was there
+  ///
+  /// Transfers this port clocks swap bytes with it, and it can clock transfers this port is waiting
+  /// on. Not meant to be combined with a `link::SerialLink`.
+  pub fn set_connector(&mut self, connector: impl SerialConnector + Send + 'static) {
+    self.connector = Some(Arc::new(Mutex::new(connector)));
+  }
+
+  /// Unplug the connector, transfers then read 0xFF as if nothing were connected
+  pub fn clear_connector(&mut self) {
+    self.connector = None;
+  }
+
+  /// Take the byte sent by a transfer clocked by this port that completed since the last call
+  pub(crate) fn take_shifted_out(&mut self) -> Option<u8> {
+    self.shifted_out.take()
+  }
+
+  /// Cycles until the transfer this port is clocking completes, or `None` if there isn't one
+  pub fn cycles_until_complete(&self) -> Option<u32> {
+    self.remaining_cycles.map(u32::from)
+  }
+
+  pub fn role(&self) -> ClockRole {
+    if get_bit(self.sc as u16, Self::SC_CLOCK_SELECT_BIT_N) { ClockRole::Master } else { ClockRole::Slave }
+  }
+
+  /// Returns true if a transfer has been started and hasn't completed yet
+  pub fn is_transferring(&self) -> bool {
+    get_bit(self.sc as u16, Self::SC_TRANSFER_START_BIT_N)
+  }
+
+  /// Returns true if a transfer has been started that waits on the other end of the cable for a clock
+  pub(crate) fn is_waiting_for_clock(&self) -> bool {
+    self.is_transferring() && self.role() == ClockRole::Slave
+  }
+
+  /// Complete an externally clocked transfer, shifting in `value` and returning the byte shifted out
+  pub(crate) fn receive(&mut self, value: u8) -> u8 {
+    let sent = self.sb;
+    self.sb = value;
+    self.sc = set_bit(self.sc as u16, Self::SC_TRANSFER_START_BIT_N, false) as u8;
+    sent
+  }
+}
+
+impl Memory for Serial {
+  fn read(&self, address: u16) -> u8 {
+    match address {
+      Self::SB_ADDRESS => self.sb,
+      Self::SC_ADDRESS => self.sc | Self::SC_UNUSED_BITS,
+      _ => unreachable!("address '0x{:x}' is not a serial register", address),
+    }
+  }
+
+  fn write(&mut self, address: u16, value: u8) {
+    match address {
+      Self::SB_ADDRESS => self.sb = value,
+      Self::SC_ADDRESS => {
+        self.sc = value & !Self::SC_UNUSED_BITS;
+        self.shifted_out = None;
+        // only the master times its own transfer, a slave's completes when a linked master clocks it
+        self.remaining_cycles = if self.is_transferring() && self.role() == ClockRole::Master {
+          Some(Self::TRANSFER_CYCLES)
+        } else {
+          None
+        };
+      }
+      _ => unreachable!("address '0x{:x}' is not a serial register", address),
+    }
+  }
+}
+
+impl Peripheral for Serial {
+  fn claims(&self, address: u16) -> bool {
+    (Self::SB_ADDRESS..=Self::SC_ADDRESS).contains(&address)
+  }
+
+  fn tick(&mut self, n_cycles: u8, _double_speed: bool) -> Option<Interrupt> {
+    Some(Interrupt::Serial).filter(|_| self.step(n_cycles))
+  }
+}
+
+impl SaveState for Serial {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(&[self.sb, self.sc])?;
+    write_bool(w, self.remaining_cycles.is_some())?;
+    write_u16(w, self.remaining_cycles.unwrap_or_default())
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    self.sb = read_u8(r)?;
+    self.sc = read_u8(r)?;
+    let transferring = read_bool(r)?;
+    let remaining_cycles = read_u16(r)?;
+    self.remaining_cycles = if transferring { Some(remaining_cycles) } else { None };
+    Ok(())
+  }
+}
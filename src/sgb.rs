@@ -0,0 +1,131 @@
+use {
+  derivative::Derivative,
+  std::sync::{Arc, Mutex},
+};
+
+/// A single Super Game Boy command packet
+pub type SgbPacket = [u8; SgbCapture::PACKET_SIZE];
+
+/// Called with each packet a game sends to the Super Game Boy
+pub type SgbCallback = Arc<Mutex<dyn FnMut(SgbPacket) + Send>>;
+
+/// Assembles the command packets a game sends to the Super Game Boy by pulsing the P14 and P15
+/// lines of the joypad register
+///
+/// A packet starts with a reset pulse (both lines low), followed by 128 bits sent LSB first, a
+/// P14 pulse for a 0 and a P15 pulse for a 1, and ends with a 0 stop bit. Both lines go back high
+/// between pulses. Only capture is done here, acting on the commands is left to the frontend.
+#[derive(Derivative, Clone, Default)]
+#[derivative(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SgbCapture {
+  /// Bytes of the packet being received
+  packet: SgbPacket,
+  /// Index of the next bit of the packet, None until a reset pulse starts one
+  bit: Option<usize>,
+  /// Whether both lines have gone high since the last pulse
+  released: bool,
+  #[derivative(Debug = "ignore")]
+  #[cfg_attr(feature = "serde", serde(skip))]
+  callback: Option<SgbCallback>,
+}
+
+impl SgbCapture {
+  pub const PACKET_SIZE: usize = 16;
+
+  const PACKET_BITS: usize = Self::PACKET_SIZE * 8;
+  /// P14 and P15, the joypad select lines the packets are sent over
+  const LINES: u8    = 0b0011_0000;
+  const RESET: u8    = 0b0000_0000;
+  const ZERO: u8     = 0b0010_0000;
+  const ONE: u8      = 0b0001_0000;
+  const RELEASED: u8 = 0b0011_0000;
+
+  /// Call `callback` with each packet once its stop bit has been received
+  pub fn set_callback(&mut self, callback: impl FnMut(SgbPacket) + Send + 'static) {
+    self.callback = Some(Arc::new(Mutex::new(callback)));
+  }
+
+  /// Observe a write of `value` to the joypad register
+  pub fn write(&mut self, value: u8) {
+    match value & Self::LINES {
+      Self::RESET => {
+        self.packet = [0; Self::PACKET_SIZE];
+        self.bit = Some(0);
+        self.released = false;
+      }
+      Self::RELEASED => self.released = true,
+      lines if self.released => {
+        self.released = false;
+        let one = lines == Self::ONE;
+        match self.bit {
+          Some(n) if n < Self::PACKET_BITS => {
+            self.packet[n / 8] |= (one as u8) << (n % 8);
+            self.bit = Some(n + 1);
+          }
+          Some(_) => {
+            // a 1 in place of the stop bit means the packet was garbled
+            if lines == Self::ZERO {
+              if let Some(callback) = self.callback.as_ref() {
+                (callback.lock().unwrap())(self.packet);
+              }
+            }
+            self.bit = None;
+          }
+          None => {}
+        }
+      }
+      _ => {}
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use {
+    super::*,
+    crate::{
+      mmu::MMU,
+      model::Model,
+      util::Memory,
+    },
+  };
+
+  /// The joypad register writes a game makes to send `packet`
+  fn pulses(packet: &SgbPacket) -> Vec<u8> {
+    let mut writes = vec![SgbCapture::RESET, SgbCapture::RELEASED];
+    let bits = (0..SgbCapture::PACKET_BITS).map(|n| packet[n / 8] & (1 << (n % 8)) != 0);
+    for one in bits.chain(std::iter::once(false)) {
+      writes.push(if one { SgbCapture::ONE } else { SgbCapture::ZERO });
+      writes.push(SgbCapture::RELEASED);
+    }
+    writes
+  }
+
+  fn captured(model: Model, packet: &SgbPacket) -> Vec<SgbPacket> {
+    let mut mmu = MMU { model, ..MMU::default() };
+    let received = Arc::new(Mutex::new(vec![]));
+    let callback_received = received.clone();
+    mmu.sgb.set_callback(move |x| callback_received.lock().unwrap().push(x));
+    for value in pulses(packet) {
+      mmu.write(MMU::JOYPAD_ADDRESS, value);
+    }
+    let packets = received.lock().unwrap().clone();
+    packets
+  }
+
+  #[test]
+  fn assembles_packet_from_pulses() {
+    // MLT_REQ asking for two players
+    let mut packet = [0; SgbCapture::PACKET_SIZE];
+    packet[0] = 0x89;
+    packet[1] = 0x01;
+    packet[15] = 0x80;
+    assert_eq!(captured(Model::Sgb, &packet), vec![packet]);
+  }
+
+  #[test]
+  fn only_captured_on_sgb() {
+    assert!(captured(Model::Dmg, &[0xFF; SgbCapture::PACKET_SIZE]).is_empty());
+  }
+}
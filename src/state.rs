@@ -0,0 +1,165 @@
+use {
+  failure::Fail,
+  std::io::{self, Read, Write},
+};
+
+/// Identifies a byte stream as a save state
+pub const MAGIC: [u8; 4] = *b"GBST";
+/// Bumped whenever the layout of a save state changes
+pub const VERSION: u16 = 26;
+
+#[derive(Debug, Fail)]
+pub enum StateError {
+  #[fail(display = "not a save state")]
+  BadMagic,
+  #[fail(display = "unsupported save state version {}", _0)]
+  UnsupportedVersion(u16),
+  #[fail(display = "save state is truncated")]
+  Truncated,
+  #[fail(display = "{}", _0)]
+  Io(#[cause] io::Error),
+}
+
+impl From<io::Error> for StateError {
+  fn from(error: io::Error) -> Self {
+    match error.kind() {
+      io::ErrorKind::UnexpectedEof => StateError::Truncated,
+      _ => StateError::Io(error),
+    }
+  }
+}
+
+/// A piece of emulator state that can be written to and restored from a save state
+pub(crate) trait SaveState {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()>;
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()>;
+}
+
+pub(crate) fn write_header(w: &mut dyn Write) -> io::Result<()> {
+  w.write_all(&MAGIC)?;
+  write_u16(w, VERSION)
+}
+
+pub(crate) fn read_header(r: &mut dyn Read) -> Result<(), StateError> {
+  let mut magic = [0; 4];
+  r.read_exact(&mut magic)?;
+  if magic != MAGIC {
+    return Err(StateError::BadMagic);
+  }
+  match read_u16(r)? {
+    VERSION => Ok(()),
+    version => Err(StateError::UnsupportedVersion(version)),
+  }
+}
+
+pub(crate) fn write_u8(w: &mut dyn Write, value: u8) -> io::Result<()> {
+  w.write_all(&[value])
+}
+
+pub(crate) fn write_u16(w: &mut dyn Write, value: u16) -> io::Result<()> {
+  w.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_u32(w: &mut dyn Write, value: u32) -> io::Result<()> {
+  w.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_u64(w: &mut dyn Write, value: u64) -> io::Result<()> {
+  w.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_bool(w: &mut dyn Write, value: bool) -> io::Result<()> {
+  write_u8(w, value as u8)
+}
+
+pub(crate) fn read_u8(r: &mut dyn Read) -> io::Result<u8> {
+  let mut buffer = [0; 1];
+  r.read_exact(&mut buffer)?;
+  Ok(buffer[0])
+}
+
+pub(crate) fn read_u16(r: &mut dyn Read) -> io::Result<u16> {
+  let mut buffer = [0; 2];
+  r.read_exact(&mut buffer)?;
+  Ok(u16::from_le_bytes(buffer))
+}
+
+pub(crate) fn read_u32(r: &mut dyn Read) -> io::Result<u32> {
+  let mut buffer = [0; 4];
+  r.read_exact(&mut buffer)?;
+  Ok(u32::from_le_bytes(buffer))
+}
+
+pub(crate) fn read_u64(r: &mut dyn Read) -> io::Result<u64> {
+  let mut buffer = [0; 8];
+  r.read_exact(&mut buffer)?;
+  Ok(u64::from_le_bytes(buffer))
+}
+
+pub(crate) fn read_bool(r: &mut dyn Read) -> io::Result<bool> {
+  Ok(read_u8(r)? != 0)
+}
+
+/// Serde support for byte arrays larger than serde implements itself, used with `#[serde(with)]`
+///
+/// They are written as bytes, and read from either bytes or a sequence so self describing formats
+/// such as JSON work too. Arrays are read into a buffer on the heap, `boxed` is for those too big to
+/// keep on the stack.
+#[cfg(feature = "serde")]
+pub(crate) mod byte_array {
+  use {
+    serde::{
+      de::{self, SeqAccess, Visitor},
+      Deserializer,
+      Serializer,
+    },
+    std::{convert::TryInto, fmt},
+  };
+
+  pub fn serialize<S: Serializer, const N: usize>(array: &[u8; N], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bytes(array)
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(deserializer: D) -> Result<[u8; N], D::Error> {
+    boxed::deserialize(deserializer).map(|array: Box<[u8; N]>| *array)
+  }
+
+  pub mod boxed {
+    use super::*;
+
+    #[allow(clippy::borrowed_box)]
+    pub fn serialize<S: Serializer, const N: usize>(array: &Box<[u8; N]>, serializer: S) -> Result<S::Ok, S::Error> {
+      serializer.serialize_bytes(&array[..])
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(deserializer: D) -> Result<Box<[u8; N]>, D::Error> {
+      deserializer.deserialize_bytes(ByteArrayVisitor::<N>)
+    }
+  }
+
+  struct ByteArrayVisitor<const N: usize>;
+
+  impl<'de, const N: usize> Visitor<'de> for ByteArrayVisitor<N> {
+    type Value = Box<[u8; N]>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "{} bytes", N)
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+      bytes.to_vec().try_into().map_err(|_| E::invalid_length(bytes.len(), &self))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+      let mut array = vec![0; N];
+      for (i, x) in array.iter_mut().enumerate() {
+        *x = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(i, &self))?;
+      }
+      match seq.next_element::<u8>()? {
+        None => Ok(array.try_into().expect("buffer is N bytes")),
+        Some(_) => Err(de::Error::invalid_length(N + 1, &self)),
+      }
+    }
+  }
+}
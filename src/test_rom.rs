@@ -0,0 +1,128 @@
+use crate::cpu::CPU;
+
+/// Assembles small programs for tests, so opcodes don't have to be encoded by hand
+///
+/// Only covers the instructions tests have needed so far, add to it as more are tested. `build` places
+/// the program at the cartridge entry point of a 32KB ROM, ready for `Gameboy::new_for_testing`.
+#[derive(Debug, Clone, Default)]
+pub struct RomBuilder {
+  program: Vec<u8>,
+}
+
+impl RomBuilder {
+  const ROM_SIZE: usize = 0x8000;
+
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Append raw bytes, for anything without a helper
+  pub fn bytes(mut self, bytes: &[u8]) -> Self {
+    self.program.extend_from_slice(bytes);
+    self
+  }
+
+  fn op(self, opcode: u8) -> Self {
+    self.bytes(&[opcode])
+  }
+
+  fn op_d8(self, opcode: u8, value: u8) -> Self {
+    self.bytes(&[opcode, value])
+  }
+
+  fn op_d16(self, opcode: u8, value: u16) -> Self {
+    self.bytes(&[opcode, value as u8, (value >> 8) as u8])
+  }
+
+  pub fn nop(self) -> Self {
+    self.op(0x00)
+  }
+
+  pub fn halt(self) -> Self {
+    self.op(0x76)
+  }
+
+  pub fn di(self) -> Self {
+    self.op(0xF3)
+  }
+
+  pub fn ei(self) -> Self {
+    self.op(0xFB)
+  }
+
+  /// LD A,d8
+  pub fn ld_a(self, value: u8) -> Self {
+    self.op_d8(0x3E, value)
+  }
+
+  /// LD HL,d16
+  pub fn ld_hl(self, value: u16) -> Self {
+    self.op_d16(0x21, value)
+  }
+
+  /// LD (HL+),A
+  pub fn ld_hli_a(self) -> Self {
+    self.op(0x22)
+  }
+
+  /// ADD A,B
+  pub fn add_a_b(self) -> Self {
+    self.op(0x80)
+  }
+
+  /// JR r8, `offset` counted from the end of the JR
+  pub fn jr(self, offset: i8) -> Self {
+    self.op_d8(0x18, offset as u8)
+  }
+
+  /// JP a16
+  pub fn jp(self, address: u16) -> Self {
+    self.op_d16(0xC3, address)
+  }
+
+  /// The program on its own
+  pub fn program(&self) -> &[u8] {
+    &self.program
+  }
+
+  /// A ROM with the program at the entry point
+  ///
+  /// # Panics
+  /// if the program runs past the end of the ROM
+  pub fn build(self) -> Vec<u8> {
+    let start = CPU::ENTRY_POINT as usize;
+    assert!(start + self.program.len() <= Self::ROM_SIZE, "program doesn't fit in the rom");
+    let mut rom = vec![0x00; Self::ROM_SIZE];
+    rom[start..start + self.program.len()].copy_from_slice(&self.program);
+    rom
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use {
+    super::*,
+    crate::Gameboy,
+  };
+
+  #[test]
+  fn emits_opcode_bytes() {
+    let builder = RomBuilder::new().ld_a(0x3C).add_a_b().jr(-2).ld_hl(0xC000).jp(0x0150);
+    assert_eq!(builder.program(), [0x3E, 0x3C, 0x80, 0x18, 0xFE, 0x21, 0x00, 0xC0, 0xC3, 0x50, 0x01]);
+
+    let rom = builder.build();
+    assert_eq!(rom.len(), 0x8000);
+    assert_eq!(rom[0x100..0x103], [0x3E, 0x3C, 0x80]);
+  }
+
+  #[test]
+  fn built_rom_runs() {
+    let rom = RomBuilder::new().ld_hl(0xC000).ld_a(0x42).ld_hli_a().halt().build();
+    let mut gameboy = Gameboy::new_for_testing(&rom);
+    while !gameboy.cpu.halted {
+      gameboy.step().unwrap();
+    }
+    assert_eq!(gameboy.read(0xC000), 0x42);
+    assert_eq!(gameboy.cpu.hl, 0xC001);
+  }
+}
@@ -0,0 +1,247 @@
+use {
+  crate::{
+    interrupt::Interrupt,
+    peripheral::Peripheral,
+    state::*,
+    util::*,
+  },
+  std::io::{self, Read, Write},
+};
+
+/// The divider and programmable timer (DIV, TIMA, TMA, TAC)
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Timer {
+  /// Internal counter incremented every cycle, DIV is its upper byte
+  counter: u16,
+  /// Timer counter
+  pub tima: u8,
+  /// Timer modulo, reloaded into TIMA when it overflows
+  pub tma: u8,
+  /// Timer control
+  pub tac: u8,
+  /// Set when a DIV reset or TAC write overflowed TIMA, until the next `step` reports it
+  reset_overflowed: bool,
+}
+
+impl Timer {
+  pub const DIV_ADDRESS: u16  = 0xFF04;
+  pub const TIMA_ADDRESS: u16 = 0xFF05;
+  pub const TMA_ADDRESS: u16  = 0xFF06;
+  pub const TAC_ADDRESS: u16  = 0xFF07;
+
+  const TAC_ENABLE_BIT_N: u8 = 2;
+  const TAC_UNUSED_BITS: u8  = 0b1111_1000;
+  /// The internal counter when the DMG bios hands over to the cartridge
+  const POST_BOOT_COUNTER: u16 = 0xABCC;
+
+  /// The timer as the DMG bios leaves it, stopped with DIV reading 0xAB
+  pub fn post_boot() -> Self {
+    Self { counter: Self::POST_BOOT_COUNTER, ..Self::default() }
+  }
+
+  /// Advance the timer `n_cycles`
+  ///
+  /// # Returns
+  /// true if TIMA overflowed and a timer interrupt should be requested
+  pub fn step(&mut self, n_cycles: u8) -> bool {
+    let mut overflowed = std::mem::take(&mut self.reset_overflowed);
+    for _ in 0..n_cycles {
+      let before = self.selected_bit();
+      self.counter = self.counter.wrapping_add(1);
+      // TIMA is clocked by the falling edge of the selected counter bit
+      if before && !self.selected_bit() {
+        overflowed |= self.increment_tima();
+      }
+    }
+    overflowed
+  }
+
+  /// Cycles until TIMA next overflows and requests a timer interrupt, or `None` if the timer is stopped
+  pub fn cycles_until_overflow(&self) -> Option<u32> {
+    if self.reset_overflowed {
+      return Some(0);
+    }
+    if !get_bit(self.tac as u16, Self::TAC_ENABLE_BIT_N) {
+      return None;
+    }
+    // the selected bit falls once every period
+    let period = 1u32 << (self.selected_bit_n() + 1);
+    let to_next_increment = period - self.counter as u32 % period;
+    Some(to_next_increment + (0xFF - self.tima as u32) * period)
+  }
+
+  /// The full 16 bit counter DIV is the upper byte of
+  pub fn internal_counter(&self) -> u16 {
+    self.counter
+  }
+
+  /// Reset the internal counter, which clocks TIMA if the selected bit falls as a result
+  fn reset_counter(&mut self) {
+    let before = self.selected_bit();
+    self.counter = 0;
+    if before {
+      self.reset_overflowed |= self.increment_tima();
+    }
+  }
+
+  /// Set TAC, which like a DIV reset clocks TIMA if the selected bit falls as a result, either by
+  /// disabling the timer or by selecting a bit that's clear
+  fn write_tac(&mut self, value: u8) {
+    let before = self.selected_bit();
+    self.tac = value & !Self::TAC_UNUSED_BITS;
+    if before && !self.selected_bit() {
+      self.reset_overflowed |= self.increment_tima();
+    }
+  }
+
+  fn increment_tima(&mut self) -> bool {
+    let (tima, overflowed) = self.tima.overflowing_add(1);
+    self.tima = if overflowed { self.tma } else { tima };
+    overflowed
+  }
+
+  /// The bit of the internal counter selected by TAC, masked by the timer enable bit
+  fn selected_bit(&self) -> bool {
+    get_bit(self.tac as u16, Self::TAC_ENABLE_BIT_N) && get_bit(self.counter, self.selected_bit_n())
+  }
+
+  /// Which bit of the internal counter TAC selects to clock TIMA
+  fn selected_bit_n(&self) -> u8 {
+    match self.tac & 0b11 {
+      0b00 => 9, //   4096 Hz
+      0b01 => 3, // 262144 Hz
+      0b10 => 5, //  65536 Hz
+      _ => 7,    //  16384 Hz
+    }
+  }
+}
+
+impl Memory for Timer {
+  fn read(&self, address: u16) -> u8 {
+    match address {
+      Self::DIV_ADDRESS => unpack_bytes_from_double(self.counter).0,
+      Self::TIMA_ADDRESS => self.tima,
+      Self::TMA_ADDRESS => self.tma,
+      Self::TAC_ADDRESS => self.tac | Self::TAC_UNUSED_BITS,
+      _ => unreachable!("address '0x{:x}' is not a timer register", address),
+    }
+  }
+
+  fn write(&mut self, address: u16, value: u8) {
+    match address {
+      // writing any value to DIV resets the whole counter
+      Self::DIV_ADDRESS => self.reset_counter(),
+      Self::TIMA_ADDRESS => self.tima = value,
+      Self::TMA_ADDRESS => self.tma = value,
+      Self::TAC_ADDRESS => self.write_tac(value),
+      _ => unreachable!("address '0x{:x}' is not a timer register", address),
+    }
+  }
+}
+
+impl Peripheral for Timer {
+  fn claims(&self, address: u16) -> bool {
+    (Self::DIV_ADDRESS..=Self::TAC_ADDRESS).contains(&address)
+  }
+
+  fn tick(&mut self, n_cycles: u8, _double_speed: bool) -> Option<Interrupt> {
+    Some(Interrupt::Timer).filter(|_| self.step(n_cycles))
+  }
+}
+
+impl SaveState for Timer {
+  fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+    write_u16(w, self.counter)?;
+    w.write_all(&[self.tima, self.tma, self.tac])
+  }
+
+  fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+    self.counter = read_u16(r)?;
+    self.tima = read_u8(r)?;
+    self.tma = read_u8(r)?;
+    self.tac = read_u8(r)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn div_write_resets_internal_counter() {
+    let mut timer = Timer::default();
+    timer.step(255);
+    timer.step(255);
+    assert_eq!(timer.internal_counter(), 510);
+    assert_eq!(timer.read(Timer::DIV_ADDRESS), 0x01);
+
+    timer.write(Timer::DIV_ADDRESS, 0xAB);
+    assert_eq!(timer.internal_counter(), 0);
+    assert_eq!(timer.read(Timer::DIV_ADDRESS), 0x00);
+
+    for _ in 0..4 {
+      timer.step(64);
+    }
+    assert_eq!(timer.internal_counter(), 256);
+    assert_eq!(timer.read(Timer::DIV_ADDRESS), 0x01);
+  }
+
+  #[test]
+  fn div_write_clocks_tima_on_falling_edge() {
+    // 262144 Hz, bit 3 of the counter
+    let mut timer = Timer { tac: 0b101, ..Timer::default() };
+    timer.step(8);
+    assert_eq!(timer.tima, 0);
+
+    timer.write(Timer::DIV_ADDRESS, 0);
+    assert_eq!(timer.tima, 1);
+
+    timer.step(4);
+    timer.write(Timer::DIV_ADDRESS, 0);
+    assert_eq!(timer.tima, 1);
+  }
+
+  #[test]
+  fn tima_overflow_from_div_write_is_reported() {
+    let mut timer = Timer { tac: 0b101, tima: 0xFF, tma: 0x42, ..Timer::default() };
+    timer.step(8);
+    timer.write(Timer::DIV_ADDRESS, 0);
+    assert_eq!(timer.tima, 0x42);
+    assert!(timer.step(1));
+    assert!(!timer.step(1));
+  }
+
+  #[test]
+  fn tima_frequencies() {
+    for &(tac, period) in [(0b100, 1024), (0b101, 16), (0b110, 64), (0b111, 256)].iter() {
+      let mut timer = Timer { tac, ..Timer::default() };
+      for _ in 0..period * 3 / 4 {
+        timer.step(4);
+      }
+      assert_eq!(timer.tima, 3, "TAC {:03b}", tac);
+    }
+  }
+
+  #[test]
+  fn tac_write_clocks_tima_on_falling_edge() {
+    // bit 3 set, then disabling the timer
+    let mut timer = Timer { tac: 0b101, ..Timer::default() };
+    timer.step(8);
+    timer.write(Timer::TAC_ADDRESS, 0b001);
+    assert_eq!(timer.tima, 1);
+
+    // switching to bit 9, which is clear
+    let mut timer = Timer { tac: 0b101, ..Timer::default() };
+    timer.step(8);
+    timer.write(Timer::TAC_ADDRESS, 0b100);
+    assert_eq!(timer.tima, 1);
+
+    // enabling the timer is no edge
+    let mut timer = Timer::default();
+    timer.step(8);
+    timer.write(Timer::TAC_ADDRESS, 0b101);
+    assert_eq!(timer.tima, 0);
+  }
+}
@@ -0,0 +1,191 @@
+use {
+  crate::{cpu::CPU, disasm::{disassemble, Radix}, util::*, Gameboy},
+  std::{
+    fmt,
+    io::{self, Write},
+  },
+};
+
+/// The registers before an instruction executes and the four bytes from PC, what gameboy-doctor compares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+  pub af: u16,
+  pub bc: u16,
+  pub de: u16,
+  pub hl: u16,
+  pub sp: u16,
+  pub pc: u16,
+  pub pcmem: [u8; 4],
+}
+
+impl Snapshot {
+  /// Snapshot `cpu`, reading the bytes at PC through `memory` as the CPU would
+  pub fn of(cpu: &CPU, memory: &impl Memory) -> Self {
+    let mut pcmem = [0; 4];
+    for (i, byte) in pcmem.iter_mut().enumerate() {
+      *byte = memory.read(cpu.pc.wrapping_add(i as u16));
+    }
+    Self { af: cpu.af, bc: cpu.bc, de: cpu.de, hl: cpu.hl, sp: cpu.sp, pc: cpu.pc, pcmem }
+  }
+}
+
+/// The gameboy-doctor log line for the snapshot
+///
+/// e.g. `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`
+impl fmt::Display for Snapshot {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let (a, flags) = unpack_bytes_from_double(self.af);
+    let (b, c) = unpack_bytes_from_double(self.bc);
+    let (d, e) = unpack_bytes_from_double(self.de);
+    let (h, l) = unpack_bytes_from_double(self.hl);
+    let [m0, m1, m2, m3] = self.pcmem;
+    write!(
+      f,
+      "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+      a, flags, b, c, d, e, h, l, self.sp, self.pc, m0, m1, m2, m3
+    )
+  }
+}
+
+/// Format the state of the CPU the way gameboy-doctor logs expect, see `Snapshot`
+pub fn doctor_line(gameboy: &Gameboy) -> String {
+  Snapshot::of(&gameboy.cpu, &gameboy.mmu).to_string()
+}
+
+/// Receives a `Snapshot` before every instruction the gameboy executes, see `Gameboy::set_trace_sink`
+pub trait TraceSink {
+  fn trace(&mut self, snapshot: &Snapshot);
+}
+
+impl<F: FnMut(&Snapshot)> TraceSink for F {
+  fn trace(&mut self, snapshot: &Snapshot) {
+    self(snapshot)
+  }
+}
+
+/// Writes a gameboy-doctor log line for every snapshot
+///
+/// Writing stops at the first error, which `error` then returns.
+#[derive(Debug)]
+pub struct DoctorLog<W: Write> {
+  writer: W,
+  error: Option<io::Error>,
+}
+
+impl<W: Write> DoctorLog<W> {
+  pub fn new(writer: W) -> Self {
+    Self { writer, error: None }
+  }
+
+  /// The error that stopped the log, if one has
+  pub fn error(&self) -> Option<&io::Error> {
+    self.error.as_ref()
+  }
+}
+
+impl<W: Write> TraceSink for DoctorLog<W> {
+  fn trace(&mut self, snapshot: &Snapshot) {
+    if self.error.is_none() {
+      self.error = writeln!(self.writer, "{}", snapshot).err();
+    }
+  }
+}
+
+/// Streams a doctor line and the disassembly of every Nth instruction executed
+#[derive(Debug)]
+pub struct Tracer<W: Write> {
+  writer: W,
+  every: u64,
+  count: u64,
+  radix: Radix,
+}
+
+impl<W: Write> Tracer<W> {
+  /// Trace every `every`th instruction into `writer`
+  pub fn new(writer: W, every: u64) -> Self {
+    Self { writer, every: every.max(1), count: 0, radix: Radix::default() }
+  }
+
+  /// Write the operands of traced instructions in `radix`
+  pub fn with_radix(self, radix: Radix) -> Self {
+    Self { radix, ..self }
+  }
+
+  /// Record the instruction `gameboy` is about to execute, call this before each step
+  pub fn trace(&mut self, gameboy: &Gameboy) -> io::Result<()> {
+    let traced = self.count.is_multiple_of(self.every);
+    self.count += 1;
+    if !traced {
+      return Ok(());
+    }
+    let (instruction, _) = disassemble(gameboy.cpu.pc, &gameboy.mmu);
+    writeln!(self.writer, "{} | {}", doctor_line(gameboy), instruction.display(self.radix))
+  }
+
+  pub fn into_inner(self) -> W {
+    self.writer
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use {super::*, crate::cartridge::Cartridge};
+
+  fn nop_gameboy() -> Gameboy {
+    let mut gameboy = Gameboy::new_with_cartridge(Cartridge::maybe_from_bytes(&[0; 0x8000]).unwrap());
+    gameboy.cpu.pc = 0x100;
+    gameboy
+  }
+
+  fn trace_lines(every: u64, n_instructions: usize) -> Vec<String> {
+    let mut gameboy = nop_gameboy();
+    let mut tracer = Tracer::new(vec![], every);
+    for _ in 0..n_instructions {
+      tracer.trace(&gameboy).unwrap();
+      gameboy.step().unwrap();
+    }
+    String::from_utf8(tracer.into_inner()).unwrap().lines().map(String::from).collect()
+  }
+
+  #[test]
+  fn traces_every_instruction() {
+    let lines = trace_lines(1, 5);
+    assert_eq!(lines.len(), 5);
+    assert_eq!(lines[0], "A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:0100 PCMEM:00,00,00,00 | NOP");
+    assert!(lines[4].contains("PC:0104"));
+  }
+
+  #[test]
+  fn throttled_trace_skips_instructions() {
+    assert_eq!(trace_lines(3, 10).len(), 4);
+  }
+
+  #[test]
+  fn trace_sink_sees_every_step() {
+    let mut gameboy = nop_gameboy();
+    let lines = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let sink_lines = lines.clone();
+    gameboy.set_trace_sink(move |snapshot: &Snapshot| sink_lines.lock().unwrap().push(snapshot.to_string()));
+    for _ in 0..3 {
+      gameboy.step().unwrap();
+    }
+    let lines = lines.lock().unwrap();
+    assert_eq!(*lines, trace_lines(1, 3).iter().map(|x| x.split(" | ").next().unwrap().to_string()).collect::<Vec<_>>());
+
+    gameboy.clear_trace_sink();
+    gameboy.step().unwrap();
+    assert_eq!(lines.len(), 3);
+  }
+
+  #[test]
+  fn doctor_log_writes_a_line_per_snapshot() {
+    let gameboy = Gameboy::new_for_testing(&[0x00; 0x8000]);
+    let mut log = DoctorLog::new(vec![]);
+    log.trace(&Snapshot::of(&gameboy.cpu, &gameboy.mmu));
+    assert!(log.error().is_none());
+    assert_eq!(
+      String::from_utf8(log.writer).unwrap(),
+      "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,00,00,00\n"
+    );
+  }
+}
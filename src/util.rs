@@ -22,7 +22,11 @@ pub fn set_upper(target: u16, value: u8) -> u16 {
 }
 
 pub fn set_bit(target: u16, n: u8, value: bool) -> u16 {
-  target | (1 << n)
+  if value {
+    target | (1 << n)
+  } else {
+    target & !(1 << n)
+  }
 }
 
 pub fn get_bit(target: u16, n: u8) -> bool {
@@ -32,16 +36,83 @@ pub fn get_bit(target: u16, n: u8) -> bool {
 pub trait Memory {
   fn read(&self, address: u16) -> u8;
 
+  /// Read the little endian word at `address`, the low byte at `address` and the high byte after it
+  ///
+  /// The high byte of a read at 0xFFFF is taken from 0x0000, like the CPU's 16 bit address bus.
+  /// Each byte goes through `read` on its own, so a pair straddling two regions reads each from its own.
+  fn read_u16_le(&self, address: u16) -> u16 {
+    pack_bytes_into_double(self.read(address.wrapping_add(1)), self.read(address))
+  }
+
+  /// Write `value` little endian at `address`, wrapping from 0xFFFF to 0x0000 like `read_u16_le`
+  fn write_u16_le(&mut self, address: u16, value: u16) {
+    let (upper, lower) = unpack_bytes_from_double(value);
+    self.write(address, lower);
+    self.write(address.wrapping_add(1), upper);
+  }
+
+  /// Read the two bytes at `address` and the one after it, big endian
+  ///
+  /// The second byte of a read at 0xFFFF is taken from 0x0000, like the CPU's 16 bit address bus.
+  /// Each byte goes through `read` on its own, so a pair straddling two regions reads each from its own.
+  #[deprecated(note = "reads big endian, the opposite of the CPU, use `read_u16_le`")]
   fn read_double(&self, address: u16) -> u16 {
-    pack_bytes_into_double(self.read(address), self.read(address + 1))
+    pack_bytes_into_double(self.read(address), self.read(address.wrapping_add(1)))
   }
 
   fn write(&mut self, address: u16, value: u8);
 
+  /// Write two bytes at `address` and the one after it big endian, wrapping from 0xFFFF to 0x0000 like
+  /// `read_double`
+  #[deprecated(note = "writes big endian, the opposite of the CPU, use `write_u16_le`")]
   fn write_double(&mut self, address: u16, value: u16) {
     let (upper, lower) = unpack_bytes_from_double(value);
     self.write(address, upper);
-    self.write(address + 1, lower);
+    self.write(address.wrapping_add(1), lower);
+  }
+
+  /// Read `address` without taking a machine cycle on the bus, for the CPU checking IF and IE
+  /// between instructions
+  fn read_internal(&self, address: u16) -> u8 {
+    self.read(address)
+  }
+
+  /// Write `address` without taking a machine cycle on the bus, for the CPU acknowledging an interrupt
+  fn write_internal(&mut self, address: u16, value: u8) {
+    self.write(address, value)
+  }
+}
+
+/// 64KB of plain RAM with no banking or memory mapped registers
+///
+/// Lets the CPU run without a cartridge or bios, handy for testing instructions in isolation
+#[derive(Debug, Clone)]
+pub struct FlatMemory(pub [u8; FlatMemory::SIZE]);
+
+impl FlatMemory {
+  pub const SIZE: usize = 0x10000;
+
+  /// Create a memory with `program` copied in at `address`
+  pub fn with_program(address: u16, program: &[u8]) -> Self {
+    let mut memory = Self::default();
+    memory.0[address as usize..address as usize + program.len()].copy_from_slice(program);
+    memory
+  }
+}
+
+impl Default for FlatMemory {
+  fn default() -> Self {
+    FlatMemory([0; Self::SIZE])
+  }
+}
+
+impl Memory for FlatMemory {
+  fn read(&self, address: u16) -> u8 {
+    self.0[address as usize]
+  }
+
+  fn write(&mut self, address: u16, value: u8) {
+    self.0[address as usize] = value;
   }
 }
 
@@ -108,4 +179,31 @@ mod test {
     let (upper, lower) = unpack_bytes_from_double(double);
     double == pack_bytes_into_double(upper, lower)
   }
+
+  #[test]
+  #[allow(deprecated)]
+  fn doubles_wrap_at_top_of_memory() {
+    let mut memory = FlatMemory::default();
+    memory.0[0xFFFF] = 0x12;
+    memory.0[0x0000] = 0x34;
+    assert_eq!(memory.read_double(0xFFFF), 0x1234);
+
+    memory.write_double(0xFFFF, 0xABCD);
+    assert_eq!((memory.0[0xFFFF], memory.0[0x0000]), (0xAB, 0xCD));
+  }
+
+  #[quickcheck]
+  fn u16_le_round_trips(address: u16, value: u16) -> bool {
+    let mut memory = FlatMemory::default();
+    memory.write_u16_le(address, value);
+    memory.read_u16_le(address) == value
+  }
+
+  #[quickcheck]
+  fn u16_le_puts_low_byte_at_lower_address(address: u16, value: u16) -> bool {
+    let mut memory = FlatMemory::default();
+    memory.write_u16_le(address, value);
+    let (upper, lower) = unpack_bytes_from_double(value);
+    memory.read(address) == lower && memory.read(address.wrapping_add(1)) == upper
+  }
 }
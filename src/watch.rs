@@ -0,0 +1,172 @@
+use {
+  crate::util::Memory,
+  derivative::Derivative,
+  std::{
+    cell::Cell,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+  },
+};
+
+/// A CPU memory access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Access {
+  Read,
+  Write,
+}
+
+/// Which accesses a `Watchpoint` fires on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WatchKind {
+  Read,
+  Write,
+  ReadWrite,
+}
+
+impl WatchKind {
+  fn matches(self, access: Access) -> bool {
+    match self {
+      WatchKind::Read => access == Access::Read,
+      WatchKind::Write => access == Access::Write,
+      WatchKind::ReadWrite => true,
+    }
+  }
+}
+
+/// Stops `Gameboy::step_or_break` after an instruction that accesses `address`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Watchpoint {
+  pub address: u16,
+  pub kind: WatchKind,
+  /// Only fire when this is the value read or written
+  pub value: Option<u8>,
+}
+
+impl Watchpoint {
+  fn matches(&self, address: u16, access: Access, value: u8) -> bool {
+    self.address == address && self.kind.matches(access) && self.value.is_none_or(|x| x == value)
+  }
+}
+
+/// The first access an instruction made that a watchpoint fired on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WatchHit {
+  pub address: u16,
+  pub access: Access,
+  /// The value read or written
+  pub value: u8,
+}
+
+/// Called with each access an `AccessHook` matches
+pub type AccessCallback = Arc<Mutex<dyn FnMut(WatchHit) + Send>>;
+
+/// Calls back with every CPU access of `kind` to an address in `range`, without stopping anything
+///
+/// Only the CPU's accesses are seen, not those of OAM DMA, VRAM DMA or the PPU.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct AccessHook {
+  pub range: RangeInclusive<u16>,
+  pub kind: WatchKind,
+  #[derivative(Debug = "ignore")]
+  callback: AccessCallback,
+}
+
+impl AccessHook {
+  pub fn new(range: RangeInclusive<u16>, kind: WatchKind, callback: impl FnMut(WatchHit) + Send + 'static) -> Self {
+    Self { range, kind, callback: Arc::new(Mutex::new(callback)) }
+  }
+}
+
+/// `memory` with the first access matching one of `watchpoints` recorded in `hit`, and every access
+/// matching one of `hooks` passed to it
+pub(crate) struct Watched<'a, M> {
+  pub memory: &'a mut M,
+  pub watchpoints: &'a [Watchpoint],
+  pub hit: &'a Cell<Option<WatchHit>>,
+  pub hooks: &'a [AccessHook],
+}
+
+impl<M> Watched<'_, M> {
+  fn check(&self, address: u16, access: Access, value: u8) {
+    if self.hit.get().is_none() && self.watchpoints.iter().any(|x| x.matches(address, access, value)) {
+      self.hit.set(Some(WatchHit { address, access, value }));
+    }
+    for hook in self.hooks.iter().filter(|x| x.range.contains(&address) && x.kind.matches(access)) {
+      (hook.callback.lock().unwrap())(WatchHit { address, access, value });
+    }
+  }
+}
+
+impl<M: Memory> Memory for Watched<'_, M> {
+  fn read(&self, address: u16) -> u8 {
+    let value = self.memory.read(address);
+    self.check(address, Access::Read, value);
+    value
+  }
+
+  fn write(&mut self, address: u16, value: u8) {
+    self.check(address, Access::Write, value);
+    self.memory.write(address, value)
+  }
+
+  fn read_internal(&self, address: u16) -> u8 {
+    self.memory.read_internal(address)
+  }
+
+  fn write_internal(&mut self, address: u16, value: u8) {
+    self.memory.write_internal(address, value)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use {
+    super::*,
+    crate::util::FlatMemory,
+  };
+
+  #[test]
+  fn only_matching_accesses_are_hit() {
+    let mut memory = FlatMemory::default();
+    let watchpoints = [
+      Watchpoint { address: 0xC000, kind: WatchKind::Write, value: None },
+      Watchpoint { address: 0xC001, kind: WatchKind::ReadWrite, value: Some(0x42) },
+    ];
+    let hit = Cell::new(None);
+    let mut watched = Watched { memory: &mut memory, watchpoints: &watchpoints, hit: &hit, hooks: &[] };
+
+    watched.read(0xC000);
+    watched.write(0xC001, 0x41);
+    watched.read(0xC002);
+    assert_eq!(hit.get(), None);
+
+    watched.write(0xC001, 0x42);
+    assert_eq!(hit.get(), Some(WatchHit { address: 0xC001, access: Access::Write, value: 0x42 }));
+    // the first hit is kept
+    watched.write(0xC000, 0x00);
+    assert_eq!(hit.get().unwrap().address, 0xC001);
+  }
+
+  #[test]
+  fn hooks_see_every_matching_access() {
+    let mut memory = FlatMemory::default();
+    let seen = Arc::new(Mutex::new(vec![]));
+    let sink = seen.clone();
+    let hooks = [AccessHook::new(0xC000..=0xC0FF, WatchKind::Write, move |x| sink.lock().unwrap().push(x))];
+    let hit = Cell::new(None);
+    let mut watched = Watched { memory: &mut memory, watchpoints: &[], hit: &hit, hooks: &hooks };
+
+    watched.write(0xC000, 1);
+    watched.read(0xC000);
+    watched.write(0xC100, 2);
+    watched.write(0xC0FF, 3);
+    let write = |address, value| WatchHit { address, access: Access::Write, value };
+    assert_eq!(*seen.lock().unwrap(), [write(0xC000, 1), write(0xC0FF, 3)]);
+    assert_eq!(hit.get(), None);
+  }
+}
@@ -0,0 +1,42 @@
+//! Runs every test ROM in `tests/roms`, or in the directory named by `GAMEBOY_TEST_ROMS`, and fails
+//! unless they all pass
+//!
+//! Drop blargg's or Mooneye's .gb files into the directory and run
+//!
+//! ```text
+//! cargo test --release --test rom_harness -- --nocapture
+//! ```
+//!
+//! to see the pass/fail matrix. With no directory there is nothing to run and the test passes.
+
+use {
+  gameboy::harness::{self, Verdict},
+  std::{
+    env,
+    path::{Path, PathBuf},
+  },
+};
+
+fn rom_dir() -> PathBuf {
+  env::var_os("GAMEBOY_TEST_ROMS")
+    .map(PathBuf::from)
+    .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms"))
+}
+
+#[test]
+fn test_roms_pass() {
+  let dir = rom_dir();
+  if !dir.is_dir() {
+    println!("no test ROM directory at {}, skipping", dir.display());
+    return;
+  }
+
+  let results = harness::run_dir(&dir, harness::DEFAULT_TIMEOUT_CYCLES).expect("failed to read the test ROM directory");
+  println!("{}", harness::matrix(&results));
+  let failed: Vec<_> = results
+    .iter()
+    .filter(|(_, report)| report.verdict != Verdict::Passed)
+    .map(|(path, report)| format!("{}: {}\n{}", path.display(), report.verdict, report.serial_text()))
+    .collect();
+  assert!(failed.is_empty(), "{} of {} test ROMs didn't pass\n{}", failed.len(), results.len(), failed.join("\n"));
+}